@@ -24,8 +24,16 @@ use kaspa_rpc_core::{
 use kaspa_grpc_client::GrpcClient;
 use kaspa_bip32::{Mnemonic, Language, ExtendedPrivateKey, ChildNumber, secp256k1::Keypair};
 // Import rusty-kaspa's automatic fee calculation functions
-use kaspa_wallet_core::tx::mass::{MassCalculator, calc_minimum_required_transaction_relay_fee};
+use kaspa_wallet_core::tx::mass::{MassCalculator, calc_minimum_required_transaction_relay_fee, MAXIMUM_STANDARD_TRANSACTION_MASS};
+use kaspa_consensus_core::config::params::Params;
+use futures::stream::{self, StreamExt};
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::future::Future;
+use std::ops::Range;
 
 // 🧪 ERROR HANDLING TEST MODES - ALL TESTS COMPLETED ✅
 const TEST_INSUFFICIENT_FUNDS: bool = false;  // ✅ COMPLETED: Graceful error handling
@@ -41,6 +49,265 @@ const COMPANY_MNEMONIC: &str = "mutual alley control inspire cloth alcohol ventu
 const MASTER_ADDRESS: &str = "kaspatest:qpxm5tpyg8p6z7f6hy9mtlwz2es03cqtavaldsctcdltmnz6yfz6gvurgpmem";
 const COMPANY_ADDRESS: &str = "kaspatest:qp0q4mdtas30e4aeqq0j3dt8nd2nqwjsewgkcxty0h3zjflvpkz6wce3qgucz";
 
+// UTXO fetch batching: addresses per get_utxos_by_addresses call and how many
+// calls may run at once (override with KASPA_UTXO_BATCH_SIZE / KASPA_UTXO_PARALLEL)
+const DEFAULT_UTXO_BATCH_SIZE: usize = 100;
+const DEFAULT_UTXO_PARALLEL: usize = 1;
+
+// Value sent Company → Master with each supply chain event unless --anchor-amount is given
+const DEFAULT_ANCHOR_SOMPIS: u64 = 50_000_000; // 0.5 KAS
+// Smallest standard output the node relays: a P2PK output plus the input that
+// later spends it is ~200 bytes, and outputs worth under 3 sompis/byte are dust
+const DUST_THRESHOLD_SOMPIS: u64 = 600;
+
+// Most extra small UTXOs an anchor sweeps in with --consolidate-opportunistically
+const OPPORTUNISTIC_EXTRA_INPUTS: usize = 4;
+
+// Default top-up sent by --auto-fund-from when no --auto-fund-amount is given
+const DEFAULT_AUTO_FUND_KAS: f64 = 1.0;
+// How long to wait for the funding UTXO to show up before retrying
+const AUTO_FUND_POLL_ATTEMPTS: u32 = 30;
+
+// Binary supply chain payloads start with this marker followed by
+// "<encoding>:<event_type>\n" and the raw bytes; UTF-8 payloads stay plain JSON
+const BINARY_PAYLOAD_MARKER: &[u8] = b"KPMBIN1:";
+
+// Which wallet UTXOs a transaction spends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CoinSelection {
+    // Every UTXO the wallet holds
+    All,
+    // The largest UTXOs covering amount + fee, plus small extras when opportunistic
+    LargestFirst { opportunistic: bool },
+}
+
+// How the supply chain event data argument becomes transaction payload bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PayloadEncoding {
+    // Event data is JSON text, wrapped in the {"type","data"} envelope
+    Utf8,
+    // Event data is base64 / hex text decoded to raw bytes (compressed or encrypted payloads)
+    Base64,
+    Hex,
+}
+
+impl PayloadEncoding {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "utf8" => Ok(PayloadEncoding::Utf8),
+            "base64" => Ok(PayloadEncoding::Base64),
+            "hex" => Ok(PayloadEncoding::Hex),
+            other => Err(format!("Unknown --payload-encoding {} (expected utf8, base64 or hex)", other)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PayloadEncoding::Utf8 => "utf8",
+            PayloadEncoding::Base64 => "base64",
+            PayloadEncoding::Hex => "hex",
+        }
+    }
+}
+
+// The event data argument as the bytes it stands for: UTF-8 text as given,
+// base64 / hex decoded
+fn event_data_bytes(encoding: PayloadEncoding, event_data: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(match encoding {
+        PayloadEncoding::Utf8 => event_data.as_bytes().to_vec(),
+        PayloadEncoding::Base64 => general_purpose::STANDARD.decode(event_data.trim())?,
+        PayloadEncoding::Hex => hex::decode(event_data.trim())?,
+    })
+}
+
+// Turn the event data argument into the bytes embedded in the transaction
+fn encode_event_payload(encoding: PayloadEncoding, event_type: &str, event_data: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if encoding == PayloadEncoding::Utf8 {
+        return Ok(format!(r#"{{"type":"{}","data":{}}}"#, event_type, event_data).into_bytes());
+    }
+    let raw = event_data_bytes(encoding, event_data)?;
+    let mut payload = BINARY_PAYLOAD_MARKER.to_vec();
+    payload.extend_from_slice(format!("{}:{}\n", encoding.name(), event_type).as_bytes());
+    payload.extend_from_slice(&raw);
+    Ok(payload)
+}
+
+// Reader side of encode_event_payload: returns the encoding, event type and
+// the event data rendered back in its original encoding
+fn decode_event_payload(payload: &[u8]) -> Result<(PayloadEncoding, String, String), Box<dyn std::error::Error>> {
+    let Some(rest) = payload.strip_prefix(BINARY_PAYLOAD_MARKER) else {
+        let envelope: serde_json::Value = serde_json::from_slice(payload)?;
+        let event_type = envelope["type"].as_str().ok_or("payload has no event type")?.to_string();
+        return Ok((PayloadEncoding::Utf8, event_type, envelope["data"].to_string()));
+    };
+    let header_end = rest.iter().position(|b| *b == b'\n').ok_or("binary payload header is not terminated")?;
+    let header = std::str::from_utf8(&rest[..header_end])?;
+    let (encoding, event_type) = header.split_once(':').ok_or("binary payload header has no event type")?;
+    let encoding = PayloadEncoding::parse(encoding)?;
+    let raw = &rest[header_end + 1..];
+    let data = match encoding {
+        PayloadEncoding::Base64 => general_purpose::STANDARD.encode(raw),
+        _ => hex::encode(raw),
+    };
+    Ok((encoding, event_type.to_string(), data))
+}
+
+// `--commit-hash` payloads start with this marker followed by
+// "<content_type>:<event_type>\n" and the 32-byte sha256 of the event data,
+// which stays off-chain in the bus
+const COMMITMENT_PAYLOAD_MARKER: &[u8] = b"KPMCMT1:";
+
+// Hash-only anchor of an off-chain supply chain event
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Commitment {
+    content_type: String,
+    event_type: String,
+    hash: [u8; 32],
+}
+
+impl Commitment {
+    // Commit to the event data bytes (decoded for base64 / hex), so the
+    // off-chain copy verifies as-is
+    fn of_event(encoding: PayloadEncoding, event_type: &str, event_data: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let content_type = match encoding {
+            PayloadEncoding::Utf8 => "application/json",
+            _ => "application/octet-stream",
+        };
+        Ok(Commitment {
+            content_type: content_type.to_string(),
+            event_type: event_type.to_string(),
+            hash: Sha256::digest(event_data_bytes(encoding, event_data)?).into(),
+        })
+    }
+
+    fn to_payload(&self) -> Vec<u8> {
+        let mut payload = COMMITMENT_PAYLOAD_MARKER.to_vec();
+        payload.extend_from_slice(format!("{}:{}\n", self.content_type, self.event_type).as_bytes());
+        payload.extend_from_slice(&self.hash);
+        payload
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let rest = payload.strip_prefix(COMMITMENT_PAYLOAD_MARKER).ok_or("transaction payload is not a hash commitment")?;
+        let header_end = rest.iter().position(|b| *b == b'\n').ok_or("commitment header is not terminated")?;
+        let header = std::str::from_utf8(&rest[..header_end])?;
+        let (content_type, event_type) = header.split_once(':').ok_or("commitment header has no event type")?;
+        let hash = rest[header_end + 1..].try_into().map_err(|_| "commitment hash is not 32 bytes")?;
+        Ok(Commitment { content_type: content_type.to_string(), event_type: event_type.to_string(), hash })
+    }
+
+    // Whether `offchain` is exactly the event data that was committed
+    fn matches(&self, offchain: &[u8]) -> bool {
+        <[u8; 32]>::from(Sha256::digest(offchain)) == self.hash
+    }
+}
+
+// Company wallet mapping used by `--supply-chain --company-id <id>`
+// (override the path with --wallet-map or KASPA_WALLET_MAP)
+const DEFAULT_WALLET_MAP: &str = "company_wallets.json";
+
+// One company's wallet in the mapping file. The mnemonic comes from exactly one
+// of `mnemonic`, `mnemonic_env` or `mnemonic_file`; `address`, when present,
+// must match the derived testnet address
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+struct CompanyWalletEntry {
+    #[serde(default)]
+    mnemonic: Option<String>,
+    #[serde(default)]
+    mnemonic_env: Option<String>,
+    #[serde(default)]
+    mnemonic_file: Option<String>,
+    #[serde(default)]
+    derivation_index: u32,
+    #[serde(default)]
+    address: Option<String>,
+}
+
+// Company id → wallet, e.g. {"42": {"mnemonic_env": "ACME_MNEMONIC", "address": "kaspatest:..."}}
+type CompanyWalletMap = HashMap<String, CompanyWalletEntry>;
+
+fn load_wallet_map(path: &str) -> Result<CompanyWalletMap, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read wallet map {}: {}", path, e))?;
+    Ok(serde_json::from_str(&text).map_err(|e| format!("Invalid wallet map {}: {}", path, e))?)
+}
+
+// Select the company's keypair and sending address, checking the key belongs
+// to the network this broadcaster submits to
+fn resolve_company_wallet(map: &CompanyWalletMap, company_id: &str) -> Result<(Keypair, Address), Box<dyn std::error::Error>> {
+    let entry = map.get(company_id).ok_or_else(|| format!("No wallet mapped for company {}", company_id))?;
+    let mnemonic = match (&entry.mnemonic, &entry.mnemonic_env, &entry.mnemonic_file) {
+        (Some(m), None, None) => m.clone(),
+        (None, Some(var), None) => env::var(var).map_err(|_| format!("Company {}: environment variable {} is not set", company_id, var))?,
+        (None, None, Some(file)) => std::fs::read_to_string(file).map_err(|e| format!("Company {}: cannot read {}: {}", company_id, file, e))?.trim().to_string(),
+        _ => return Err(format!("Company {}: set exactly one of mnemonic, mnemonic_env or mnemonic_file", company_id).into()),
+    };
+    let keypair = generate_keypair_from_mnemonic(&mnemonic, entry.derivation_index)?;
+    let derived = address_from_keypair(&keypair);
+    if let Some(expected) = &entry.address {
+        let expected = Address::try_from(expected.as_str()).map_err(|e| format!("Company {}: invalid address {}: {}", company_id, expected, e))?;
+        if expected.prefix != derived.prefix {
+            return Err(format!("Company {}: address {} is not on {} network", company_id, expected, derived.prefix).into());
+        }
+        if expected != derived {
+            return Err(format!("Company {}: key derives {} but the mapping expects {}", company_id, derived, expected).into());
+        }
+    }
+    Ok((keypair, derived))
+}
+
+// Failures the message bus needs to tell apart from generic errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BroadcasterError {
+    // The sending wallet has no UTXOs at all and must be funded first
+    WalletUnfunded { address: String },
+    // The transaction is heavier than the network relays; the node would reject it
+    MassLimitExceeded { mass: u64, limit: u64 },
+}
+
+impl BroadcasterError {
+    fn code(&self) -> &'static str {
+        match self {
+            BroadcasterError::WalletUnfunded { .. } => "WALLET_UNFUNDED",
+            BroadcasterError::MassLimitExceeded { .. } => "MASS_LIMIT_EXCEEDED",
+        }
+    }
+}
+
+impl fmt::Display for BroadcasterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BroadcasterError::WalletUnfunded { address } => write!(f, "wallet {} has no UTXOs and needs funding", address),
+            BroadcasterError::MassLimitExceeded { mass, limit } => write!(
+                f,
+                "transaction mass {} exceeds the network limit of {}; split the payload into chunks or compress it",
+                mass, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BroadcasterError {}
+
+// Largest transaction the network relays (the node rejected anything over this
+// in TEST_LARGE_PAYLOAD): the standard-transaction mass bound, never more than
+// a block of this network can hold
+fn network_mass_limit(network_id: NetworkId) -> u64 {
+    let params: Params = network_id.into();
+    MAXIMUM_STANDARD_TRANSACTION_MASS.min(params.max_block_mass)
+}
+
+// Refuse to submit a transaction the node would reject for its mass
+fn check_mass(mass: u64, limit: u64) -> Result<(), BroadcasterError> {
+    if mass > limit {
+        return Err(BroadcasterError::MassLimitExceeded { mass, limit });
+    }
+    Ok(())
+}
+
+fn as_broadcaster_error(err: &(dyn std::error::Error + 'static)) -> Option<&BroadcasterError> {
+    err.downcast_ref::<BroadcasterError>()
+}
+
 // Generate keypair using proper BIP39 derivation (matching kaspa-cli)
 fn generate_keypair_from_mnemonic(mnemonic_str: &str, derivation_index: u32) -> Result<Keypair, Box<dyn std::error::Error>> {
     println!("🔍 Parsing mnemonic: {} words", mnemonic_str.split_whitespace().count());
@@ -178,6 +445,108 @@ fn rpc_utxos_to_utxo_entries(utxos: &[RpcUtxosByAddressesEntry]) -> Vec<UtxoEntr
     }).collect()
 }
 
+// UTXO fetch batch size and parallelism, from the environment or the defaults
+fn utxo_fetch_config() -> (usize, usize) {
+    let var = |name: &str, default: usize| env::var(name).ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(default);
+    (var("KASPA_UTXO_BATCH_SIZE", DEFAULT_UTXO_BATCH_SIZE), var("KASPA_UTXO_PARALLEL", DEFAULT_UTXO_PARALLEL))
+}
+
+// Split `addresses` into batches of at most `batch_size`, fetch up to `parallel`
+// batches at once, and merge the results in address order
+async fn fetch_utxos_batched<A, E, F, Fut>(addresses: &[A], batch_size: usize, parallel: usize, fetch: F) -> Result<Vec<E>, Box<dyn std::error::Error>>
+where
+    A: Clone,
+    F: Fn(Vec<A>) -> Fut,
+    Fut: Future<Output = Result<Vec<E>, Box<dyn std::error::Error>>>,
+{
+    let batches: Vec<Vec<A>> = addresses.chunks(batch_size.max(1)).map(|c| c.to_vec()).collect();
+    let results: Vec<_> = stream::iter(batches).map(&fetch).buffered(parallel.max(1)).collect().await;
+    let mut merged = Vec::new();
+    for batch in results {
+        merged.extend(batch?);
+    }
+    Ok(merged)
+}
+
+async fn fetch_utxos(rpc_client: &GrpcClient, addresses: &[Address]) -> Result<Vec<RpcUtxosByAddressesEntry>, Box<dyn std::error::Error>> {
+    let (batch_size, parallel) = utxo_fetch_config();
+    fetch_utxos_batched(addresses, batch_size, parallel, |batch| async move {
+        Ok(rpc_client.get_utxos_by_addresses_call(None, GetUtxosByAddressesRequest::new(batch)).await?.entries)
+    }).await
+}
+
+// Testnet P2PK address of a derived keypair
+fn address_from_keypair(keypair: &Keypair) -> Address {
+    Address::new(
+        kaspa_addresses::Prefix::Testnet,
+        kaspa_addresses::Version::PubKey,
+        keypair.x_only_public_key().0.serialize().as_slice(),
+    )
+}
+
+// Connect to the local Kaspa node over gRPC
+async fn connect_rpc() -> Result<GrpcClient, Box<dyn std::error::Error>> {
+    println!("🔌 Connecting to Kaspa node...");
+    let rpc_client = GrpcClient::connect_with_args(
+        NotificationMode::Direct,
+        "grpc://127.0.0.1:16210".to_string(),
+        None,
+        true,
+        None,
+        false,
+        Some(500_000),
+        Default::default(),
+    ).await?;
+    println!("✅ Connected to Kaspa node!");
+    Ok(rpc_client)
+}
+
+// Sign every input with the sender key (Schnorr, SIG_HASH_ALL)
+fn sign_transaction(keypair: &Keypair, tx: Transaction, entries: Vec<UtxoEntry>) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let mut mutable_tx = MutableTransaction::with_entries(tx, entries);
+
+    for i in 0..mutable_tx.tx.inputs.len() {
+        let sig_hash = calc_schnorr_signature_hash(&mutable_tx.as_verifiable(), i, SIG_HASH_ALL, &SigHashReusedValuesUnsync::new());
+        let msg = secp256k1::Message::from_digest_slice(sig_hash.as_bytes().as_slice())?;
+        let signature = keypair.sign_schnorr(msg);
+        
+        let mut sig_bytes = Vec::new();
+        sig_bytes.extend_from_slice(signature.as_ref().as_slice());
+        sig_bytes.push(SIG_HASH_ALL.to_u8());
+        
+        let mut script_builder = ScriptBuilder::new();
+        script_builder.add_data(&sig_bytes)?;
+        mutable_tx.tx.inputs[i].signature_script = script_builder.drain();
+    }
+
+    Ok(mutable_tx.tx)
+}
+
+// Convert a signed consensus transaction into its RPC form for submission
+fn to_rpc_transaction(tx: &Transaction) -> RpcTransaction {
+    RpcTransaction {
+        version: tx.version,
+        inputs: tx.inputs.iter().map(|input| RpcTransactionInput {
+            previous_outpoint: input.previous_outpoint.into(),
+            signature_script: input.signature_script.clone(),
+            sequence: input.sequence,
+            sig_op_count: input.sig_op_count,
+            verbose_data: None,
+        }).collect(),
+        outputs: tx.outputs.iter().map(|output| RpcTransactionOutput {
+            value: output.value,
+            script_public_key: output.script_public_key.clone().into(),
+            verbose_data: None,
+        }).collect(),
+        lock_time: tx.lock_time,
+        subnetwork_id: tx.subnetwork_id.clone(),
+        gas: tx.gas,
+        payload: tx.payload.clone(),
+        mass: 0,
+        verbose_data: None,
+    }
+}
+
 // 🔍 Query transaction status (for confirmation tracking)
 async fn query_transaction_status(transaction_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
     println!("🔍 QUERYING TRANSACTION STATUS");
@@ -201,6 +570,47 @@ async fn query_transaction_status(transaction_hash: &str) -> Result<(), Box<dyn
     Ok(())
 }
 
+// Check an off-chain event against the commitment anchored by `--commit-hash`.
+// Nodes only serve unconfirmed transactions by id, so a confirmed one needs its
+// payload supplied (`tx_payload`, e.g. copied from an explorer).
+async fn verify_commitment(transaction_id: &str, payload_file: &str, tx_payload: Option<Vec<u8>>) -> Result<(), Box<dyn std::error::Error>> {
+    println!("🔍 VERIFYING HASH COMMITMENT");
+    println!("============================");
+    println!("📋 Transaction: {}", transaction_id);
+    let offchain = std::fs::read(payload_file).map_err(|e| format!("Cannot read --payload {}: {}", payload_file, e))?;
+
+    let tx_payload = match tx_payload {
+        Some(payload) => payload,
+        None => {
+            let rpc_client = connect_rpc().await?;
+            let entry = rpc_client
+                .get_mempool_entry(transaction_id.parse().map_err(|_| format!("Invalid transaction id {}", transaction_id))?, true, false)
+                .await
+                .map_err(|_| format!("Transaction {} is not in the node's mempool (already in a block?); pass its payload with --tx-payload <hex>", transaction_id))?;
+            entry.transaction.payload
+        }
+    };
+    let commitment = Commitment::from_payload(&tx_payload)?;
+    let verified = commitment.matches(&offchain);
+
+    println!("COMMITMENT_RESULT_START");
+    println!("{}", serde_json::json!({
+        "verified": verified,
+        "transactionId": transaction_id,
+        "committedHash": hex::encode(commitment.hash),
+        "payloadHash": hex::encode(Sha256::digest(&offchain)),
+        "contentType": commitment.content_type,
+        "eventType": commitment.event_type,
+    }));
+    println!("COMMITMENT_RESULT_END");
+
+    if !verified {
+        return Err(format!("{} does not match the commitment anchored in {}", payload_file, transaction_id).into());
+    }
+    println!("✅ Off-chain payload matches the anchored commitment");
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments for message bus integration
@@ -217,17 +627,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     match args[1].as_str() {
         "--supply-chain" => {
-            if args.len() < 5 {
-                eprintln!("❌ Supply chain mode requires: --supply-chain <company_mnemonic> <event_data> <event_type>");
+            // Either a company mnemonic or `--company-id <id>` selects the sending wallet
+            let by_company_id = args.get(2).map(|a| a.as_str()) == Some("--company-id");
+            let first = if by_company_id { 4 } else { 3 };
+            if args.len() < first + 2 {
+                eprintln!("❌ Supply chain mode requires: --supply-chain <company_mnemonic|--company-id <id>> <event_data> <event_type>");
                 print_usage();
                 return Ok(());
             }
             
-            let company_mnemonic = &args[2];
-            let event_data = &args[3];
-            let event_type = &args[4];
+            let event_data = &args[first];
+            let event_type = &args[first + 1];
+            let options = parse_supply_chain_options(&args[first + 2..])?;
+            let (company_keypair, company_addr) = if by_company_id {
+                let map_path = options.wallet_map.clone()
+                    .or_else(|| env::var("KASPA_WALLET_MAP").ok())
+                    .unwrap_or_else(|| DEFAULT_WALLET_MAP.to_string());
+                resolve_company_wallet(&load_wallet_map(&map_path)?, &args[3])?
+            } else {
+                (generate_keypair_from_mnemonic(&args[2], 0)?, Address::try_from(COMPANY_ADDRESS)?)
+            };
             
-            submit_supply_chain_event(company_mnemonic, event_data, event_type).await?;
+            let result = submit_with_auto_fund(
+                || submit_supply_chain_event(company_keypair, company_addr.clone(), event_data, event_type, options.payload_encoding, options.anchor_amount, options.coin_selection(), options.commit_hash),
+                options.auto_fund.as_ref().map(|(master_mnemonic, amount_kas)| {
+                    move |address: String| fund_and_wait(master_mnemonic, *amount_kas, address)
+                }),
+            ).await;
+            if let Err(err) = result {
+                if let Some(known) = as_broadcaster_error(err.as_ref()) {
+                    print_error_result(known);
+                }
+                return Err(err);
+            }
         }
         "--funding" => {
             if args.len() < 4 {
@@ -242,6 +674,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             
             submit_funding_transaction(amount_kas, recipient_address).await?;
         }
+        "--consolidate" => {
+            if args.len() < 3 {
+                eprintln!("❌ Consolidate mode requires: --consolidate <wallet_mnemonic>");
+                print_usage();
+                return Ok(());
+            }
+            
+            consolidate_wallet(&args[2]).await?;
+        }
+        "--verify-commitment" => {
+            let payload_file = match args.get(3).map(|a| a.as_str()) {
+                Some("--payload") if args.len() >= 5 => &args[4],
+                _ => {
+                    eprintln!("❌ Verify commitment mode requires: --verify-commitment <transaction_id> --payload <file> [--tx-payload <hex>]");
+                    print_usage();
+                    return Ok(());
+                }
+            };
+            let tx_payload = match args.get(5).map(|a| a.as_str()) {
+                Some("--tx-payload") => Some(hex::decode(args.get(6).ok_or("--tx-payload requires a value")?.trim())?),
+                Some(other) => return Err(format!("Unknown verify commitment option: {}", other).into()),
+                None => None,
+            };
+            verify_commitment(&args[2], payload_file, tx_payload).await?;
+        }
         "--query-transaction" => {
             if args.len() < 3 {
                 eprintln!("❌ Query transaction mode requires: --query-transaction <transaction_hash>");
@@ -265,16 +722,179 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// Options accepted after the positional supply chain arguments
+#[derive(Debug, Clone, PartialEq)]
+struct SupplyChainOptions {
+    auto_fund: Option<(String, f64)>,
+    payload_encoding: PayloadEncoding,
+    anchor_amount: u64,
+    wallet_map: Option<String>,
+    largest_first: bool,
+    consolidate_opportunistically: bool,
+    commit_hash: bool,
+}
+
+impl SupplyChainOptions {
+    // Spend every UTXO unless asked to select; consolidating implies selecting,
+    // since extras only mean something when not everything is spent
+    fn coin_selection(&self) -> CoinSelection {
+        if self.largest_first || self.consolidate_opportunistically {
+            CoinSelection::LargestFirst { opportunistic: self.consolidate_opportunistically }
+        } else {
+            CoinSelection::All
+        }
+    }
+}
+
+// Parse the optional `--auto-fund-from <master_mnemonic> [--auto-fund-amount <kas>]`
+// `--payload-encoding <utf8|base64|hex>`, `--anchor-amount <kas>`,
+// `--wallet-map <path>`, `--largest-first`, `--consolidate-opportunistically`
+// and `--commit-hash` flags following the supply chain arguments
+fn parse_supply_chain_options(rest: &[String]) -> Result<SupplyChainOptions, Box<dyn std::error::Error>> {
+    let mut mnemonic = None;
+    let mut amount_kas = DEFAULT_AUTO_FUND_KAS;
+    let mut payload_encoding = PayloadEncoding::Utf8;
+    let mut anchor_amount = DEFAULT_ANCHOR_SOMPIS;
+    let mut wallet_map = None;
+    let mut largest_first = false;
+    let mut consolidate_opportunistically = false;
+    let mut commit_hash = false;
+    let mut i = 0;
+    while i < rest.len() {
+        let switch = match rest[i].as_str() {
+            "--largest-first" => Some(&mut largest_first),
+            "--consolidate-opportunistically" => Some(&mut consolidate_opportunistically),
+            "--commit-hash" => Some(&mut commit_hash),
+            _ => None,
+        };
+        if let Some(switch) = switch {
+            *switch = true;
+            i += 1;
+            continue;
+        }
+        let value = rest.get(i + 1).ok_or_else(|| format!("{} requires a value", rest[i]))?;
+        match rest[i].as_str() {
+            "--auto-fund-from" => mnemonic = Some(value.clone()),
+            "--auto-fund-amount" => amount_kas = value.parse().map_err(|_| "Invalid --auto-fund-amount. Use decimal (e.g., 0.5)")?,
+            "--payload-encoding" => payload_encoding = PayloadEncoding::parse(value)?,
+            "--wallet-map" => wallet_map = Some(value.clone()),
+            "--anchor-amount" => {
+                let kas: f64 = value.parse().map_err(|_| "Invalid --anchor-amount. Use decimal (e.g., 0.00001)")?;
+                anchor_amount = anchor_amount_sompis(kas)?;
+            }
+            other => return Err(format!("Unknown supply chain option: {}", other).into()),
+        }
+        i += 2;
+    }
+    Ok(SupplyChainOptions { auto_fund: mnemonic.map(|m| (m, amount_kas)), payload_encoding, anchor_amount, wallet_map, largest_first, consolidate_opportunistically, commit_hash })
+}
+
+// The anchor output only carries the event, so any non-dust value will do
+fn anchor_amount_sompis(kas: f64) -> Result<u64, String> {
+    let sompis = (kas * 100_000_000.0).round();
+    if !sompis.is_finite() || sompis < DUST_THRESHOLD_SOMPIS as f64 {
+        return Err(format!(
+            "--anchor-amount {} KAS is below the dust threshold of {} sompis ({} KAS)",
+            kas, DUST_THRESHOLD_SOMPIS, DUST_THRESHOLD_SOMPIS as f64 / 100_000_000.0
+        ));
+    }
+    Ok(sompis as u64)
+}
+
+// Run a submission; if it fails because the wallet is unfunded and a funder is
+// configured, fund the wallet once and retry
+async fn submit_with_auto_fund<S, SF, F, FF>(mut submit: S, fund: Option<F>) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: FnMut() -> SF,
+    SF: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+    F: FnOnce(String) -> FF,
+    FF: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+{
+    let err = match submit().await {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    let address = match as_broadcaster_error(err.as_ref()) {
+        Some(BroadcasterError::WalletUnfunded { address }) => address.clone(),
+        _ => return Err(err),
+    };
+    let Some(fund) = fund else { return Err(err) };
+    println!("💸 Wallet {} is unfunded - auto-funding from master wallet", address);
+    fund(address).await?;
+    println!("🔁 Retrying submission after funding...");
+    submit().await
+}
+
+// Send `amount_kas` from the master wallet to `address` and wait until the
+// funding UTXO is visible so the retried submission can spend it
+async fn fund_and_wait(master_mnemonic: &str, amount_kas: f64, address: String) -> Result<(), Box<dyn std::error::Error>> {
+    let master_keypair = generate_keypair_from_mnemonic(master_mnemonic, 0)?;
+    let master_addr = address_from_keypair(&master_keypair);
+    let recipient_addr = Address::try_from(address.as_str())?;
+    let funding_payload = format!(r#"{{"type":"AUTO_FUNDING","amount_kas":{},"timestamp":"{}"}}"#,
+                                  amount_kas,
+                                  chrono::Utc::now().to_rfc3339());
+    submit_transaction(
+        master_keypair,
+        master_addr,
+        recipient_addr.clone(),
+        (amount_kas * 100_000_000.0) as u64,
+        funding_payload.into_bytes(),
+        "auto-funding transaction",
+        CoinSelection::All
+    ).await?;
+
+    let rpc_client = connect_rpc().await?;
+    for _ in 0..AUTO_FUND_POLL_ATTEMPTS {
+        let entries = fetch_utxos(&rpc_client, &[recipient_addr.clone()]).await?;
+        if !entries.is_empty() {
+            println!("✅ Funding UTXO visible");
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    Err(format!("Funding UTXO for {} not visible after {}s", address, AUTO_FUND_POLL_ATTEMPTS).into())
+}
+
+// Structured failure block for the message bus, mirroring TRANSACTION_RESULT
+fn print_error_result(err: &BroadcasterError) {
+    println!("TRANSACTION_RESULT_START");
+    println!("{}", serde_json::json!({
+        "success": false,
+        "error": err.code(),
+        "message": err.to_string(),
+        "address": match err { BroadcasterError::WalletUnfunded { address } => Some(address), _ => None },
+        "mass": match err { BroadcasterError::MassLimitExceeded { mass, .. } => Some(mass), _ => None },
+        "massLimit": match err { BroadcasterError::MassLimitExceeded { limit, .. } => Some(limit), _ => None },
+    }));
+    println!("TRANSACTION_RESULT_END");
+}
+
 fn print_usage() {
     println!("📋 USAGE:");
     println!("  Supply Chain Event:");
     println!("    cargo run -- --supply-chain <company_mnemonic> '<event_json>' <event_type>");
     println!("    Example: cargo run -- --supply-chain 'word1 word2...' '{{\"scan\":\"ABC123\"}}' SUPPLY_CHAIN_EVENT");
+    println!("    Or by company: cargo run -- --supply-chain --company-id <id> '<event_json>' <event_type> [--wallet-map <path>]");
+    println!("    Options: --auto-fund-from <master_mnemonic> [--auto-fund-amount <kas>]  fund an empty wallet and retry");
+    println!("             --payload-encoding <utf8|base64|hex>  how <event_json> becomes payload bytes (default utf8)");
+    println!("             --anchor-amount <kas>  value sent with each event (default 0.5, minimum {} sompis)", DUST_THRESHOLD_SOMPIS);
+    println!("             --largest-first  spend only the largest UTXOs covering amount + fee instead of every UTXO");
+    println!("             --consolidate-opportunistically  --largest-first, plus up to {} small UTXOs per anchor while the mass limit allows", OPPORTUNISTIC_EXTRA_INPUTS);
+    println!("             --commit-hash  anchor only the sha256 of <event_json>; keep the event itself off-chain");
     println!("");
     println!("  Funding Transaction:");
     println!("    cargo run -- --funding <amount_kas> <recipient_address>");
     println!("    Example: cargo run -- --funding 0.5 kaspatest:qp0q4md...");
     println!("");
+    println!("  Consolidate Wallet UTXOs:");
+    println!("    cargo run -- --consolidate <wallet_mnemonic>");
+    println!("    Example: cargo run -- --consolidate 'word1 word2...'");
+    println!("");
+    println!("  Verify Hash Commitment:");
+    println!("    cargo run -- --verify-commitment <transaction_id> --payload <file> [--tx-payload <hex>]");
+    println!("    Checks <file> against a --commit-hash anchor; pass --tx-payload (from an explorer) once the tx has left the mempool");
+    println!("");
     println!("  Query Transaction:");
     println!("    cargo run -- --query-transaction <transaction_hash>");
     println!("    Example: cargo run -- --query-transaction 0x1234567890abcdef...");
@@ -284,32 +904,37 @@ fn print_usage() {
 }
 
 // Supply chain event submission (Company → Master)
-async fn submit_supply_chain_event(company_mnemonic: &str, event_data: &str, event_type: &str) -> Result<(), Box<dyn std::error::Error>> {
+async fn submit_supply_chain_event(company_keypair: Keypair, company_addr: Address, event_data: &str, event_type: &str, payload_encoding: PayloadEncoding, anchor_amount: u64, coin_selection: CoinSelection, commit_hash: bool) -> Result<(), Box<dyn std::error::Error>> {
     println!("📦 SUPPLY CHAIN EVENT SUBMISSION");
     println!("================================");
     println!("🔄 Flow: Company → Master Wallet");
     println!("📋 Event Type: {}", event_type);
-    println!("📏 Event Data: {} bytes", event_data.len());
+    println!("📏 Event Data: {} bytes ({})", event_data.len(), payload_encoding.name());
+    println!("⚓ Anchor amount: {} sompis ({} KAS)", anchor_amount, anchor_amount as f64 / 100_000_000.0);
     
-    // Generate company keypair
-    let company_keypair = generate_keypair_from_mnemonic(company_mnemonic, 0)?;
-    let company_addr = Address::try_from(COMPANY_ADDRESS)?;
     let master_addr = Address::try_from(MASTER_ADDRESS)?;
     
     println!("🏢 Sender: Company wallet ({})", company_addr);
     println!("🏛️ Recipient: Master wallet ({})", master_addr);
     
-    // Create enhanced payload
-    let enhanced_payload = format!(r#"{{"type":"{}","data":{}}}"#, event_type, event_data);
+    // Create enhanced payload, or just its hash when the event stays off-chain
+    let enhanced_payload = if commit_hash {
+        let commitment = Commitment::of_event(payload_encoding, event_type, event_data)?;
+        println!("🔒 Committing sha256 {} ({}) - event data stays off-chain", hex::encode(commitment.hash), commitment.content_type);
+        commitment.to_payload()
+    } else {
+        encode_event_payload(payload_encoding, event_type, event_data)?
+    };
     
     // Submit transaction (minimal amount for supply chain events)
     submit_transaction(
         company_keypair,
         company_addr,
         master_addr,
-        50_000_000u64, // 0.5 KAS
+        anchor_amount,
         enhanced_payload,
-        "supply chain event"
+        "supply chain event",
+        coin_selection
     ).await
 }
 
@@ -342,8 +967,9 @@ async fn submit_funding_transaction(amount_kas: f64, recipient_address: &str) ->
         master_addr,
         recipient_addr,
         amount_sompis,
-        funding_payload,
-        "funding transaction"
+        funding_payload.into_bytes(),
+        "funding transaction",
+        CoinSelection::All
     ).await
 }
 
@@ -353,39 +979,51 @@ async fn submit_transaction(
     sender_address: Address,
     recipient_address: Address,
     send_amount: u64,
-    payload_data: String,
-    transaction_type: &str
+    payload_data: Vec<u8>,
+    transaction_type: &str,
+    coin_selection: CoinSelection
 ) -> Result<(), Box<dyn std::error::Error>> {
     
-    // Create RPC client
-    println!("🔌 Connecting to Kaspa node...");
-    let rpc_client = GrpcClient::connect_with_args(
-        NotificationMode::Direct,
-        "grpc://127.0.0.1:16210".to_string(),
-        None,
-        true,
-        None,
-        false,
-        Some(500_000),
-        Default::default(),
-    ).await?;
-
-    println!("✅ Connected to Kaspa node!");
+    let rpc_client = connect_rpc().await?;
     
     // Get UTXOs for sender wallet
     println!("💰 Fetching UTXOs for sender wallet...");
-    let utxos_response = rpc_client.get_utxos_by_addresses_call(
-        None,
-        GetUtxosByAddressesRequest::new(vec![sender_address.clone()])
-    ).await?;
-    
-    let utxos = utxos_response.entries;
+    let utxos = fetch_utxos(&rpc_client, &[sender_address.clone()]).await?;
     if utxos.is_empty() {
-        return Err(format!("No UTXOs found for sender wallet - wallet needs funding").into());
+        return Err(BroadcasterError::WalletUnfunded { address: sender_address.to_string() }.into());
     }
 
     println!("✅ Found {} UTXOs", utxos.len());
 
+    // Choose the inputs: every UTXO, or enough to cover amount + fee plus small
+    // ones when consolidating
+    let network_id = kaspa_consensus_core::network::NetworkId::with_suffix(kaspa_consensus_core::network::NetworkType::Testnet, 10);
+    let mass_calculator = MassCalculator::new(&network_id.into());
+    let utxos = match coin_selection {
+        CoinSelection::All => utxos,
+        CoinSelection::LargestFirst { opportunistic } => {
+            let spending = |selected: &[usize]| {
+                let chosen: Vec<_> = selected.iter().map(|&i| utxos[i].clone()).collect();
+                let outputs = vec![
+                    TransactionOutput { value: send_amount, script_public_key: pay_to_address_script(&recipient_address) },
+                    TransactionOutput { value: 0, script_public_key: pay_to_address_script(&sender_address) },
+                ];
+                Transaction::new(0, utxos_to_inputs(&chosen), outputs, 0, Default::default(), 0, payload_data.clone())
+            };
+            let amounts: Vec<u64> = utxos.iter().map(|utxo| utxo.utxo_entry.amount).collect();
+            let selected = select_inputs(
+                &amounts,
+                send_amount,
+                opportunistic,
+                network_mass_limit(network_id),
+                |selected| mass_calculator.calc_compute_mass_for_unsigned_consensus_transaction(&spending(selected), 1),
+                calc_minimum_required_transaction_relay_fee,
+            );
+            println!("🪙 Spending {} of {} UTXOs", selected.len(), amounts.len());
+            selected.iter().map(|&i| utxos[i].clone()).collect()
+        }
+    };
+
     // Calculate total balance
     let total_balance: u64 = utxos.iter().map(|utxo| utxo.utxo_entry.amount).sum();
     println!("💰 Total balance: {} sompis ({} KAS)", total_balance, total_balance as f64 / 100_000_000.0);
@@ -415,15 +1053,14 @@ async fn submit_transaction(
         },
     ];
 
-    let transaction_payload = payload_data.as_bytes().to_vec();
+    let transaction_payload = payload_data;
     
     // Step 2: Calculate transaction mass
     let initial_consensus_tx = Transaction::new(0, inputs.clone(), initial_outputs, 0, Default::default(), 0, transaction_payload.clone());
 
     println!("🧮 Calculating transaction mass using rusty-kaspa...");
-    let network_id = kaspa_consensus_core::network::NetworkId::with_suffix(kaspa_consensus_core::network::NetworkType::Testnet, 10);
-    let mass_calculator = MassCalculator::new(&network_id.into());
     let transaction_mass = mass_calculator.calc_compute_mass_for_unsigned_consensus_transaction(&initial_consensus_tx, 1);
+    check_mass(transaction_mass, network_mass_limit(network_id))?;
     
     // Step 3: Calculate required fee using rusty-kaspa
     let calculated_fee = calc_minimum_required_transaction_relay_fee(transaction_mass);
@@ -472,47 +1109,11 @@ async fn submit_transaction(
 
     // Step 6: Sign transaction
     println!("🔐 Signing transaction...");
-    let mut mutable_tx = MutableTransaction::with_entries(consensus_tx.clone(), utxo_entries.clone());
-
-    for i in 0..mutable_tx.tx.inputs.len() {
-        let sig_hash = calc_schnorr_signature_hash(&mutable_tx.as_verifiable(), i, SIG_HASH_ALL, &SigHashReusedValuesUnsync::new());
-        let msg = secp256k1::Message::from_digest_slice(sig_hash.as_bytes().as_slice())?;
-        let signature = sender_keypair.sign_schnorr(msg);
-        
-        let mut sig_bytes = Vec::new();
-        sig_bytes.extend_from_slice(signature.as_ref().as_slice());
-        sig_bytes.push(SIG_HASH_ALL.to_u8());
-        
-        let mut script_builder = ScriptBuilder::new();
-        script_builder.add_data(&sig_bytes)?;
-        mutable_tx.tx.inputs[i].signature_script = script_builder.drain();
-    }
-
+    let signed_consensus_tx = sign_transaction(&sender_keypair, consensus_tx, utxo_entries)?;
     println!("✅ Transaction signed!");
 
     // Step 7: Submit transaction
-    let signed_consensus_tx = &mutable_tx.tx;
-    let rpc_transaction = RpcTransaction {
-        version: signed_consensus_tx.version,
-        inputs: signed_consensus_tx.inputs.iter().map(|input| RpcTransactionInput {
-            previous_outpoint: input.previous_outpoint.into(),
-            signature_script: input.signature_script.clone(),
-            sequence: input.sequence,
-            sig_op_count: input.sig_op_count,
-            verbose_data: None,
-        }).collect(),
-        outputs: signed_consensus_tx.outputs.iter().map(|output| RpcTransactionOutput {
-            value: output.value,
-            script_public_key: output.script_public_key.clone().into(),
-            verbose_data: None,
-        }).collect(),
-        lock_time: signed_consensus_tx.lock_time,
-        subnetwork_id: signed_consensus_tx.subnetwork_id.clone(),
-        gas: signed_consensus_tx.gas,
-        payload: signed_consensus_tx.payload.clone(),
-        mass: 0,
-        verbose_data: None,
-    };
+    let rpc_transaction = to_rpc_transaction(&signed_consensus_tx);
      
     println!("📡 Submitting {} with automatic fee calculation...", transaction_type);
     let submit_response = rpc_client.submit_transaction_call(
@@ -528,7 +1129,12 @@ async fn submit_transaction(
     println!("==========================================");
     println!("📋 Transaction ID: {}", submit_response.transaction_id);
     println!("🌐 Explorer: https://kas.fyi/transaction/{}", submit_response.transaction_id);
-    println!("📦 Payload embedded: {}", payload_data);
+    let commitment = Commitment::from_payload(&transaction_payload).ok();
+    match (&commitment, std::str::from_utf8(&transaction_payload)) {
+        (Some(commitment), _) => println!("📦 Commitment embedded: sha256 {}", hex::encode(commitment.hash)),
+        (None, Ok(text)) if !transaction_payload.starts_with(BINARY_PAYLOAD_MARKER) => println!("📦 Payload embedded: {}", text),
+        _ => println!("📦 Payload embedded: {} binary bytes", transaction_payload.len()),
+    }
     println!("💰 Fees calculated automatically by rusty-kaspa!");
     println!("");
     
@@ -538,7 +1144,11 @@ async fn submit_transaction(
     println!("  \"success\": true,");
     println!("  \"transactionId\": \"{}\",", submit_response.transaction_id);
     println!("  \"explorerUrl\": \"https://kas.fyi/transaction/{}\",", submit_response.transaction_id);
-    println!("  \"payloadSize\": {},", payload_data.len());
+    if let Some(commitment) = &commitment {
+        println!("  \"committedHash\": \"{}\",", hex::encode(commitment.hash));
+        println!("  \"contentType\": \"{}\",", commitment.content_type);
+    }
+    println!("  \"payloadSize\": {},", transaction_payload.len());
     println!("  \"transactionType\": \"{}\"", transaction_type);
     println!("}}");
     println!("TRANSACTION_RESULT_END");
@@ -546,4 +1156,445 @@ async fn submit_transaction(
     println!("✅ Transaction permanently anchored on Kaspa blockchain!");
     
     Ok(())
-} 
\ No newline at end of file
+}
+
+// Pick the UTXOs (indices into `amounts`) that fund a payment of `amount`:
+// largest first until they cover it plus the fee for spending them. With
+// `opportunistic`, up to OPPORTUNISTIC_EXTRA_INPUTS of the smallest remaining
+// UTXOs ride along, each only if the transaction stays within `mass_limit` and
+// the UTXO is worth more than the fee it adds. `mass_of` gives the mass of a
+// transaction spending the given inputs. If even every UTXO falls short, all
+// are returned and the caller reports the shortfall.
+fn select_inputs(
+    amounts: &[u64],
+    amount: u64,
+    opportunistic: bool,
+    mass_limit: u64,
+    mass_of: impl Fn(&[usize]) -> u64,
+    fee_of: impl Fn(u64) -> u64,
+) -> Vec<usize> {
+    let mut by_size: Vec<usize> = (0..amounts.len()).collect();
+    by_size.sort_by_key(|&i| std::cmp::Reverse(amounts[i]));
+    let total = |selected: &[usize]| selected.iter().map(|&i| amounts[i]).sum::<u64>();
+
+    let mut selected = Vec::new();
+    let mut remaining = by_size.into_iter();
+    for i in remaining.by_ref() {
+        selected.push(i);
+        if total(&selected) >= amount + fee_of(mass_of(&selected)) {
+            break;
+        }
+    }
+    if !opportunistic {
+        return selected;
+    }
+
+    let mut smallest: Vec<usize> = remaining.collect();
+    smallest.reverse();
+    let mut extra = 0;
+    for i in smallest {
+        if extra == OPPORTUNISTIC_EXTRA_INPUTS {
+            break;
+        }
+        let fee_before = fee_of(mass_of(&selected));
+        selected.push(i);
+        let mass = mass_of(&selected);
+        if mass > mass_limit || amounts[i] <= fee_of(mass).saturating_sub(fee_before) {
+            selected.pop();
+            continue;
+        }
+        extra += 1;
+    }
+    selected
+}
+
+// Split `count` UTXOs into consecutive batches whose consolidation transaction
+// stays within `mass_limit`. `mass_of` returns the mass of a sweep spending a range.
+fn split_consolidation_batches(
+    count: usize,
+    mass_limit: u64,
+    mass_of: impl Fn(Range<usize>) -> u64,
+) -> Result<Vec<Range<usize>>, String> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    while start < count {
+        let mut end = start;
+        while end < count && mass_of(start..end + 1) <= mass_limit {
+            end += 1;
+        }
+        if end == start {
+            return Err(format!("UTXO #{} alone exceeds the {} mass limit", start, mass_limit));
+        }
+        batches.push(start..end);
+        start = end;
+    }
+    Ok(batches)
+}
+
+// Sweep every UTXO of a wallet into a single self-payment output, splitting
+// into several transactions when one would exceed the mass limit
+async fn consolidate_wallet(mnemonic: &str) -> Result<(), Box<dyn std::error::Error>> {
+    println!("🧹 WALLET UTXO CONSOLIDATION");
+    println!("============================");
+
+    let keypair = generate_keypair_from_mnemonic(mnemonic, 0)?;
+    let address = address_from_keypair(&keypair);
+    println!("👛 Wallet: {}", address);
+
+    let rpc_client = connect_rpc().await?;
+    let utxos = fetch_utxos(&rpc_client, &[address.clone()]).await?;
+
+    let before = utxos.len();
+    println!("✅ Found {} UTXOs", before);
+    if before < 2 {
+        println!("✅ Nothing to consolidate");
+        return Ok(());
+    }
+
+    let network_id = NetworkId::with_suffix(NetworkType::Testnet, 10);
+    let mass_calculator = MassCalculator::new(&network_id.into());
+    let sweep_tx = |range: Range<usize>| {
+        let batch = &utxos[range];
+        let total: u64 = batch.iter().map(|utxo| utxo.utxo_entry.amount).sum();
+        let outputs = vec![TransactionOutput { value: total, script_public_key: pay_to_address_script(&address) }];
+        Transaction::new(0, utxos_to_inputs(batch), outputs, 0, Default::default(), 0, vec![])
+    };
+    let mass_of = |range: Range<usize>| {
+        mass_calculator.calc_compute_mass_for_unsigned_consensus_transaction(&sweep_tx(range), 1)
+    };
+
+    let batches = split_consolidation_batches(before, network_mass_limit(network_id), &mass_of)?;
+    println!("📦 Consolidating in {} transaction(s)", batches.len());
+
+    let mut total_fees = 0u64;
+    for (n, range) in batches.iter().enumerate() {
+        let batch = &utxos[range.clone()];
+        let total: u64 = batch.iter().map(|utxo| utxo.utxo_entry.amount).sum();
+        let fee = calc_minimum_required_transaction_relay_fee(mass_of(range.clone()));
+        if fee >= total {
+            return Err(format!("Batch {} holds {} sompis, not enough to cover its {} sompi fee", n + 1, total, fee).into());
+        }
+
+        let outputs = vec![TransactionOutput { value: total - fee, script_public_key: pay_to_address_script(&address) }];
+        let tx = Transaction::new(0, utxos_to_inputs(batch), outputs, 0, Default::default(), 0, vec![]);
+        let signed_tx = sign_transaction(&keypair, tx, rpc_utxos_to_utxo_entries(batch))?;
+
+        let submit_response = rpc_client.submit_transaction_call(
+            None,
+            SubmitTransactionRequest {
+                transaction: to_rpc_transaction(&signed_tx),
+                allow_orphan: false,
+            }
+        ).await?;
+        total_fees += fee;
+        println!("  {}. {} inputs → {} KAS (fee {} sompis) tx {}", n + 1, batch.len(), (total - fee) as f64 / 100_000_000.0, fee, submit_response.transaction_id);
+    }
+
+    println!("🎉 CONSOLIDATION COMPLETE");
+    println!("  UTXOs before: {}", before);
+    println!("  UTXOs after:  {}", batches.len());
+    println!("  Total fees:   {} sompis ({} KAS)", total_fees, total_fees as f64 / 100_000_000.0);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1k base + 1k per input, 1 sompi per gram
+    fn mass_per_input(selected: &[usize]) -> u64 {
+        1_000 + 1_000 * selected.len() as u64
+    }
+
+    #[test]
+    fn anchor_spends_largest_utxos_needed_for_amount_and_fee() {
+        let amounts = [5_000, 900_000, 20_000, 300_000];
+        assert_eq!(select_inputs(&amounts, 500_000, false, 100_000, mass_per_input, |mass| mass), vec![1]);
+        assert_eq!(select_inputs(&amounts, 1_000_000, false, 100_000, mass_per_input, |mass| mass), vec![1, 3]);
+        // Short of funds: everything is returned for the caller to report
+        assert_eq!(select_inputs(&amounts, 5_000_000, false, 100_000, mass_per_input, |mass| mass).len(), 4);
+    }
+
+    #[test]
+    fn anchors_spend_every_utxo_unless_selection_is_asked_for() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_supply_chain_options(&args(&[])).unwrap().coin_selection(), CoinSelection::All);
+        let options = parse_supply_chain_options(&args(&["--largest-first"])).unwrap();
+        assert_eq!(options.coin_selection(), CoinSelection::LargestFirst { opportunistic: false });
+    }
+
+    #[test]
+    fn opportunistic_inputs_are_added_only_while_mass_allows() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let options = parse_supply_chain_options(&args(&["--consolidate-opportunistically", "--anchor-amount", "0.001"])).unwrap();
+        assert!(options.consolidate_opportunistically);
+        assert_eq!(options.coin_selection(), CoinSelection::LargestFirst { opportunistic: true });
+        assert_eq!(options.anchor_amount, 100_000);
+
+        let amounts = [900_000, 30_000, 20_000, 10_000, 40_000, 50_000, 60_000];
+        // Room for every extra: the cap of OPPORTUNISTIC_EXTRA_INPUTS applies, smallest first
+        let roomy = select_inputs(&amounts, 500_000, true, 100_000, mass_per_input, |mass| mass);
+        assert_eq!(roomy, vec![0, 3, 2, 1, 4]);
+        assert_eq!(roomy.len(), 1 + OPPORTUNISTIC_EXTRA_INPUTS);
+        // A 4k limit fits the payment input and two extras
+        let tight = select_inputs(&amounts, 500_000, true, 4_000, mass_per_input, |mass| mass);
+        assert_eq!(tight, vec![0, 3, 2]);
+        assert!(mass_per_input(&tight) <= 4_000);
+        // Already at the limit: no extras at all
+        assert_eq!(select_inputs(&amounts, 500_000, true, 2_000, mass_per_input, |mass| mass), vec![0]);
+    }
+
+    #[test]
+    fn opportunistic_inputs_skip_utxos_worth_less_than_their_fee() {
+        let amounts = [900_000, 600, 5_000];
+        assert_eq!(select_inputs(&amounts, 500_000, true, 100_000, mass_per_input, |mass| mass), vec![0, 2]);
+    }
+
+    #[test]
+    fn consolidation_fits_in_one_transaction_when_under_limit() {
+        let batches = split_consolidation_batches(50, 100_000, |r| 1_000 + 1_000 * r.len() as u64).unwrap();
+        assert_eq!(batches, vec![0..50]);
+    }
+
+    #[test]
+    fn consolidation_splits_when_inputs_exceed_mass_limit() {
+        // 1k base + 1k per input => at most 99 inputs per transaction
+        let batches = split_consolidation_batches(250, 100_000, |r| 1_000 + 1_000 * r.len() as u64).unwrap();
+        assert_eq!(batches, vec![0..99, 99..198, 198..250]);
+        assert_eq!(batches.iter().map(|b| b.len()).sum::<usize>(), 250);
+    }
+
+    #[test]
+    fn consolidation_rejects_single_oversized_input() {
+        assert!(split_consolidation_batches(3, 100_000, |_| 100_001).is_err());
+    }
+
+    #[test]
+    fn testnet_mass_limit_comes_from_network_params() {
+        assert_eq!(network_mass_limit(NetworkId::with_suffix(NetworkType::Testnet, 10)), 100_000);
+    }
+
+    #[test]
+    fn mass_at_or_under_the_limit_passes() {
+        let limit = network_mass_limit(NetworkId::with_suffix(NetworkType::Testnet, 10));
+        assert!(check_mass(limit, limit).is_ok());
+        assert!(check_mass(limit - 1, limit).is_ok());
+    }
+
+    #[test]
+    fn mass_over_the_limit_is_a_dedicated_error() {
+        let limit = network_mass_limit(NetworkId::with_suffix(NetworkType::Testnet, 10));
+        let err = check_mass(limit + 1, limit).unwrap_err();
+        assert_eq!(err, BroadcasterError::MassLimitExceeded { mass: limit + 1, limit });
+        assert_eq!(err.code(), "MASS_LIMIT_EXCEEDED");
+        assert!(err.to_string().contains("chunks or compress"));
+    }
+
+    fn unfunded() -> Box<dyn std::error::Error> {
+        BroadcasterError::WalletUnfunded { address: COMPANY_ADDRESS.to_string() }.into()
+    }
+
+    #[test]
+    fn unfunded_wallet_maps_to_dedicated_error() {
+        let err = unfunded();
+        assert_eq!(
+            as_broadcaster_error(err.as_ref()),
+            Some(&BroadcasterError::WalletUnfunded { address: COMPANY_ADDRESS.to_string() })
+        );
+        assert_eq!(as_broadcaster_error(err.as_ref()).unwrap().code(), "WALLET_UNFUNDED");
+        let other: Box<dyn std::error::Error> = "rpc connection refused".into();
+        assert!(as_broadcaster_error(other.as_ref()).is_none());
+    }
+
+    #[tokio::test]
+    async fn auto_fund_funds_once_then_retries() {
+        use std::cell::RefCell;
+        let attempts = RefCell::new(0);
+        let funded = RefCell::new(Vec::new());
+        let result = submit_with_auto_fund(
+            || {
+                *attempts.borrow_mut() += 1;
+                let first = *attempts.borrow() == 1;
+                async move { if first { Err(unfunded()) } else { Ok(()) } }
+            },
+            Some(|address: String| {
+                funded.borrow_mut().push(address);
+                async { Ok(()) }
+            }),
+        ).await;
+        assert!(result.is_ok());
+        assert_eq!(*attempts.borrow(), 2);
+        assert_eq!(*funded.borrow(), vec![COMPANY_ADDRESS.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn unfunded_error_surfaces_without_funder_or_for_other_failures() {
+        let no_funder: Option<fn(String) -> std::future::Ready<Result<(), Box<dyn std::error::Error>>>> = None;
+        let err = submit_with_auto_fund(|| async { Err(unfunded()) }, no_funder).await.unwrap_err();
+        assert!(as_broadcaster_error(err.as_ref()).is_some());
+
+        let err = submit_with_auto_fund(
+            || async { Err::<(), Box<dyn std::error::Error>>("mass limit exceeded".into()) },
+            Some(|_: String| async { panic!("must not fund on unrelated errors") }),
+        ).await.unwrap_err();
+        assert_eq!(err.to_string(), "mass limit exceeded");
+    }
+
+    #[test]
+    fn auto_fund_flags_parse_with_default_amount() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let auto_fund = |v: &[&str]| parse_supply_chain_options(&args(v)).map(|o| o.auto_fund);
+        assert_eq!(auto_fund(&[]).unwrap(), None);
+        assert_eq!(auto_fund(&["--auto-fund-from", "m w"]).unwrap(), Some(("m w".to_string(), DEFAULT_AUTO_FUND_KAS)));
+        assert_eq!(auto_fund(&["--auto-fund-from", "m w", "--auto-fund-amount", "2.5"]).unwrap(), Some(("m w".to_string(), 2.5)));
+        assert!(auto_fund(&["--auto-fund-amount"]).is_err());
+    }
+
+    #[test]
+    fn payload_encoding_flag_defaults_to_utf8() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_supply_chain_options(&args(&[])).unwrap().payload_encoding, PayloadEncoding::Utf8);
+        assert_eq!(parse_supply_chain_options(&args(&["--payload-encoding", "hex"])).unwrap().payload_encoding, PayloadEncoding::Hex);
+        assert!(parse_supply_chain_options(&args(&["--payload-encoding", "rot13"])).is_err());
+    }
+
+    #[test]
+    fn anchor_amount_is_configurable_down_to_dust() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_supply_chain_options(&args(&[])).unwrap().anchor_amount, DEFAULT_ANCHOR_SOMPIS);
+        assert_eq!(parse_supply_chain_options(&args(&["--anchor-amount", "0.001"])).unwrap().anchor_amount, 100_000);
+        assert_eq!(anchor_amount_sompis(0.000006).unwrap(), DUST_THRESHOLD_SOMPIS);
+    }
+
+    #[test]
+    fn anchor_amount_below_dust_is_rejected() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let err = parse_supply_chain_options(&args(&["--anchor-amount", "0.000001"])).unwrap_err();
+        assert!(err.to_string().contains("dust"), "{}", err);
+        assert!(anchor_amount_sompis(0.0).is_err());
+        assert!(anchor_amount_sompis(-1.0).is_err());
+        assert!(parse_supply_chain_options(&args(&["--anchor-amount", "lots"])).is_err());
+    }
+
+    fn wallet_map(json: &str) -> CompanyWalletMap {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn company_id_resolves_to_its_mapped_keypair() {
+        let company = generate_keypair_from_mnemonic(COMPANY_MNEMONIC, 0).unwrap();
+        let company_address = address_from_keypair(&company).to_string();
+        let map = wallet_map(&format!(
+            r#"{{"42": {{"mnemonic": "{}", "address": "{}"}}, "7": {{"mnemonic": "{}"}}}}"#,
+            COMPANY_MNEMONIC, company_address, MASTER_MNEMONIC
+        ));
+
+        let (keypair, address) = resolve_company_wallet(&map, "42").unwrap();
+        assert_eq!(keypair.secret_bytes(), company.secret_bytes());
+        assert_eq!(address.to_string(), company_address);
+        let (keypair, _) = resolve_company_wallet(&map, "7").unwrap();
+        assert_eq!(keypair.secret_bytes(), generate_keypair_from_mnemonic(MASTER_MNEMONIC, 0).unwrap().secret_bytes());
+        assert!(resolve_company_wallet(&map, "99").is_err());
+    }
+
+    #[test]
+    fn mapped_key_must_match_the_expected_address() {
+        let master_address = address_from_keypair(&generate_keypair_from_mnemonic(MASTER_MNEMONIC, 0).unwrap()).to_string();
+        let map = wallet_map(&format!(r#"{{"42": {{"mnemonic": "{}", "address": "{}"}}}}"#, COMPANY_MNEMONIC, master_address));
+        assert!(resolve_company_wallet(&map, "42").is_err());
+
+        let ambiguous = wallet_map(&format!(r#"{{"42": {{"mnemonic": "{}", "mnemonic_env": "X"}}}}"#, COMPANY_MNEMONIC));
+        assert!(resolve_company_wallet(&ambiguous, "42").is_err());
+    }
+
+    #[test]
+    fn utf8_payload_is_the_json_envelope() {
+        let payload = encode_event_payload(PayloadEncoding::Utf8, "SCAN", r#"{"scan":"ABC123"}"#).unwrap();
+        assert_eq!(payload, br#"{"type":"SCAN","data":{"scan":"ABC123"}}"#.to_vec());
+        let (encoding, event_type, data) = decode_event_payload(&payload).unwrap();
+        assert_eq!((encoding, event_type.as_str(), data.as_str()), (PayloadEncoding::Utf8, "SCAN", r#"{"scan":"ABC123"}"#));
+    }
+
+    #[test]
+    fn binary_payloads_embed_raw_bytes_and_round_trip() {
+        // Not valid UTF-8: would be mangled if embedded as a JSON string
+        let raw = [0x1f, 0x8b, 0x08, 0x00, 0xff, 0xfe, 0x00, 0x0a];
+        for (encoding, text) in [
+            (PayloadEncoding::Base64, general_purpose::STANDARD.encode(raw)),
+            (PayloadEncoding::Hex, hex::encode(raw)),
+        ] {
+            let payload = encode_event_payload(encoding, "SEALED", &text).unwrap();
+            let mut expected = format!("KPMBIN1:{}:SEALED\n", encoding.name()).into_bytes();
+            expected.extend_from_slice(&raw);
+            assert_eq!(payload, expected);
+
+            let (decoded_encoding, event_type, data) = decode_event_payload(&payload).unwrap();
+            assert_eq!(decoded_encoding, encoding);
+            assert_eq!(event_type, "SEALED");
+            assert_eq!(data, text);
+        }
+        assert!(encode_event_payload(PayloadEncoding::Hex, "SEALED", "not hex").is_err());
+    }
+
+    #[test]
+    fn commit_hash_anchors_only_the_hash_and_content_type() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(!parse_supply_chain_options(&args(&[])).unwrap().commit_hash);
+        assert!(parse_supply_chain_options(&args(&["--commit-hash", "--anchor-amount", "0.001"])).unwrap().commit_hash);
+
+        let event = generate_large_payload_test_data(10);
+        let commitment = Commitment::of_event(PayloadEncoding::Utf8, "SCAN", &event).unwrap();
+        let payload = commitment.to_payload();
+        let mut expected = b"KPMCMT1:application/json:SCAN\n".to_vec();
+        expected.extend_from_slice(&Sha256::digest(event.as_bytes()));
+        assert_eq!(payload, expected);
+        assert!(payload.len() < 64 && event.len() > 10_000);
+        assert_eq!(Commitment::from_payload(&payload).unwrap(), commitment);
+
+        // Binary events commit to the decoded bytes, so the raw off-chain copy verifies
+        let raw = [0x1f, 0x8b, 0x08, 0x00];
+        let binary = Commitment::of_event(PayloadEncoding::Hex, "SEALED", &hex::encode(raw)).unwrap();
+        assert_eq!(binary.content_type, "application/octet-stream");
+        assert!(binary.matches(&raw));
+    }
+
+    #[test]
+    fn commitment_verifies_only_the_exact_off_chain_payload() {
+        let event = r#"{"scan":"ABC123"}"#;
+        let anchored = Commitment::of_event(PayloadEncoding::Utf8, "SCAN", event).unwrap().to_payload();
+        let commitment = Commitment::from_payload(&anchored).unwrap();
+        assert!(commitment.matches(event.as_bytes()));
+        assert!(!commitment.matches(br#"{"scan":"ABC124"}"#));
+        assert!(!commitment.matches(format!("{}\n", event).as_bytes()));
+
+        // Full-payload anchors and truncated commitments are not commitments
+        assert!(Commitment::from_payload(&encode_event_payload(PayloadEncoding::Utf8, "SCAN", event).unwrap()).is_err());
+        assert!(Commitment::from_payload(&anchored[..anchored.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn utxo_scan_is_split_into_batches_and_merged() {
+        use std::sync::Mutex;
+        let addresses: Vec<u32> = (0..300).collect();
+        let batch_sizes = Mutex::new(Vec::new());
+        let utxos = fetch_utxos_batched(&addresses, 128, 3, |batch: Vec<u32>| {
+            batch_sizes.lock().unwrap().push(batch.len());
+            async move { Ok(batch.iter().map(|a| format!("utxo-{}", a)).collect::<Vec<_>>()) }
+        }).await.unwrap();
+
+        let mut sizes = batch_sizes.into_inner().unwrap();
+        sizes.sort_unstable();
+        assert_eq!(sizes, vec![44, 128, 128]);
+        assert_eq!(utxos, (0..300).map(|a| format!("utxo-{}", a)).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn utxo_fetch_fails_if_any_batch_fails() {
+        let addresses: Vec<u32> = (0..10).collect();
+        let res = fetch_utxos_batched(&addresses, 4, 2, |batch: Vec<u32>| async move {
+            if batch.contains(&5) { Err::<Vec<u32>, Box<dyn std::error::Error>>("node rejected request".into()) } else { Ok(batch) }
+        }).await;
+        assert!(res.is_err());
+    }
+}
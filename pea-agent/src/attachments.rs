@@ -0,0 +1,98 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::{fs, path::Path, time::Duration};
+
+/// Reference to a binary attachment (photo, sensor trace). It travels inside the
+/// signed event metadata, so the blob uploaded later can't be swapped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttachmentRef {
+    pub sha256: String,
+    pub name: String,
+    pub size: u64,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Read an attachment from disk and compute its reference.
+pub fn load(path: &Path) -> Result<(AttachmentRef, Vec<u8>)> {
+    let bytes = fs::read(path).map_err(|e| anyhow!("attachment {:?}: {}", path, e))?;
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("attachment").to_string();
+    Ok((AttachmentRef { sha256: sha256_hex(&bytes), name, size: bytes.len() as u64 }, bytes))
+}
+
+/// Attachment references listed under an event's `metadata.attachments`.
+pub fn refs_in(payload: &[u8]) -> Vec<AttachmentRef> {
+    serde_json::from_slice::<serde_json::Value>(payload).ok()
+        .and_then(|v| v.get("metadata")?.get("attachments").cloned())
+        .and_then(|a| serde_json::from_value(a).ok())
+        .unwrap_or_default()
+}
+
+/// Upload endpoint: the configured one, or `<bus>/api/attachments`.
+pub fn endpoint(bus: &str, configured: Option<&String>) -> String {
+    configured.cloned().unwrap_or_else(|| format!("{}/api/attachments", bus))
+}
+
+/// PUT one blob to `<endpoint>/<sha256>`, after checking it still matches its hash.
+pub async fn upload(client: &reqwest::Client, endpoint: &str, device_id: &str, token: Option<&str>, att: &AttachmentRef, bytes: Vec<u8>) -> Result<()> {
+    if sha256_hex(&bytes) != att.sha256 { return Err(anyhow!("attachment {} does not match its hash", att.name)); }
+    let mut req = client.put(format!("{}/{}", endpoint.trim_end_matches('/'), att.sha256))
+        .header("X-PEA-Device-Id", device_id)
+        .header("X-PEA-Content-Sha256", &att.sha256)
+        .header("Content-Type", "application/octet-stream")
+        .body(bytes)
+        .timeout(Duration::from_secs(60));
+    if let Some(t) = token { req = req.header("Authorization", format!("Bearer {}", t)); }
+    let resp = crate::outbound::send(req).await?;
+    if !resp.status().is_success() { return Err(anyhow!("attachment {} upload status {}", att.name, resp.status())); }
+    Ok(())
+}
+
+/// Upload the stashed blobs of a drained event. The event itself has already
+/// been accepted, so a blob that doesn't go up is kept for [`upload_pending`]
+/// rather than dropped. Returns the failures.
+pub async fn upload_queued(client: &reqwest::Client, endpoint: &str, device_id: &str, token: Option<&str>, payload: &[u8]) -> Vec<anyhow::Error> {
+    let mut failed = Vec::new();
+    for att in refs_in(payload) {
+        let bytes = match crate::queue::load_attachment(&att.sha256) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!(attachment = %att.name, error = %e, "attachment of a delivered event was never stashed");
+                failed.push(e);
+                continue;
+            }
+        };
+        match upload(client, endpoint, device_id, token, &att, bytes).await {
+            Ok(()) => { let _ = crate::queue::remove_attachment(&att.sha256); }
+            Err(e) => {
+                tracing::warn!(attachment = %att.name, error = %e, "attachment upload failed, kept for retry");
+                if let Err(e) = crate::queue::defer_upload(&att, None) { tracing::error!(attachment = %att.name, error = %e, "could not keep attachment for retry"); }
+                failed.push(e);
+            }
+        }
+    }
+    failed
+}
+
+/// Retry the uploads owed for accepted events. Returns how many went up;
+/// the rest stay pending.
+pub async fn upload_pending(client: &reqwest::Client, endpoint: &str, device_id: &str, token: Option<&str>) -> Result<usize> {
+    let mut uploaded = 0;
+    for att in crate::queue::pending_uploads()? {
+        let res = match crate::queue::load_attachment(&att.sha256) {
+            Ok(bytes) => upload(client, endpoint, device_id, token, &att, bytes).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => {
+                let _ = crate::queue::remove_attachment(&att.sha256);
+                uploaded += 1;
+            }
+            Err(e) => tracing::warn!(attachment = %att.name, error = %e, "pending attachment upload failed"),
+        }
+    }
+    Ok(uploaded)
+}
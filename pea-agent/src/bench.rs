@@ -0,0 +1,130 @@
+use anyhow::{Result, anyhow};
+use std::{future::Future, sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+
+/// Event type of `benchmark-bus` events, so they are never mistaken for scans.
+pub const BENCHMARK_EVENT_TYPE: &str = "BENCHMARK";
+/// Tells the bus to answer the request without recording or anchoring it.
+pub const BENCHMARK_HEADER: &str = "X-PEA-Benchmark";
+
+/// Latencies of one `benchmark-bus` run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    /// Round-trip time of each accepted request, fastest first.
+    pub latencies: Vec<Duration>,
+    pub failed: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchReport {
+    /// Nearest-rank percentile of the accepted requests.
+    pub fn percentile(&self, pct: usize) -> Option<Duration> {
+        let rank = (self.latencies.len() * pct).div_ceil(100).max(1);
+        self.latencies.get(rank - 1).copied()
+    }
+
+    pub fn min(&self) -> Option<Duration> { self.latencies.first().copied() }
+    pub fn max(&self) -> Option<Duration> { self.latencies.last().copied() }
+
+    /// Accepted requests per second over the whole run.
+    pub fn per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.latencies.len() as f64 / secs } else { 0.0 }
+    }
+}
+
+/// The signed event request of the submit path, marked as a benchmark.
+pub fn request(ctx: &crate::submit::SubmitContext<'_>, payload: Vec<u8>, timeout: Duration) -> reqwest::RequestBuilder {
+    crate::submit::event_request(ctx, payload, timeout).0.header(BENCHMARK_HEADER, "1")
+}
+
+/// Send `count` requests, `concurrency` at a time, timing each. `send(i)`
+/// performs request `i`; requests go straight to the network rather than
+/// through the outbound limiter, so `concurrency` is what the bus sees.
+pub async fn run<F, Fut>(count: usize, concurrency: usize, send: F) -> BenchReport
+where
+    F: Fn(usize) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let send = Arc::new(send);
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let mut workers = tokio::task::JoinSet::new();
+    for _ in 0..concurrency.clamp(1, count.max(1)) {
+        let (send, next) = (send.clone(), next.clone());
+        workers.spawn(async move {
+            let mut samples = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= count { return samples; }
+                let t0 = Instant::now();
+                let outcome = send(i).await;
+                if let Err(e) = &outcome { tracing::warn!(request = i, error = %e, "benchmark request failed"); }
+                samples.push(outcome.map(|_| t0.elapsed()));
+            }
+        });
+    }
+    let mut report = BenchReport { latencies: Vec::with_capacity(count), failed: 0, elapsed: Duration::ZERO };
+    while let Some(samples) = workers.join_next().await {
+        for sample in samples.unwrap_or_default() {
+            match sample {
+                Ok(latency) => report.latencies.push(latency),
+                Err(_) => report.failed += 1,
+            }
+        }
+    }
+    report.elapsed = started.elapsed();
+    report.latencies.sort_unstable();
+    report
+}
+
+/// Treat anything but a 2xx as a failed request.
+pub async fn check(resp: reqwest::Result<reqwest::Response>) -> Result<()> {
+    let resp = resp?;
+    if resp.status().is_success() { Ok(()) } else { Err(anyhow!("status {}", resp.status())) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Keypair;
+
+    #[tokio::test]
+    async fn benchmark_reports_latency_stats_from_the_bus() {
+        let mut server = mockito::Server::new_async().await;
+        let bench = server.mock("POST", "/api/supply-chain/event")
+            .match_header(BENCHMARK_HEADER, "1")
+            .match_header("X-PEA-Device-Id", "dev-1")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({ "eventType": BENCHMARK_EVENT_TYPE })))
+            .with_status(202)
+            .with_body(r#"{"accepted":true}"#)
+            .expect(12)
+            .create_async().await;
+
+        let bus = server.url();
+        let kp = Arc::new(Keypair::generate(&mut rand::rngs::OsRng));
+        let report = run(12, 3, move |i| {
+            let (bus, kp) = (bus.clone(), kp.clone());
+            async move {
+                let client = reqwest::Client::new();
+                let ctx = crate::submit::SubmitContext { client: &client, bus: &bus, device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+                let payload = serde_json::to_vec(&serde_json::json!({ "productId": format!("BENCH-{}", i), "eventType": BENCHMARK_EVENT_TYPE }))?;
+                check(request(&ctx, payload, Duration::from_secs(5)).send().await).await
+            }
+        }).await;
+        bench.assert_async().await;
+
+        assert_eq!((report.latencies.len(), report.failed), (12, 0));
+        let (min, median, p95, max) = (report.min().unwrap(), report.percentile(50).unwrap(), report.percentile(95).unwrap(), report.max().unwrap());
+        assert!(min <= median && median <= p95 && p95 <= max && max <= report.elapsed);
+        assert!(report.per_second() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_counted_not_timed() {
+        let report = run(10, 4, |i| async move { if i % 2 == 0 { Ok(()) } else { Err(anyhow!("status 503")) } }).await;
+        assert_eq!((report.latencies.len(), report.failed), (5, 5));
+        assert_eq!(report.percentile(95), report.max());
+        let empty = run(3, 8, |_| async { Err(anyhow!("unreachable")) }).await;
+        assert_eq!((empty.min(), empty.percentile(50), empty.failed), (None, None, 3));
+    }
+}
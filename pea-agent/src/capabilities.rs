@@ -0,0 +1,181 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use std::time::Duration;
+use crate::state;
+
+/// API versions this agent can speak, oldest first.
+const SUPPORTED_API_VERSIONS: [u32; 1] = [1];
+/// Signature algorithms this agent can produce.
+const SUPPORTED_SIG_ALGORITHMS: [&str; 1] = ["ed25519"];
+/// Cached capabilities are re-fetched after this long.
+pub const RECHECK_AFTER: Duration = Duration::from_secs(6 * 3600);
+
+/// How event JSON is turned into the bytes that get signed and sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Canonicalization {
+    /// Bytes as serialized by the agent; the server verifies what it receives.
+    #[default]
+    Raw,
+    /// Objects with keys sorted, no whitespace (same as `stable_stringify`).
+    SortedKeys,
+}
+
+impl Canonicalization {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Self::Raw),
+            "sorted-keys" => Some(Self::SortedKeys),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::SortedKeys => "sorted-keys",
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, event: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Raw => serde_json::to_vec(event)?,
+            Self::SortedKeys => crate::provision::stable_stringify(&serde_json::to_value(event)?).into_bytes(),
+        })
+    }
+
+    /// Re-encode an already serialized payload (queued before negotiation).
+    pub fn reencode(self, payload: Vec<u8>) -> Vec<u8> {
+        match self {
+            Self::Raw => payload,
+            Self::SortedKeys => serde_json::from_slice::<serde_json::Value>(&payload)
+                .map(|v| crate::provision::stable_stringify(&v).into_bytes())
+                .unwrap_or(payload),
+        }
+    }
+}
+
+fn default_api_versions() -> Vec<u32> { vec![1] }
+fn default_canonicalization() -> Vec<String> { vec!["raw".into()] }
+fn default_sig_algorithms() -> Vec<String> { vec!["ed25519".into()] }
+
+/// What `/api/capabilities` reports. Servers predating the endpoint are assumed
+/// to speak v1 with raw ed25519-signed JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerCapabilities {
+    #[serde(default = "default_api_versions")]
+    pub api_versions: Vec<u32>,
+    #[serde(default = "default_canonicalization")]
+    pub canonicalization: Vec<String>,
+    #[serde(default = "default_sig_algorithms")]
+    pub sig_algorithms: Vec<String>,
+}
+
+impl Default for ServerCapabilities {
+    fn default() -> Self {
+        Self { api_versions: default_api_versions(), canonicalization: default_canonicalization(), sig_algorithms: default_sig_algorithms() }
+    }
+}
+
+/// Settings both sides agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub api_version: u32,
+    pub canonicalization: Canonicalization,
+    pub sig_algorithm: &'static str,
+}
+
+impl Default for Negotiated {
+    fn default() -> Self {
+        negotiate(&ServerCapabilities::default()).expect("agent supports its own defaults")
+    }
+}
+
+/// Pick the newest common API version, the server's most preferred
+/// canonicalization we implement, and a shared signature algorithm.
+pub fn negotiate(caps: &ServerCapabilities) -> Result<Negotiated> {
+    let api_version = SUPPORTED_API_VERSIONS.iter().rev().find(|v| caps.api_versions.contains(v)).copied()
+        .ok_or_else(|| anyhow!("incompatible bus: API versions {:?}, agent supports {:?}", caps.api_versions, SUPPORTED_API_VERSIONS))?;
+    let canonicalization = caps.canonicalization.iter().find_map(|c| Canonicalization::parse(c))
+        .ok_or_else(|| anyhow!("incompatible bus: canonicalization {:?} not supported by agent", caps.canonicalization))?;
+    let sig_algorithm = SUPPORTED_SIG_ALGORITHMS.iter().find(|a| caps.sig_algorithms.iter().any(|s| s == *a)).copied()
+        .ok_or_else(|| anyhow!("incompatible bus: signature algorithms {:?}, agent supports {:?}", caps.sig_algorithms, SUPPORTED_SIG_ALGORITHMS))?;
+    Ok(Negotiated { api_version, canonicalization, sig_algorithm })
+}
+
+/// GET `/api/capabilities`; a 404 means an older bus with the v1 defaults.
+pub async fn fetch(client: &reqwest::Client, bus: &str) -> Result<ServerCapabilities> {
+    let resp = crate::outbound::send(client.get(format!("{}/api/capabilities", bus)).timeout(Duration::from_secs(10))).await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND { return Ok(ServerCapabilities::default()); }
+    if !resp.status().is_success() { return Err(anyhow!("capabilities status {}", resp.status())); }
+    Ok(resp.json().await?)
+}
+
+/// Negotiated settings, re-fetching the server's capabilities when the cached
+/// copy is older than [`RECHECK_AFTER`]. An unreachable bus falls back to the
+/// cache (or v1 defaults); an incompatible one is an error.
+pub async fn ensure(client: &reqwest::Client, bus: &str) -> Result<Negotiated> {
+    let st = state::load();
+    let fresh = st.capabilities_checked_at.as_deref()
+        .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| chrono::Utc::now().signed_duration_since(t).to_std().map(|age| age < RECHECK_AFTER).unwrap_or(false))
+        .unwrap_or(false);
+    if let (true, Some(caps)) = (fresh, &st.capabilities) {
+        return negotiate(caps);
+    }
+    match fetch(client, bus).await {
+        Ok(caps) => {
+            let negotiated = negotiate(&caps);
+            let _ = state::update(|s| {
+                s.capabilities = Some(caps);
+                s.capabilities_checked_at = Some(chrono::Utc::now().to_rfc3339());
+            });
+            negotiated
+        }
+        Err(_) => negotiate(&st.capabilities.unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(versions: &[u32], canon: &[&str], sigs: &[&str]) -> ServerCapabilities {
+        ServerCapabilities {
+            api_versions: versions.to_vec(),
+            canonicalization: canon.iter().map(|s| s.to_string()).collect(),
+            sig_algorithms: sigs.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn compatible_capabilities_are_negotiated() {
+        let n = negotiate(&caps(&[1, 2], &["jcs", "sorted-keys", "raw"], &["ecdsa-p256", "ed25519"])).unwrap();
+        assert_eq!(n, Negotiated { api_version: 1, canonicalization: Canonicalization::SortedKeys, sig_algorithm: "ed25519" });
+        let legacy: ServerCapabilities = serde_json::from_str("{}").unwrap();
+        assert_eq!(negotiate(&legacy).unwrap(), Negotiated::default());
+    }
+
+    #[test]
+    fn incompatible_capabilities_are_rejected() {
+        assert!(negotiate(&caps(&[2], &["raw"], &["ed25519"])).is_err());
+        assert!(negotiate(&caps(&[1], &["jcs"], &["ed25519"])).is_err());
+        assert!(negotiate(&caps(&[1], &["raw"], &["ecdsa-p256"])).is_err());
+    }
+
+    #[test]
+    fn sorted_keys_encoding_orders_fields() {
+        #[derive(Serialize)]
+        struct E { z: u8, a: u8 }
+        assert_eq!(Canonicalization::Raw.encode(&E { z: 1, a: 2 }).unwrap(), br#"{"z":1,"a":2}"#);
+        assert_eq!(Canonicalization::SortedKeys.encode(&E { z: 1, a: 2 }).unwrap(), br#"{"a":2,"z":1}"#);
+        assert_eq!(Canonicalization::SortedKeys.reencode(br#"{"z":1, "a":2}"#.to_vec()), br#"{"a":2,"z":1}"#);
+    }
+
+    #[tokio::test]
+    async fn older_bus_without_endpoint_gets_defaults() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server.mock("GET", "/api/capabilities").with_status(404).create_async().await;
+        let caps = fetch(&reqwest::Client::new(), &server.url()).await.unwrap();
+        assert_eq!(caps, ServerCapabilities::default());
+    }
+}
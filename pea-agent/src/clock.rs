@@ -0,0 +1,147 @@
+use chrono::{DateTime, Utc};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Largest server-time offset corrected automatically when
+/// `--max-clock-correction` isn't given.
+pub const DEFAULT_MAX_CORRECTION: Duration = Duration::from_secs(300);
+
+/// HTTP `Date` headers have one-second resolution; smaller offsets are noise.
+const MIN_OFFSET_MS: i64 = 1000;
+
+static MAX_CORRECTION: OnceLock<Duration> = OnceLock::new();
+
+/// Install the correction bound; call once at startup.
+pub fn set_max_correction(max: Duration) {
+    let _ = MAX_CORRECTION.set(max);
+}
+
+/// The local clock is further from the bus's than we are willing to correct,
+/// so events would carry a wrong time either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkewTooLarge {
+    pub offset_ms: i64,
+    pub max: Duration,
+}
+
+impl std::fmt::Display for ClockSkewTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "local clock is {:+.1}s off the bus clock, beyond the {}s correction limit; fix the system clock (NTP)",
+            self.offset_ms as f64 / -1000.0, self.max.as_secs())
+    }
+}
+
+impl std::error::Error for ClockSkewTooLarge {}
+
+/// Server-minus-local offset implied by a response's `Date` header.
+pub fn offset_from_date_header(date: &str, local: DateTime<Utc>) -> Option<i64> {
+    let server = DateTime::parse_from_rfc2822(date).ok()?.with_timezone(&Utc);
+    Some((server - local).num_milliseconds())
+}
+
+/// `now` shifted by the learned offset, if the offset is within `max`.
+pub fn correct(now: DateTime<Utc>, offset_ms: Option<i64>, max: Duration) -> Result<DateTime<Utc>, ClockSkewTooLarge> {
+    Ok(now + chrono::Duration::milliseconds(checked_offset(offset_ms, max)?))
+}
+
+/// The part of `offset_ms` to apply: none below the `Date` header's
+/// resolution, an error beyond `max`.
+fn checked_offset(offset_ms: Option<i64>, max: Duration) -> Result<i64, ClockSkewTooLarge> {
+    let offset_ms = match offset_ms {
+        Some(o) if o.abs() >= MIN_OFFSET_MS => o,
+        _ => return Ok(0),
+    };
+    if offset_ms.unsigned_abs() > max.as_millis() as u64 {
+        return Err(ClockSkewTooLarge { offset_ms, max });
+    }
+    Ok(offset_ms)
+}
+
+fn max_correction() -> Duration {
+    MAX_CORRECTION.get().copied().unwrap_or(DEFAULT_MAX_CORRECTION)
+}
+
+/// Current time for event timestamps, corrected by the offset last learned
+/// from the bus. This is the time that gets signed.
+pub fn corrected_now() -> anyhow::Result<DateTime<Utc>> {
+    Ok(correct(Utc::now(), crate::state::load().clock_offset_ms, max_correction())?)
+}
+
+/// An offset read once and applied to every timestamp in a drain pass or
+/// heartbeat. Unlike [`corrected_now`], a skew beyond the bound doesn't fail:
+/// it is logged and the local clock used as is, so a bad clock can't hold up
+/// queued events or the heartbeat that would report it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Correction {
+    offset_ms: i64,
+}
+
+impl Correction {
+    /// The correction for the offset last learned from the bus.
+    pub fn current() -> Self {
+        Self::bounded(crate::state::load().clock_offset_ms, max_correction())
+    }
+
+    fn bounded(offset_ms: Option<i64>, max: Duration) -> Self {
+        match checked_offset(offset_ms, max) {
+            Ok(offset_ms) => Self { offset_ms },
+            Err(e) => {
+                tracing::warn!(offset_ms = e.offset_ms, "{}; using the local clock uncorrected", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn apply(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now + chrono::Duration::milliseconds(self.offset_ms)
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.apply(Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn offset_is_read_from_the_date_header() {
+        let local = at("2024-05-01T12:00:00Z");
+        assert_eq!(offset_from_date_header("Wed, 01 May 2024 12:02:30 GMT", local), Some(150_000));
+        assert_eq!(offset_from_date_header("yesterday", local), None);
+    }
+
+    #[test]
+    fn offsets_within_bound_are_corrected() {
+        let now = at("2024-05-01T12:00:00Z");
+        let max = Duration::from_secs(300);
+        assert_eq!(correct(now, Some(120_000), max).unwrap(), at("2024-05-01T12:02:00Z"));
+        assert_eq!(correct(now, Some(-300_000), max).unwrap(), at("2024-05-01T11:55:00Z"));
+        assert_eq!(correct(now, Some(400), max).unwrap(), now, "sub-second offsets are noise");
+        assert_eq!(correct(now, None, max).unwrap(), now);
+    }
+
+    #[test]
+    fn offsets_over_bound_are_rejected_not_applied() {
+        let now = at("2024-05-01T12:00:00Z");
+        let max = Duration::from_secs(300);
+        let err = correct(now, Some(-3_600_000), max).unwrap_err();
+        assert_eq!(err, ClockSkewTooLarge { offset_ms: -3_600_000, max });
+        assert!(err.to_string().contains("fix the system clock"));
+        assert!(correct(now, Some(300_001), max).is_err());
+    }
+
+    #[test]
+    fn a_pass_correction_falls_back_to_the_local_clock_over_bound() {
+        let now = at("2024-05-01T12:00:00Z");
+        let max = Duration::from_secs(300);
+        assert_eq!(Correction::bounded(Some(120_000), max).apply(now), at("2024-05-01T12:02:00Z"));
+        assert_eq!(Correction::bounded(Some(-3_600_000), max).apply(now), now);
+        assert_eq!(Correction::bounded(None, max), Correction::default());
+    }
+}
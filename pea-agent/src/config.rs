@@ -0,0 +1,138 @@
+use anyhow::{Result, anyhow};
+use clap::{ArgMatches, parser::ValueSource};
+use directories::ProjectDirs;
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+
+/// Settings read from the config file. Each is optional: a flag given on the
+/// command line wins over the file, and the file over the built-in default.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub message_bus_url: Option<String>,
+    pub company_id: Option<u32>,
+    /// Seconds between heartbeats in `run` and `heartbeat-loop`.
+    pub heartbeat_interval: Option<u64>,
+    /// Seconds between queue drains in `run`.
+    pub queue_drain_interval: Option<u64>,
+    /// Client certificate for a bus behind mutual TLS, as `--client-cert`.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Extra CAs to trust for the bus certificate, as `--ca-bundle`.
+    pub ca_bundle: Option<String>,
+    /// Proxy for bus traffic, as `--proxy`; wins over `HTTPS_PROXY`/`ALL_PROXY`.
+    pub proxy: Option<String>,
+}
+
+/// `config.toml` in the project config dir, read when `--config` isn't given.
+pub fn default_path() -> Result<PathBuf> {
+    let proj = ProjectDirs::from("com","kmp","pea-agent").ok_or_else(|| anyhow!("no project dirs"))?;
+    Ok(proj.config_dir().join("config.toml"))
+}
+
+/// Load `explicit`, which must exist, or else the default file if there is one.
+pub fn load(explicit: Option<&Path>) -> Result<Config> {
+    match explicit {
+        Some(path) => load_file(path),
+        None => {
+            let path = default_path()?;
+            if path.exists() { load_file(&path) } else { Ok(Config::default()) }
+        }
+    }
+}
+
+/// Parse `path` as JSON when it ends in `.json`, as TOML otherwise.
+pub fn load_file(path: &Path) -> Result<Config> {
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow!("config file {}: {}", path.display(), e))?;
+    let parsed = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&text).map_err(|e| e.to_string())
+    };
+    parsed.map_err(|e| anyhow!("config file {} is malformed: {}", path.display(), e))
+}
+
+impl Config {
+    /// The file's value for global flag `id`, spelled as the flag would be.
+    pub fn flag_value(&self, id: &str) -> Option<String> {
+        match id {
+            "bus" => self.message_bus_url.clone(),
+            "company" => self.company_id.map(|c| c.to_string()),
+            "client-cert" => self.client_cert.clone(),
+            "client-key" => self.client_key.clone(),
+            "ca-bundle" => self.ca_bundle.clone(),
+            "proxy" => self.proxy.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `id` was given on the command line or through its env var, rather
+/// than left at its default.
+pub fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+}
+
+/// `id` as given on the command line, else the file's value, else the default.
+pub fn layered<T: Clone + Send + Sync + 'static>(matches: &ArgMatches, id: &str, file: Option<T>) -> Option<T> {
+    match file {
+        Some(value) if !given(matches, id) => Some(value),
+        _ => matches.get_one::<T>(id).cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(dir: &Path, name: &str, text: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn flags_override_the_file_which_overrides_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = file(dir.path(), "config.toml", "message_bus_url = \"https://bus.file\"\ncompany_id = 7\nheartbeat_interval = 600\n");
+        let cfg = load(Some(&path)).unwrap();
+        assert_eq!(cfg.queue_drain_interval, None);
+
+        let matches = crate::cli().try_get_matches_from(["pea-agent", "--company", "9", "run", "--qd", "10"]).unwrap();
+        assert_eq!(layered(&matches, "bus", cfg.message_bus_url.clone()).unwrap(), "https://bus.file");
+        assert_eq!(layered(&matches, "company", cfg.company_id), Some(9));
+        let (_, run) = matches.subcommand().unwrap();
+        assert_eq!(layered(run, "hb", cfg.heartbeat_interval), Some(600));
+        assert_eq!(layered(run, "qd", cfg.queue_drain_interval), Some(10));
+
+        let matches = crate::cli().try_get_matches_from(["pea-agent", "run"]).unwrap();
+        let none = Config::default();
+        assert_eq!(layered(&matches, "bus", none.message_bus_url).unwrap(), "http://localhost:3001");
+        assert_eq!(layered(&matches, "company", none.company_id), Some(1));
+        assert_eq!(layered(matches.subcommand().unwrap().1, "qd", none.queue_drain_interval), Some(30));
+    }
+
+    #[test]
+    fn json_files_are_read_by_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = file(dir.path(), "pea.json", r#"{ "message_bus_url": "https://bus.json", "queue_drain_interval": 15 }"#);
+        assert_eq!(load_file(&path).unwrap(), Config { message_bus_url: Some("https://bus.json".into()), queue_drain_interval: Some(15), ..Default::default() });
+    }
+
+    #[test]
+    fn missing_or_malformed_files_are_clear_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("absent.toml");
+        let err = load(Some(&missing)).unwrap_err().to_string();
+        assert!(err.contains("absent.toml"), "{}", err);
+
+        let broken = file(dir.path(), "broken.toml", "message_bus_url = ");
+        let err = load(Some(&broken)).unwrap_err().to_string();
+        assert!(err.contains("broken.toml") && err.contains("malformed"), "{}", err);
+
+        let typo = file(dir.path(), "typo.toml", "bus_url = \"https://bus\"\n");
+        assert!(load(Some(&typo)).unwrap_err().to_string().contains("bus_url"));
+        let wrong_type = file(dir.path(), "wrong.json", r#"{ "company_id": "seven" }"#);
+        assert!(load(Some(&wrong_type)).unwrap_err().to_string().contains("malformed"));
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::{collections::VecDeque, fs, path::{Path, PathBuf}, sync::OnceLock, time::Duration};
+
+/// Entries kept when `--dedup-capacity` isn't given.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// How long a delivered event is remembered, and how many are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupPolicy {
+    pub window: Duration,
+    pub capacity: usize,
+}
+
+static POLICY: OnceLock<DedupPolicy> = OnceLock::new();
+
+/// Turn on edge-side deduplication for this process; off unless called.
+pub fn configure(policy: DedupPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+/// One delivered event: hash of its canonical content and when it was delivered.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Seen {
+    hash: String,
+    at: i64,
+}
+
+/// Recently delivered events, persisted so a restart doesn't re-send what
+/// already went out. Oldest first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Ring {
+    entries: VecDeque<Seen>,
+}
+
+impl Ring {
+    /// The ring at `path`; empty when there is none yet, or with a warning
+    /// when it can't be read.
+    fn load(path: &Path) -> Ring {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ring::default(),
+            Err(e) => {
+                tracing::warn!(file = ?path, error = %e, "delivery ledger unreadable, starting empty");
+                return Ring::default();
+            }
+        };
+        serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            tracing::warn!(file = ?path, error = %e, "delivery ledger unparseable, starting empty");
+            Ring::default()
+        })
+    }
+
+    fn prune(&mut self, policy: DedupPolicy, now: i64) {
+        let cutoff = now - policy.window.as_secs() as i64;
+        self.entries.retain(|s| s.at > cutoff);
+        while self.entries.len() > policy.capacity { self.entries.pop_front(); }
+    }
+}
+
+fn ring_path() -> Result<PathBuf> {
+    Ok(crate::paths::state_dir()?.join("delivered.json"))
+}
+
+/// Hash of the event with keys sorted, so re-encoding a queued payload for
+/// another canonicalization still matches.
+fn content_hash(payload: &[u8]) -> String {
+    let mut h = Sha256::new();
+    match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(v) => h.update(crate::provision::stable_stringify(&v).as_bytes()),
+        Err(_) => h.update(payload),
+    }
+    hex::encode(h.finalize())
+}
+
+/// The ring this process checks and records deliveries in.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    path: PathBuf,
+    policy: DedupPolicy,
+}
+
+/// The configured ledger; `None` when deduplication is off or there's no state dir.
+pub fn ledger() -> Option<Ledger> {
+    Some(Ledger { path: ring_path().ok()?, policy: *POLICY.get()? })
+}
+
+impl Ledger {
+    #[cfg(test)]
+    pub fn at(path: PathBuf, policy: DedupPolicy) -> Self {
+        Self { path, policy }
+    }
+
+    /// Whether this event was delivered within the window. False when the ring
+    /// can't be read.
+    pub fn seen(&self, payload: &[u8]) -> bool {
+        seen_in(&self.path, self.policy, payload, chrono::Utc::now().timestamp())
+    }
+
+    /// Remember a delivered event, pruning entries past the window or capacity.
+    pub fn record(&self, payload: &[u8]) -> Result<()> {
+        record_in(&self.path, self.policy, payload, chrono::Utc::now().timestamp())
+    }
+}
+
+/// Whether this event was delivered within the window. Always false when
+/// deduplication is off or the ring can't be read.
+pub fn seen(payload: &[u8]) -> bool {
+    ledger().is_some_and(|l| l.seen(payload))
+}
+
+fn seen_in(path: &Path, policy: DedupPolicy, payload: &[u8], now: i64) -> bool {
+    let hash = content_hash(payload);
+    let cutoff = now - policy.window.as_secs() as i64;
+    Ring::load(path).entries.iter().any(|s| s.at > cutoff && s.hash == hash)
+}
+
+/// Remember a delivered event, pruning entries past the window or capacity.
+pub fn record(payload: &[u8]) -> Result<()> {
+    ledger().map_or(Ok(()), |l| l.record(payload))
+}
+
+fn record_in(path: &Path, policy: DedupPolicy, payload: &[u8], now: i64) -> Result<()> {
+    // Concurrent deliveries (a drain and a live scan, or two processes) each
+    // load, append and rewrite the ring; the lock keeps one from dropping the
+    // other's entry.
+    let lock_path = path.with_extension("lock");
+    let lock = fs::OpenOptions::new().write(true).create(true).truncate(false).open(&lock_path)
+        .map_err(|e| anyhow::anyhow!("opening ledger lock {:?}: {}", lock_path, e))?;
+    lock.lock().map_err(|e| anyhow::anyhow!("locking {:?}: {}", lock_path, e))?;
+    let mut ring = Ring::load(path);
+    ring.entries.push_back(Seen { hash: content_hash(payload), at: now });
+    ring.prune(policy, now);
+    crate::paths::write_atomic(path, &serde_json::to_vec(&ring)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_POLICY: DedupPolicy = DedupPolicy { window: Duration::from_secs(3600), capacity: 3 };
+
+    #[test]
+    fn restart_within_window_skips_delivered_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delivered.json");
+        let event = br#"{"productId":"p1","timestamp":"2024-01-01T00:00:00Z"}"#;
+        assert!(!seen_in(&path, TEST_POLICY, event, 1_000));
+        record_in(&path, TEST_POLICY, event, 1_000).unwrap();
+
+        // A fresh process only has the file to go on; key order doesn't matter.
+        let reencoded = br#"{"timestamp":"2024-01-01T00:00:00Z","productId":"p1"}"#;
+        assert!(seen_in(&path, TEST_POLICY, reencoded, 1_000 + 60));
+        assert!(!seen_in(&path, TEST_POLICY, br#"{"productId":"p2"}"#, 1_000 + 60));
+        assert!(!seen_in(&path, TEST_POLICY, event, 1_000 + 3600), "forgotten once the window passes");
+    }
+
+    #[test]
+    fn ring_is_bounded_by_capacity_and_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delivered.json");
+        for i in 0..5 { record_in(&path, TEST_POLICY, format!("{{\"n\":{}}}", i).as_bytes(), 1_000 + i).unwrap(); }
+        let ring = Ring::load(&path);
+        assert_eq!(ring.entries.len(), 3);
+        assert!(!seen_in(&path, TEST_POLICY, b"{\"n\":0}", 1_010), "oldest evicted past capacity");
+        assert!(seen_in(&path, TEST_POLICY, b"{\"n\":4}", 1_010));
+
+        record_in(&path, TEST_POLICY, b"{\"n\":9}", 1_000 + 4000).unwrap();
+        assert_eq!(Ring::load(&path).entries.len(), 1, "expired entries pruned on write");
+    }
+
+    #[test]
+    fn concurrent_records_are_all_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delivered.json");
+        let policy = DedupPolicy { capacity: 100, ..TEST_POLICY };
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let path = &path;
+                s.spawn(move || {
+                    for i in 0..10 { record_in(path, policy, format!("{{\"t\":{},\"n\":{}}}", t, i).as_bytes(), 1_000).unwrap(); }
+                });
+            }
+        });
+        assert_eq!(Ring::load(&path).entries.len(), 40);
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+
+/// Encrypt `data` with DPAPI for the current user: only the same user on the
+/// same machine can [`unprotect`] it.
+#[cfg(windows)]
+pub fn protect(data: &[u8]) -> Result<Vec<u8>> {
+    imp::protect(data)
+}
+
+/// Decrypt a blob from [`protect`].
+#[cfg(windows)]
+pub fn unprotect(blob: &[u8]) -> Result<Vec<u8>> {
+    imp::unprotect(blob)
+}
+
+#[cfg(not(windows))]
+pub fn protect(_data: &[u8]) -> Result<Vec<u8>> {
+    Err(anyhow::anyhow!("the dpapi vault backend is only available on Windows"))
+}
+
+#[cfg(not(windows))]
+pub fn unprotect(_blob: &[u8]) -> Result<Vec<u8>> {
+    Err(anyhow::anyhow!("the dpapi vault backend is only available on Windows"))
+}
+
+#[cfg(windows)]
+mod imp {
+    use anyhow::{Result, anyhow};
+    use windows::core::PCWSTR;
+    use windows::Win32::Foundation::{LocalFree, HLOCAL};
+    use windows::Win32::Security::Cryptography::{CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB};
+
+    fn input(data: &[u8]) -> CRYPT_INTEGER_BLOB {
+        CRYPT_INTEGER_BLOB { cbData: data.len() as u32, pbData: data.as_ptr() as *mut u8 }
+    }
+
+    /// Copy out a blob DPAPI allocated and release it with `LocalFree`.
+    unsafe fn take(out: CRYPT_INTEGER_BLOB) -> Vec<u8> {
+        let bytes = std::slice::from_raw_parts(out.pbData, out.cbData as usize).to_vec();
+        let _ = LocalFree(HLOCAL(out.pbData.cast()));
+        bytes
+    }
+
+    pub fn protect(data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = CRYPT_INTEGER_BLOB::default();
+        unsafe {
+            CryptProtectData(&input(data), PCWSTR::null(), None, None, None, CRYPTPROTECT_UI_FORBIDDEN, &mut out)
+                .map_err(|e| anyhow!("CryptProtectData failed: {}", e))?;
+            Ok(take(out))
+        }
+    }
+
+    pub fn unprotect(blob: &[u8]) -> Result<Vec<u8>> {
+        let mut out = CRYPT_INTEGER_BLOB::default();
+        unsafe {
+            CryptUnprotectData(&input(blob), None, None, None, None, CRYPTPROTECT_UI_FORBIDDEN, &mut out)
+                .map_err(|e| anyhow!("CryptUnprotectData failed: {}", e))?;
+            Ok(take(out))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(windows)]
+    #[test]
+    fn protected_data_round_trips_for_the_same_user() {
+        let blob = protect(b"vault key material").unwrap();
+        assert!(!blob.windows(18).any(|w| w == b"vault key material"));
+        assert_eq!(unprotect(&blob).unwrap(), b"vault key material");
+
+        let mut tampered = blob.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        assert!(unprotect(&tampered).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn dpapi_vault_stores_and_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let v = crate::vault::Vault::with_backend("kmp-pea", "device-ed25519-sk", crate::vault::VaultBackend::Dpapi).in_dir(dir.path());
+        v.store_secret(b"secret").unwrap();
+        assert_eq!(v.load_secret().unwrap(), b"secret");
+        assert!(!std::fs::read(dir.path().join("vault-key.dpapi")).unwrap().is_empty());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn dpapi_is_a_clear_error_off_windows() {
+        let err = protect(b"x").unwrap_err().to_string();
+        assert!(err.contains("only available on Windows"), "{}", err);
+        assert!(unprotect(b"x").is_err());
+    }
+}
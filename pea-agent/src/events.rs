@@ -0,0 +1,51 @@
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+/// Lines buffered per `--status-stream` client; one that falls further behind
+/// is disconnected rather than slowing the agent.
+pub const STREAM_BUFFER: usize = 256;
+
+/// Datagram socket in the state dir where a `run` with `--status-stream`
+/// collects the events other agent processes (`submit`, `scan-*`, ...) publish.
+pub const RELAY_SOCKET: &str = "events.sock";
+
+static EVENTS: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+
+/// Start collecting state changes for streaming clients in this process.
+/// Until this is called, `publish` hands its lines to the relay socket.
+pub fn enable() -> broadcast::Sender<String> {
+    EVENTS.get_or_init(|| broadcast::channel(STREAM_BUFFER).0).clone()
+}
+
+/// Push one state change (`scan`, `enqueue`, `submit`, `drain`, `heartbeat`)
+/// as a JSON line: `{"event": kind, "at": <rfc3339>, ...fields}`. The process
+/// streaming events sends it to its clients; any other process forwards it to
+/// that one through [`RELAY_SOCKET`], and drops it if nothing is listening.
+pub fn publish(kind: &str, fields: serde_json::Value) {
+    match EVENTS.get() {
+        Some(tx) => {
+            if tx.receiver_count() > 0 { let _ = tx.send(line(kind, fields)); }
+        }
+        None => {
+            #[cfg(unix)]
+            if let Ok(dir) = crate::paths::state_dir() { forward(&dir.join(RELAY_SOCKET), kind, fields); }
+        }
+    }
+}
+
+fn line(kind: &str, fields: serde_json::Value) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert("event".into(), kind.into());
+    line.insert("at".into(), chrono::Utc::now().to_rfc3339().into());
+    if let serde_json::Value::Object(fields) = fields { line.extend(fields); }
+    serde_json::Value::Object(line).to_string()
+}
+
+/// Send one event line to the relay socket at `relay`. Best effort and
+/// non-blocking: a missing or backed-up daemon never holds up the caller.
+#[cfg(unix)]
+pub fn forward(relay: &std::path::Path, kind: &str, fields: serde_json::Value) {
+    let Ok(socket) = std::os::unix::net::UnixDatagram::unbound() else { return };
+    if socket.set_nonblocking(true).is_err() { return; }
+    let _ = socket.send_to(line(kind, fields).as_bytes(), relay);
+}
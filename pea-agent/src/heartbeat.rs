@@ -1,55 +1,193 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use serde::Serialize;
-use sha2::{Sha256, Digest};
-use ed25519_dalek::{Keypair, Signer};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Verifier};
+use crate::submit::{SignedHeaders, SigningScheme};
 use base64::{engine::general_purpose, Engine as _};
+use crate::state::ServerTrust;
+use std::collections::BTreeMap;
+
+/// Shape of the heartbeat payload, sent and signed as `schema_version`.
+pub const HEARTBEAT_SCHEMA_VERSION: &str = "heartbeat/v1";
 
 #[derive(Serialize)]
 pub struct Heartbeat<'a> {
+    schema_version: &'a str,
     device_id: &'a str,
     timestamp: String,
+    nonce: &'a str,
     queue_size: u32,
     queue_bytes: u64,
     version: &'a str,
+    /// A secret write failed over from the preferred vault backend this run.
+    degraded_storage: bool,
+    /// Operator-assigned labels (region, customer, line) the server groups devices by.
+    tags: &'a BTreeMap<String, String>,
+    /// Round-trip latency trend of recent submits and heartbeats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency: Option<crate::latency::LatencySummary>,
+}
+
+impl Heartbeat<'_> {
+    /// Serialized body and its signature under `scheme`, bound to the
+    /// heartbeat's own nonce and the `timestamp` header value.
+    fn signed(&self, kp: &Keypair, scheme: SigningScheme, timestamp: &str) -> Result<(Vec<u8>, SignedHeaders)> {
+        let payload = serde_json::to_vec(self)?;
+        let signed = scheme.sign_request(kp, self.device_id, &payload, self.nonce, timestamp);
+        Ok((payload, signed))
+    }
 }
 
 fn load_trust_token() -> Option<String> {
-    use crate::vault::{Vault, VaultBackend};
-    // Try OS keyring first, then file fallback
-    for backend in [VaultBackend::OsKeyring, VaultBackend::File] {
+    use crate::vault::Vault;
+    // Try mounted secrets dir, OS keyring, then file fallback
+    for backend in Vault::read_backends() {
         let v = Vault::with_backend("kmp-pea", "trust-ack-jwt", backend);
         if let Ok(bytes) = v.load_secret() {
-            if let Ok(s) = String::from_utf8(bytes) { return Some(s); }
+            if let Ok(s) = String::from_utf8(bytes) { return Some(s.trim().to_string()); }
         }
     }
     None
 }
 
-pub async fn send_heartbeat(bus: &str, device_id: &str, kp: &Keypair) -> Result<()> {
+/// Check the server's heartbeat ack.
+///
+/// The bus answers with `{"ack": {"nonce", "status", ...}, "ack_signature": "<b64>"}`
+/// where the signature is ed25519 over the canonical (sorted-key) JSON of `ack`.
+/// A missing ack means the bus predates the handshake and is not an error.
+pub fn verify_ack(body: &serde_json::Value, nonce: &str, server_key: Option<&PublicKey>) -> ServerTrust {
+    let ack = match body.get("ack") {
+        Some(ack) if ack.is_object() => ack,
+        _ => return ServerTrust::Unacknowledged,
+    };
+    if ack.get("nonce").and_then(|n| n.as_str()) != Some(nonce) { return ServerTrust::Invalid; }
+    let key = match server_key {
+        Some(key) => key,
+        None => return ServerTrust::Unverified,
+    };
+    let sig = body.get("ack_signature").and_then(|s| s.as_str())
+        .and_then(|s| general_purpose::STANDARD.decode(s).ok())
+        .and_then(|b| Signature::from_bytes(&b).ok());
+    match sig {
+        Some(sig) if key.verify(crate::provision::stable_stringify(ack).as_bytes(), &sig).is_ok() => ServerTrust::Trusted,
+        _ => ServerTrust::Invalid,
+    }
+}
+
+#[tracing::instrument(name = "heartbeat", skip_all, fields(device_id = device_id, nonce = tracing::field::Empty))]
+pub async fn send_heartbeat(bus: &str, device_id: &str, kp: &Keypair, server_key: Option<&PublicKey>, tags: &BTreeMap<String, String>) -> Result<ServerTrust> {
     let (q_count, q_bytes) = crate::queue::stats().unwrap_or((0, 0));
+    let nonce = crate::rng::nonce();
+    tracing::Span::current().record("nonce", nonce.as_str());
+    let now = crate::clock::Correction::current().now();
     let hb = Heartbeat {
+        schema_version: HEARTBEAT_SCHEMA_VERSION,
         device_id,
-        timestamp: chrono::Utc::now().to_rfc3339(),
+        timestamp: now.to_rfc3339(),
+        nonce: &nonce,
         queue_size: q_count as u32,
         queue_bytes: q_bytes as u64,
         version: env!("CARGO_PKG_VERSION"),
+        degraded_storage: crate::vault::degraded(),
+        tags,
+        latency: crate::latency::summary(),
     };
-    let payload = serde_json::to_vec(&hb)?;
-    let mut h = Sha256::new();
-    h.update(&payload);
-    let digest = h.finalize();
-    let sig = kp.sign(&payload);
-    let client = reqwest::Client::new();
-    let mut req = client
-        .post(format!("{}/api/monitoring/heartbeat", bus))
-        .header("X-PEA-Device-Id", device_id)
-        .header("X-PEA-Public-Key", general_purpose::STANDARD.encode(kp.public.as_bytes()))
-        .header("X-PEA-Signature", general_purpose::STANDARD.encode(sig.to_bytes()))
-        .header("X-PEA-Payload-Hash", hex::encode(digest))
-        .json(&hb);
+    let session = crate::session::current(kp, device_id);
+    let scheme = crate::submit::signing_scheme();
+    let (payload, signed) = hb.signed(session.as_ref().map_or(kp, |s| &s.keypair), scheme, &now.timestamp_millis().to_string())?;
+    let client = crate::outbound::client();
+    let mut req = client.post(format!("{}/api/monitoring/heartbeat", bus));
+    if let Some(session) = &session { req = session.headers(req); }
+    let req = scheme.attach(req, kp, device_id, signed);
+    let mut req = crate::submit::payload_hash().headers(req, &payload)
+        .header("Content-Type", "application/json")
+        .body(payload);
     if let Some(tok) = load_trust_token() {
         req = req.header("Authorization", format!("Bearer {}", tok));
     }
-    let _ = req.send().await?;
-    Ok(())
-} 
\ No newline at end of file
+    let started = std::time::Instant::now();
+    let resp = crate::outbound::send(req).await?;
+    let status = resp.status().as_u16();
+    if !resp.status().is_success() {
+        tracing::warn!(status, "heartbeat rejected");
+        return Err(anyhow!("heartbeat status {}", resp.status()));
+    }
+    crate::latency::record(started.elapsed());
+    let offset = resp.headers().get(reqwest::header::DATE).and_then(|d| d.to_str().ok())
+        .and_then(|d| crate::clock::offset_from_date_header(d, chrono::Utc::now()));
+    let body: serde_json::Value = resp.json().await.unwrap_or(serde_json::Value::Null);
+    let trust = verify_ack(&body, &nonce, server_key);
+    crate::provision::record_echoed_device_id(&body, trust, device_id);
+    let view = body.get("ack").and_then(|a| a.get("device")).cloned();
+    let _ = crate::state::update(|s| {
+        s.server_trust = Some(trust);
+        if trust == ServerTrust::Trusted { s.server_view = view; }
+        s.last_heartbeat_at = Some(chrono::Utc::now().to_rfc3339());
+        if offset.is_some() { s.clock_offset_ms = offset; }
+    });
+    if trust == ServerTrust::Invalid { tracing::warn!(status, "server ack failed verification"); }
+    tracing::info!(status, server_trust = ?trust, queue_size = q_count, "heartbeat sent");
+    Ok(trust)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Signer;
+
+    fn server_keypair() -> Keypair {
+        Keypair::generate(&mut rand::rngs::OsRng)
+    }
+
+    fn signed_body(kp: &Keypair, ack: serde_json::Value) -> serde_json::Value {
+        let sig = kp.sign(crate::provision::stable_stringify(&ack).as_bytes());
+        serde_json::json!({ "ack": ack, "ack_signature": general_purpose::STANDARD.encode(sig.to_bytes()) })
+    }
+
+    #[test]
+    fn tags_are_part_of_the_signed_payload() {
+        let kp = server_keypair();
+        let tags: BTreeMap<String, String> = [("region", "us-east"), ("line", "3")].iter()
+            .map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let hb = Heartbeat {
+            schema_version: HEARTBEAT_SCHEMA_VERSION, device_id: "dev-1", timestamp: "2024-01-01T00:00:00Z".into(), nonce: "n-1",
+            queue_size: 0, queue_bytes: 0, version: "test", degraded_storage: false, tags: &tags, latency: None,
+        };
+        let (payload, signed) = hb.signed(&kp, SigningScheme::Body, "1704067200000").unwrap();
+        let sig = signed.signature;
+        assert_eq!(signed.nonce, "n-1");
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["tags"], serde_json::json!({ "line": "3", "region": "us-east" }));
+        assert_eq!(json["schema_version"], HEARTBEAT_SCHEMA_VERSION);
+        assert!(json.get("latency").is_none(), "no samples yet: field omitted");
+        assert!(kp.public.verify(&payload, &sig).is_ok());
+
+        let mut tampered = tags.clone();
+        tampered.insert("region".into(), "eu-west".into());
+        let forged = serde_json::to_vec(&Heartbeat { tags: &tampered, ..hb }).unwrap();
+        assert!(kp.public.verify(&forged, &sig).is_err());
+    }
+
+    #[test]
+    fn valid_ack_is_trusted() {
+        let server = server_keypair();
+        let body = signed_body(&server, serde_json::json!({ "nonce": "n-1", "status": "ok", "device": { "last_seen": "2024-01-01T00:00:00Z" } }));
+        assert_eq!(verify_ack(&body, "n-1", Some(&server.public)), ServerTrust::Trusted);
+        assert_eq!(verify_ack(&body, "n-1", None), ServerTrust::Unverified);
+    }
+
+    #[test]
+    fn missing_ack_degrades_gracefully() {
+        assert_eq!(verify_ack(&serde_json::json!({ "ok": true }), "n-1", Some(&server_keypair().public)), ServerTrust::Unacknowledged);
+        assert_eq!(verify_ack(&serde_json::Value::Null, "n-1", None), ServerTrust::Unacknowledged);
+    }
+
+    #[test]
+    fn bad_signature_or_nonce_is_invalid() {
+        let server = server_keypair();
+        let imposter = server_keypair();
+        let body = signed_body(&imposter, serde_json::json!({ "nonce": "n-1", "status": "ok" }));
+        assert_eq!(verify_ack(&body, "n-1", Some(&server.public)), ServerTrust::Invalid);
+        let body = signed_body(&server, serde_json::json!({ "nonce": "n-2", "status": "ok" }));
+        assert_eq!(verify_ack(&body, "n-1", Some(&server.public)), ServerTrust::Invalid);
+    }
+}
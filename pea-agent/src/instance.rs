@@ -0,0 +1,89 @@
+use anyhow::Result;
+use std::{fmt, fs, io::Write, path::{Path, PathBuf}};
+
+/// Lock file name under the state dir, shared by every mode that drains the queue.
+const LOCK_FILE: &str = "agent.lock";
+
+/// Another live process holds the instance lock.
+#[derive(Debug)]
+pub struct AlreadyRunning {
+    /// What the holder wrote into the lock, if it got that far.
+    pub pid: Option<u32>,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "another pea-agent (pid {}) is already running; it holds {:?}. Stop it first", pid, self.path),
+            None => write!(f, "another pea-agent is already running; it holds {:?}. Stop it first", self.path),
+        }
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+/// Held for the life of a long-running mode so two agents never drain the same
+/// queue: an exclusive OS lock on the lock file, which the kernel releases
+/// when the holder exits, crashed or not. The file stays behind, naming the
+/// last holder's pid.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: fs::File,
+}
+
+/// Take the instance lock at `path`, or `agent.lock` in the state dir.
+pub fn acquire(path: Option<&Path>) -> Result<InstanceLock> {
+    match path {
+        Some(path) => acquire_at(path),
+        None => acquire_at(&crate::paths::state_dir()?.join(LOCK_FILE)),
+    }
+}
+
+fn acquire_at(path: &Path) -> Result<InstanceLock> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+        .map_err(|e| anyhow::anyhow!("opening instance lock {:?}: {}", path, e))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            let pid = fs::read_to_string(path).ok().and_then(|s| s.trim().parse().ok());
+            return Err(AlreadyRunning { pid, path: path.to_path_buf() }.into());
+        }
+        Err(fs::TryLockError::Error(e)) => return Err(anyhow::anyhow!("locking {:?}: {}", path, e)),
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    file.sync_all()?;
+    Ok(InstanceLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_instance_is_refused_until_the_first_exits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOCK_FILE);
+        let first = acquire_at(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), std::process::id().to_string());
+
+        let err = acquire_at(&path).unwrap_err();
+        let running = err.downcast_ref::<AlreadyRunning>().expect("AlreadyRunning");
+        assert_eq!(running.pid, Some(std::process::id()));
+        assert!(err.to_string().contains(&format!("pid {}", std::process::id())), "{}", err);
+
+        drop(first);
+        assert!(acquire_at(&path).is_ok());
+    }
+
+    #[test]
+    fn a_lock_file_left_behind_does_not_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOCK_FILE);
+        // Whatever a crashed holder left, live pid or not, only the OS lock counts.
+        fs::write(&path, std::process::id().to_string()).unwrap();
+        let _lock = acquire_at(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), std::process::id().to_string());
+    }
+}
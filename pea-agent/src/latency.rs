@@ -0,0 +1,152 @@
+use serde::{Serialize, Deserialize};
+use std::{collections::VecDeque, sync::{Mutex, OnceLock}, time::{Duration, Instant}};
+
+/// Weight of the newest sample in the moving average.
+pub const DEFAULT_ALPHA: f64 = 0.2;
+/// Samples kept for the p95; older ones fall off.
+pub const WINDOW: usize = 64;
+/// The tracker is written to the state file at most this often, so a busy
+/// drain doesn't rewrite it on every event.
+const SAVE_EVERY: Duration = Duration::from_secs(60);
+
+/// Round-trip latency of successful submits and heartbeats: an exponential
+/// moving average plus the last `WINDOW` samples for a p95. Fixed size, so it
+/// can be updated on every request and kept in the state file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LatencyTracker {
+    ema_ms: Option<f64>,
+    window: VecDeque<u64>,
+}
+
+/// What heartbeats, `status` and the status socket report.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub ema_ms: f64,
+    pub p95_ms: u64,
+    pub samples: usize,
+}
+
+impl LatencyTracker {
+    pub fn record(&mut self, ms: u64, alpha: f64) {
+        self.ema_ms = Some(match self.ema_ms {
+            Some(ema) => alpha * ms as f64 + (1.0 - alpha) * ema,
+            None => ms as f64,
+        });
+        if self.window.len() == WINDOW { self.window.pop_front(); }
+        self.window.push_back(ms);
+    }
+
+    /// Nearest-rank p95 over the window.
+    pub fn p95_ms(&self) -> Option<u64> {
+        let mut sorted: Vec<u64> = self.window.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * 95).div_ceil(100);
+        sorted.get(rank.checked_sub(1)?).copied()
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        Some(LatencySummary { ema_ms: self.ema_ms?, p95_ms: self.p95_ms()?, samples: self.window.len() })
+    }
+}
+
+static ALPHA: OnceLock<f64> = OnceLock::new();
+static TRACKER: Mutex<Option<LatencyTracker>> = Mutex::new(None);
+static SAVED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Set the smoothing factor for the rest of the process. Call once at startup.
+pub fn configure(alpha: f64) {
+    let _ = ALPHA.set(alpha);
+}
+
+/// `--latency-alpha` value parser: a weight in (0, 1].
+pub fn parse_alpha(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok(alpha),
+        _ => Err("expected a smoothing factor between 0 (exclusive) and 1, e.g. 0.2".into()),
+    }
+}
+
+/// The tracker, resumed from the state file on first use.
+fn with_tracker<T>(f: impl FnOnce(&mut LatencyTracker) -> T) -> T {
+    let mut guard = TRACKER.lock().unwrap_or_else(|e| e.into_inner());
+    let tracker = guard.get_or_insert_with(|| crate::state::load().latency.unwrap_or_default());
+    f(tracker)
+}
+
+/// Whether a save last made at `saved_at` is old enough to make another.
+fn save_due(saved_at: Option<Instant>, now: Instant) -> bool {
+    saved_at.is_none_or(|at| now.duration_since(at) >= SAVE_EVERY)
+}
+
+/// Record one successful round trip, saving the tracker if the last save is
+/// more than [`SAVE_EVERY`] ago.
+pub fn record(elapsed: Duration) {
+    let alpha = ALPHA.get().copied().unwrap_or(DEFAULT_ALPHA);
+    let tracker = with_tracker(|t| {
+        t.record(elapsed.as_millis() as u64, alpha);
+        t.clone()
+    });
+    let now = Instant::now();
+    {
+        let mut saved_at = SAVED_AT.lock().unwrap_or_else(|e| e.into_inner());
+        if !save_due(*saved_at, now) { return; }
+        *saved_at = Some(now);
+    }
+    let _ = crate::state::update(|s| s.latency = Some(tracker));
+}
+
+/// Save the tracker now, for samples recorded since the last throttled save;
+/// `run` calls this on its way out.
+pub fn save() -> anyhow::Result<()> {
+    let tracker = with_tracker(|t| t.clone());
+    crate::state::update(|s| s.latency = Some(tracker))
+}
+
+pub fn summary() -> Option<LatencySummary> {
+    with_tracker(|t| t.summary())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ema_follows_the_sample_sequence() {
+        let mut tracker = LatencyTracker::default();
+        assert_eq!(tracker.summary(), None);
+        let mut emas = Vec::new();
+        for ms in [100, 200, 100, 400] {
+            tracker.record(ms, 0.5);
+            emas.push(tracker.summary().unwrap().ema_ms);
+        }
+        assert_eq!(emas, [100.0, 150.0, 125.0, 262.5]);
+
+        let mut steady = LatencyTracker::default();
+        for _ in 0..10 { steady.record(80, DEFAULT_ALPHA); }
+        steady.record(1080, DEFAULT_ALPHA);
+        assert!((steady.summary().unwrap().ema_ms - 280.0).abs() < 1e-9, "one spike moves the average by alpha");
+    }
+
+    #[test]
+    fn p95_covers_a_bounded_window() {
+        let mut tracker = LatencyTracker::default();
+        for ms in 1..=WINDOW as u64 { tracker.record(ms, DEFAULT_ALPHA); }
+        assert_eq!(tracker.p95_ms(), Some(61));
+        for _ in 0..1000 { tracker.record(5, DEFAULT_ALPHA); }
+        let summary = tracker.summary().unwrap();
+        assert_eq!((summary.p95_ms, summary.samples), (5, WINDOW));
+
+        let saved: LatencyTracker = serde_json::from_value(serde_json::to_value(&tracker).unwrap()).unwrap();
+        assert_eq!(saved, tracker);
+        assert!(parse_alpha("0").is_err() && parse_alpha("1.5").is_err() && parse_alpha("fast").is_err());
+        assert_eq!(parse_alpha("1"), Ok(1.0));
+    }
+
+    #[test]
+    fn saves_are_throttled() {
+        let now = Instant::now();
+        assert!(save_due(None, now), "the first sample is saved at once");
+        assert!(!save_due(Some(now), now + Duration::from_secs(5)));
+        assert!(save_due(Some(now), now + SAVE_EVERY));
+    }
+}
@@ -0,0 +1,100 @@
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+/// How log events are written to stderr: human-readable lines, or one JSON
+/// object per event for journald and log shippers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub const NAMES: [&'static str; 2] = ["text", "json"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Level filter from `PEA_LOG`, else `RUST_LOG`, else `info`; either takes
+/// the usual directives, e.g. `pea_agent::queue=debug,warn`.
+pub fn filter() -> EnvFilter {
+    EnvFilter::try_from_env("PEA_LOG")
+        .or_else(|_| EnvFilter::try_from_default_env())
+        .unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// A subscriber writing `format` to `writer`. JSON events carry the fields of
+/// the span they happened in, so a submit's device id and nonce travel with it.
+pub fn subscriber<W>(format: LogFormat, filter: EnvFilter, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where W: for<'a> MakeWriter<'a> + Send + Sync + 'static {
+    let fmt = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(fmt.finish()),
+        LogFormat::Json => Box::new(fmt.json().with_current_span(true).with_span_list(false).finish()),
+    }
+}
+
+/// Install the process's logger, writing to stderr; call once at startup.
+pub fn init(format: LogFormat) {
+    let _ = tracing::subscriber::set_global_default(subscriber(format, filter(), std::io::stderr));
+}
+
+#[cfg(test)]
+pub mod capture {
+    use std::{io, sync::{Arc, Mutex}};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// Collects what a test subscriber writes, for asserting on JSON events.
+    #[derive(Clone, Default)]
+    pub struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        /// Every JSON event written so far.
+        pub fn events(&self) -> Vec<serde_json::Value> {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+        }
+    }
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Captured;
+        fn make_writer(&'a self) -> Captured { self.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_events_carry_their_span_and_respect_the_filter() {
+        let captured = capture::Captured::default();
+        let subscriber = subscriber(LogFormat::Json, EnvFilter::new("info"), captured.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("drain", device_id = "dev-1");
+            let _entered = span.enter();
+            tracing::warn!(entries = 2, "queue: dead-lettered");
+            tracing::debug!("filtered out");
+        });
+        let events = captured.events();
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0]["level"], "WARN");
+        assert_eq!(events[0]["fields"]["entries"], 2);
+        assert_eq!(events[0]["span"]["device_id"], "dev-1");
+        assert_eq!(LogFormat::parse("json"), Some(LogFormat::Json));
+    }
+}
@@ -0,0 +1,139 @@
+use serde::Serialize;
+use std::{fmt, sync::OnceLock};
+
+/// How subcommands report what they did: `name: value` lines, or with
+/// `--output json` a single JSON object on stdout and every other line on stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    #[default]
+    Text,
+    Json,
+}
+
+impl OutputMode {
+    pub const NAMES: [&'static str; 2] = ["text", "json"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+static MODE: OnceLock<OutputMode> = OnceLock::new();
+
+/// Install the output mode for the rest of the process; call once at startup.
+pub fn configure(mode: OutputMode) {
+    let _ = MODE.set(mode);
+}
+
+pub fn mode() -> OutputMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Print a progress or log line meant for people: stdout as text, stderr under
+/// `--output json` so stdout carries only the result object.
+pub fn say_line(line: fmt::Arguments<'_>) {
+    match mode() {
+        OutputMode::Text => println!("{}", line),
+        OutputMode::Json => eprintln!("{}", line),
+    }
+}
+
+macro_rules! say {
+    ($($arg:tt)*) => { $crate::output::say_line(format_args!($($arg)*)) };
+}
+pub(crate) use say;
+
+/// The result of one subcommand. Text mode prints each field as it is
+/// reported; JSON mode collects them into the object [`Report::finish`] prints.
+pub struct Report {
+    mode: OutputMode,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Report {
+    pub fn new(mode: OutputMode, command: &str) -> Self {
+        let mut fields = serde_json::Map::new();
+        fields.insert("command".into(), command.into());
+        Self { mode, fields }
+    }
+
+    /// Report `name`, printed as `name: value`.
+    pub fn field(&mut self, name: &str, value: impl Serialize + fmt::Display) {
+        self.field_as(name, &value, &value);
+    }
+
+    /// Report `name`, printed as `name: text` but serialized from `value`.
+    pub fn field_as(&mut self, name: &str, text: impl fmt::Display, value: impl Serialize) {
+        match self.mode {
+            OutputMode::Text => println!("{}: {}", name, text),
+            OutputMode::Json => self.set(name, value),
+        }
+    }
+
+    /// Record `name` in the JSON object only, for results the text output
+    /// already puts in its own words.
+    pub fn set(&mut self, name: &str, value: impl Serialize) {
+        if self.mode == OutputMode::Json {
+            self.fields.insert(name.into(), serde_json::to_value(value).unwrap_or_default());
+        }
+    }
+
+    /// Print `line` as text only, for results JSON carries through [`Report::set`].
+    pub fn text(&self, line: impl fmt::Display) {
+        if self.mode == OutputMode::Text { println!("{}", line); }
+    }
+
+    /// Append `value` to the list under `name`, printed as `name: value`.
+    pub fn push(&mut self, name: &str, value: impl Serialize + fmt::Display) {
+        match self.mode {
+            OutputMode::Text => println!("{}: {}", name, value),
+            OutputMode::Json => {
+                let list = self.fields.entry(name).or_insert_with(|| serde_json::Value::Array(Vec::new()));
+                if let serde_json::Value::Array(items) = list { items.push(serde_json::to_value(value).unwrap_or_default()); }
+            }
+        }
+    }
+
+    /// The JSON object, with `ok` and any `error` from `result`.
+    pub fn to_json(&self, result: &anyhow::Result<()>) -> serde_json::Value {
+        let mut fields = self.fields.clone();
+        fields.insert("ok".into(), result.is_ok().into());
+        if let Err(e) = result { fields.insert("error".into(), format!("{:#}", e).into()); }
+        serde_json::Value::Object(fields)
+    }
+
+    /// Print the JSON object under `--output json`; nothing as text.
+    pub fn finish(&self, result: &anyhow::Result<()>) {
+        if self.mode == OutputMode::Json { println!("{}", self.to_json(result)); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_reports_collect_typed_fields() {
+        let mut out = Report::new(OutputMode::Json, "heartbeat");
+        out.field("queued", 3);
+        out.field_as("server_trust", "Verified", "verified");
+        out.set("sent", true);
+        out.push("warning", "slow");
+        out.push("warning", "late");
+        assert_eq!(out.to_json(&Ok(())), serde_json::json!({
+            "command": "heartbeat", "ok": true, "queued": 3, "server_trust": "verified", "sent": true, "warning": ["slow", "late"],
+        }));
+        let failed = out.to_json(&Err(anyhow::anyhow!("bus down")));
+        assert_eq!((&failed["ok"], &failed["error"]), (&serde_json::json!(false), &serde_json::json!("bus down")));
+
+        let mut text = Report::new(OutputMode::Text, "heartbeat");
+        text.set("sent", true);
+        assert_eq!(text.to_json(&Ok(())), serde_json::json!({ "command": "heartbeat", "ok": true }));
+        assert_eq!(OutputMode::parse("json"), Some(OutputMode::Json));
+        assert_eq!(OutputMode::parse("yaml"), None);
+    }
+}
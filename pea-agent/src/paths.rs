@@ -0,0 +1,147 @@
+use anyhow::{Result, anyhow};
+use directories::ProjectDirs;
+use std::{fs, io::Write, path::{Path, PathBuf}, sync::OnceLock, time::{Duration, SystemTime}};
+
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Directory for everything the agent writes at runtime: the queue, the file
+/// vault, state, the dedup ring and update records. Defaults to the project
+/// data dir; `PEA_STATE_DIR` moves it off a read-only image (e.g. onto a tmpfs
+/// overlay) while configuration stays where the image put it.
+pub fn state_dir() -> Result<PathBuf> {
+    if let Some(dir) = STATE_DIR.get() { return Ok(dir.clone()); }
+    let proj = ProjectDirs::from("com","kmp","pea-agent").ok_or_else(|| anyhow!("no project dirs"))?;
+    let override_dir = std::env::var_os("PEA_STATE_DIR").filter(|v| !v.is_empty()).map(PathBuf::from);
+    let dir = resolve_state_dir(proj.data_dir(), override_dir)?;
+    Ok(STATE_DIR.get_or_init(|| dir).clone())
+}
+
+/// `name` under the state dir, created if missing.
+pub fn state_subdir(name: &str) -> Result<PathBuf> {
+    let dir = state_dir()?.join(name);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// A temp file younger than this may still be mid-write by another process.
+const STALE_TMP_AGE: Duration = Duration::from_secs(60);
+
+/// Where [`write_atomic`] stages `path`: `<name>.<pid>-<random>.tmp` beside
+/// it, so it is on the same filesystem, never matches the extension readers
+/// look for, and isn't shared by two writers.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}-{:08x}.tmp", std::process::id(), rand::random::<u32>()));
+    path.with_file_name(name)
+}
+
+/// Replace `path` with `data` via a fsynced temp file renamed into place, so a
+/// crash mid-write leaves the previous contents rather than a truncated file.
+/// The file is readable by its owner only from the moment it is created.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = tmp_path(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)?;
+    if let Err(e) = file.write_all(data).and_then(|_| file.sync_all()) {
+        drop(file);
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    drop(file);
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let _ = fs::File::open(dir).and_then(|d| d.sync_all());
+    }
+    Ok(())
+}
+
+/// Delete every stale temp file interrupted [`write_atomic`]s of `path` left
+/// beside it.
+pub fn remove_stale_tmps(path: &Path) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else { return };
+    let prefix = format!("{}.", name.to_string_lossy());
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for ent in entries.flatten() {
+        let file = ent.file_name().to_string_lossy().into_owned();
+        if file.starts_with(&prefix) && file.ends_with(".tmp") { remove_stale_tmp(&ent.path()); }
+    }
+}
+
+/// Delete `tmp` if an interrupted [`write_atomic`] left it behind. Returns
+/// whether it was removed.
+pub fn remove_stale_tmp(tmp: &Path) -> bool {
+    let stale = fs::metadata(tmp).and_then(|m| m.modified()).ok()
+        .and_then(|m| SystemTime::now().duration_since(m).ok())
+        .is_some_and(|age| age >= STALE_TMP_AGE);
+    stale && fs::remove_file(tmp).is_ok()
+}
+
+fn resolve_state_dir(data_dir: &Path, override_dir: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(dir) = override_dir {
+        ensure_writable(&dir).map_err(|e| anyhow!("PEA_STATE_DIR {:?} is not writable: {}", dir, e))?;
+        return Ok(dir);
+    }
+    ensure_writable(data_dir).map_err(|e| {
+        anyhow!("data dir {:?} is not writable ({}); on a read-only image set PEA_STATE_DIR to a writable path", data_dir, e)
+    })?;
+    Ok(data_dir.to_path_buf())
+}
+
+/// Create `dir` if needed and prove a file can be written in it.
+fn ensure_writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write-probe-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_only_data_dir_needs_and_uses_the_state_redirect() {
+        let tmp = tempfile::tempdir().unwrap();
+        // A path under a regular file can't be created or written, even as root.
+        let image = tmp.path().join("image");
+        fs::write(&image, b"read-only rootfs").unwrap();
+        let data_dir = image.join("pea-agent");
+
+        let err = resolve_state_dir(&data_dir, None).unwrap_err();
+        assert!(err.to_string().contains("PEA_STATE_DIR"), "{}", err);
+
+        let overlay = tmp.path().join("tmpfs/pea-agent");
+        assert_eq!(resolve_state_dir(&data_dir, Some(overlay.clone())).unwrap(), overlay);
+        assert!(overlay.is_dir());
+        assert_eq!(fs::read_dir(&overlay).unwrap().count(), 0, "probe file cleaned up");
+
+        assert!(resolve_state_dir(&data_dir, Some(image.join("elsewhere"))).is_err());
+        let writable = tmp.path().join("data");
+        assert_eq!(resolve_state_dir(&writable, None).unwrap(), writable);
+    }
+
+    #[test]
+    fn atomic_writes_stage_privately_under_unique_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.bin");
+        assert_ne!(tmp_path(&path), tmp_path(&path), "concurrent writers never share a temp file");
+        assert!(tmp_path(&path).to_string_lossy().ends_with(".tmp"));
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "nothing staged is left behind");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
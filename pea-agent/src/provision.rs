@@ -1,9 +1,11 @@
 use anyhow::{Result, anyhow};
-use serde::Serialize;
-use sha2::{Sha256, Digest};
 use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use serde::{Serialize, Deserialize};
+use crate::vault::Vault;
+use std::{collections::BTreeMap, path::Path};
 
-fn stable_stringify(v: &serde_json::Value) -> String {
+pub(crate) fn stable_stringify(v: &serde_json::Value) -> String {
     match v {
         serde_json::Value::Null | serde_json::Value::Bool(_) | serde_json::Value::Number(_) | serde_json::Value::String(_) => v.to_string(),
         serde_json::Value::Array(a) => {
@@ -27,24 +29,592 @@ fn hmac(dev_secret: &str, body: &serde_json::Value, nonce: &str, ts: &str) -> St
     hex::encode(mac.finalize().into_bytes())
 }
 
-pub async fn provision(bus: &str, device_id: &str, public_key_b64: &str, secret: &str, company_id: Option<u32>) -> Result<String> {
-    let body = serde_json::json!({
+/// Everything the installer-secret HMAC covers, exactly as it goes on the wire.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub body: serde_json::Value,
+    /// `stable_stringify(body)`, the form the HMAC is computed over.
+    pub canonical: String,
+    pub nonce: String,
+    pub timestamp: String,
+    pub hmac: String,
+}
+
+impl Handshake {
+    /// Sign `body` with a fresh nonce and the current time.
+    pub fn new(secret: &str, body: serde_json::Value) -> Self {
+        let nonce = crate::rng::nonce();
+        let timestamp = format!("{}", chrono::Utc::now().timestamp_millis());
+        let hmac = hmac(secret, &body, &nonce, &timestamp);
+        Self { canonical: stable_stringify(&body), body, nonce, timestamp, hmac }
+    }
+
+    /// The POST carrying this handshake.
+    fn request(&self, url: String, company_id: Option<u32>) -> reqwest::RequestBuilder {
+        let mut req = crate::outbound::client().post(url)
+            .header("X-PEA-Nonce", &self.nonce)
+            .header("X-PEA-Timestamp", &self.timestamp)
+            .header("X-PEA-HMAC", &self.hmac)
+            .json(&self.body);
+        if let Some(cid) = company_id { req = req.header("X-Company-Id", format!("{}", cid)); }
+        req
+    }
+}
+
+/// POST `body` to `url` authenticated with the installer secret: an HMAC over
+/// the canonical body, a fresh nonce and the timestamp.
+fn signed_request(url: String, secret: &str, body: &serde_json::Value, company_id: Option<u32>) -> reqwest::RequestBuilder {
+    Handshake::new(secret, body.clone()).request(url, company_id)
+}
+
+/// Body `provision` registers; also what `provision-debug` prints.
+pub fn registration_body(device_id: &str, public_key_b64: &str, tags: &BTreeMap<String, String>) -> serde_json::Value {
+    serde_json::json!({
         "device_id": device_id,
         "public_key_b64": public_key_b64,
-        "metadata": {"platform": std::env::consts::OS}
+        "metadata": {"platform": std::env::consts::OS},
+        "tags": tags
+    })
+}
+
+/// The bus has this device on record under a different id than the agent
+/// computes, typically because the hostname or user the id derives from changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdMismatch {
+    pub local: String,
+    pub server: String,
+}
+
+impl std::fmt::Display for DeviceIdMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "device id mismatch: this agent computes {:?} but the bus has it as {:?}; restore the hostname/user it was provisioned under, or re-provision to register the new id", self.local, self.server)
+    }
+}
+
+/// Compare the device id a bus response echoes (`device_id` at the top level
+/// or inside `ack`) with the one this agent computes. Responses from buses
+/// that don't echo an id never mismatch.
+pub fn check_echoed_device_id(body: &serde_json::Value, local: &str) -> Option<DeviceIdMismatch> {
+    let echoed = body.get("device_id").or_else(|| body.get("ack").and_then(|a| a.get("device_id")))?.as_str()?;
+    (echoed != local).then(|| DeviceIdMismatch { local: local.to_string(), server: echoed.to_string() })
+}
+
+/// What a heartbeat answer says about the device id: `None` when it says
+/// nothing that can be relied on, else the mismatch, if any, to remember. Only
+/// the id inside an ack that verified against the server key counts, so a
+/// forged or unsigned echo can't make `status` and `doctor` demand a
+/// re-provision.
+pub fn verified_echo(body: &serde_json::Value, trust: crate::state::ServerTrust, local: &str) -> Option<Option<DeviceIdMismatch>> {
+    if trust != crate::state::ServerTrust::Trusted { return None; }
+    let ack = body.get("ack")?;
+    ack.get("device_id")?;
+    Some(check_echoed_device_id(&serde_json::json!({ "ack": ack }), local))
+}
+
+/// Warn about and remember a device id mismatch from a verified heartbeat
+/// ack, or clear a remembered one once the bus echoes the agent's id again.
+pub fn record_echoed_device_id(body: &serde_json::Value, trust: crate::state::ServerTrust, local: &str) {
+    let Some(mismatch) = verified_echo(body, trust, local) else { return };
+    if let Some(m) = &mismatch { tracing::warn!(local = %m.local, server = %m.server, "{}", m); }
+    let _ = crate::state::update(|s| s.device_id_mismatch = mismatch.map(|m| m.server));
+}
+
+/// Register this device's key. Returns the bus's trust ack, or `None` when it
+/// answered without one, so the caller keeps the stored token rather than
+/// overwriting it.
+#[tracing::instrument(skip_all, fields(device_id = device_id, company_id = ?company_id))]
+pub async fn provision(bus: &str, device_id: &str, public_key_b64: &str, secret: &str, company_id: Option<u32>, tags: &BTreeMap<String, String>) -> Result<Option<String>> {
+    let body = registration_body(device_id, public_key_b64, tags);
+    let req = signed_request(format!("{}/api/provisioning/register", bus), secret, &body, company_id);
+    let resp = crate::outbound::send(req).await?;
+    let status = resp.status().as_u16();
+    if !resp.status().is_success() {
+        tracing::warn!(status, "registration rejected");
+        return Err(anyhow!("status {}", resp.status()));
+    }
+    tracing::info!(status, "device registered");
+    let v: serde_json::Value = resp.json().await?;
+    // The registration answer isn't signed, so this is only a hint; the
+    // next verified heartbeat is what `status` and `doctor` go by.
+    if let Some(m) = check_echoed_device_id(&v, device_id) { tracing::warn!(local = %m.local, server = %m.server, "{}", m); }
+    Ok(v.get("trust_ack").and_then(|x| x.as_str()).filter(|t| !t.is_empty()).map(str::to_string))
+}
+
+/// Whether the bus accepted an installer secret in a `--verify-only` handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    Accepted,
+    /// The bus refused the secret or company id; `reason` is its explanation, if any.
+    Rejected { status: u16, reason: Option<String> },
+}
+
+/// Run the provisioning HMAC handshake against the verify endpoint without
+/// generating, registering or storing anything. Errors mean the bus couldn't
+/// give an answer, not that the secret is wrong.
+pub async fn verify_secret(bus: &str, device_id: &str, secret: &str, company_id: Option<u32>) -> Result<Verification> {
+    let body = serde_json::json!({
+        "device_id": device_id,
+        "metadata": {"platform": std::env::consts::OS},
     });
-    let nonce = uuid::Uuid::new_v4().to_string();
-    let ts = format!("{}", chrono::Utc::now().timestamp_millis());
-    let sig = hmac(secret, &body, &nonce, &ts);
-    let client = reqwest::Client::new();
-    let mut req = client.post(format!("{}/api/provisioning/register", bus))
-        .header("X-PEA-Nonce", &nonce)
-        .header("X-PEA-Timestamp", &ts)
-        .header("X-PEA-HMAC", &sig)
-        .json(&body);
-    if let Some(cid) = company_id { req = req.header("X-Company-Id", format!("{}", cid)); }
-    let resp = req.send().await?;
+    let req = signed_request(format!("{}/api/provisioning/verify", bus), secret, &body, company_id);
+    let resp = crate::outbound::send(req).await?;
+    let status = resp.status();
+    if status.is_success() { return Ok(Verification::Accepted); }
+    if !matches!(status.as_u16(), 400 | 401 | 403) { return Err(anyhow!("verify status {}", status)); }
+    let reason = resp.json::<serde_json::Value>().await.ok()
+        .and_then(|v| v.get("error").and_then(|e| e.as_str()).map(str::to_string));
+    Ok(Verification::Rejected { status: status.as_u16(), reason })
+}
+
+/// Re-key and re-provision the device without risking its current identity.
+///
+/// The new key is registered before anything on disk is touched, and the stored
+/// key and token are only replaced once provisioning succeeds. If writing the new
+/// identity fails part-way, the previous key and token are restored. `prepare`
+/// runs between the two, for whatever must be ready before the new key is
+/// stored (staging the queue for it); its failure keeps the existing identity.
+#[allow(clippy::too_many_arguments)]
+pub async fn reset_identity<S>(bus: &str, device_id: &str, secret: &str, company_id: Option<u32>, tags: &BTreeMap<String, String>, key_vaults: &[Vault], token_vaults: &[Vault], prepare: impl FnOnce(&Keypair) -> Result<S>) -> Result<(Keypair, String, S)> {
+    let kp = crate::rng::keypair();
+    let token = provision(bus, device_id, &general_purpose::STANDARD.encode(kp.public.as_bytes()), secret, company_id, tags).await
+        .map_err(|e| anyhow!("provisioning failed, existing identity kept: {}", e))?
+        .ok_or_else(|| anyhow!("provisioning returned no trust ack, existing identity kept"))?;
+    let prepared = prepare(&kp)?;
+    store_identity(&kp, Some(&token), key_vaults, token_vaults)?;
+    Ok((kp, token, prepared))
+}
+
+/// Replace the stored key and token, restoring the previous ones if a write
+/// fails. Without a new token the stored one is kept.
+pub fn store_identity(kp: &Keypair, token: Option<&str>, key_vaults: &[Vault], token_vaults: &[Vault]) -> Result<()> {
+    let key_backup = backup(key_vaults);
+    let token_backup = backup(token_vaults);
+    let stored = Vault::store_with_failover(key_vaults, &kp.secret.to_bytes())
+        .and_then(|_| token.map_or(Ok(()), |token| Vault::store_with_failover(token_vaults, token.as_bytes())));
+    if let Err(e) = stored {
+        restore(key_vaults, &key_backup);
+        restore(token_vaults, &token_backup);
+        return Err(anyhow!("storing new identity failed, previous identity restored: {}", e));
+    }
+    Ok(())
+}
+
+/// A device identity generated centrally (factory or air-gapped provisioning)
+/// and signed by the issuing tool, installed with `provision-import`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityBundle {
+    pub device_id: String,
+    pub secret_key_b64: String,
+    pub public_key_b64: String,
+    pub trust_ack: String,
+    /// Issuer signature over the canonical JSON of every other field.
+    pub signature_b64: String,
+}
+
+impl IdentityBundle {
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let mut v = serde_json::to_value(self)?;
+        if let Some(obj) = v.as_object_mut() { obj.remove("signature_b64"); }
+        Ok(stable_stringify(&v).into_bytes())
+    }
+}
+
+/// Key of the central tool that signs identity bundles, from the flag or
+/// `PEA_IDENTITY_ISSUER_KEY`.
+pub fn issuer_key(arg: Option<&String>) -> Result<PublicKey> {
+    let b64 = arg.cloned().or_else(|| std::env::var("PEA_IDENTITY_ISSUER_KEY").ok())
+        .ok_or_else(|| anyhow!("no identity issuer key: pass --issuer-key or set PEA_IDENTITY_ISSUER_KEY"))?;
+    let bytes = general_purpose::STANDARD.decode(b64.trim())?;
+    PublicKey::from_bytes(&bytes).map_err(|e| anyhow!("invalid issuer key: {}", e))
+}
+
+/// Read an identity bundle and check the issuer's signature and that the
+/// keypair in it is consistent.
+pub fn verify_identity(path: &Path, issuer: &PublicKey) -> Result<(Keypair, IdentityBundle)> {
+    let raw = std::fs::read(path).map_err(|e| anyhow!("identity file {:?}: {}", path, e))?;
+    let bundle: IdentityBundle = serde_json::from_slice(&raw).map_err(|e| anyhow!("identity file {:?}: {}", path, e))?;
+    let sig = Signature::from_bytes(&general_purpose::STANDARD.decode(bundle.signature_b64.trim())?)
+        .map_err(|e| anyhow!("malformed identity signature: {}", e))?;
+    issuer.verify(&bundle.signed_bytes()?, &sig).map_err(|_| anyhow!("identity file signature does not verify; not importing"))?;
+    let secret = SecretKey::from_bytes(&general_purpose::STANDARD.decode(&bundle.secret_key_b64)?)
+        .map_err(|e| anyhow!("identity secret key: {}", e))?;
+    let public = PublicKey::from(&secret);
+    if general_purpose::STANDARD.encode(public.as_bytes()) != bundle.public_key_b64 {
+        return Err(anyhow!("identity public key does not match its secret key"));
+    }
+    Ok((Keypair { secret, public }, bundle))
+}
+
+/// Install a verified identity bundle in place of the current key and token,
+/// with the same restore-on-failure guarantee as [`store_identity`]. `prepare`
+/// runs once the bundle has checked out, before anything is stored, as in
+/// [`reset_identity`].
+pub fn import_identity<S>(path: &Path, issuer: &PublicKey, device_id: &str, key_vaults: &[Vault], token_vaults: &[Vault], prepare: impl FnOnce(&Keypair) -> Result<S>) -> Result<(Keypair, S)> {
+    let (kp, bundle) = verify_identity(path, issuer)?;
+    if bundle.device_id != device_id {
+        return Err(anyhow!("identity was issued for device {:?}, this is {:?}", bundle.device_id, device_id));
+    }
+    let prepared = prepare(&kp)?;
+    store_identity(&kp, Some(&bundle.trust_ack), key_vaults, token_vaults)?;
+    Ok((kp, prepared))
+}
+
+/// Register `new_public` as an additional key for this device, endorsed by the
+/// current key. The bus accepts both until the old one is retired, so events
+/// signed under either key during the overlap still verify. Returns the new
+/// trust ack, or `None` when the bus answered without one.
+pub async fn register_rotation(bus: &str, device_id: &str, old: &Keypair, new_public: &PublicKey, secret: &str, company_id: Option<u32>) -> Result<Option<String>> {
+    let body = serde_json::json!({
+        "device_id": device_id,
+        "public_key_b64": general_purpose::STANDARD.encode(new_public.as_bytes()),
+        "previous_public_key_b64": general_purpose::STANDARD.encode(old.public.as_bytes()),
+        "endorsement_b64": general_purpose::STANDARD.encode(old.sign(new_public.as_bytes()).to_bytes()),
+    });
+    let req = signed_request(format!("{}/api/provisioning/rotate", bus), secret, &body, company_id);
+    let resp = crate::outbound::send(req).await?;
     if !resp.status().is_success() { return Err(anyhow!("status {}", resp.status())); }
     let v: serde_json::Value = resp.json().await?;
-    Ok(v.get("trust_ack").and_then(|x| x.as_str()).unwrap_or("").to_string())
-} 
\ No newline at end of file
+    Ok(v.get("trust_ack").and_then(|x| x.as_str()).filter(|t| !t.is_empty()).map(str::to_string))
+}
+
+/// End the overlap: ask the bus to stop accepting `old_public`. Signed with the
+/// new key, which proves the rotation completed on this device.
+pub async fn retire_key(bus: &str, device_id: &str, new: &Keypair, old_public: &PublicKey) -> Result<()> {
+    let body = serde_json::to_vec(&serde_json::json!({
+        "device_id": device_id,
+        "public_key_b64": general_purpose::STANDARD.encode(old_public.as_bytes()),
+    }))?;
+    let client = crate::outbound::client();
+    let req = client.post(format!("{}/api/provisioning/retire", bus))
+        .header("X-PEA-Device-Id", device_id)
+        .header("X-PEA-Public-Key", general_purpose::STANDARD.encode(new.public.as_bytes()))
+        .header("X-PEA-Signature", general_purpose::STANDARD.encode(new.sign(&body).to_bytes()))
+        .header("Content-Type", "application/json")
+        .body(body);
+    let resp = crate::outbound::send(req).await?;
+    if !resp.status().is_success() { return Err(anyhow!("status {}", resp.status())); }
+    Ok(())
+}
+
+/// Whether the bus recorded a revocation notice.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RevocationNotice {
+    Acknowledged,
+    /// The bus was unreachable or refused the notice; the reason is kept for the log.
+    NotDelivered(String),
+}
+
+/// Report this device as compromised, signed with its key one last time so the
+/// bus can tell the notice from a forgery, then run `wipe`. The wipe happens
+/// whether or not the notice got through: a compromised device must not keep
+/// its secrets because the bus is down.
+pub async fn revoke(bus: &str, device_id: &str, kp: &Keypair, reason: Option<&str>, wipe: impl FnOnce() -> Result<()>) -> Result<RevocationNotice> {
+    let notice = match notify_revocation(bus, device_id, kp, reason).await {
+        Ok(()) => RevocationNotice::Acknowledged,
+        Err(e) => RevocationNotice::NotDelivered(e.to_string()),
+    };
+    wipe()?;
+    Ok(notice)
+}
+
+async fn notify_revocation(bus: &str, device_id: &str, kp: &Keypair, reason: Option<&str>) -> Result<()> {
+    let body = serde_json::to_vec(&serde_json::json!({
+        "device_id": device_id,
+        "public_key_b64": general_purpose::STANDARD.encode(kp.public.as_bytes()),
+        "reason": reason,
+        "revoked_at": chrono::Utc::now().to_rfc3339(),
+    }))?;
+    let client = crate::outbound::client();
+    let req = client.post(format!("{}/api/provisioning/revoke", bus))
+        .header("X-PEA-Device-Id", device_id)
+        .header("X-PEA-Public-Key", general_purpose::STANDARD.encode(kp.public.as_bytes()))
+        .header("X-PEA-Signature", general_purpose::STANDARD.encode(kp.sign(&body).to_bytes()))
+        .header("Content-Type", "application/json")
+        .timeout(std::time::Duration::from_secs(10))
+        .body(body);
+    let resp = crate::outbound::send(req).await?;
+    if !resp.status().is_success() { return Err(anyhow!("status {}", resp.status())); }
+    Ok(())
+}
+
+fn backup(vaults: &[Vault]) -> Vec<Option<Vec<u8>>> {
+    vaults.iter().map(|v| v.load_secret().ok()).collect()
+}
+
+fn restore(vaults: &[Vault], backup: &[Option<Vec<u8>>]) {
+    for (v, saved) in vaults.iter().zip(backup) {
+        match saved {
+            Some(data) => { let _ = v.store_secret(data); }
+            None => { let _ = v.delete_secret(); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::VaultBackend;
+
+    fn file_vault(dir: &std::path::Path, account: &str) -> Vault {
+        Vault::with_backend("kmp-pea", account, VaultBackend::File).in_dir(dir)
+    }
+
+    #[tokio::test]
+    async fn failed_reset_keeps_old_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = file_vault(dir.path(), "device-ed25519-sk");
+        let token = file_vault(dir.path(), "trust-ack-jwt");
+        key.store_secret(&[1u8; 32]).unwrap();
+        token.store_secret(b"old.token.jwt").unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let _m = server.mock("POST", "/api/provisioning/register").with_status(500).create_async().await;
+
+        let res = reset_identity(&server.url(), "dev-1", "s3cret", None, &BTreeMap::new(), &[key], &[token], |_| Ok(())).await;
+        assert!(res.is_err());
+        assert_eq!(file_vault(dir.path(), "device-ed25519-sk").load_secret().unwrap(), vec![1u8; 32]);
+        assert_eq!(file_vault(dir.path(), "trust-ack-jwt").load_secret().unwrap(), b"old.token.jwt");
+    }
+
+    #[tokio::test]
+    async fn successful_reset_replaces_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = file_vault(dir.path(), "device-ed25519-sk");
+        let token = file_vault(dir.path(), "trust-ack-jwt");
+        key.store_secret(&[1u8; 32]).unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let _m = server.mock("POST", "/api/provisioning/register")
+            .with_status(200)
+            .with_body(r#"{"trust_ack":"new.token.jwt"}"#)
+            .create_async().await;
+
+        let (kp, tok, ()) = reset_identity(&server.url(), "dev-1", "s3cret", None, &BTreeMap::new(), &[key], &[token], |_| Ok(())).await.unwrap();
+        assert_eq!(tok, "new.token.jwt");
+        assert_eq!(file_vault(dir.path(), "device-ed25519-sk").load_secret().unwrap(), kp.secret.to_bytes().to_vec());
+        assert_eq!(file_vault(dir.path(), "trust-ack-jwt").load_secret().unwrap(), b"new.token.jwt");
+    }
+
+    #[tokio::test]
+    async fn an_answer_without_a_trust_ack_keeps_the_stored_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = file_vault(dir.path(), "device-ed25519-sk");
+        let token = file_vault(dir.path(), "trust-ack-jwt");
+        key.store_secret(&[1u8; 32]).unwrap();
+        token.store_secret(b"old.token.jwt").unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let _m = server.mock("POST", "/api/provisioning/register")
+            .with_status(200)
+            .with_body(r#"{"device_id":"dev-1"}"#)
+            .create_async().await;
+
+        assert_eq!(provision(&server.url(), "dev-1", "pk", "s3cret", None, &BTreeMap::new()).await.unwrap(), None);
+        let err = reset_identity(&server.url(), "dev-1", "s3cret", None, &BTreeMap::new(), &[key], &[token], |_| Ok(())).await.unwrap_err();
+        assert!(err.to_string().contains("no trust ack"), "{}", err);
+        assert_eq!(file_vault(dir.path(), "device-ed25519-sk").load_secret().unwrap(), vec![1u8; 32]);
+        assert_eq!(file_vault(dir.path(), "trust-ack-jwt").load_secret().unwrap(), b"old.token.jwt");
+    }
+
+    #[tokio::test]
+    async fn a_rotation_without_a_trust_ack_keeps_the_stored_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = file_vault(dir.path(), "device-ed25519-sk");
+        let token = file_vault(dir.path(), "trust-ack-jwt");
+        key.store_secret(&[1u8; 32]).unwrap();
+        token.store_secret(b"old.token.jwt").unwrap();
+        let mut rng = rand::rngs::OsRng;
+        let (old, new) = (Keypair::generate(&mut rng), Keypair::generate(&mut rng));
+
+        let mut server = mockito::Server::new_async().await;
+        let _m = server.mock("POST", "/api/provisioning/rotate").with_status(200).with_body("{}").create_async().await;
+
+        let tok = register_rotation(&server.url(), "dev-1", &old, &new.public, "s3cret", None).await.unwrap();
+        assert_eq!(tok, None);
+        store_identity(&new, tok.as_deref(), &[key], &[token]).unwrap();
+        assert_eq!(file_vault(dir.path(), "device-ed25519-sk").load_secret().unwrap(), new.secret.to_bytes().to_vec());
+        assert_eq!(file_vault(dir.path(), "trust-ack-jwt").load_secret().unwrap(), b"old.token.jwt");
+    }
+
+    #[tokio::test]
+    async fn rotation_is_endorsed_by_old_key_and_retired_by_new() {
+        let mut rng = rand::rngs::OsRng;
+        let old = Keypair::generate(&mut rng);
+        let new = Keypair::generate(&mut rng);
+        let b64 = |b: &[u8]| general_purpose::STANDARD.encode(b);
+
+        let mut server = mockito::Server::new_async().await;
+        let rotate = server.mock("POST", "/api/provisioning/rotate")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "public_key_b64": b64(new.public.as_bytes()),
+                "previous_public_key_b64": b64(old.public.as_bytes()),
+                "endorsement_b64": b64(&old.sign(new.public.as_bytes()).to_bytes()),
+            })))
+            .with_status(200)
+            .with_body(r#"{"trust_ack":"rotated.token.jwt"}"#)
+            .create_async().await;
+        let retire = server.mock("POST", "/api/provisioning/retire")
+            .match_header("X-PEA-Public-Key", b64(new.public.as_bytes()).as_str())
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({ "public_key_b64": b64(old.public.as_bytes()) })))
+            .with_status(200)
+            .create_async().await;
+
+        let tok = register_rotation(&server.url(), "dev-1", &old, &new.public, "s3cret", None).await.unwrap();
+        assert_eq!(tok.as_deref(), Some("rotated.token.jwt"));
+        retire_key(&server.url(), "dev-1", &new, &old.public).await.unwrap();
+        rotate.assert_async().await;
+        retire.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn revocation_is_acknowledged_before_the_wipe() {
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let public = kp.public;
+        let mut server = mockito::Server::new_async().await;
+        let notice = server.mock("POST", "/api/provisioning/revoke")
+            .match_request(move |req| {
+                let sig = req.header("x-pea-signature").first().and_then(|v| v.to_str().ok())
+                    .and_then(|s| general_purpose::STANDARD.decode(s).ok()).and_then(|s| Signature::from_bytes(&s).ok());
+                let body = req.body().unwrap();
+                let reason = serde_json::from_slice::<serde_json::Value>(body).unwrap()["reason"].clone();
+                reason == "tampered" && sig.is_some_and(|sig| public.verify(body, &sig).is_ok())
+            })
+            .with_status(200)
+            .create_async().await;
+
+        let wiped = std::cell::Cell::new(false);
+        let outcome = revoke(&server.url(), "dev-1", &kp, Some("tampered"), || {
+            assert!(notice.matched(), "wipe ran before the notice was sent");
+            wiped.set(true);
+            Ok(())
+        }).await.unwrap();
+        assert_eq!(outcome, RevocationNotice::Acknowledged);
+        assert!(wiped.get());
+    }
+
+    #[tokio::test]
+    async fn revocation_still_wipes_when_the_bus_is_unreachable() {
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let wiped = std::cell::Cell::new(false);
+        let outcome = revoke("http://127.0.0.1:1", "dev-1", &kp, None, || { wiped.set(true); Ok(()) }).await.unwrap();
+        assert!(matches!(outcome, RevocationNotice::NotDelivered(_)));
+        assert!(wiped.get());
+    }
+
+    #[test]
+    fn echoed_device_id_that_differs_is_a_mismatch() {
+        let heartbeat = serde_json::json!({ "ack": { "nonce": "n-1", "device_id": "old-host-dev" } });
+        let mismatch = check_echoed_device_id(&heartbeat, "new-host-dev").expect("mismatch");
+        assert_eq!(mismatch, DeviceIdMismatch { local: "new-host-dev".into(), server: "old-host-dev".into() });
+        assert!(mismatch.to_string().contains("re-provision"), "{}", mismatch);
+
+        let registration = serde_json::json!({ "trust_ack": "t", "device_id": "old-host-dev" });
+        assert!(check_echoed_device_id(&registration, "new-host-dev").is_some());
+        assert_eq!(check_echoed_device_id(&registration, "old-host-dev"), None);
+        assert_eq!(check_echoed_device_id(&serde_json::json!({ "trust_ack": "t" }), "new-host-dev"), None);
+    }
+
+    #[test]
+    fn only_a_verified_ack_can_record_a_mismatch() {
+        use crate::state::ServerTrust;
+        let heartbeat = serde_json::json!({ "ack": { "nonce": "n-1", "device_id": "old-host-dev" } });
+        for trust in [ServerTrust::Unverified, ServerTrust::Unacknowledged, ServerTrust::Invalid] {
+            assert_eq!(verified_echo(&heartbeat, trust, "new-host-dev"), None, "{:?}", trust);
+        }
+        assert!(matches!(verified_echo(&heartbeat, ServerTrust::Trusted, "new-host-dev"), Some(Some(m)) if m.server == "old-host-dev"));
+        assert_eq!(verified_echo(&heartbeat, ServerTrust::Trusted, "old-host-dev"), Some(None), "a matching echo clears the flag");
+
+        // An id outside the signed ack is not vouched for.
+        let unsigned = serde_json::json!({ "device_id": "old-host-dev", "ack": { "nonce": "n-1" } });
+        assert_eq!(verified_echo(&unsigned, ServerTrust::Trusted, "new-host-dev"), None);
+    }
+
+    #[tokio::test]
+    async fn verify_only_reports_accepted_and_rejected_secrets() {
+        let mut server = mockito::Server::new_async().await;
+        let _ok = server.mock("POST", "/api/provisioning/verify")
+            .match_request(|req| {
+                let header = |name: &str| req.header(name).first().and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+                let body: serde_json::Value = serde_json::from_slice(req.body().unwrap()).unwrap();
+                header("x-company-id") == "7"
+                    && header("x-pea-hmac") == hmac("good-secret", &body, &header("x-pea-nonce"), &header("x-pea-timestamp"))
+            })
+            .with_status(200)
+            .with_body(r#"{"ok":true}"#)
+            .create_async().await;
+        let _rejected = server.mock("POST", "/api/provisioning/verify")
+            .with_status(401)
+            .with_body(r#"{"error":"invalid installer secret"}"#)
+            .create_async().await;
+
+        assert_eq!(verify_secret(&server.url(), "dev-1", "good-secret", Some(7)).await.unwrap(), Verification::Accepted);
+        assert_eq!(
+            verify_secret(&server.url(), "dev-1", "wrong-secret", Some(7)).await.unwrap(),
+            Verification::Rejected { status: 401, reason: Some("invalid installer secret".into()) },
+        );
+        assert!(matches!(verify_secret(&server.url(), "dev-1", "good-secret", Some(8)).await.unwrap(), Verification::Rejected { .. }));
+    }
+
+    fn issue_identity(issuer: &Keypair, device: &Keypair, device_id: &str) -> IdentityBundle {
+        let mut bundle = IdentityBundle {
+            device_id: device_id.into(),
+            secret_key_b64: general_purpose::STANDARD.encode(device.secret.as_bytes()),
+            public_key_b64: general_purpose::STANDARD.encode(device.public.as_bytes()),
+            trust_ack: "factory.token.jwt".into(),
+            signature_b64: String::new(),
+        };
+        bundle.signature_b64 = general_purpose::STANDARD.encode(issuer.sign(&bundle.signed_bytes().unwrap()).to_bytes());
+        bundle
+    }
+
+    #[test]
+    fn signed_identity_is_imported_and_tampered_one_rejected() {
+        let mut rng = rand::rngs::OsRng;
+        let issuer = Keypair::generate(&mut rng);
+        let device = Keypair::generate(&mut rng);
+        let dir = tempfile::tempdir().unwrap();
+        let key = || file_vault(dir.path(), "device-ed25519-sk");
+        let token = || file_vault(dir.path(), "trust-ack-jwt");
+        key().store_secret(&[1u8; 32]).unwrap();
+        token().store_secret(b"old.token.jwt").unwrap();
+
+        let path = dir.path().join("identity.json");
+        let mut tampered = issue_identity(&issuer, &device, "line3-scanner");
+        tampered.trust_ack = "forged.token.jwt".into();
+        std::fs::write(&path, serde_json::to_vec(&tampered).unwrap()).unwrap();
+        let err = import_identity(&path, &issuer.public, "line3-scanner", &[key()], &[token()], |_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("does not verify"));
+        assert_eq!(key().load_secret().unwrap(), vec![1u8; 32], "existing identity untouched");
+        assert_eq!(token().load_secret().unwrap(), b"old.token.jwt");
+
+        std::fs::write(&path, serde_json::to_vec(&issue_identity(&issuer, &device, "line3-scanner")).unwrap()).unwrap();
+        assert!(import_identity(&path, &issuer.public, "other-device", &[key()], &[token()], |_| Ok(())).is_err());
+        let other_issuer = Keypair::generate(&mut rng);
+        assert!(import_identity(&path, &other_issuer.public, "line3-scanner", &[key()], &[token()], |_| Ok(())).is_err());
+
+        let (kp, ()) = import_identity(&path, &issuer.public, "line3-scanner", &[key()], &[token()], |_| Ok(())).unwrap();
+        assert_eq!(kp.public, device.public);
+        assert_eq!(key().load_secret().unwrap(), device.secret.to_bytes().to_vec());
+        assert_eq!(token().load_secret().unwrap(), b"factory.token.jwt");
+    }
+
+    #[test]
+    fn debug_handshake_matches_the_request_provision_sends() {
+        let mut tags = BTreeMap::new();
+        tags.insert("line".to_string(), "3".to_string());
+        let body = registration_body("dev-1", "cHVibGlj", &tags);
+        let hs = Handshake::new("s3cret", body.clone());
+        assert_eq!(hs.hmac, hmac("s3cret", &body, &hs.nonce, &hs.timestamp));
+
+        let req = hs.request("http://bus/api/provisioning/register".into(), Some(7)).build().unwrap();
+        let header = |name: &str| req.headers()[name].to_str().unwrap().to_string();
+        assert_eq!(header("X-PEA-HMAC"), hs.hmac);
+        assert_eq!(header("X-PEA-Nonce"), hs.nonce);
+        assert_eq!(header("X-PEA-Timestamp"), hs.timestamp);
+        assert_eq!(header("X-Company-Id"), "7");
+        // What the server canonicalizes from the received body is what was printed.
+        let sent: serde_json::Value = serde_json::from_slice(req.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(stable_stringify(&sent), hs.canonical);
+        assert_eq!(hmac("s3cret", &sent, &header("X-PEA-Nonce"), &header("X-PEA-Timestamp")), hs.hmac);
+    }
+}
@@ -1,74 +1,1662 @@
 use anyhow::{Result, anyhow};
-use directories::ProjectDirs;
-use std::{fs, path::PathBuf, time::Duration};
+use std::{fs, path::{Path, PathBuf}, time::{Duration, SystemTime}};
 use aes_gcm::{Aes256Gcm, Nonce};
-use aead::{Aead, KeyInit};
+use aead::{Aead, KeyInit, Payload};
 use sha2::{Sha256, Digest};
+use hkdf::Hkdf;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock, atomic::{AtomicU64, Ordering}};
+use zeroize::{Zeroize, Zeroizing};
+use crate::shutdown::Shutdown;
+use serde::Serialize;
+
+/// Directory entries are read this many at a time while draining, so a backlog
+/// of 100k+ events after a long outage never gets materialized in memory at once.
+const DRAIN_WINDOW: usize = 256;
+
+/// Longest a delivery hook may hold up the drain before it is abandoned.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn queue_dir() -> Result<PathBuf> {
-    let proj = ProjectDirs::from("com","kmp","pea-agent").ok_or_else(|| anyhow!("no project dirs"))?;
-    let dir = proj.data_dir().join("queue");
-    fs::create_dir_all(&dir)?;
-    Ok(dir)
+    crate::paths::state_subdir("queue")
+}
+
+/// Queue encryption key, installed by `unlock` once the device secret is loaded.
+static QUEUE_KEY: Mutex<Option<Zeroizing<[u8; 32]>>> = Mutex::new(None);
+
+/// Present in the queue dir once entries sealed under the host-derived key
+/// have been re-encrypted under the device-derived one.
+const MIGRATED_MARKER: &str = ".device-key";
+
+/// Derive the queue key from the provisioned device secret, so the queue is
+/// protected by the same device-bound key as the identity.
+pub fn derive_key(device_secret: &[u8]) -> Zeroizing<[u8; 32]> {
+    let hk = Hkdf::<Sha256>::new(Some(b"pea-agent/queue"), device_secret);
+    let mut k = Zeroizing::new([0u8; 32]);
+    hk.expand(b"queue-key v1", k.as_mut()).expect("32 bytes is a valid HKDF-SHA256 length");
+    k
 }
 
-fn key() -> [u8;32] {
+/// Pre-HKDF derivation from hostname and username; only read to migrate old entries.
+fn legacy_key() -> Zeroizing<[u8;32]> {
     let mut h = Sha256::new();
-    h.update(whoami::hostname()); h.update(whoami::username());
-    let out = h.finalize(); let mut k=[0u8;32]; k.copy_from_slice(&out); k
+    h.update(whoami::fallible::hostname().unwrap_or_else(|_| "unknown-host".to_string())); h.update(whoami::username());
+    let mut out = h.finalize(); let mut k = Zeroizing::new([0u8;32]); k.copy_from_slice(&out); out.zeroize(); k
 }
 
-pub fn enqueue(name: &str, data: &[u8]) -> Result<()> {
+/// A copy of the installed queue key, wiped when the caller drops it.
+fn key() -> Result<Zeroizing<[u8;32]>> {
+    QUEUE_KEY.lock().unwrap_or_else(|e| e.into_inner()).clone()
+        .ok_or_else(|| anyhow!("queue is locked: no device key loaded (provision the device first)"))
+}
+
+fn install_key(k: Zeroizing<[u8; 32]>) {
+    *QUEUE_KEY.lock().unwrap_or_else(|e| e.into_inner()) = Some(k);
+}
+
+/// Install the queue key for `device_secret`, first re-encrypting any entries
+/// left from the host-derived key.
+pub fn unlock(device_secret: &[u8]) -> Result<()> {
+    let k = derive_key(device_secret);
+    if key().ok().as_ref() == Some(&k) { return Ok(()); }
     let dir = queue_dir()?;
-    let mut nonce_bytes = rand::random::<[u8;12]>();
-    let cipher = Aes256Gcm::new_from_slice(&key()).unwrap();
+    if !dir.join(MIGRATED_MARKER).exists() {
+        let moved = reencrypt_in(&dir, &legacy_key(), &k)?;
+        if moved > 0 { tracing::info!(entries = moved, "re-encrypted queue entries under the device key"); }
+        fs::write(dir.join(MIGRATED_MARKER), b"")?;
+    }
+    install_key(k);
+    Ok(())
+}
+
+/// Sibling of the queue dir where a rekey builds the re-sealed copy.
+const REKEY_STAGING: &str = "queue.rekey";
+/// Sibling the live queue is moved to while the staged copy takes its place.
+const REKEY_RETIRED: &str = "queue.old";
+
+/// The queue re-sealed for a replaced device secret (rotation, reset or
+/// import), built beside the live queue so nothing changes until
+/// [`StagedRekey::commit`]. Dropped uncommitted, the copy is discarded. Holds
+/// the drain lock throughout, so no drain delivers from the copy's source.
+pub struct StagedRekey {
+    live: PathBuf,
+    staging: PathBuf,
+    key: Zeroizing<[u8; 32]>,
+    lock: Option<DrainLock>,
+}
+
+/// Stage the queue for `new_secret`: entries and attachments the installed
+/// key opens are re-sealed, everything else is copied as is. Without an
+/// installed key (no identity yet) the copy is verbatim.
+pub fn stage_rekey(new_secret: &[u8]) -> Result<StagedRekey> {
+    stage_rekey_in(&queue_dir()?, key().ok().as_deref(), derive_key(new_secret))
+}
+
+fn stage_rekey_in(live: &Path, old: Option<&[u8; 32]>, new: Zeroizing<[u8; 32]>) -> Result<StagedRekey> {
+    let lock = DrainLock::acquire(live)?.ok_or_else(|| anyhow!("a queue drain is in progress; stop the agent and retry"))?;
+    let staging = live.with_file_name(REKEY_STAGING);
+    if staging.exists() { fs::remove_dir_all(&staging)?; }
+    let staged = StagedRekey { live: live.to_path_buf(), staging, key: new, lock: Some(lock) };
+    copy_resealed(live, &staged.staging, old, &staged.key)?;
+    Ok(staged)
+}
+
+fn copy_resealed(from: &Path, to: &Path, old: Option<&[u8; 32]>, new: &[u8; 32]) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for ent in fs::read_dir(from)? {
+        let ent = ent?;
+        let (src, dest) = (ent.path(), to.join(ent.file_name()));
+        if ent.file_name() == DRAIN_LOCK { continue; }
+        if ent.file_type()?.is_dir() {
+            copy_resealed(&src, &dest, old, new)?;
+            continue;
+        }
+        let data = fs::read(&src)?;
+        let sealed = matches!(src.extension().and_then(|s| s.to_str()), Some("bin" | "blob"));
+        let data = match old.filter(|_| sealed).and_then(|old| open_with(old, &data, &aad(&src)).ok()) {
+            Some(plain) => seal_with(new, &plain, &aad(&src))?,
+            None => data,
+        };
+        fs::write(&dest, data)?;
+    }
+    Ok(())
+}
+
+impl StagedRekey {
+    /// Put the staged queue in place of the live one and install its key.
+    /// Call once the new identity is stored.
+    pub fn commit(mut self) -> Result<()> {
+        self.swap()?;
+        install_key(self.key.clone());
+        Ok(())
+    }
+
+    fn swap(&mut self) -> Result<()> {
+        let retired = self.live.with_file_name(REKEY_RETIRED);
+        if retired.exists() { fs::remove_dir_all(&retired)?; }
+        // Windows won't move a dir with the lock file open in it.
+        self.lock.take();
+        fs::rename(&self.live, &retired)?;
+        if let Err(e) = fs::rename(&self.staging, &self.live) {
+            let _ = fs::rename(&retired, &self.live);
+            return Err(anyhow!("swapping in the re-keyed queue: {}", e));
+        }
+        if let Err(e) = fs::remove_dir_all(&retired) { tracing::warn!(dir = ?retired, error = %e, "previous queue left behind"); }
+        Ok(())
+    }
+}
+
+impl Drop for StagedRekey {
+    fn drop(&mut self) {
+        if self.staging.exists() { let _ = fs::remove_dir_all(&self.staging); }
+    }
+}
+
+/// Re-seal entries and attachments readable under `from` with `to`. Entries
+/// already under `to` are left alone, so an interrupted pass can be rerun.
+fn reencrypt_in(dir: &Path, from: &[u8; 32], to: &[u8; 32]) -> Result<usize> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for (d, ext) in [(dir.to_path_buf(), "bin"), (dir.join(DEAD_LETTER_DIR), "bin"), (dir.join("attachments"), "blob")] {
+        if !d.is_dir() { continue; }
+        for ent in fs::read_dir(&d)? {
+            let p = ent?.path();
+            if p.extension().and_then(|s| s.to_str()) == Some(ext) { files.push(p); }
+        }
+    }
+    let mut moved = 0;
+    for p in files {
+        let data = fs::read(&p)?;
+        let aad = aad(&p);
+        if open_with(to, &data, &aad).is_ok() { continue; }
+        match open_with(from, &data, &aad) {
+            Ok(plain) => {
+                let tmp = p.with_extension("rekey");
+                fs::write(&tmp, seal_with(to, &plain, &aad)?)?;
+                fs::rename(&tmp, &p)?;
+                moved += 1;
+            }
+            Err(_) => tracing::warn!(entry = ?p, "queue entry readable under neither key, left as is"),
+        }
+    }
+    Ok(moved)
+}
+
+/// Marks a queued plaintext carrying an envelope header. Entries written before
+/// envelopes existed are bare event JSON and never start with this.
+const ENVELOPE_MAGIC: &[u8; 6] = b"PEAQ1\0";
+
+/// Queue an event. With a TTL, the entry is dropped instead of delivered once
+/// the TTL has elapsed.
+pub fn enqueue(name: &str, data: &[u8], ttl: Option<Duration>) -> Result<()> {
+    match MAX_BYTES.get() {
+        Some(&max_bytes) => enqueue_bounded(name, data, ttl, max_bytes),
+        None => enqueue_in(&queue_dir()?, name, data, ttl).map(|_| ()),
+    }
+}
+
+static MAX_BYTES: OnceLock<u64> = OnceLock::new();
+
+/// Cap the queue's total size for the rest of the process; call once at startup.
+pub fn set_max_bytes(max_bytes: u64) {
+    let _ = MAX_BYTES.set(max_bytes);
+}
+
+/// [`enqueue`], first evicting the oldest entries until the new one fits in
+/// `max_bytes` of queue. An entry bigger than the whole cap is refused without
+/// evicting anything.
+pub fn enqueue_bounded(name: &str, data: &[u8], ttl: Option<Duration>, max_bytes: u64) -> Result<()> {
+    enqueue_bounded_in(&queue_dir()?, name, data, ttl, max_bytes).map(|_| ())
+}
+
+/// Last enqueue stamp handed out, so entries queued within one clock tick
+/// still get distinct, increasing names.
+static LAST_STAMP: AtomicU64 = AtomicU64::new(0);
+
+/// Longest name label kept in an entry's file name; scanned codes can be long
+/// enough to push the whole name past the filesystem's 255-byte limit.
+const MAX_LABEL: usize = 64;
+
+/// `<enqueue-ns>-<random>-<name>.bin`: unique even when the same product is
+/// queued twice before a drain, and still showing which product the entry is
+/// for. Characters that don't belong in a file name become `_`, and the label
+/// stops after [`MAX_LABEL`] bytes.
+fn entry_file_name(name: &str) -> String {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    let last = LAST_STAMP.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1))).unwrap_or_else(|last| last);
+    let stamp = now.max(last + 1);
+    let label: String = name.chars().take(MAX_LABEL).map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect();
+    format!("{:020}-{:08x}-{}.bin", stamp, rand::random::<u32>(), label)
+}
+
+fn enqueue_in(dir: &Path, name: &str, data: &[u8], ttl: Option<Duration>) -> Result<PathBuf> {
+    let path = dir.join(entry_file_name(name));
+    write_entry(&path, name, seal_entry(&path, data, ttl)?)
+}
+
+fn enqueue_bounded_in(dir: &Path, name: &str, data: &[u8], ttl: Option<Duration>, max_bytes: u64) -> Result<PathBuf> {
+    let path = dir.join(entry_file_name(name));
+    let sealed = seal_entry(&path, data, ttl)?;
+    let needed = sealed.len() as u64;
+    if needed > max_bytes {
+        return Err(anyhow!("queue entry {} is {} bytes, more than the whole {}-byte queue cap", name, needed, max_bytes));
+    }
+    let mut used = stats_in(dir)?.1 as u64;
+    if used + needed <= max_bytes { return write_entry(&path, name, sealed); }
+    // Evicting under a drain could delete the entry it is submitting; the
+    // queue stays over its cap until the next enqueue instead.
+    let Some(_lock) = DrainLock::acquire(dir)? else {
+        tracing::warn!(max_bytes, "queue over its byte cap during a drain, nothing evicted");
+        return write_entry(&path, name, sealed);
+    };
+    'evict: for window in EntryWindows::new(dir, 64)? {
+        for oldest in window? {
+            if used + needed <= max_bytes { break 'evict; }
+            let size = fs::metadata(&oldest)?.len();
+            fs::remove_file(&oldest)?;
+            let _ = fs::remove_file(attempts_path(&oldest));
+            used = used.saturating_sub(size);
+            tracing::warn!(max_bytes, entry = ?oldest.file_name().unwrap_or_default(), "queue over its byte cap, evicted oldest entry");
+        }
+    }
+    write_entry(&path, name, sealed)
+}
+
+/// Envelope (magic, expiry) plus payload, sealed under the queue key for `path`.
+fn seal_entry(path: &Path, data: &[u8], ttl: Option<Duration>) -> Result<Vec<u8>> {
+    let expires_at = ttl.map(|t| now_secs() + t.as_secs()).unwrap_or(0);
+    let mut plain = Vec::with_capacity(ENVELOPE_MAGIC.len() + 8 + data.len());
+    plain.extend_from_slice(ENVELOPE_MAGIC);
+    plain.extend_from_slice(&expires_at.to_be_bytes());
+    plain.extend_from_slice(data);
+    seal(&plain, &aad(path))
+}
+
+fn write_entry(path: &Path, name: &str, sealed: Vec<u8>) -> Result<PathBuf> {
+    crate::paths::write_atomic(path, &sealed)?;
+    crate::events::publish("enqueue", serde_json::json!({ "name": name }));
+    Ok(path.to_path_buf())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Split a decrypted entry into its expiry (unix seconds, if any) and payload.
+fn unwrap_envelope(plain: Vec<u8>) -> (Option<u64>, Vec<u8>) {
+    let header = ENVELOPE_MAGIC.len() + 8;
+    if plain.len() < header || !plain.starts_with(ENVELOPE_MAGIC) { return (None, plain); }
+    let mut exp = [0u8; 8];
+    exp.copy_from_slice(&plain[ENVELOPE_MAGIC.len()..header]);
+    let exp = u64::from_be_bytes(exp);
+    ((exp != 0).then_some(exp), plain[header..].to_vec())
+}
+
+/// Starts every queued file sealed with associated data. Files from before
+/// then are a bare nonce and ciphertext and still open.
+const SEALED_MAGIC: &[u8; 4] = b"PQS2";
+
+/// Associated data of a queued file: its name, which is the entry's or
+/// attachment's identity, so bytes copied over another entry fail to open.
+fn aad(path: &Path) -> Vec<u8> {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    format!("pea-queue/{}", name).into_bytes()
+}
+
+/// magic || nonce || AES-GCM ciphertext, the on-disk format of every queued file.
+fn seal(data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    seal_with(&*key()?, data, aad)
+}
+
+fn seal_with(key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let nonce_bytes = rand::random::<[u8;12]>();
+    let cipher = Aes256Gcm::new_from_slice(key).unwrap();
     let nonce = Nonce::from_slice(&nonce_bytes);
-    let ct = cipher.encrypt(nonce, data).map_err(|_| anyhow!("encrypt failed"))?;
-    let mut out = Vec::with_capacity(12+ct.len());
+    let ct = cipher.encrypt(nonce, Payload { msg: data, aad }).map_err(|_| anyhow!("encrypt failed"))?;
+    let mut out = Vec::with_capacity(SEALED_MAGIC.len() + 12 + ct.len());
+    out.extend_from_slice(SEALED_MAGIC);
     out.extend_from_slice(&nonce_bytes);
     out.extend_from_slice(&ct);
-    fs::write(dir.join(format!("{}.bin", name)), out)?;
+    Ok(out)
+}
+
+fn open(data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    open_with(&*key()?, data, aad)
+}
+
+fn open_with(key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+    if let Some(sealed) = data.strip_prefix(SEALED_MAGIC.as_slice()).filter(|s| s.len() >= 12) {
+        let (nonce_bytes, ct) = sealed.split_at(12);
+        if let Ok(plain) = cipher.decrypt(Nonce::from_slice(nonce_bytes), Payload { msg: ct, aad }) { return Ok(plain); }
+    }
+    // Sealed before files carried associated data.
+    if data.len() < 12 { return Err(anyhow!("queued file truncated")); }
+    let (nonce_bytes, ct) = data.split_at(12);
+    cipher.decrypt(Nonce::from_slice(nonce_bytes), ct).map_err(|_| anyhow!("decrypt failed"))
+}
+
+/// Attachment blobs of queued events live beside them, keyed by content hash,
+/// until the event has been delivered and the blob uploaded.
+fn attachment_dir(dir: &Path) -> Result<PathBuf> {
+    let d = dir.join("attachments");
+    fs::create_dir_all(&d)?;
+    Ok(d)
+}
+
+pub fn stash_attachment(sha256: &str, data: &[u8]) -> Result<()> {
+    stash_attachment_in(&queue_dir()?, sha256, data)
+}
+
+fn stash_attachment_in(dir: &Path, sha256: &str, data: &[u8]) -> Result<()> {
+    let path = attachment_dir(dir)?.join(format!("{}.blob", sha256));
+    crate::paths::write_atomic(&path, &seal(data, &aad(&path))?)
+}
+
+pub fn load_attachment(sha256: &str) -> Result<Vec<u8>> {
+    load_attachment_in(&queue_dir()?, sha256)
+}
+
+fn load_attachment_in(dir: &Path, sha256: &str) -> Result<Vec<u8>> {
+    let path = attachment_dir(dir)?.join(format!("{}.blob", sha256));
+    open(&fs::read(&path).map_err(|e| anyhow!("attachment {} not queued: {}", sha256, e))?, &aad(&path))
+}
+
+pub fn remove_attachment(sha256: &str) -> Result<()> {
+    remove_attachment_in(&queue_dir()?, sha256)
+}
+
+fn remove_attachment_in(dir: &Path, sha256: &str) -> Result<()> {
+    let dir = attachment_dir(dir)?;
+    let _ = fs::remove_file(dir.join(format!("{}.pending", sha256)));
+    fs::remove_file(dir.join(format!("{}.blob", sha256)))?;
     Ok(())
 }
 
-pub async fn drain<F>(mut submit: F) -> Result<()>
-where F: FnMut(Vec<u8>) -> std::pin::Pin<Box<dyn std::future::Future<Output=Result<()>> + Send>> {
-    let dir = queue_dir()?;
-    let entries = fs::read_dir(&dir)?;
-    for ent in entries {
-        let ent = ent?; let path = ent.path();
-        if path.extension().and_then(|s| s.to_str()) != Some("bin") { continue; }
-        let data = fs::read(&path)?; let (nonce_bytes, ct) = data.split_at(12);
-        let nonce = Nonce::from_slice(nonce_bytes);
-        let cipher = Aes256Gcm::new_from_slice(&key()).unwrap();
-        match cipher.decrypt(nonce, ct) {
-            Ok(pt) => {
-                if let Err(e) = submit(pt).await {
-                    eprintln!("queue submit error: {}", e);
-                    // backoff simple sleep
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                    continue;
-                }
-                let _ = fs::remove_file(&path);
-            }
-            Err(_) => {
-                eprintln!("queue decrypt error for {:?}", path);
+/// Keep the blob of an event the bus already accepted for a later upload. A
+/// `<sha256>.pending` beside the blob marks it as owed, since its event is no
+/// longer in the queue to carry it; `data` stashes the blob if it isn't yet.
+pub fn defer_upload(att: &crate::attachments::AttachmentRef, data: Option<&[u8]>) -> Result<()> {
+    defer_upload_in(&queue_dir()?, att, data)
+}
+
+fn defer_upload_in(dir: &Path, att: &crate::attachments::AttachmentRef, data: Option<&[u8]>) -> Result<()> {
+    if let Some(data) = data { stash_attachment_in(dir, &att.sha256, data)?; }
+    crate::paths::write_atomic(&attachment_dir(dir)?.join(format!("{}.pending", att.sha256)), &serde_json::to_vec(att)?)
+}
+
+/// Uploads owed for accepted events, as marked by [`defer_upload`].
+pub fn pending_uploads() -> Result<Vec<crate::attachments::AttachmentRef>> {
+    pending_uploads_in(&queue_dir()?)
+}
+
+fn pending_uploads_in(dir: &Path) -> Result<Vec<crate::attachments::AttachmentRef>> {
+    let mut out = Vec::new();
+    for ent in fs::read_dir(attachment_dir(dir)?)? {
+        let path = ent?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("pending") { continue; }
+        match fs::read(&path).map_err(anyhow::Error::from).and_then(|b| Ok(serde_json::from_slice(&b)?)) {
+            Ok(att) => out.push(att),
+            Err(e) => tracing::warn!(file = ?path, error = %e, "unreadable pending upload marker"),
+        }
+    }
+    Ok(out)
+}
+
+/// Walks the queue oldest-first by enqueue time, in windows of at most `size`
+/// entries, so events reach the bus in the order they were captured. The
+/// directory is listed and sorted once, and the windows are cut from that
+/// listing, so a drain makes a single pass however long the backlog; only
+/// paths are held, and payloads are read one at a time by the drain. Entries
+/// queued after the listing wait for the next pass.
+struct EntryWindows {
+    entries: std::vec::IntoIter<PathBuf>,
+    size: usize,
+}
+
+/// The stamp an entry's name starts with, in Unix nanoseconds.
+fn name_stamp(path: &Path) -> Option<u64> {
+    path.file_name().and_then(|n| n.to_str()).and_then(|n| n.split_once('-'))
+        .filter(|(stamp, _)| stamp.len() == 20).and_then(|(stamp, _)| stamp.parse().ok())
+}
+
+/// When an entry was queued: the stamp its name starts with, or its mtime for
+/// entries named before stamps existed. Unix nanoseconds either way.
+fn enqueued_at(path: &Path, meta: Option<fs::Metadata>) -> u64 {
+    name_stamp(path).unwrap_or_else(|| {
+        meta.and_then(|m| m.modified().ok()).and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()).map_or(0, |d| d.as_nanos() as u64)
+    })
+}
+
+impl EntryWindows {
+    fn new(dir: &Path, size: usize) -> Result<Self> {
+        let mut keyed = Vec::new();
+        for ent in fs::read_dir(dir)? {
+            let ent = ent?;
+            let path = ent.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("bin") { continue; }
+            let at = name_stamp(&path).unwrap_or_else(|| enqueued_at(&path, ent.metadata().ok()));
+            keyed.push((at, path));
+        }
+        keyed.sort_unstable();
+        let entries: Vec<PathBuf> = keyed.into_iter().map(|(_, path)| path).collect();
+        Ok(Self { entries: entries.into_iter(), size: size.max(1) })
+    }
+}
+
+impl Iterator for EntryWindows {
+    type Item = Result<Vec<PathBuf>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let window: Vec<PathBuf> = self.entries.by_ref().take(self.size).collect();
+        (!window.is_empty()).then_some(Ok(window))
+    }
+}
+
+/// Entries whose retry policy gave up on them are moved here, out of the drain.
+const DEAD_LETTER_DIR: &str = "dead";
+/// Failed attempts before an entry is dead-lettered, absent a `--dead-letter` policy.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// When to stop retrying a queued event that keeps failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Give up on a rejection once the entry has been queued this long.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self { Self { max_attempts: DEFAULT_MAX_ATTEMPTS, max_age: None } }
+}
+
+/// Retry policies by event type (`eventType` of the queued payload), with a
+/// default for untyped events and types without their own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetryPolicies {
+    default: RetryPolicy,
+    by_type: HashMap<String, RetryPolicy>,
+}
+
+impl RetryPolicies {
+    /// Parse `--dead-letter [TYPE=]ATTEMPTS[:MAX_AGE_SECS]` values; without a
+    /// TYPE the policy replaces the default.
+    pub fn parse<'a>(values: impl Iterator<Item = &'a String>) -> Result<Self> {
+        let mut policies = Self::default();
+        for v in values {
+            let (ty, spec) = match v.split_once('=') {
+                Some((ty, spec)) => (Some(ty.trim()), spec),
+                None => (None, v.as_str()),
+            };
+            let (attempts, age) = match spec.split_once(':') {
+                Some((attempts, age)) => (attempts, Some(age)),
+                None => (spec, None),
+            };
+            let bad = || anyhow!("--dead-letter expects [TYPE=]ATTEMPTS[:MAX_AGE_SECS], e.g. RECALL=500:604800, got {:?}", v);
+            let max_attempts = attempts.trim().parse::<u32>().ok().filter(|n| *n > 0).ok_or_else(bad)?;
+            let max_age = age.map(|a| a.trim().parse::<u64>().map(Duration::from_secs)).transpose().map_err(|_| bad())?;
+            let policy = RetryPolicy { max_attempts, max_age };
+            match ty {
+                Some(ty) => { policies.by_type.insert(ty.to_string(), policy); }
+                None => policies.default = policy,
             }
         }
+        Ok(policies)
     }
+
+    pub fn for_type(&self, event_type: Option<&str>) -> RetryPolicy {
+        event_type.and_then(|t| self.by_type.get(t)).copied().unwrap_or(self.default)
+    }
+}
+
+static RETRY_POLICIES: OnceLock<RetryPolicies> = OnceLock::new();
+
+/// Install the dead-letter policies; call once at startup.
+pub fn set_retry_policies(policies: RetryPolicies) {
+    let _ = RETRY_POLICIES.set(policies);
+}
+
+/// Failed attempts so far live in a `<name>.attempts` sidecar, so counting
+/// them never rewrites (and re-dates) the entry itself.
+fn attempts_path(path: &Path) -> PathBuf {
+    path.with_extension("attempts")
+}
+
+/// Whether the bus answered and refused the event for good, so sending it
+/// again as is won't help. Only these count towards dead-lettering; an
+/// unreachable, overloaded or rate-limiting bus says nothing about the entry.
+fn is_rejection(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<crate::submit::EventRejected>().is_some_and(crate::submit::EventRejected::is_definitive)
+}
+
+fn record_failure(path: &Path) -> Result<u32> {
+    let sidecar = attempts_path(path);
+    let attempts = fs::read_to_string(&sidecar).ok().and_then(|s| s.trim().parse::<u32>().ok()).unwrap_or(0) + 1;
+    fs::write(&sidecar, attempts.to_string())?;
+    Ok(attempts)
+}
+
+fn dead_letter(dir: &Path, path: &Path) -> Result<()> {
+    let dead = dir.join(DEAD_LETTER_DIR);
+    fs::create_dir_all(&dead)?;
+    let name = path.file_name().ok_or_else(|| anyhow!("queue entry {:?} has no name", path))?;
+    fs::rename(path, dead.join(name))?;
+    let sidecar = attempts_path(path);
+    if sidecar.exists() { fs::rename(&sidecar, attempts_path(&dead.join(name)))?; }
     Ok(())
 }
 
+/// Longest pause between drain passes while the bus stays unreachable.
+pub const MAX_DRAIN_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// Pauses between drain passes that stopped on an unreachable bus: `base`,
+/// twice that, four times, ... up to the cap, each shortened by up to half at
+/// random so agents that lost the bus together don't retry in lockstep. A pass
+/// that reaches the bus resets it.
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        Self { base, cap: cap.max(base), failures: 0 }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.base.saturating_mul(1 << self.failures.min(16)).min(self.cap);
+        self.failures += 1;
+        delay.mul_f64(1.0 - rand::random::<f64>() * 0.5)
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// What one drain pass did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DrainStats {
+    pub delivered: usize,
+    /// Entries dropped because their TTL elapsed before delivery.
+    pub expired: usize,
+    /// Entries that could not be decrypted and were left in place.
+    pub corrupt: usize,
+    /// Entries moved to the dead-letter dir after exhausting their retry policy.
+    pub dead: usize,
+    /// Another drain held the queue lock, so this one touched nothing.
+    pub busy: bool,
+    /// A submit failed without the bus answering it (a transport error or a
+    /// 5xx), so the pass stopped there rather than fail every later entry too.
+    pub unreachable: bool,
+}
+
+/// Held by a drain for its whole pass, so two drains (`run` and a manual
+/// `queue-drain`, or two tasks in one process) never submit the same entry.
+const DRAIN_LOCK: &str = ".lock";
+
+/// An exclusive OS lock on `queue/.lock`. The kernel drops it when the holder
+/// exits, however it exits, so there is nothing to go stale or reclaim; the
+/// file itself stays behind and only names the last holder's pid.
+struct DrainLock {
+    _file: fs::File,
+}
+
+impl DrainLock {
+    /// `None` when another drain holds the lock.
+    fn acquire(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(DRAIN_LOCK);
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .map_err(|e| anyhow!("opening drain lock {:?}: {}", path, e))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => return Ok(None),
+            Err(fs::TryLockError::Error(e)) => return Err(anyhow!("locking {:?}: {}", path, e)),
+        }
+        file.set_len(0)?;
+        std::io::Write::write_all(&mut file, std::process::id().to_string().as_bytes())?;
+        Ok(Some(Self { _file: file }))
+    }
+}
+
+/// A queued entry that was just submitted, with whatever `submit` returned for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivered<A> {
+    /// Queue entry name (the file stem).
+    pub id: String,
+    /// `productId` of the event, when the payload carries one.
+    pub product: Option<String>,
+    pub ack: A,
+}
+
+/// Called after each successful submission during a drain.
+pub type DeliveryHook<A> = Box<dyn FnMut(Delivered<A>) -> std::pin::Pin<Box<dyn std::future::Future<Output=()> + Send>> + Send>;
+
+/// Submit every queued entry once. `stop` is checked before each entry, so a
+/// shutdown waits for at most the submit in flight.
+pub async fn drain<A, F>(submit: F, on_delivered: Option<DeliveryHook<A>>, stop: &Shutdown) -> Result<DrainStats>
+where F: FnMut(Vec<u8>) -> std::pin::Pin<Box<dyn std::future::Future<Output=Result<A>> + Send>> {
+    let policies = RETRY_POLICIES.get().cloned().unwrap_or_default();
+    drain_with_hook_in(&queue_dir()?, DRAIN_WINDOW, &policies, stop, submit, on_delivered).await
+}
+
+#[cfg(test)]
+async fn drain_in<A, F>(dir: &Path, window: usize, submit: F) -> Result<DrainStats>
+where F: FnMut(Vec<u8>) -> std::pin::Pin<Box<dyn std::future::Future<Output=Result<A>> + Send>> {
+    drain_with_hook_in(dir, window, &RetryPolicies::default(), &Shutdown::default(), submit, None).await
+}
+
+async fn drain_with_hook_in<A, F>(dir: &Path, window: usize, policies: &RetryPolicies, stop: &Shutdown, mut submit: F, mut on_delivered: Option<DeliveryHook<A>>) -> Result<DrainStats>
+where F: FnMut(Vec<u8>) -> std::pin::Pin<Box<dyn std::future::Future<Output=Result<A>> + Send>> {
+    let mut stats = DrainStats::default();
+    let Some(_lock) = DrainLock::acquire(dir)? else {
+        tracing::info!("drain already in progress, skipped");
+        return Ok(DrainStats { busy: true, ..stats });
+    };
+    for ent in fs::read_dir(dir)? {
+        let tmp = ent?.path();
+        if tmp.extension().and_then(|s| s.to_str()) == Some("tmp") && crate::paths::remove_stale_tmp(&tmp) {
+            tracing::info!(file = ?tmp.file_name().unwrap_or_default(), "removed a file left by an interrupted write");
+        }
+    }
+    for batch in EntryWindows::new(dir, window)? {
+        for path in batch? {
+            if stop.requested() {
+                tracing::info!("shutdown requested, stopping this drain pass");
+                return Ok(stats);
+            }
+            let data = match fs::read(&path) {
+                // Removed since the listing; nothing left to deliver.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                read => read?,
+            };
+            match open(&data, &aad(&path)) {
+                Ok(plain) => {
+                    let (expires_at, pt) = unwrap_envelope(plain);
+                    if expires_at.is_some_and(|exp| exp <= now_secs()) {
+                        stats.expired += 1;
+                        let _ = fs::remove_file(&path);
+                        let _ = fs::remove_file(attempts_path(&path));
+                        continue;
+                    }
+                    let event = serde_json::from_slice::<serde_json::Value>(&pt).ok();
+                    let field = |name: &str| event.as_ref().and_then(|v| v.get(name)).and_then(|p| p.as_str()).map(str::to_string);
+                    let (product, event_type) = (field("productId"), field("eventType"));
+                    let ack = match submit(pt).await {
+                        Ok(ack) => ack,
+                        Err(e) => {
+                            if e.is::<crate::outbound::BudgetExhausted>() {
+                                tracing::warn!("retry budget spent, stopping this drain pass");
+                                return Ok(stats);
+                            }
+                            tracing::warn!(entry = ?path.file_name().unwrap_or_default(), error = %e, "queued event not delivered");
+                            if !is_rejection(&e) {
+                                tracing::info!("bus unreachable, stopping this drain pass");
+                                stats.unreachable = true;
+                                return Ok(stats);
+                            }
+                            let policy = policies.for_type(event_type.as_deref());
+                            let attempts = record_failure(&path)?;
+                            // The stamp in the name is covered by the entry's AAD, and
+                            // rekeying or repairing an entry keeps its name.
+                            let queued = enqueued_at(&path, fs::metadata(&path).ok());
+                            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+                            let age = Duration::from_nanos(now.saturating_sub(queued));
+                            if attempts >= policy.max_attempts || policy.max_age.is_some_and(|max| age >= max) {
+                                dead_letter(dir, &path)?;
+                                stats.dead += 1;
+                                tracing::warn!(entry = ?path.file_name().unwrap_or_default(), event_type = event_type.as_deref().unwrap_or("untyped"), attempts,
+                                    "giving up on queued event, moved to {}/", DEAD_LETTER_DIR);
+                            }
+                            continue;
+                        }
+                    };
+                    stats.delivered += 1;
+                    let _ = fs::remove_file(&path);
+                    let _ = fs::remove_file(attempts_path(&path));
+                    if let Some(hook) = on_delivered.as_mut() {
+                        let id = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+                        if tokio::time::timeout(HOOK_TIMEOUT, hook(Delivered { id: id.clone(), product, ack })).await.is_err() {
+                            tracing::warn!(entry = %id, "delivery hook timed out");
+                        }
+                    }
+                }
+                Err(_) => {
+                    stats.corrupt += 1;
+                    tracing::error!(entry = ?path, "queue entry failed to decrypt");
+                }
+            }
+        }
+    }
+    if stats.expired > 0 { tracing::info!(entries = stats.expired, "dropped queue entries whose TTL elapsed"); }
+    if stats.dead > 0 { tracing::warn!(entries = stats.dead, "dead-lettered queue entries"); }
+    tracing::debug!(delivered = stats.delivered, corrupt = stats.corrupt, "drain pass finished");
+    Ok(stats)
+}
+
+/// Outcome of a `flush`: what was delivered and what is still queued.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FlushReport {
+    pub delivered: usize,
+    pub expired: usize,
+    /// Entries still queued when the flush gave up, including `corrupt` ones.
+    pub remaining: usize,
+    /// Entries that no pass could decrypt; they will never drain.
+    pub corrupt: usize,
+    /// Entries moved to the dead-letter dir during the flush.
+    pub dead: usize,
+}
+
+impl FlushReport {
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// First and largest pause between flush passes.
+const FLUSH_BACKOFF: (Duration, Duration) = (Duration::from_secs(1), Duration::from_secs(30));
+
+/// Run `pass` (one drain of the queue) repeatedly, backing off between passes,
+/// until the queue is empty, `timeout` elapses or `stop` is requested. Stops
+/// early once only undecryptable entries are left, since no further pass can
+/// deliver them.
+pub async fn flush<F, Fut>(timeout: Duration, stop: &Shutdown, pass: F) -> Result<FlushReport>
+where F: FnMut() -> Fut, Fut: std::future::Future<Output=Result<DrainStats>> {
+    flush_in(&queue_dir()?, timeout, stop, pass).await
+}
+
+async fn flush_in<F, Fut>(dir: &Path, timeout: Duration, stop: &Shutdown, mut pass: F) -> Result<FlushReport>
+where F: FnMut() -> Fut, Fut: std::future::Future<Output=Result<DrainStats>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut report = FlushReport::default();
+    let mut backoff = FLUSH_BACKOFF.0;
+    loop {
+        let stats = pass().await?;
+        report.delivered += stats.delivered;
+        report.expired += stats.expired;
+        report.dead += stats.dead;
+        report.corrupt = stats.corrupt;
+        report.remaining = stats_in(dir)?.0;
+        if report.remaining <= report.corrupt { return Ok(report); }
+        let now = tokio::time::Instant::now();
+        if now >= deadline || stop.sleep(backoff.min(deadline - now)).await { return Ok(report); }
+        backoff = (backoff * 2).min(FLUSH_BACKOFF.1);
+    }
+}
+
 pub fn stats() -> Result<(usize, usize)> {
-    let dir = queue_dir()?;
+    stats_in(&queue_dir()?)
+}
+
+fn stats_in(dir: &Path) -> Result<(usize, usize)> {
     let mut count = 0usize; let mut bytes = 0usize;
-    for ent in fs::read_dir(&dir)? { let ent = ent?; let p = ent.path(); if p.extension().and_then(|s| s.to_str())==Some("bin"){ count+=1; bytes+=fs::metadata(p)?.len() as usize; } }
+    for ent in fs::read_dir(dir)? { let ent = ent?; let p = ent.path(); if p.extension().and_then(|s| s.to_str())==Some("bin"){ count+=1; bytes+=fs::metadata(p)?.len() as usize; } }
     Ok((count, bytes))
 }
 
-pub fn prune_by_age(days: u64) -> Result<()> {
-    use std::time::{SystemTime, Duration};
-    let dir = queue_dir()?; let cutoff = SystemTime::now() - Duration::from_secs(days*24*3600);
-    for ent in fs::read_dir(&dir)? { let ent = ent?; let p = ent.path(); if p.extension().and_then(|s| s.to_str())!=Some("bin"){continue;} let md = fs::metadata(&p)?; if let Ok(m) = md.modified(){ if m < cutoff { let _=fs::remove_file(&p); } } }
-    Ok(())
-} 
\ No newline at end of file
+/// Count and bytes of dead-lettered entries.
+pub fn dead_letter_stats() -> Result<(usize, usize)> {
+    dead_letter_stats_in(&queue_dir()?)
+}
+
+fn dead_letter_stats_in(dir: &Path) -> Result<(usize, usize)> {
+    let dead = dir.join(DEAD_LETTER_DIR);
+    if !dead.is_dir() { return Ok((0, 0)); }
+    stats_in(&dead)
+}
+
+/// One dead-lettered entry as `queue-dead-list` shows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeadEntry {
+    pub name: String,
+    pub attempts: u32,
+    pub product: Option<String>,
+    pub event_type: Option<String>,
+}
+
+pub fn dead_letter_list() -> Result<Vec<DeadEntry>> {
+    dead_letter_list_in(&queue_dir()?)
+}
+
+/// Dead-lettered entries, oldest first. Fields of entries that no longer
+/// decrypt are left empty rather than failing the listing.
+fn dead_letter_list_in(dir: &Path) -> Result<Vec<DeadEntry>> {
+    let dead = dir.join(DEAD_LETTER_DIR);
+    if !dead.is_dir() { return Ok(Vec::new()); }
+    let mut out = Vec::new();
+    for window in EntryWindows::new(&dead, usize::MAX)? {
+        for path in window? {
+            let attempts = fs::read_to_string(attempts_path(&path)).ok().and_then(|s| s.trim().parse().ok()).unwrap_or(0);
+            let (product, event_type) = describe(&path).unwrap_or_default();
+            out.push(DeadEntry {
+                name: path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default(),
+                attempts,
+                product,
+                event_type,
+            });
+        }
+    }
+    Ok(out)
+}
+
+/// `productId` and `eventType` of a queued entry.
+fn describe(path: &Path) -> Result<(Option<String>, Option<String>)> {
+    let (_, payload) = unwrap_envelope(open(&fs::read(path)?, &aad(path))?);
+    let event = serde_json::from_slice::<serde_json::Value>(&payload).ok();
+    let field = |name: &str| event.as_ref().and_then(|v| v.get(name)).and_then(|p| p.as_str()).map(str::to_string);
+    Ok((field("productId"), field("eventType")))
+}
+
+/// One pending entry as `queue-list` shows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QueueItemInfo {
+    pub file_name: String,
+    pub enqueued_at: chrono::DateTime<chrono::Utc>,
+    pub size: u64,
+    pub product: Option<String>,
+    pub event_type: Option<String>,
+    /// Why the entry could not be read; it stays listed, flagged, rather than
+    /// failing the whole listing.
+    pub error: Option<String>,
+}
+
+/// Pending entries in drain order, without draining or modifying them.
+pub fn list() -> Result<Vec<QueueItemInfo>> {
+    list_in(&queue_dir()?)
+}
+
+fn list_in(dir: &Path) -> Result<Vec<QueueItemInfo>> {
+    let mut out = Vec::new();
+    for window in EntryWindows::new(dir, usize::MAX)? {
+        for path in window? {
+            let meta = fs::metadata(&path).ok();
+            let size = meta.as_ref().map_or(0, |m| m.len());
+            let enqueued_at = chrono::DateTime::from_timestamp_nanos(enqueued_at(&path, meta) as i64);
+            let (fields, error) = match describe(&path) {
+                Ok(fields) => (fields, None),
+                Err(e) => (Default::default(), Some(e.to_string())),
+            };
+            out.push(QueueItemInfo {
+                file_name: path.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default(),
+                enqueued_at,
+                size,
+                product: fields.0,
+                event_type: fields.1,
+                error,
+            });
+        }
+    }
+    Ok(out)
+}
+
+/// One queue file decrypted out-of-band by `queue-decrypt`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Inspected {
+    pub expires_at: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+/// Read a queue key exported as 32 raw bytes, hex or base64.
+pub fn parse_key_file(bytes: &[u8]) -> Result<[u8; 32]> {
+    use base64::{engine::general_purpose, Engine as _};
+    let decoded = if bytes.len() == 32 {
+        bytes.to_vec()
+    } else {
+        let text = std::str::from_utf8(bytes).map_err(|_| anyhow!("key file is neither 32 raw bytes nor text"))?.trim();
+        hex::decode(text).or_else(|_| general_purpose::STANDARD.decode(text))
+            .map_err(|_| anyhow!("key file is neither hex nor base64"))?
+    };
+    decoded.try_into().map_err(|v: Vec<u8>| anyhow!("queue key must be 32 bytes, got {}", v.len()))
+}
+
+/// Decrypt and authenticate a single queue file without touching the queue.
+/// Uses this machine's queue key unless one is supplied.
+pub fn inspect(path: &Path, key_override: Option<[u8; 32]>) -> Result<Inspected> {
+    let data = fs::read(path).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+    let key = match key_override { Some(k) => Zeroizing::new(k), None => key()? };
+    let plain = open_with(&key, &data, &aad(path))
+        .map_err(|e| if data.len() < 12 { e } else { anyhow!("authentication failed: wrong key, or the file was modified or corrupted") })?;
+    let (expires_at, payload) = unwrap_envelope(plain);
+    Ok(Inspected { expires_at, payload })
+}
+
+/// The key `queue-repair` tries on entries the current key can't open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OldKeySource {
+    /// The pre-HKDF key derived from hostname and username.
+    Legacy,
+    /// A queue key exported as 32 raw bytes, hex or base64.
+    KeyFile(PathBuf),
+    /// A previous device secret, put through the current queue KDF.
+    SecretFile(PathBuf),
+}
+
+impl OldKeySource {
+    /// `legacy`, `key-file:PATH` or `secret-file:PATH`.
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "legacy" => Ok(Self::Legacy),
+            Some(("key-file", path)) if !path.is_empty() => Ok(Self::KeyFile(path.into())),
+            Some(("secret-file", path)) if !path.is_empty() => Ok(Self::SecretFile(path.into())),
+            _ => Err(anyhow!("--old-key-source expects legacy, key-file:PATH or secret-file:PATH, got {:?}", s)),
+        }
+    }
+
+    pub fn key(&self) -> Result<Zeroizing<[u8; 32]>> {
+        let read = |p: &Path| fs::read(p).map_err(|e| anyhow!("{:?}: {}", p, e));
+        match self {
+            Self::Legacy => Ok(legacy_key()),
+            Self::KeyFile(p) => parse_key_file(&read(p)?).map(Zeroizing::new),
+            Self::SecretFile(p) => Ok(derive_key(&read(p)?)),
+        }
+    }
+}
+
+/// What a `queue-repair` pass did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Repaired {
+    /// Entries re-sealed under the current key and back in the pending queue.
+    pub repaired: usize,
+    /// Entries the old key couldn't open either; left where they were.
+    pub unreadable: usize,
+}
+
+/// Recover entries sealed under `old`: pending entries the current key can't
+/// open are re-sealed under the current key in place. Anything `old` can't
+/// authenticate is left untouched, still counted as corrupt by the drain.
+pub fn repair(old: &[u8; 32]) -> Result<Repaired> {
+    repair_in(&queue_dir()?, old, &*key()?)
+}
+
+fn repair_in(dir: &Path, old: &[u8; 32], current: &[u8; 32]) -> Result<Repaired> {
+    let _lock = DrainLock::acquire(dir)?.ok_or_else(|| anyhow!("a queue drain is in progress; stop the agent and retry"))?;
+    let mut candidates = Vec::new();
+    for ent in fs::read_dir(dir)? {
+        let p = ent?.path();
+        if p.extension().and_then(|s| s.to_str()) == Some("bin") { candidates.push(p); }
+    }
+    let mut report = Repaired::default();
+    for p in candidates {
+        let data = fs::read(&p)?;
+        let aad = aad(&p);
+        if open_with(current, &data, &aad).is_ok() { continue; }
+        let Ok(plain) = open_with(old, &data, &aad) else {
+            report.unreadable += 1;
+            continue;
+        };
+        crate::paths::write_atomic(&p, &seal_with(current, &plain, &aad)?)?;
+        report.repaired += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const TEST_DEVICE_SECRET: &[u8] = b"device-secret-for-queue-tests-32";
+
+    /// Every test installs the same key, so parallel tests never disagree.
+    fn unlocked() {
+        install_key(derive_key(TEST_DEVICE_SECRET));
+    }
+
+    /// Files in the queue dir besides the drain lock, which outlives drains.
+    fn leftovers(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().filter(|e| e.as_ref().unwrap().file_name() != DRAIN_LOCK).count()
+    }
+
+    #[test]
+    fn windows_never_exceed_configured_size() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..500 { enqueue_in(dir.path(), &format!("p{}", i), b"{}", None).unwrap(); }
+        fs::write(dir.path().join("notes.txt"), b"ignored").unwrap();
+        let windows = EntryWindows::new(dir.path(), 64).unwrap();
+        // Later enqueues are left for the next pass rather than re-listing.
+        enqueue_in(dir.path(), "late", b"{}", None).unwrap();
+        let mut seen = Vec::new();
+        for window in windows {
+            let window = window.unwrap();
+            assert!(window.len() <= 64);
+            seen.extend(window);
+        }
+        assert_eq!(seen.len(), 500);
+        let mut sorted = seen.clone();
+        sorted.sort_by_key(|p| enqueued_at(p, None));
+        assert_eq!(seen, sorted, "oldest first across windows");
+    }
+
+    #[tokio::test]
+    async fn drain_delivers_large_queue_window_by_window() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..1000 { enqueue_in(dir.path(), &format!("p{}", i), format!("{{\"n\":{}}}", i).as_bytes(), None).unwrap(); }
+        let seen = Arc::new(Mutex::new(0usize));
+        let counter = seen.clone();
+        drain_in(dir.path(), 50, move |_pt| {
+            let counter = counter.clone();
+            Box::pin(async move { *counter.lock().unwrap() += 1; Ok(()) })
+        }).await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), 1000);
+        assert_eq!(leftovers(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn delivery_hook_fires_once_per_delivered_entry() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let id = |p: PathBuf| p.file_stem().unwrap().to_string_lossy().into_owned();
+        let p1 = id(enqueue_in(dir.path(), "p1-100", br#"{"productId":"p1","n":1}"#, None).unwrap());
+        let p2 = id(enqueue_in(dir.path(), "p2-200", br#"{"productId":"p2","n":2}"#, None).unwrap());
+        enqueue_in(dir.path(), "bad", br#"{"productId":"p3","fail":true}"#, None).unwrap();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let sink = fired.clone();
+        let hook: DeliveryHook<String> = Box::new(move |d| {
+            let sink = sink.clone();
+            Box::pin(async move { sink.lock().unwrap().push(d) })
+        });
+        let stats = drain_with_hook_in(dir.path(), 10, &RetryPolicies::default(), &Shutdown::default(), |pt| Box::pin(async move {
+            let v: serde_json::Value = serde_json::from_slice(&pt).unwrap();
+            if v.get("fail").is_some() { return Err(rejected()); }
+            Ok(format!("ack-{}", v["n"]))
+        }), Some(hook)).await.unwrap();
+
+        assert_eq!(stats.delivered, 2);
+        let mut fired = fired.lock().unwrap().clone();
+        fired.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(fired, vec![
+            Delivered { id: p1, product: Some("p1".into()), ack: "ack-1".to_string() },
+            Delivered { id: p2, product: Some("p2".into()), ack: "ack-2".to_string() },
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_delivery_hook_does_not_stall_the_drain() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..3 { enqueue_in(dir.path(), &format!("p{}", i), b"{}", None).unwrap(); }
+        let hook: DeliveryHook<()> = Box::new(|_| Box::pin(std::future::pending()));
+        let stats = drain_with_hook_in(dir.path(), 10, &RetryPolicies::default(), &Shutdown::default(), |_| Box::pin(async { Ok(()) }), Some(hook)).await.unwrap();
+        assert_eq!(stats.delivered, 3);
+    }
+
+    #[test]
+    fn stashed_attachments_are_encrypted_at_rest() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        stash_attachment_in(dir.path(), "abc", b"secret-photo").unwrap();
+        let raw = fs::read(dir.path().join("attachments").join("abc.blob")).unwrap();
+        assert!(!raw.windows(12).any(|w| w == b"secret-photo"));
+        assert_eq!(load_attachment_in(dir.path(), "abc").unwrap(), b"secret-photo");
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_dropped_and_fresh_ones_delivered() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        enqueue_in(dir.path(), "ping", br#"{"eventType":"PRESENCE"}"#, Some(Duration::ZERO)).unwrap();
+        enqueue_in(dir.path(), "scan", br#"{"eventType":"QUALITY_CHECK"}"#, Some(Duration::from_secs(3600))).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let stats = drain_in(dir.path(), 10, move |pt| {
+            let sink = sink.clone();
+            Box::pin(async move { sink.lock().unwrap().push(pt); Ok(()) })
+        }).await.unwrap();
+        assert_eq!(stats, DrainStats { delivered: 1, expired: 1, corrupt: 0, dead: 0, busy: false, unreachable: false });
+        assert_eq!(*seen.lock().unwrap(), vec![br#"{"eventType":"QUALITY_CHECK"}"#.to_vec()]);
+        assert_eq!(leftovers(dir.path()), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_empties_the_queue_once_a_transient_failure_clears() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..3 { enqueue_in(dir.path(), &format!("p{}", i), b"{}", None).unwrap(); }
+        let failures = Arc::new(Mutex::new(4usize));
+        let report = flush_in(dir.path(), Duration::from_secs(120), &Shutdown::default(), || {
+            let failures = failures.clone();
+            drain_in(dir.path(), 10, move |_| {
+                let failures = failures.clone();
+                Box::pin(async move {
+                    let mut left = failures.lock().unwrap();
+                    if *left > 0 { *left -= 1; return Err(anyhow!("bus unavailable")); }
+                    Ok(())
+                })
+            })
+        }).await.unwrap();
+        assert!(report.is_empty());
+        assert_eq!(report.delivered, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_reports_what_remains_when_the_timeout_elapses() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        enqueue_in(dir.path(), "stuck", b"{}", None).unwrap();
+        fs::write(dir.path().join("garbage.bin"), b"not a sealed entry").unwrap();
+        let started = tokio::time::Instant::now();
+        let report = flush_in(dir.path(), Duration::from_secs(20), &Shutdown::default(), || {
+            drain_in::<(), _>(dir.path(), 10, |_| Box::pin(async { Err(anyhow!("bus unavailable")) }))
+        }).await.unwrap();
+        assert_eq!(report, FlushReport { delivered: 0, expired: 0, remaining: 2, corrupt: 1, dead: 0 });
+        assert!(started.elapsed() >= Duration::from_secs(20));
+    }
+
+    #[tokio::test]
+    async fn repair_rekeys_old_key_entries_and_leaves_garbage_alone() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let old = derive_key(b"previous-device-secret");
+        let mut plain = ENVELOPE_MAGIC.to_vec();
+        plain.extend_from_slice(&0u64.to_be_bytes());
+        plain.extend_from_slice(br#"{"productId":"P-old"}"#);
+        let sealed_old = seal_with(&old, &plain, &aad(Path::new("old-key.bin"))).unwrap();
+        fs::write(dir.path().join("old-key.bin"), &sealed_old).unwrap();
+        fs::write(dir.path().join("garbage.bin"), b"not a sealed entry at all").unwrap();
+        enqueue_in(dir.path(), "current", br#"{"productId":"P-new"}"#, None).unwrap();
+
+        let held = DrainLock::acquire(dir.path()).unwrap().expect("lock is free");
+        let err = repair_in(dir.path(), &old, &key().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("drain is in progress"), "{}", err);
+        assert_eq!(fs::read(dir.path().join("old-key.bin")).unwrap(), sealed_old, "nothing rewritten under a drain");
+        drop(held);
+
+        let report = repair_in(dir.path(), &old, &key().unwrap()).unwrap();
+        assert_eq!(report, Repaired { repaired: 1, unreadable: 1 });
+        assert_ne!(fs::read(dir.path().join("old-key.bin")).unwrap(), sealed_old);
+        assert_eq!(fs::read(dir.path().join("garbage.bin")).unwrap(), b"not a sealed entry at all");
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        drain_in(dir.path(), 10, move |pt| {
+            let sink = sink.clone();
+            Box::pin(async move { sink.lock().unwrap().push(pt); Ok(()) })
+        }).await.unwrap();
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(seen, vec![br#"{"productId":"P-new"}"#.to_vec(), br#"{"productId":"P-old"}"#.to_vec()]);
+    }
+
+    #[test]
+    fn inspect_decrypts_an_entry_with_a_supplied_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let exported = [7u8; 32];
+        let path = dir.path().join("scan.bin");
+        let mut plain = ENVELOPE_MAGIC.to_vec();
+        plain.extend_from_slice(&1_700_000_000u64.to_be_bytes());
+        plain.extend_from_slice(br#"{"eventType":"SCAN"}"#);
+        fs::write(&path, seal_with(&exported, &plain, &aad(&path)).unwrap()).unwrap();
+
+        let key = parse_key_file(hex::encode(exported).as_bytes()).unwrap();
+        let got = inspect(&path, Some(key)).unwrap();
+        assert_eq!(got, Inspected { expires_at: Some(1_700_000_000), payload: br#"{"eventType":"SCAN"}"#.to_vec() });
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "inspection must not consume the entry");
+    }
+
+    #[test]
+    fn inspect_reports_tampered_entries() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let path = enqueue_in(dir.path(), "scan", b"{}", None).unwrap();
+        assert_eq!(inspect(&path, None).unwrap().payload, b"{}");
+
+        let mut raw = fs::read(&path).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 0x01;
+        fs::write(&path, &raw).unwrap();
+        let err = inspect(&path, None).unwrap_err().to_string();
+        assert!(err.contains("authentication failed"), "{}", err);
+
+        fs::write(&path, b"short").unwrap();
+        assert!(inspect(&path, None).unwrap_err().to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn queue_key_is_derived_deterministically_from_the_device_secret() {
+        let k = derive_key(TEST_DEVICE_SECRET);
+        assert_eq!(k, derive_key(TEST_DEVICE_SECRET));
+        assert_ne!(k, derive_key(b"some-other-device-secret-32bytes"));
+        assert_ne!(k, legacy_key());
+        assert_eq!(open_with(&k, &seal_with(&k, b"{}", b"a").unwrap(), b"a").unwrap(), b"{}");
+        assert!(open_with(&derive_key(b"other"), &seal_with(&k, b"{}", b"a").unwrap(), b"a").is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_entries_are_reencrypted_under_the_device_key() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let mut plain = ENVELOPE_MAGIC.to_vec();
+        plain.extend_from_slice(&0u64.to_be_bytes());
+        plain.extend_from_slice(b"{\"n\":1}");
+        fs::write(dir.path().join("old.bin"), seal_with(&legacy_key(), &plain, &aad(Path::new("old.bin"))).unwrap()).unwrap();
+        fs::create_dir_all(dir.path().join("attachments")).unwrap();
+        fs::write(dir.path().join("attachments").join("abc.blob"), seal_with(&legacy_key(), b"photo", &aad(Path::new("abc.blob"))).unwrap()).unwrap();
+        enqueue_in(dir.path(), "new", b"{\"n\":2}", None).unwrap();
+
+        let device_key = derive_key(TEST_DEVICE_SECRET);
+        assert_eq!(reencrypt_in(dir.path(), &legacy_key(), &device_key).unwrap(), 2);
+        assert_eq!(reencrypt_in(dir.path(), &legacy_key(), &device_key).unwrap(), 0, "rerun is a no-op");
+        assert_eq!(load_attachment_in(dir.path(), "abc").unwrap(), b"photo");
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let stats = drain_in(dir.path(), 10, move |pt| {
+            let sink = sink.clone();
+            Box::pin(async move { sink.lock().unwrap().push(pt); Ok(()) })
+        }).await.unwrap();
+        assert_eq!(stats.delivered, 2);
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(seen, vec![b"{\"n\":1}".to_vec(), b"{\"n\":2}".to_vec()]);
+    }
+
+    #[test]
+    fn legacy_entries_without_envelope_have_no_ttl() {
+        assert_eq!(unwrap_envelope(b"{}".to_vec()), (None, b"{}".to_vec()));
+    }
+
+    fn rejected() -> anyhow::Error {
+        crate::submit::EventRejected { status: 400, code: Some("invalid_event".into()), message: "rejected".into() }.into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn event_types_hit_dead_letter_at_their_own_thresholds() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let event = |ty: &str| serde_json::to_vec(&serde_json::json!({ "productId": "P-1", "eventType": ty })).unwrap();
+        let scan = enqueue_in(dir.path(), "scan", &event("QUALITY_CHECK"), None).unwrap();
+        let recall = enqueue_in(dir.path(), "recall", &event("RECALL"), None).unwrap();
+        let untyped = enqueue_in(dir.path(), "untyped", b"{}", None).unwrap();
+        let two_hours_ago = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64 - 7_200_000_000_000;
+        let stale = dir.path().join(format!("{:020}-00000000-stale.bin", two_hours_ago));
+        fs::write(&stale, seal_entry(&stale, &event("ROUTINE"), None).unwrap()).unwrap();
+
+        let flags = ["2", "RECALL=5", "ROUTINE=100:3600"].map(String::from);
+        let policies = RetryPolicies::parse(flags.iter()).unwrap();
+        assert_eq!(policies.for_type(Some("RECALL")), RetryPolicy { max_attempts: 5, max_age: None });
+        assert_eq!(policies.for_type(Some("QUALITY_CHECK")), RetryPolicy { max_attempts: 2, max_age: None });
+        assert_eq!(RetryPolicies::default().for_type(None).max_attempts, DEFAULT_MAX_ATTEMPTS);
+
+        let failing = |_: Vec<u8>| -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> { Box::pin(async { Err(rejected()) }) };
+        let dead = |entry: &PathBuf| dir.path().join(DEAD_LETTER_DIR).join(entry.file_name().unwrap()).exists();
+        // ROUTINE is past its max age on the first failure; the rest are within attempts.
+        let first = drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap();
+        assert_eq!(first.dead, 1);
+        assert!(dead(&stale));
+        // Default (2 attempts) catches the typed scan and the untyped event on the second pass.
+        let second = drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap();
+        assert_eq!(second.dead, 2);
+        assert!(dead(&scan) && dead(&untyped) && !dead(&recall));
+        assert_eq!(fs::read_to_string(dir.path().join(DEAD_LETTER_DIR).join(scan.with_extension("attempts").file_name().unwrap())).unwrap(), "2");
+        // RECALL keeps retrying until its own limit of 5.
+        for _ in 0..2 { assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap().dead, 0); }
+        assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap().dead, 1);
+        assert!(dead(&recall));
+        assert_eq!(stats_in(dir.path()).unwrap().0, 0);
+
+        assert!(RetryPolicies::parse(["RECALL=0".to_string()].iter()).is_err());
+        assert!(RetryPolicies::parse(["RECALL=5:soon".to_string()].iter()).is_err());
+    }
+
+    #[test]
+    fn the_same_product_queued_twice_keeps_both_entries() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let first = enqueue_in(dir.path(), "P-1", br#"{"productId":"P-1","n":1}"#, None).unwrap();
+        let second = enqueue_in(dir.path(), "P-1", br#"{"productId":"P-1","n":2}"#, None).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(stats_in(dir.path()).unwrap().0, 2);
+        for path in [&first, &second] {
+            assert!(path.file_name().unwrap().to_str().unwrap().ends_with("-P-1.bin"), "{:?}", path);
+        }
+        let odd = enqueue_in(dir.path(), "lot/7 A", b"{}", None).unwrap();
+        assert_eq!(odd.parent(), Some(dir.path()));
+        assert!(odd.to_str().unwrap().ends_with("-lot_7_A.bin"));
+
+        let long = enqueue_in(dir.path(), &"é".repeat(300), b"{}", None).unwrap();
+        let name = long.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with(&format!("-{}.bin", "_".repeat(MAX_LABEL))), "{}", name);
+        assert_eq!(inspect(&long, None).unwrap().payload, b"{}");
+    }
+
+    #[tokio::test]
+    async fn drain_submits_oldest_first() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let stamped = |name: &str, stamp: u64| {
+            let path = dir.path().join(format!("{:020}-00000000-{}.bin", stamp, name));
+            fs::write(&path, seal_entry(&path, format!("{{\"productId\":\"{}\"}}", name).as_bytes(), None).unwrap()).unwrap();
+        };
+        stamped("third", 1_700_000_300_000_000_000);
+        stamped("first", 1_700_000_100_000_000_000);
+        stamped("second", 1_700_000_200_000_000_000);
+        // Named before stamps existed: ordered by mtime.
+        let legacy_path = dir.path().join("legacy.bin");
+        fs::write(&legacy_path, seal_entry(&legacy_path, br#"{"productId":"legacy"}"#, None).unwrap()).unwrap();
+        fs::File::options().write(true).open(&legacy_path).unwrap().set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000)).unwrap();
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        drain_in(dir.path(), 2, move |pt| {
+            let sink = sink.clone();
+            Box::pin(async move {
+                let v: serde_json::Value = serde_json::from_slice(&pt).unwrap();
+                sink.lock().unwrap().push(v["productId"].as_str().unwrap().to_string());
+                Ok(())
+            })
+        }).await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), ["legacy", "first", "second", "third"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_entries_are_counted_listed_and_skipped_by_drain() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let bad = enqueue_in(dir.path(), "bad", br#"{"productId":"P-bad","eventType":"QUALITY_CHECK"}"#, None).unwrap();
+        let policies = RetryPolicies::parse(["3".to_string()].iter()).unwrap();
+        let failing = |_: Vec<u8>| -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> { Box::pin(async { Err(rejected()) }) };
+
+        for attempt in 1..=2 {
+            assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap().dead, 0);
+            assert_eq!(fs::read_to_string(attempts_path(&bad)).unwrap(), attempt.to_string());
+        }
+        assert_eq!(dead_letter_stats_in(dir.path()).unwrap(), (0, 0));
+        assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap().dead, 1);
+        assert!(!bad.exists() && !attempts_path(&bad).exists());
+
+        let (count, bytes) = dead_letter_stats_in(dir.path()).unwrap();
+        assert_eq!((count, stats_in(dir.path()).unwrap().0), (1, 0));
+        assert!(bytes > 0);
+        assert_eq!(dead_letter_list_in(dir.path()).unwrap(), [DeadEntry {
+            name: bad.file_stem().unwrap().to_string_lossy().into_owned(),
+            attempts: 3,
+            product: Some("P-bad".into()),
+            event_type: Some("QUALITY_CHECK".into()),
+        }]);
+
+        enqueue_in(dir.path(), "good", br#"{"productId":"P-good"}"#, None).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let stats = drain_in(dir.path(), 10, move |pt| {
+            let sink = sink.clone();
+            Box::pin(async move { sink.lock().unwrap().push(pt); Ok(()) })
+        }).await.unwrap();
+        assert_eq!(stats.delivered, 1);
+        assert_eq!(*seen.lock().unwrap(), [br#"{"productId":"P-good"}"#.to_vec()]);
+        assert_eq!(dead_letter_stats_in(dir.path()).unwrap().0, 1, "drain must leave dead entries alone");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unreachable_bus_stops_the_pass_at_once() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..6 { enqueue_in(dir.path(), &format!("p{}", i), b"{}", None).unwrap(); }
+        // One delivery, then the bus goes away.
+        let calls = Arc::new(Mutex::new(0usize));
+        let log = calls.clone();
+        let started = tokio::time::Instant::now();
+        let stats = drain_in(dir.path(), 2, move |_| {
+            let log = log.clone();
+            Box::pin(async move {
+                let mut calls = log.lock().unwrap();
+                *calls += 1;
+                if *calls == 1 { Ok(()) } else { Err(anyhow!("connection refused")) }
+            })
+        }).await.unwrap();
+        assert_eq!((stats.delivered, stats.unreachable), (1, true));
+        assert_eq!(*calls.lock().unwrap(), 2, "no further entry is tried once the bus is gone");
+        assert_eq!(started.elapsed(), Duration::ZERO, "the pass returns without sleeping");
+        assert_eq!(stats_in(dir.path()).unwrap().0, 5);
+
+        let stats = drain_in::<(), _>(dir.path(), 2, |_| Box::pin(async { Err(rejected()) })).await.unwrap();
+        assert!(!stats.unreachable, "a rejection is an answer, the pass goes on");
+        let sidecars = fs::read_dir(dir.path()).unwrap().filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|x| x == "attempts")).count();
+        assert_eq!(sidecars, 5, "every remaining entry was tried");
+    }
+
+    #[test]
+    fn passes_back_off_exponentially_until_the_bus_is_reached() {
+        let base = Duration::from_secs(30);
+        let mut backoff = Backoff::new(base, MAX_DRAIN_BACKOFF);
+        let within = |gap: Duration, full: Duration| gap >= full / 2 && gap <= full;
+        let delays: Vec<_> = (0..3).map(|_| backoff.next_delay()).collect();
+        assert!(within(delays[0], base) && within(delays[1], base * 2) && within(delays[2], base * 4), "{:?}", delays);
+        backoff.reset();
+        assert!(within(backoff.next_delay(), base), "reaching the bus resets the backoff");
+
+        let capped = (0..20).map(|_| backoff.next_delay()).last().unwrap();
+        assert!(within(capped, MAX_DRAIN_BACKOFF));
+        assert_eq!(Backoff::new(Duration::from_secs(3600), MAX_DRAIN_BACKOFF).cap, Duration::from_secs(3600), "never shorter than the interval");
+    }
+
+    #[test]
+    fn a_full_queue_evicts_its_oldest_entries_first() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let payload = |i: usize| format!("{{\"productId\":\"P-{}\"}}", i).into_bytes();
+        let entry_size = seal_entry(&dir.path().join(entry_file_name("P-0")), &payload(0), None).unwrap().len() as u64;
+        let cap = entry_size * 3 + entry_size / 2;
+        for i in 0..6 { enqueue_bounded_in(dir.path(), &format!("P-{}", i), &payload(i), None, cap).unwrap(); }
+
+        let (count, bytes) = stats_in(dir.path()).unwrap();
+        assert_eq!(count, 3);
+        assert!(bytes as u64 <= cap);
+        let mut left: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "bin"))
+            .map(|p| String::from_utf8(unwrap_envelope(open(&fs::read(&p).unwrap(), &aad(&p)).unwrap()).1).unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, [3, 4, 5].map(|i| String::from_utf8(payload(i)).unwrap()));
+
+        let err = enqueue_bounded_in(dir.path(), "huge", &vec![b'x'; cap as usize], None, cap).unwrap_err();
+        assert!(err.to_string().contains("queue cap"), "{}", err);
+        assert_eq!(stats_in(dir.path()).unwrap().0, 3, "an entry that can never fit must not evict anything");
+
+        // A drain in progress may be submitting the oldest entry: keep it.
+        let held = DrainLock::acquire(dir.path()).unwrap().expect("lock is free");
+        enqueue_bounded_in(dir.path(), "P-6", &payload(6), None, cap).unwrap();
+        assert_eq!(stats_in(dir.path()).unwrap().0, 4, "nothing evicted under a drain");
+        drop(held);
+        enqueue_bounded_in(dir.path(), "P-7", &payload(7), None, cap).unwrap();
+        assert_eq!(stats_in(dir.path()).unwrap().0, 3);
+    }
+
+    #[test]
+    fn list_shows_pending_entries_without_draining_them() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let before = chrono::Utc::now();
+        enqueue_in(dir.path(), "P-1", br#"{"productId":"P-1","eventType":"QUALITY_CHECK"}"#, None).unwrap();
+        enqueue_in(dir.path(), "P-2", br#"{"productId":"P-2","eventType":"RECALL"}"#, None).unwrap();
+        fs::write(dir.path().join("00000000000000000001-00000000-junk.bin"), b"not a sealed entry at all").unwrap();
+
+        let items = list_in(dir.path()).unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!((items[0].error.as_deref(), items[0].product.as_deref()), (Some("decrypt failed"), None), "unreadable entries are flagged, oldest first");
+        let listed: Vec<_> = items[1..].iter().map(|i| (i.product.as_deref().unwrap(), i.event_type.as_deref().unwrap())).collect();
+        assert_eq!(listed, [("P-1", "QUALITY_CHECK"), ("P-2", "RECALL")]);
+        for item in &items[1..] {
+            assert!(item.error.is_none() && item.size > 0 && item.enqueued_at >= before);
+            assert!(item.file_name.ends_with(&format!("-{}.bin", item.product.as_deref().unwrap())));
+        }
+        assert_eq!(stats_in(dir.path()).unwrap().0, 3, "listing must not consume entries");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_drains_submit_each_entry_once() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..5 { enqueue_in(dir.path(), &format!("P-{}", i), format!("{{\"n\":{}}}", i).as_bytes(), None).unwrap(); }
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let drain = |submitted: Arc<Mutex<Vec<Vec<u8>>>>| drain_in(dir.path(), 2, move |pt| {
+            let submitted = submitted.clone();
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                submitted.lock().unwrap().push(pt);
+                Ok(())
+            })
+        });
+        let (a, b) = tokio::join!(drain(submitted.clone()), drain(submitted.clone()));
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert!(a.busy != b.busy, "exactly one drain runs: {:?} {:?}", a, b);
+        assert_eq!(a.delivered + b.delivered, 5);
+        let mut submitted = submitted.lock().unwrap().clone();
+        submitted.sort();
+        submitted.dedup();
+        assert_eq!(submitted.len(), 5, "no entry submitted twice");
+
+        // A lock file left behind, empty or not, doesn't block the next drain.
+        fs::write(dir.path().join(DRAIN_LOCK), b"").unwrap();
+        enqueue_in(dir.path(), "P-5", b"{}", None).unwrap();
+        assert_eq!(drain(Default::default()).await.unwrap(), DrainStats { delivered: 1, ..Default::default() });
+        // A held one does, however long the holder has been at it.
+        let held = DrainLock::acquire(dir.path()).unwrap().expect("lock is free");
+        enqueue_in(dir.path(), "P-6", b"{}", None).unwrap();
+        assert!(drain(Default::default()).await.unwrap().busy);
+        drop(held);
+        assert_eq!(drain(Default::default()).await.unwrap().delivered, 1);
+    }
+
+    #[tokio::test]
+    async fn drain_ignores_and_cleans_up_interrupted_writes() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let good = enqueue_in(dir.path(), "P-1", br#"{"productId":"P-1"}"#, None).unwrap();
+        let sealed = fs::read(&good).unwrap();
+        assert_eq!(leftovers(dir.path()), 1, "nothing staged once the entry is in place");
+        let torn = crate::paths::tmp_path(&dir.path().join("00000000000000000002-00000000-P-2.bin"));
+        fs::write(&torn, &sealed[..sealed.len() / 2]).unwrap();
+        fs::File::options().write(true).open(&torn).unwrap().set_modified(SystemTime::now() - Duration::from_secs(120)).unwrap();
+
+        let stats = drain_in(dir.path(), 10, |_| Box::pin(async { Ok(()) })).await.unwrap();
+        assert_eq!((stats.delivered, stats.corrupt), (1, 0));
+        assert!(!torn.exists());
+        assert_eq!(leftovers(dir.path()), 0);
+    }
+
+    #[test]
+    fn swapped_queue_files_fail_authentication() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let a = enqueue_in(dir.path(), "P-a", br#"{"productId":"P-a"}"#, None).unwrap();
+        let b = enqueue_in(dir.path(), "P-b", br#"{"productId":"P-b"}"#, None).unwrap();
+        let (a_bytes, b_bytes) = (fs::read(&a).unwrap(), fs::read(&b).unwrap());
+        fs::write(&a, &b_bytes).unwrap();
+        fs::write(&b, &a_bytes).unwrap();
+        for path in [&a, &b] {
+            assert!(inspect(path, None).unwrap_err().to_string().contains("authentication failed"));
+        }
+
+        stash_attachment_in(dir.path(), "aaaa", b"photo a").unwrap();
+        stash_attachment_in(dir.path(), "bbbb", b"photo b").unwrap();
+        let attachments = dir.path().join("attachments");
+        fs::copy(attachments.join("aaaa.blob"), attachments.join("bbbb.blob")).unwrap();
+        assert!(load_attachment_in(dir.path(), "bbbb").is_err());
+        assert_eq!(load_attachment_in(dir.path(), "aaaa").unwrap(), b"photo a");
+
+        // Entries sealed before associated data still open.
+        let old = dir.path().join("old.bin");
+        let mut plain = ENVELOPE_MAGIC.to_vec();
+        plain.extend_from_slice(&0u64.to_be_bytes());
+        plain.extend_from_slice(b"{}");
+        let nonce = rand::random::<[u8; 12]>();
+        let ct = Aes256Gcm::new_from_slice(key().unwrap().as_slice()).unwrap().encrypt(Nonce::from_slice(&nonce), plain.as_slice()).unwrap();
+        fs::write(&old, [nonce.as_slice(), &ct].concat()).unwrap();
+        assert_eq!(inspect(&old, None).unwrap().payload, b"{}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_rejections_count_towards_dead_lettering() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let entry = enqueue_in(dir.path(), "P-1", br#"{"productId":"P-1","eventType":"ROUTINE"}"#, None).unwrap();
+        // Rekeying rewrites the file; the entry's age still comes from its name.
+        fs::File::options().write(true).open(&entry).unwrap().set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        let policies = RetryPolicies::parse(["2:3600".to_string()].iter()).unwrap();
+        let offline = |_: Vec<u8>| -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> { Box::pin(async { Err(anyhow!("connection refused")) }) };
+        let overloaded = |_: Vec<u8>| -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> {
+            Box::pin(async { Err(crate::submit::EventRejected { status: 503, code: None, message: "busy".into() }.into()) })
+        };
+        for _ in 0..3 {
+            assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), offline, None).await.unwrap().dead, 0);
+            assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), overloaded, None).await.unwrap().dead, 0);
+        }
+        assert!(entry.exists() && !attempts_path(&entry).exists(), "transport failures and 5xx are not attempts");
+
+        let failing = |_: Vec<u8>| -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> { Box::pin(async { Err(rejected()) }) };
+        assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap().dead, 0, "queued just now, whatever the mtime says");
+        assert_eq!(drain_with_hook_in(dir.path(), 10, &policies, &Shutdown::default(), failing, None).await.unwrap().dead, 1);
+        assert_eq!(dead_letter_list_in(dir.path()).unwrap()[0].attempts, 2);
+    }
+
+    #[test]
+    fn deferred_uploads_stay_listed_until_removed() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        let att = |sha: &str| crate::attachments::AttachmentRef { sha256: sha.into(), name: format!("{}.jpg", sha), size: 5 };
+        stash_attachment_in(dir.path(), "queued", b"photo").unwrap();
+        defer_upload_in(dir.path(), &att("stashed"), Some(b"photo")).unwrap();
+        stash_attachment_in(dir.path(), "drained", b"photo").unwrap();
+        defer_upload_in(dir.path(), &att("drained"), None).unwrap();
+
+        let mut pending = pending_uploads_in(dir.path()).unwrap();
+        pending.sort_by(|a, b| a.sha256.cmp(&b.sha256));
+        assert_eq!(pending, [att("drained"), att("stashed")], "blobs of still-queued events are not owed yet");
+        assert_eq!(load_attachment_in(dir.path(), "stashed").unwrap(), b"photo");
+
+        remove_attachment_in(dir.path(), "stashed").unwrap();
+        assert_eq!(pending_uploads_in(dir.path()).unwrap(), [att("drained")]);
+    }
+
+    #[test]
+    fn a_staged_rekey_changes_nothing_until_committed() {
+        unlocked();
+        let root = tempfile::tempdir().unwrap();
+        let live = root.path().join("queue");
+        fs::create_dir_all(&live).unwrap();
+        let entry = enqueue_in(&live, "P-1", br#"{"productId":"P-1"}"#, None).unwrap();
+        stash_attachment_in(&live, "abc", b"photo").unwrap();
+        fs::write(attempts_path(&entry), "2").unwrap();
+        let before = fs::read(&entry).unwrap();
+        let new = derive_key(b"the-next-device-secret");
+
+        let staged = stage_rekey_in(&live, Some(&key().unwrap()), new.clone()).unwrap();
+        assert!(DrainLock::acquire(&live).unwrap().is_none(), "no drain while the copy is staged");
+        drop(staged);
+        assert_eq!(fs::read(&entry).unwrap(), before, "abandoned before the identity was stored");
+        assert!(!root.path().join(REKEY_STAGING).exists());
+
+        let mut staged = stage_rekey_in(&live, Some(&key().unwrap()), new.clone()).unwrap();
+        staged.swap().unwrap();
+        let data = fs::read(&entry).unwrap();
+        assert_eq!(unwrap_envelope(open_with(&new, &data, &aad(&entry)).unwrap()).1, br#"{"productId":"P-1"}"#);
+        let blob = live.join("attachments").join("abc.blob");
+        assert_eq!(open_with(&new, &fs::read(&blob).unwrap(), &aad(&blob)).unwrap(), b"photo");
+        assert_eq!(fs::read_to_string(attempts_path(&entry)).unwrap(), "2");
+        assert!(!root.path().join(REKEY_RETIRED).exists() && !root.path().join(REKEY_STAGING).exists());
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_stops_the_drain_between_entries() {
+        unlocked();
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..3 { enqueue_in(dir.path(), &format!("p{}", i), b"{}", None).unwrap(); }
+        let stop = Arc::new(Shutdown::default());
+        let requester = stop.clone();
+        let stats = drain_with_hook_in(dir.path(), 10, &RetryPolicies::default(), &stop, move |_| {
+            let stop = requester.clone();
+            Box::pin(async move { stop.request(); Ok(()) })
+        }, None).await.unwrap();
+        assert_eq!(stats.delivered, 1, "the submit in flight finishes, no further entry starts");
+        assert_eq!(stats_in(dir.path()).unwrap().0, 2);
+
+        let started = tokio::time::Instant::now();
+        let report = flush_in(dir.path(), Duration::from_secs(3600), &stop, || drain_in::<(), _>(dir.path(), 10, |_| Box::pin(async { Err(anyhow!("bus unavailable")) }))).await.unwrap();
+        assert_eq!(report.remaining, 2);
+        assert!(started.elapsed() < Duration::from_secs(60), "flush doesn't wait out its timeout once stopped");
+    }
+}
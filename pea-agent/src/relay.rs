@@ -0,0 +1,69 @@
+use anyhow::{Result, anyhow};
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A local store-and-forward gateway that events are sent through when the
+/// device can't reach the bus itself. The relay authenticates the hop with a
+/// shared secret; the device signature headers and body pass through untouched
+/// so the bus still verifies the event end to end.
+pub struct Relay {
+    url: String,
+    secret: String,
+}
+
+static RELAY: OnceLock<Relay> = OnceLock::new();
+
+/// Send events through `relay` for the rest of the process; call once at startup.
+pub fn configure(relay: Relay) {
+    let _ = RELAY.set(relay);
+}
+
+pub fn get() -> Option<&'static Relay> {
+    RELAY.get()
+}
+
+impl Relay {
+    pub fn new(url: &str, secret: &str) -> Self {
+        Self { url: url.trim_end_matches('/').to_string(), secret: secret.to_string() }
+    }
+
+    pub fn event_url(&self) -> String {
+        format!("{}/api/supply-chain/event", self.url)
+    }
+
+    fn mac(&self, input: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes()).expect("HMAC takes any key length");
+        mac.update(input.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Add the hop credentials: an HMAC over the relay timestamp and the payload hash.
+    pub fn authorize(&self, req: reqwest::RequestBuilder, payload: &[u8]) -> reqwest::RequestBuilder {
+        let ts = chrono::Utc::now().timestamp_millis().to_string();
+        let auth = self.mac(&format!("{}|{}", ts, hex::encode(Sha256::digest(payload))));
+        req.header("X-PEA-Relay-Timestamp", ts).header("X-PEA-Relay-Auth", auth)
+    }
+
+    /// The relay proves it holds the secret too by MACing the payload hash back,
+    /// so an impostor on the plant network can't swallow events.
+    pub fn verify_ack(&self, headers: &reqwest::header::HeaderMap, payload: &[u8]) -> Result<()> {
+        let expected = self.mac(&format!("relay-ack|{}", hex::encode(Sha256::digest(payload))));
+        match headers.get("X-PEA-Relay-Proof").and_then(|v| v.to_str().ok()) {
+            Some(proof) if bool::from(proof.as_bytes().ct_eq(expected.as_bytes())) => Ok(()),
+            Some(_) => Err(anyhow!("relay proof does not verify; event not considered delivered")),
+            None => Err(anyhow!("relay answered without X-PEA-Relay-Proof; event not considered delivered")),
+        }
+    }
+}
+
+/// Check the relay's proof on a successful answer; nothing to check without a relay.
+pub fn check_ack(resp: &reqwest::Response, payload: &[u8]) -> Result<()> {
+    match get() {
+        Some(relay) => relay.verify_ack(resp.headers(), payload),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,51 @@
+use ed25519_dalek::Keypair;
+
+// Deterministic output is for golden-file tests only; a release binary must
+// never produce predictable nonces or keys.
+#[cfg(all(feature = "test-determinism", not(debug_assertions)))]
+compile_error!("the test-determinism feature must not be enabled in release builds");
+
+/// Seeded randomness for tests. Debug builds with `test-determinism` read the
+/// seed from `PEA_TEST_SEED`; unit tests set it with [`reseed`]. Each thread
+/// has its own stream so concurrently running tests don't interleave draws.
+#[cfg(any(test, feature = "test-determinism"))]
+mod seeded {
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::cell::RefCell;
+
+    thread_local! {
+        static RNG: RefCell<Option<StdRng>> = RefCell::new(
+            std::env::var("PEA_TEST_SEED").ok().and_then(|s| s.parse().ok()).map(StdRng::seed_from_u64)
+        );
+    }
+
+    pub fn with<T>(f: impl FnOnce(&mut StdRng) -> T) -> Option<T> {
+        RNG.with(|rng| rng.borrow_mut().as_mut().map(f))
+    }
+
+    #[cfg(test)]
+    pub fn reseed(seed: Option<u64>) {
+        RNG.with(|rng| *rng.borrow_mut() = seed.map(StdRng::seed_from_u64));
+    }
+}
+
+#[cfg(test)]
+pub use seeded::reseed;
+
+/// A fresh request nonce.
+pub fn nonce() -> String {
+    #[cfg(any(test, feature = "test-determinism"))]
+    if let Some(bytes) = seeded::with(|rng| rand::Rng::gen::<[u8; 16]>(&mut *rng)) {
+        return uuid::Builder::from_random_bytes(bytes).into_uuid().to_string();
+    }
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// A freshly generated device keypair.
+pub fn keypair() -> Keypair {
+    #[cfg(any(test, feature = "test-determinism"))]
+    if let Some(kp) = seeded::with(Keypair::generate) {
+        return kp;
+    }
+    Keypair::generate(&mut rand::rngs::OsRng)
+}
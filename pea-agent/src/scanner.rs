@@ -1,31 +1,180 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use serde::Serialize;
+use std::sync::OnceLock;
 
 #[derive(Debug, Serialize, Clone)]
 pub struct ScanData {
     pub product_id: String,
     pub location: String,
-    pub timestamp: String,
+    pub timestamp: serde_json::Value,
 }
 
-pub trait Scanner: Send + Sync {
-    fn name(&self) -> &str;
-    fn poll(&mut self) -> Option<ScanData>;
+/// Event type for codes no prefix route claims, unless `--event-type` says otherwise.
+pub const DEFAULT_EVENT_TYPE: &str = "QUALITY_CHECK";
+
+/// Maps code prefixes to event types, so one scanner can read e.g. product
+/// barcodes and `OP:`-prefixed operator badges in the same session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixRoutes {
+    routes: Vec<(String, String)>,
+    fallback: Option<String>,
 }
 
-pub struct MockScanner {
-    pub location: String,
+impl PrefixRoutes {
+    /// Parse `PREFIX=EVENT_TYPE` entries.
+    pub fn parse<'a>(entries: impl Iterator<Item = &'a String>) -> Result<Self> {
+        let mut routes = entries.map(|e| {
+            let (prefix, ty) = e.split_once('=').filter(|(p, t)| !p.is_empty() && !t.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("--route expects PREFIX=EVENT_TYPE, got {:?}", e))?;
+            Ok((prefix.to_string(), ty.to_string()))
+        }).collect::<Result<Vec<_>>>()?;
+        // Longest prefix first, so `OP:ADM:` beats `OP:`.
+        routes.sort_by_key(|r| std::cmp::Reverse(r.0.len()));
+        Ok(Self { routes, fallback: None })
+    }
+
+    /// Report codes no route claims as `event_type` instead of [`DEFAULT_EVENT_TYPE`].
+    pub fn with_fallback(mut self, event_type: String) -> Self {
+        self.fallback = Some(event_type);
+        self
+    }
+
+    /// The code with any matched prefix stripped, and its event type.
+    pub fn route<'a>(&'a self, code: &'a str) -> (&'a str, &'a str) {
+        self.routes.iter()
+            .find_map(|(prefix, ty)| code.strip_prefix(prefix.as_str()).map(|rest| (rest, ty.as_str())))
+            .unwrap_or((code, self.fallback.as_deref().unwrap_or(DEFAULT_EVENT_TYPE)))
+    }
+}
+
+/// Default bound on one scanned code. 2D codes can carry a few kilobytes, far
+/// more than a single serial read or HID report.
+pub const DEFAULT_MAX_CODE_LEN: usize = 8 * 1024;
+
+/// A scanned code ran past the configured maximum and was discarded whole
+/// rather than truncated.
+#[derive(Debug)]
+pub struct CodeTooLong {
+    pub max_len: usize,
+}
+
+impl std::fmt::Display for CodeTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "scanned code longer than {} bytes discarded (raise --max-code-len)", self.max_len)
+    }
+}
+
+impl std::error::Error for CodeTooLong {}
+
+/// Assemble one code from fixed-size reads. `read` fills the chunk it is given
+/// and returns how many bytes it wrote (0 or a timeout when the line is idle).
+/// A frame ends at a CR/LF terminator or when the line goes idle; a short read
+/// alone does not end it. Bytes after the terminator stay in `carry` and start
+/// the next frame. Frames longer than `max_len` are drained up to their
+/// terminator and rejected with [`CodeTooLong`].
+#[cfg_attr(not(any(feature = "scanner-serial", feature = "scanner-hid")), allow(dead_code))]
+pub fn read_frame(mut read: impl FnMut(&mut [u8]) -> std::io::Result<usize>, chunk_len: usize, max_len: usize, carry: &mut Vec<u8>) -> Result<Option<String>> {
+    let mut chunk = vec![0u8; chunk_len.max(1)];
+    let mut overflow = false;
+    loop {
+        if let Some(end) = carry.iter().position(|b| *b == b'\r' || *b == b'\n') {
+            let rest = carry.split_off(end + 1);
+            let frame = std::mem::replace(carry, rest);
+            if overflow || end > max_len { return Err(CodeTooLong { max_len }.into()); }
+            match frame_text(&frame) {
+                Some(code) => return Ok(Some(code)),
+                // The second half of a CR/LF pair, or a blank line.
+                None => continue,
+            }
+        }
+        if carry.len() > max_len {
+            overflow = true;
+            carry.clear();
+        }
+        let n = match read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            let frame = std::mem::take(carry);
+            if overflow { return Err(CodeTooLong { max_len }.into()); }
+            return Ok(frame_text(&frame));
+        }
+        carry.extend_from_slice(&chunk[..n]);
+    }
 }
-impl MockScanner {
-    pub fn new(location: String) -> Self { Self { location } }
+
+#[cfg_attr(not(any(feature = "scanner-serial", feature = "scanner-hid")), allow(dead_code))]
+fn frame_text(frame: &[u8]) -> Option<String> {
+    let s = String::from_utf8_lossy(frame).trim().to_string();
+    if s.is_empty() { None } else { Some(s) }
+}
+
+pub fn simulate_scan(product_id: &str, location: &str, format: crate::submit::TimestampFormat) -> Result<ScanData> {
+    crate::events::publish("scan", serde_json::json!({ "product": product_id }));
+    Ok(ScanData { product_id: product_id.to_string(), location: location.to_string(), timestamp: format.now()? })
 }
-impl Scanner for MockScanner {
-    fn name(&self) -> &str { "mock-scanner" }
-    fn poll(&mut self) -> Option<ScanData> { None }
+
+/// How one simulated scan in a `scanner-sim --count` stream ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimOutcome {
+    Submitted,
+    Enqueued,
+    /// Already delivered within the dedup window, so not sent again.
+    Duplicate,
 }
 
-pub fn simulate_scan(product_id: &str, location: &str) -> ScanData {
-    ScanData { product_id: product_id.to_string(), location: location.to_string(), timestamp: chrono::Utc::now().to_rfc3339() }
+/// Totals for a simulated scan stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SimReport {
+    pub submitted: usize,
+    pub enqueued: usize,
+    pub duplicates: usize,
+    /// Scans that neither reached the bus nor the queue.
+    pub failed: usize,
+    pub elapsed: std::time::Duration,
+}
+
+impl SimReport {
+    pub fn per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { (self.submitted + self.enqueued + self.duplicates + self.failed) as f64 / secs } else { 0.0 }
+    }
+}
+
+/// Product ids for a simulated stream, one per line; blank lines and `#` comments are skipped.
+pub fn load_products(path: &std::path::Path) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("reading products from {:?}: {}", path, e))?;
+    let products: Vec<String> = text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')).map(str::to_string).collect();
+    if products.is_empty() { return Err(anyhow::anyhow!("{:?} lists no products", path)); }
+    Ok(products)
+}
+
+/// Drive `count` scans through `scan` at `rate` per second, each for a product
+/// drawn from `products` (or a random `SIM-` id when the list is empty). Scans
+/// run one after another, so a slow bus lowers the achieved rate instead of
+/// piling up requests.
+pub async fn sim_stream<F, Fut>(products: &[String], count: usize, rate: f64, mut scan: F) -> SimReport
+where F: FnMut(String) -> Fut, Fut: std::future::Future<Output = Result<SimOutcome>> {
+    use rand::{Rng, seq::SliceRandom};
+    let mut ticks = tokio::time::interval(std::time::Duration::from_secs_f64(1.0 / rate.max(0.001)));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let started = tokio::time::Instant::now();
+    let mut report = SimReport::default();
+    for _ in 0..count {
+        ticks.tick().await;
+        let product = products.choose(&mut rand::thread_rng()).cloned()
+            .unwrap_or_else(|| format!("SIM-{:06}", rand::thread_rng().gen_range(0, 1_000_000)));
+        match scan(product).await {
+            Ok(SimOutcome::Submitted) => report.submitted += 1,
+            Ok(SimOutcome::Enqueued) => report.enqueued += 1,
+            Ok(SimOutcome::Duplicate) => report.duplicates += 1,
+            Err(e) => { tracing::warn!(error = %e, "simulated scan failed"); report.failed += 1; }
+        }
+    }
+    report.elapsed = started.elapsed();
+    report
 }
 
 #[cfg(feature = "scanner-serial")]
@@ -42,27 +191,21 @@ pub mod serial_backend {
         Ok(out)
     }
 
-    pub fn poll_serial_once(port_name: &str) -> Result<Option<String>> {
+    /// Read the next code from `port_name`. Bytes read past its terminator
+    /// are kept in `carry` for the next call.
+    pub fn poll_serial_once(port_name: &str, max_len: usize, carry: &mut Vec<u8>) -> Result<Option<String>> {
         let mut port = serialport::new(port_name, 9600)
             .timeout(Duration::from_millis(300))
             .open()?;
-        let mut buf = [0u8; 512];
-        match port.read(&mut buf) {
-            Ok(n) if n > 0 => {
-                let s = String::from_utf8_lossy(&buf[..n]).trim().to_string();
-                if !s.is_empty() { Ok(Some(s)) } else { Ok(None) }
-            }
-            _ => Ok(None),
-        }
+        read_frame(|buf| port.read(buf), 512, max_len, carry)
     }
 }
 
 #[cfg(not(feature = "scanner-serial"))]
 pub mod serial_backend {
-    use super::*;
     use anyhow::Result;
     pub fn list_ports() -> Result<Vec<String>> { Ok(vec![]) }
-    pub fn poll_serial_once(_port_name: &str) -> Result<Option<String>> { Ok(None) }
+    pub fn poll_serial_once(_port_name: &str, _max_len: usize, _carry: &mut Vec<u8>) -> Result<Option<String>> { Ok(None) }
 }
 
 #[cfg(feature = "scanner-hid")]
@@ -78,7 +221,7 @@ pub mod hid_backend {
         }
         Ok(out)
     }
-    pub fn read_once(path: Option<&str>, vid: Option<u16>, pid: Option<u16>) -> Result<Option<String>> {
+    pub fn read_once(path: Option<&str>, vid: Option<u16>, pid: Option<u16>, max_len: usize) -> Result<Option<String>> {
         let api = HidApi::new()?;
         let device = if let Some(p) = path {
             api.open_path(p).map_err(|e| anyhow!("{}", e))?
@@ -87,30 +230,347 @@ pub mod hid_backend {
         } else {
             return Ok(None);
         };
-        let mut buf = [0u8; 64];
-        match device.read_timeout(&mut buf, 200) {
-            Ok(n) if n > 0 => {
-                let s = String::from_utf8_lossy(&buf[..n]).trim().to_string();
-                if s.is_empty() { Ok(None) } else { Ok(Some(s)) }
-            }
-            _ => Ok(None)
-        }
+        // A read error mid-frame ends the frame, like an idle line.
+        read_frame(|buf| Ok(device.read_timeout(buf, 200).unwrap_or(0)), 64, max_len, &mut Vec::new())
     }
 }
 
 #[cfg(not(feature = "scanner-hid"))]
 pub mod hid_backend {
-    use super::*;
     use anyhow::Result;
     pub fn list_devices() -> Result<Vec<String>> { Ok(vec![]) }
-    pub fn read_once(_path: Option<&str>, _vid: Option<u16>, _pid: Option<u16>) -> Result<Option<String>> { Ok(None) }
+    pub fn read_once(_path: Option<&str>, _vid: Option<u16>, _pid: Option<u16>, _max_len: usize) -> Result<Option<String>> { Ok(None) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Serial,
+    Hid,
+}
+
+impl Backend {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim() {
+            "serial" => Ok(Backend::Serial),
+            "hid" => Ok(Backend::Hid),
+            other => Err(anyhow!("unknown scanner backend {:?} (expected serial or hid)", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Serial => "serial",
+            Backend::Hid => "hid",
+        }
+    }
+
+    fn feature(&self) -> &'static str {
+        match self {
+            Backend::Serial => "scanner-serial",
+            Backend::Hid => "scanner-hid",
+        }
+    }
+
+    /// Whether this build includes the backend at all.
+    pub fn compiled(&self) -> bool {
+        match self {
+            Backend::Serial => cfg!(feature = "scanner-serial"),
+            Backend::Hid => cfg!(feature = "scanner-hid"),
+        }
+    }
+}
+
+/// Which compiled-in backends may open devices. Both are on unless switched
+/// off by `PEA_SCANNER_DISABLE` (comma-separated) or `--disable-serial` /
+/// `--disable-hid`; the flags win over the environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backends {
+    serial: bool,
+    hid: bool,
+}
+
+impl Default for Backends {
+    fn default() -> Self { Self { serial: true, hid: true } }
 }
 
-pub fn list_available_devices() -> Result<Vec<String>> {
-    let mut devices = Vec::new();
-    // Serial
-    if let Ok(ports) = serial_backend::list_ports() { for p in ports { devices.push(format!("serial:{}", p)); } }
-    // HID
-    if let Ok(hids) = hid_backend::list_devices() { for h in hids { devices.push(format!("hid:{}", h)); } }
-    Ok(devices)
-} 
\ No newline at end of file
+impl Backends {
+    pub fn from_env(disable: Option<&str>) -> Result<Self> {
+        let mut backends = Self::default();
+        for name in disable.unwrap_or_default().split(',').filter(|n| !n.trim().is_empty()) {
+            backends.set(Backend::parse(name)?, false);
+        }
+        Ok(backends)
+    }
+
+    pub fn enabled(&self, backend: Backend) -> bool {
+        match backend {
+            Backend::Serial => self.serial,
+            Backend::Hid => self.hid,
+        }
+    }
+
+    /// Toggling a backend this build lacks is allowed but does nothing.
+    pub fn set(&mut self, backend: Backend, on: bool) {
+        if !backend.compiled() {
+            tracing::warn!(backend = backend.name(), feature = backend.feature(), "scanner backend is not in this build; {} it has no effect",
+                if on { "enabling" } else { "disabling" });
+        }
+        match backend {
+            Backend::Serial => self.serial = on,
+            Backend::Hid => self.hid = on,
+        }
+    }
+
+    pub fn ensure(&self, backend: Backend) -> Result<()> {
+        if self.enabled(backend) { return Ok(()); }
+        Err(anyhow!("the {} scanner backend is disabled (--disable-{} or PEA_SCANNER_DISABLE); pass --enable-{} to use it", backend.name(), backend.name(), backend.name()))
+    }
+}
+
+static BACKENDS: OnceLock<Backends> = OnceLock::new();
+
+/// Gate scanner backends for the rest of the process; call once at startup.
+pub fn configure(backends: Backends) {
+    let _ = BACKENDS.set(backends);
+}
+
+pub fn backends() -> Backends {
+    BACKENDS.get().copied().unwrap_or_default()
+}
+
+/// How a backend fared while listing devices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendHealth {
+    Disabled,
+    Ready(usize),
+    /// Initialization failed; the message says why and what to try.
+    Unavailable(String),
+}
+
+/// Devices found, plus each backend's health, so an empty list can be told
+/// apart from a backend that could not start.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceList {
+    pub devices: Vec<String>,
+    pub health: Vec<(Backend, BackendHealth)>,
+}
+
+impl DeviceList {
+    fn probe(&mut self, backends: Backends, backend: Backend, list_fn: impl FnOnce() -> Result<Vec<String>>) {
+        let health = if !backends.enabled(backend) {
+            BackendHealth::Disabled
+        } else {
+            match list_fn() {
+                Ok(found) => {
+                    let n = found.len();
+                    self.devices.extend(found.into_iter().map(|d| format!("{}:{}", backend.name(), d)));
+                    BackendHealth::Ready(n)
+                }
+                Err(e) => BackendHealth::Unavailable(init_warning(backend, &e)),
+            }
+        };
+        self.health.push((backend, health));
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &str> {
+        self.health.iter().filter_map(|(_, h)| match h {
+            BackendHealth::Unavailable(why) => Some(why.as_str()),
+            _ => None,
+        })
+    }
+}
+
+pub fn list_available_devices() -> DeviceList {
+    enumerate(backends(), serial_backend::list_ports, hid_backend::list_devices)
+}
+
+/// What to tell the operator when `backend` fails to initialize with `err`.
+fn init_warning(backend: Backend, err: &anyhow::Error) -> String {
+    let text = err.to_string();
+    let lower = text.to_lowercase();
+    let hint = match backend {
+        _ if !lower.contains("permission") && !lower.contains("access") => "check the device driver is loaded",
+        Backend::Serial => "add the user to the dialout group",
+        Backend::Hid => "add a udev rule granting access to /dev/hidraw*",
+    };
+    let name = match backend {
+        Backend::Serial => "Serial",
+        Backend::Hid => "HID",
+    };
+    format!("{} unavailable: {} — {}", name, text, hint)
+}
+
+fn enumerate(backends: Backends, serial: impl FnOnce() -> Result<Vec<String>>, hid: impl FnOnce() -> Result<Vec<String>>) -> DeviceList {
+    let mut list = DeviceList::default();
+    list.probe(backends, Backend::Serial, serial);
+    list.probe(backends, Backend::Hid, hid);
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(entries: &[&str]) -> PrefixRoutes {
+        PrefixRoutes::parse(entries.iter().map(|s| s.to_string()).collect::<Vec<_>>().iter()).unwrap()
+    }
+
+    /// Serves `input` in reads of at most `chunk` bytes, then idles.
+    fn reader(input: &[u8]) -> impl FnMut(&mut [u8]) -> std::io::Result<usize> + '_ {
+        let mut pos = 0;
+        move |buf: &mut [u8]| {
+            let n = buf.len().min(input.len() - pos);
+            buf[..n].copy_from_slice(&input[pos..pos + n]);
+            pos += n;
+            Ok(n)
+        }
+    }
+
+    /// Like [`reader`], but never hands out more than `step` bytes at once, the
+    /// way a slow serial line delivers a code.
+    fn trickle(input: &[u8], step: usize) -> impl FnMut(&mut [u8]) -> std::io::Result<usize> + '_ {
+        let mut inner = reader(input);
+        move |buf: &mut [u8]| {
+            let n = buf.len().min(step);
+            inner(&mut buf[..n])
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_stream_submits_count_scans_at_the_target_rate() {
+        let products = vec!["P-1".to_string(), "P-2".to_string()];
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let report = sim_stream(&products, 5, 10.0, move |product| {
+            sink.lock().unwrap().push((tokio::time::Instant::now(), product));
+            async { Ok(SimOutcome::Submitted) }
+        }).await;
+        assert_eq!(report.submitted, 5);
+        assert_eq!((report.enqueued, report.failed), (0, 0));
+
+        let seen = seen.lock().unwrap().clone();
+        assert!(seen.iter().all(|(_, p)| products.contains(p)));
+        for pair in seen.windows(2) {
+            assert_eq!(pair[1].0 - pair[0].0, std::time::Duration::from_millis(100));
+        }
+        assert!((report.per_second() - 12.5).abs() < 0.01, "5 scans over 400ms: {}", report.per_second());
+
+        let report = sim_stream(&[], 3, 1000.0, |product| async move {
+            assert!(product.starts_with("SIM-"));
+            Ok(SimOutcome::Enqueued)
+        }).await;
+        assert_eq!(report.enqueued, 3);
+    }
+
+    #[test]
+    fn disabled_backends_are_not_enumerated() {
+        let serial = || Ok(vec!["/dev/ttyUSB0".to_string()]);
+        let hid = || Ok(vec!["05e0:1200 Scanner".to_string()]);
+        assert_eq!(enumerate(Backends::default(), serial, hid).devices, ["serial:/dev/ttyUSB0", "hid:05e0:1200 Scanner"]);
+
+        let mut no_hid = Backends::default();
+        no_hid.set(Backend::Hid, false);
+        assert_eq!(enumerate(no_hid, serial, || panic!("disabled backend must not be opened")).devices, ["serial:/dev/ttyUSB0"]);
+        assert!(no_hid.ensure(Backend::Hid).unwrap_err().to_string().contains("--enable-hid"));
+        assert!(no_hid.ensure(Backend::Serial).is_ok());
+
+        let from_env = Backends::from_env(Some("serial, hid")).unwrap();
+        assert!(enumerate(from_env, || panic!("serial is disabled"), || panic!("hid is disabled")).devices.is_empty());
+        assert_eq!(Backends::from_env(None).unwrap(), Backends::default());
+        assert!(Backends::from_env(Some("usb")).is_err());
+    }
+
+    #[test]
+    fn backend_init_failures_are_reported_not_swallowed() {
+        let list = enumerate(Backends::default(), || Ok(vec!["/dev/ttyUSB0".to_string()]), || Err(anyhow!("Permission denied (os error 13)")));
+        assert_eq!(list.devices, ["serial:/dev/ttyUSB0"]);
+        assert_eq!(list.warnings().collect::<Vec<_>>(), ["HID unavailable: Permission denied (os error 13) — add a udev rule granting access to /dev/hidraw*"]);
+        assert_eq!(list.health[0], (Backend::Serial, BackendHealth::Ready(1)));
+
+        let list = enumerate(Backends::default(), || Err(anyhow!("no such driver")), || Ok(vec![]));
+        assert!(list.devices.is_empty());
+        assert_eq!(list.health, [
+            (Backend::Serial, BackendHealth::Unavailable("Serial unavailable: no such driver — check the device driver is loaded".into())),
+            (Backend::Hid, BackendHealth::Ready(0)),
+        ]);
+    }
+
+    #[test]
+    fn codes_longer_than_one_read_are_captured_whole() {
+        let code = "A".repeat(3000);
+        let input = format!("{}\r\n", code);
+        assert_eq!(read_frame(reader(input.as_bytes()), 512, DEFAULT_MAX_CODE_LEN, &mut Vec::new()).unwrap(), Some(code.clone()));
+        // No terminator: the idle line ends the frame.
+        assert_eq!(read_frame(reader(code.as_bytes()), 64, DEFAULT_MAX_CODE_LEN, &mut Vec::new()).unwrap(), Some(code));
+        assert_eq!(read_frame(reader(b"4006381333931\r"), 512, 16, &mut Vec::new()).unwrap(), Some("4006381333931".into()));
+        assert_eq!(read_frame(reader(b""), 512, 16, &mut Vec::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn short_reads_do_not_split_a_code() {
+        let mut carry = Vec::new();
+        let mut read = trickle(b"4006381333931\r\nSECOND\rTHIRD", 3);
+        assert_eq!(read_frame(&mut read, 512, 16, &mut carry).unwrap(), Some("4006381333931".into()));
+        assert_eq!(read_frame(&mut read, 512, 16, &mut carry).unwrap(), Some("SECOND".into()));
+        assert_eq!(read_frame(&mut read, 512, 16, &mut carry).unwrap(), Some("THIRD".into()));
+        assert_eq!(read_frame(&mut read, 512, 16, &mut carry).unwrap(), None);
+    }
+
+    #[test]
+    fn bytes_after_a_terminator_start_the_next_code() {
+        let mut carry = Vec::new();
+        let mut read = reader(b"ONE\rTWO\r");
+        assert_eq!(read_frame(&mut read, 512, 16, &mut carry).unwrap(), Some("ONE".into()));
+        assert_eq!(carry, b"TWO\r");
+        assert_eq!(read_frame(&mut read, 512, 16, &mut carry).unwrap(), Some("TWO".into()));
+        assert!(carry.is_empty());
+    }
+
+    #[test]
+    fn codes_over_the_max_are_rejected_not_truncated() {
+        let input = format!("{}\r\n", "B".repeat(5000));
+        let err = read_frame(reader(input.as_bytes()), 512, 4096, &mut Vec::new()).unwrap_err();
+        assert!(err.is::<CodeTooLong>(), "{}", err);
+
+        // The rejected frame is drained, so the next read starts clean, however
+        // the bytes are split across reads.
+        let input = format!("{}\rNEXT\r", "B".repeat(1023));
+        for step in [1, 7, 512, 2048] {
+            let mut carry = Vec::new();
+            let mut read = trickle(input.as_bytes(), step);
+            assert!(read_frame(&mut read, 2048, 600, &mut carry).unwrap_err().is::<CodeTooLong>(), "step {}", step);
+            assert_eq!(read_frame(&mut read, 2048, 600, &mut carry).unwrap(), Some("NEXT".into()), "step {}", step);
+        }
+        // One byte over the limit is enough, even inside a single read.
+        let input = format!("{}\rOK\r", "C".repeat(17));
+        let mut carry = Vec::new();
+        assert!(read_frame(reader(input.as_bytes()), 512, 16, &mut carry).is_err());
+        assert_eq!(carry, b"OK\r");
+    }
+
+    #[test]
+    fn matching_prefix_is_stripped_and_routed() {
+        let r = routes(&["OP:=OPERATOR_LOGIN", "LOT-=LOT_RECEIVED"]);
+        assert_eq!(r.route("OP:jdoe"), ("jdoe", "OPERATOR_LOGIN"));
+        assert_eq!(r.route("LOT-7781"), ("7781", "LOT_RECEIVED"));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let r = routes(&["OP:=OPERATOR_LOGIN", "OP:ADM:=ADMIN_LOGIN"]);
+        assert_eq!(r.route("OP:ADM:root"), ("root", "ADMIN_LOGIN"));
+        assert_eq!(r.route("OP:jdoe"), ("jdoe", "OPERATOR_LOGIN"));
+    }
+
+    #[test]
+    fn unmatched_codes_fall_through_to_default() {
+        let r = routes(&["OP:=OPERATOR_LOGIN"]);
+        assert_eq!(r.route("4006381333931"), ("4006381333931", DEFAULT_EVENT_TYPE));
+        assert_eq!(PrefixRoutes::default().route("OP:x"), ("OP:x", DEFAULT_EVENT_TYPE));
+        assert!(PrefixRoutes::parse(["OP:".to_string()].iter()).is_err());
+
+        let r = r.with_fallback("RECEIVED".into());
+        assert_eq!(r.route("4006381333931"), ("4006381333931", "RECEIVED"));
+        assert_eq!(r.route("OP:jdoe"), ("jdoe", "OPERATOR_LOGIN"));
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{Result, anyhow};
+use directories::BaseDirs;
+use std::{fs, path::{Path, PathBuf}, process::Command};
+
+pub const UNIT_NAME: &str = "pea-agent.service";
+/// Beside the unit, readable by its owner only: the service's secrets, which
+/// `systemctl show` would print from `Environment=`.
+pub const ENV_FILE_NAME: &str = "pea-agent.env";
+
+/// Which systemd instance runs the agent: the invoking user's, or the
+/// system's (needs root, starts at boot without a login).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    User,
+    System,
+}
+
+impl Scope {
+    pub fn unit_dir(self) -> Result<PathBuf> {
+        match self {
+            Scope::User => Ok(BaseDirs::new().ok_or_else(|| anyhow!("no home dir"))?.config_dir().join("systemd/user")),
+            Scope::System => Ok(PathBuf::from("/etc/systemd/system")),
+        }
+    }
+
+    fn wanted_by(self) -> &'static str {
+        match self {
+            Scope::User => "default.target",
+            Scope::System => "multi-user.target",
+        }
+    }
+
+    fn systemctl_flags(self) -> &'static [&'static str] {
+        match self {
+            Scope::User => &["--user"],
+            Scope::System => &[],
+        }
+    }
+}
+
+/// The `run` invocation the unit starts, with settings resolved at install
+/// time so the service doesn't depend on the installing shell's environment.
+#[derive(Debug, Clone)]
+pub struct Unit {
+    pub binary: PathBuf,
+    pub bus: String,
+    pub company_id: u32,
+    pub heartbeat_interval: u64,
+    pub queue_drain_interval: u64,
+    pub config: Option<PathBuf>,
+    /// Global flags given at install time, passed on to `run`.
+    pub global_args: Vec<String>,
+    /// Who a system unit runs as: the installing user, whose keys and state
+    /// the agent was set up with.
+    pub user: Option<String>,
+    /// Agent settings from the installing environment.
+    pub environment: Vec<(String, String)>,
+    /// Secret settings, loaded from [`ENV_FILE_NAME`] instead of the unit.
+    pub secrets: Vec<(String, String)>,
+}
+
+impl Unit {
+    /// Arguments after the binary, for systemd and the Windows service alike.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(config) = &self.config { args.extend(["--config".into(), config.to_string_lossy().into_owned()]); }
+        args.extend(self.global_args.iter().cloned());
+        args.extend(["--bus".into(), self.bus.clone(), "--company".into(), self.company_id.to_string(), "run".into(),
+            "--hb".into(), self.heartbeat_interval.to_string(), "--qd".into(), self.queue_drain_interval.to_string()]);
+        args
+    }
+
+    pub fn exec_start(&self) -> String {
+        std::iter::once(self.binary.to_string_lossy().into_owned()).chain(self.args()).map(|a| quote(&a)).collect::<Vec<_>>().join(" ")
+    }
+
+    /// The unit file, loading [`Unit::secrets`] from `env_file` when there are any.
+    pub fn render(&self, scope: Scope, env_file: &Path) -> String {
+        let mut service = String::new();
+        // A user unit always runs as its owner and may not name another.
+        if let (Scope::System, Some(user)) = (scope, &self.user) { service.push_str(&format!("User={}\n", user.replace('%', "%%"))); }
+        for (key, value) in &self.environment { service.push_str(&format!("{}\n", environment(key, value))); }
+        if !self.secrets.is_empty() { service.push_str(&format!("EnvironmentFile={}\n", quote(&env_file.to_string_lossy()))); }
+        format!("[Unit]\n\
+            Description=KMP PEA Agent\n\
+            Wants=network-online.target\n\
+            After=network-online.target\n\
+            \n\
+            [Service]\n\
+            {}\
+            ExecStart={}\n\
+            Restart=always\n\
+            RestartSec=3\n\
+            \n\
+            [Install]\n\
+            WantedBy={}\n", service, self.exec_start(), scope.wanted_by())
+    }
+}
+
+/// Quote `arg` for an `ExecStart=` line: `%` and `$` would otherwise expand
+/// as specifiers and variables, and whitespace would split it.
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';')) { return escaped; }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// An `Environment=` line. Unlike in `ExecStart=`, `$` is literal there.
+fn environment(key: &str, value: &str) -> String {
+    let assignment = format!("{}={}", key, value).replace('%', "%%");
+    format!("Environment=\"{}\"", assignment.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The environment file holding `secrets`, one `KEY="value"` per line.
+fn env_file(secrets: &[(String, String)]) -> String {
+    secrets.iter().map(|(key, value)| {
+        let escaped: String = value.chars().flat_map(|c| match c {
+            '\\' | '"' | '$' | '`' => vec!['\\', c],
+            '\n' => vec!['\\', 'n'],
+            c => vec![c],
+        }).collect();
+        format!("{}=\"{}\"\n", key, escaped)
+    }).collect()
+}
+
+/// systemd's own test for whether it is the running init system.
+fn ensure_systemd() -> Result<()> {
+    if !Path::new("/run/systemd/system").is_dir() {
+        return Err(anyhow!("systemd is not running on this host (no /run/systemd/system); start `pea-agent run` from its init system instead"));
+    }
+    Ok(())
+}
+
+fn systemctl(scope: Scope, args: &[&str]) -> Result<()> {
+    let out = Command::new("systemctl").args(scope.systemctl_flags()).args(args).output()
+        .map_err(|e| anyhow!("systemctl not runnable ({})", e))?;
+    if !out.status.success() {
+        return Err(anyhow!("systemctl {} failed: {}", args.join(" "), String::from_utf8_lossy(&out.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Write the unit, reload systemd and start the service now and at every boot
+/// (or login, for [`Scope::User`]). Returns the unit's path.
+pub fn install(unit: &Unit, scope: Scope) -> Result<PathBuf> {
+    ensure_systemd()?;
+    let path = write_unit_in(&scope.unit_dir()?, unit, scope)?;
+    systemctl(scope, &["daemon-reload"])?;
+    systemctl(scope, &["enable", "--now", UNIT_NAME])?;
+    Ok(path)
+}
+
+/// Stop and disable the service, remove its unit and reload systemd.
+pub fn uninstall(scope: Scope) -> Result<PathBuf> {
+    ensure_systemd()?;
+    let path = scope.unit_dir()?.join(UNIT_NAME);
+    if !path.exists() { return Err(anyhow!("no {} installed at {:?}", UNIT_NAME, path)); }
+    systemctl(scope, &["disable", "--now", UNIT_NAME])?;
+    fs::remove_file(&path)?;
+    let _ = fs::remove_file(path.with_file_name(ENV_FILE_NAME));
+    systemctl(scope, &["daemon-reload"])?;
+    Ok(path)
+}
+
+/// Write the unit, and its secrets to [`ENV_FILE_NAME`] beside it; both are
+/// created readable by their owner only.
+fn write_unit_in(dir: &Path, unit: &Unit, scope: Scope) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let env_path = dir.join(ENV_FILE_NAME);
+    if unit.secrets.is_empty() {
+        match fs::remove_file(&env_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(anyhow!("removing {:?}: {}", env_path, e)),
+            _ => {}
+        }
+    } else {
+        crate::paths::write_atomic(&env_path, env_file(&unit.secrets).as_bytes())?;
+    }
+    let path = dir.join(UNIT_NAME);
+    crate::paths::write_atomic(&path, unit.render(scope, &env_path).as_bytes())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(binary: &str) -> Unit {
+        Unit { binary: binary.into(), bus: "https://bus.example".into(), company_id: 7, heartbeat_interval: 600, queue_drain_interval: 30, config: None,
+            global_args: Vec::new(), user: None, environment: Vec::new(), secrets: Vec::new() }
+    }
+
+    #[test]
+    fn units_start_run_with_the_resolved_settings() {
+        let env_file = Path::new("/etc/systemd/system/pea-agent.env");
+        let rendered = unit("/usr/local/bin/pea-agent").render(Scope::System, env_file);
+        assert!(!rendered.contains("EnvironmentFile="), "no secrets, no file");
+        let exec: Vec<_> = rendered.lines().filter(|l| l.starts_with("ExecStart=")).collect();
+        assert_eq!(exec, ["ExecStart=/usr/local/bin/pea-agent --bus https://bus.example --company 7 run --hb 600 --qd 30"]);
+        assert!(rendered.contains("\nRestart=always\n") && rendered.ends_with("WantedBy=multi-user.target\n"), "{}", rendered);
+        assert!(unit("/bin/pea").render(Scope::User, env_file).ends_with("WantedBy=default.target\n"));
+
+        let odd = Unit { config: Some("/etc/pea agent/50%.toml".into()), ..unit("/opt/pea agent/pea-agent") };
+        assert_eq!(odd.exec_start(), r#""/opt/pea agent/pea-agent" --config "/etc/pea agent/50%%.toml" --bus https://bus.example --company 7 run --hb 600 --qd 30"#);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_unit_in(&dir.path().join("systemd/user"), &unit("/usr/local/bin/pea-agent"), Scope::System).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), rendered);
+        assert!(!path.with_file_name(ENV_FILE_NAME).exists());
+        assert_eq!(path.file_name().unwrap(), UNIT_NAME);
+    }
+
+    #[test]
+    fn system_units_run_as_the_installer_with_their_settings() {
+        let unit = Unit {
+            global_args: vec!["--relay".into(), "https://relay.example".into(), "--strict".into()],
+            user: Some("alice".into()),
+            environment: vec![("PEA_STATE_DIR".into(), "/srv/pea state".into())],
+            secrets: vec![("PEA_INSTALLER_SECRET".into(), "s3\"cr%t$\\".into())],
+            ..unit("/usr/local/bin/pea-agent")
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_unit_in(&dir.path().join("pea units"), &unit, Scope::System).unwrap();
+        let rendered = fs::read_to_string(&path).unwrap();
+        let service: Vec<_> = rendered.lines().skip_while(|l| *l != "[Service]").skip(1).take(4).collect();
+        let env_path = path.with_file_name(ENV_FILE_NAME);
+        assert_eq!(service, [
+            "User=alice".to_string(),
+            r#"Environment="PEA_STATE_DIR=/srv/pea state""#.to_string(),
+            format!("EnvironmentFile={}", quote(&env_path.to_string_lossy())),
+            "ExecStart=/usr/local/bin/pea-agent --relay https://relay.example --strict --bus https://bus.example --company 7 run --hb 600 --qd 30".to_string(),
+        ]);
+        assert!(!rendered.contains("s3"), "secrets stay out of the unit");
+        assert_eq!(fs::read_to_string(&env_path).unwrap(), "PEA_INSTALLER_SECRET=\"s3\\\"cr%t\\$\\\\\"\n");
+        assert!(!unit.render(Scope::User, &env_path).contains("User="));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for file in [&path, &env_path] {
+                assert_eq!(fs::metadata(file).unwrap().permissions().mode() & 0o777, 0o600, "{:?}", file);
+            }
+        }
+
+        // Reinstalling without secrets drops the old file.
+        write_unit_in(&dir.path().join("pea units"), &Unit { secrets: Vec::new(), ..unit }, Scope::System).unwrap();
+        assert!(!env_path.exists());
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer};
+use serde::{Serialize, Deserialize};
+use std::{sync::{Arc, Mutex, OnceLock}, time::Duration};
+
+/// Certificate format, signed as `version`.
+pub const SESSION_CERT_VERSION: &str = "pea-session/v1";
+
+/// Binds a short-lived session key to the device key. The device key signs the
+/// sorted-key JSON of this struct; events and heartbeats are then signed with
+/// the session key and carry the certificate, so the bus can chain the
+/// signature back to the registered device key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionCert {
+    pub version: String,
+    pub device_id: String,
+    /// Base64 ed25519 public half of the session key.
+    pub session_key: String,
+    pub not_before: String,
+    pub expires_at: String,
+}
+
+impl SessionCert {
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        Ok(crate::provision::stable_stringify(&serde_json::to_value(self)?).into_bytes())
+    }
+
+    fn expires(&self) -> Result<chrono::DateTime<chrono::Utc>> {
+        Ok(chrono::DateTime::parse_from_rfc3339(&self.expires_at)?.with_timezone(&chrono::Utc))
+    }
+}
+
+/// An ephemeral keypair and the device key's certificate for it.
+pub struct Session {
+    pub keypair: Keypair,
+    pub cert: SessionCert,
+    cert_sig: Signature,
+    /// The device key that signed `cert`, so reuse needs no re-verification.
+    certified_by: PublicKey,
+}
+
+impl Session {
+    /// Generate a session key valid from `now` for `ttl`, certified by `device`.
+    pub fn issue(device: &Keypair, device_id: &str, ttl: Duration, now: chrono::DateTime<chrono::Utc>) -> Result<Self> {
+        let keypair = crate::rng::keypair();
+        let cert = SessionCert {
+            version: SESSION_CERT_VERSION.into(),
+            device_id: device_id.into(),
+            session_key: general_purpose::STANDARD.encode(keypair.public.as_bytes()),
+            not_before: now.to_rfc3339(),
+            expires_at: (now + chrono::Duration::from_std(ttl)?).to_rfc3339(),
+        };
+        let cert_sig = device.sign(&cert.signed_bytes()?);
+        Ok(Self { keypair, cert, cert_sig, certified_by: device.public })
+    }
+
+    /// Whether to issue a replacement: past expiry, or within the last tenth
+    /// of the lifetime so a request in flight never carries a lapsed certificate.
+    pub fn needs_rotation(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        let (Ok(expires), Ok(not_before)) = (self.cert.expires(), chrono::DateTime::parse_from_rfc3339(&self.cert.not_before)) else { return true };
+        now + (expires - not_before.with_timezone(&chrono::Utc)) / 10 >= expires
+    }
+
+    /// Whether this certificate is from `device`, so a rotated device key
+    /// never keeps using a session certified by its predecessor.
+    fn cert_signed_by(&self, device: &Keypair) -> bool {
+        self.certified_by == device.public
+    }
+
+    /// Add the certificate headers; the caller signs with [`Session::keypair`].
+    pub fn headers(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let cert = serde_json::to_vec(&self.cert).unwrap_or_default();
+        req.header("X-PEA-Session-Cert", general_purpose::STANDARD.encode(cert))
+            .header("X-PEA-Session-Cert-Sig", general_purpose::STANDARD.encode(self.cert_sig.to_bytes()))
+    }
+}
+
+static TTL: OnceLock<Duration> = OnceLock::new();
+static CURRENT: Mutex<Option<Arc<Session>>> = Mutex::new(None);
+
+/// Sign events and heartbeats with session keys living `ttl` for the rest of
+/// the process; call once at startup. Until this is called the device key
+/// signs everything directly.
+pub fn configure(ttl: Duration) {
+    let _ = TTL.set(ttl);
+}
+
+/// The session to sign with, issuing a fresh one when the current one is due
+/// for rotation. `None` when session keys are off or issuing failed.
+pub fn current(device: &Keypair, device_id: &str) -> Option<Arc<Session>> {
+    let ttl = *TTL.get()?;
+    let now = chrono::Utc::now();
+    let mut guard = CURRENT.lock().unwrap_or_else(|e| e.into_inner());
+    match guard.as_ref() {
+        Some(s) if !s.needs_rotation(now) && s.cert.device_id == device_id && s.cert_signed_by(device) => Some(s.clone()),
+        _ => {
+            let session = Arc::new(Session::issue(device, device_id, ttl, now).map_err(|e| tracing::warn!(error = %e, "session key not issued")).ok()?);
+            *guard = Some(session.clone());
+            Some(session)
+        }
+    }
+}
+
+/// Drop the current session, so nothing signs with it again.
+pub fn forget() {
+    *CURRENT.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// `--session-key-ttl` value parser: a lifetime of at least a minute.
+pub fn parse_ttl(s: &str) -> Result<Duration, String> {
+    match s.trim().parse::<u64>() {
+        Ok(secs) if secs >= 60 => Ok(Duration::from_secs(secs)),
+        _ => Err("expected a session key lifetime in seconds, at least 60, e.g. 3600".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::submit::SigningScheme;
+    use anyhow::anyhow;
+    use ed25519_dalek::Verifier;
+
+    fn at(rfc3339: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&chrono::Utc)
+    }
+
+    /// The bus's side: check the device signature and validity window of the
+    /// certificate headers and return the key request signatures verify under.
+    fn verify(cert_b64: &str, sig_b64: &str, device_key: &PublicKey, now: chrono::DateTime<chrono::Utc>) -> Result<PublicKey> {
+        let cert: SessionCert = serde_json::from_slice(&general_purpose::STANDARD.decode(cert_b64)?)?;
+        if cert.version != SESSION_CERT_VERSION { return Err(anyhow!("unsupported session certificate {}", cert.version)); }
+        let sig = Signature::from_bytes(&general_purpose::STANDARD.decode(sig_b64)?)?;
+        device_key.verify(&cert.signed_bytes()?, &sig).map_err(|_| anyhow!("session certificate not signed by the device key"))?;
+        let not_before = chrono::DateTime::parse_from_rfc3339(&cert.not_before)?.with_timezone(&chrono::Utc);
+        if now < not_before || now >= cert.expires()? { return Err(anyhow!("session certificate expired or not yet valid")); }
+        Ok(PublicKey::from_bytes(&general_purpose::STANDARD.decode(&cert.session_key)?)?)
+    }
+
+    fn header(req: &reqwest::Request, name: &str) -> String {
+        req.headers()[name].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn certificate_binds_the_session_key_to_the_device_key() {
+        let device = crate::rng::keypair();
+        let now = at("2024-05-01T12:00:00Z");
+        let session = Session::issue(&device, "dev-1", Duration::from_secs(3600), now).unwrap();
+        assert_eq!(session.cert.device_id, "dev-1");
+        assert_eq!(session.cert.expires_at, "2024-05-01T13:00:00+00:00");
+        assert_eq!(session.cert.session_key, general_purpose::STANDARD.encode(session.keypair.public.as_bytes()));
+
+        let req = session.headers(reqwest::Client::new().post("http://bus.invalid/")).build().unwrap();
+        let (cert, sig) = (header(&req, "X-PEA-Session-Cert"), header(&req, "X-PEA-Session-Cert-Sig"));
+        assert_eq!(verify(&cert, &sig, &device.public, now).unwrap(), session.keypair.public);
+        let other = crate::rng::keypair();
+        assert!(verify(&cert, &sig, &other.public, now).is_err(), "certificate must chain to this device");
+        assert!(session.cert_signed_by(&device) && !session.cert_signed_by(&other), "a rotated device key gets a new session");
+    }
+
+    #[test]
+    fn events_are_signed_with_the_session_key_and_carry_the_certificate() {
+        let device = crate::rng::keypair();
+        let now = chrono::Utc::now();
+        let session = Session::issue(&device, "dev-1", Duration::from_secs(600), now).unwrap();
+        let payload = br#"{"productId":"P-1"}"#;
+        for scheme in [SigningScheme::Body, SigningScheme::Context] {
+            let (req, sent) = scheme.sign_with(reqwest::Client::new().post("http://bus.invalid/"), &device, "dev-1", payload, Some(&session));
+            let req = req.build().unwrap();
+            let sig = Signature::from_bytes(&general_purpose::STANDARD.decode(header(&req, "X-PEA-Signature")).unwrap()).unwrap();
+            assert_eq!(sig, sent, "the signature returned is the one sent");
+            let signed = scheme.signed_bytes(payload, "dev-1", &header(&req, "X-PEA-Nonce"), &header(&req, "X-PEA-Timestamp"));
+            let key = verify(&header(&req, "X-PEA-Session-Cert"), &header(&req, "X-PEA-Session-Cert-Sig"), &device.public, now).unwrap();
+            assert!(key.verify(&signed, &sig).is_ok());
+            assert!(device.public.verify(&signed, &sig).is_err(), "the device key must not sign events directly");
+            assert_eq!(header(&req, "X-PEA-Public-Key"), general_purpose::STANDARD.encode(device.public.as_bytes()));
+        }
+        let direct = SigningScheme::Body.sign_with(reqwest::Client::new().post("http://bus.invalid/"), &device, "dev-1", payload, None).0.build().unwrap();
+        assert!(!direct.headers().contains_key("X-PEA-Session-Cert"));
+    }
+
+    #[test]
+    fn expired_sessions_are_rotated_and_rejected() {
+        let device = crate::rng::keypair();
+        let issued = at("2024-05-01T12:00:00Z");
+        let session = Session::issue(&device, "dev-1", Duration::from_secs(1000), issued).unwrap();
+        assert!(!session.needs_rotation(at("2024-05-01T12:14:59Z")));
+        assert!(session.needs_rotation(at("2024-05-01T12:15:00Z")), "rotated in the last tenth of its life");
+        assert!(session.needs_rotation(at("2024-05-01T13:00:00Z")));
+
+        let req = session.headers(reqwest::Client::new().post("http://bus.invalid/")).build().unwrap();
+        let (cert, sig) = (header(&req, "X-PEA-Session-Cert"), header(&req, "X-PEA-Session-Cert-Sig"));
+        assert!(verify(&cert, &sig, &device.public, at("2024-05-01T12:16:39Z")).is_ok());
+        assert!(verify(&cert, &sig, &device.public, at("2024-05-01T12:16:40Z")).is_err());
+        assert!(verify(&cert, &sig, &device.public, at("2024-05-01T11:59:59Z")).is_err());
+
+        assert!(parse_ttl("59").is_err() && parse_ttl("soon").is_err());
+        assert_eq!(parse_ttl("3600"), Ok(Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use std::{future::Future, sync::{atomic::{AtomicBool, Ordering}, Once, OnceLock}, time::Duration};
+use tokio::sync::Notify;
+
+/// A stop request for the long-running loops. They check it between
+/// iterations, so a drain or state write in progress finishes before they
+/// return instead of being cut off mid-write.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for `duration`, waking early when shutdown is requested. Returns
+    /// whether it was.
+    pub async fn sleep(&self, duration: Duration) -> bool {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Register before checking the flag, so a request in between isn't missed.
+        notified.as_mut().enable();
+        if self.requested() { return true; }
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => self.requested(),
+            _ = notified => true,
+        }
+    }
+
+    /// Run `tick` every `interval` until shutdown is requested, always letting
+    /// the tick in progress finish. An error from a tick ends the loop with it.
+    pub async fn every<F, Fut>(&self, interval: Duration, mut tick: F) -> Result<()>
+    where F: FnMut() -> Fut, Fut: Future<Output = Result<()>> {
+        while !self.requested() {
+            tick().await?;
+            if self.sleep(interval).await { break; }
+        }
+        Ok(())
+    }
+}
+
+static SHUTDOWN: OnceLock<Shutdown> = OnceLock::new();
+static LISTENING: Once = Once::new();
+
+/// The process's stop request, without installing a signal handler; the
+/// Windows service fires it when the service manager asks the agent to stop.
+pub fn global() -> &'static Shutdown {
+    SHUTDOWN.get_or_init(Shutdown::default)
+}
+
+/// [`global`], fired by SIGINT or SIGTERM (Ctrl-C on Windows) once the first
+/// caller installs the handler. A second signal exits at once.
+pub fn listen() -> &'static Shutdown {
+    let shutdown = global();
+    LISTENING.call_once(|| {
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                if signal().await.is_err() { return; }
+                if shutdown.requested() {
+                    tracing::warn!("second signal, exiting now");
+                    std::process::exit(130);
+                }
+                tracing::info!("shutdown requested, finishing the current iteration; signal again to exit now");
+                shutdown.request();
+            }
+        });
+    });
+    shutdown
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = term.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    #[tokio::test]
+    async fn a_request_mid_tick_lets_the_tick_finish_then_returns_ok() {
+        let shutdown = Shutdown::default();
+        let (started, finished) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let res = shutdown.every(Duration::from_millis(1), || async {
+            started.fetch_add(1, Ordering::SeqCst);
+            if started.load(Ordering::SeqCst) == 2 { shutdown.request(); }
+            tokio::task::yield_now().await;
+            finished.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }).await;
+        assert!(res.is_ok());
+        assert_eq!((started.into_inner(), finished.into_inner()), (2, 2), "the interrupted tick completes, no further tick starts");
+    }
+
+    #[tokio::test]
+    async fn a_request_wakes_a_sleeping_loop() {
+        let shutdown = Arc::new(Shutdown::default());
+        let stopper = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            stopper.request();
+        });
+        let ticks = AtomicUsize::new(0);
+        let res = tokio::time::timeout(Duration::from_secs(5), shutdown.every(Duration::from_secs(3600), || async {
+            ticks.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })).await.expect("loop should stop without waiting out its interval");
+        assert!(res.is_ok());
+        assert_eq!(ticks.into_inner(), 1);
+        assert!(shutdown.sleep(Duration::from_secs(3600)).await, "later sleeps return at once");
+
+        let failing = Shutdown::default();
+        assert!(failing.every(Duration::from_millis(1), || async { Err(anyhow::anyhow!("bus down")) }).await.is_err());
+    }
+}
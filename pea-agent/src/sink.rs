@@ -0,0 +1,131 @@
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::Signature;
+use sha2::{Sha256, Digest};
+use std::{fs, io::Write, path::{Path, PathBuf}, sync::{Mutex, OnceLock}};
+
+/// Size at which the sink file is rotated when `--local-sink-max-bytes` isn't given.
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Rotated files kept beside the live one (`<path>.1` is the newest).
+const KEEP_ROTATED: usize = 3;
+
+/// What happened to an event when it was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkStatus {
+    /// The bus accepted it.
+    Delivered,
+    /// The bus couldn't be reached or refused it for now; it went to the queue.
+    Queued,
+    /// The bus answered with a rejection.
+    Rejected,
+}
+
+impl SinkStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Delivered => "delivered",
+            Self::Queued => "queued",
+            Self::Rejected => "rejected",
+        }
+    }
+}
+
+struct Sink {
+    path: PathBuf,
+    max_bytes: u64,
+    // Serializes appends and rotation between concurrent drains.
+    lock: Mutex<()>,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Append every event to `path` as NDJSON from now on; off unless called.
+pub fn configure(path: PathBuf, max_bytes: u64) {
+    let _ = SINK.set(Sink { path, max_bytes: max_bytes.max(1), lock: Mutex::new(()) });
+}
+
+/// Record an event, the signature it was sent with (none if it was queued
+/// unsent) and what became of it. A no-op without `--local-sink`.
+pub fn record(payload: &[u8], sig: Option<&Signature>, status: SinkStatus) -> Result<()> {
+    let Some(sink) = SINK.get() else { return Ok(()) };
+    let _guard = sink.lock.lock().unwrap_or_else(|e| e.into_inner());
+    append_in(&sink.path, sink.max_bytes, &line(payload, sig, status, chrono::Utc::now())?)
+}
+
+fn line(payload: &[u8], sig: Option<&Signature>, status: SinkStatus, at: chrono::DateTime<chrono::Utc>) -> Result<Vec<u8>> {
+    let event = serde_json::from_slice::<serde_json::Value>(payload)
+        .unwrap_or_else(|_| String::from_utf8_lossy(payload).into_owned().into());
+    let mut out = serde_json::to_vec(&serde_json::json!({
+        "at": at.to_rfc3339(),
+        "status": status.name(),
+        "payload_sha256": hex::encode(Sha256::digest(payload)),
+        "signature": sig.map(|s| general_purpose::STANDARD.encode(s.to_bytes())),
+        "event": event,
+    }))?;
+    out.push(b'\n');
+    Ok(out)
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Shift `<path>` to `<path>.1`, `.1` to `.2` and so on, dropping the oldest.
+fn rotate(path: &Path) -> Result<()> {
+    for n in (1..KEEP_ROTATED).rev() {
+        let from = rotated(path, n);
+        if from.exists() { fs::rename(&from, rotated(path, n + 1))?; }
+    }
+    fs::rename(path, rotated(path, 1))?;
+    Ok(())
+}
+
+/// Append one line, rotating first if it would take the file past `max_bytes`.
+/// The line is synced before returning so a power cut doesn't lose it.
+fn append_in(path: &Path, max_bytes: u64, line: &[u8]) -> Result<()> {
+    let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if size > 0 && size + line.len() as u64 > max_bytes { rotate(path)?; }
+    let mut f = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    f.write_all(line)?;
+    f.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, Signer};
+
+    #[test]
+    fn events_are_appended_and_rotated_at_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.ndjson");
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let at = chrono::Utc::now();
+        let payload = br#"{"productId":"P-1","eventType":"QUALITY_CHECK"}"#;
+        let delivered = line(payload, Some(&kp.sign(payload)), SinkStatus::Delivered, at).unwrap();
+        let queued = line(payload, None, SinkStatus::Queued, at).unwrap();
+        let max = 2 * delivered.len() as u64 + 8;
+
+        append_in(&path, max, &delivered).unwrap();
+        append_in(&path, max, &queued).unwrap();
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["status"], "delivered");
+        assert_eq!(lines[1]["status"], "queued");
+        assert_eq!(lines[0]["event"]["productId"], "P-1");
+        assert_eq!(lines[0]["signature"], general_purpose::STANDARD.encode(kp.sign(payload).to_bytes()));
+        assert!(lines[1]["signature"].is_null(), "a queued event hasn't been signed for sending yet");
+
+        append_in(&path, max, &delivered).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), delivered, "live file restarted after rotation");
+        assert_eq!(fs::read_to_string(rotated(&path, 1)).unwrap().lines().count(), 2);
+
+        for _ in 0..2 * KEEP_ROTATED { append_in(&path, max, &delivered).unwrap(); append_in(&path, max, &delivered).unwrap(); }
+        assert!(rotated(&path, KEEP_ROTATED).exists());
+        assert!(!rotated(&path, KEEP_ROTATED + 1).exists(), "oldest rotations are dropped");
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::{fs, path::{Path, PathBuf}, sync::{Mutex, PoisonError}};
+
+/// Whether the bus proved it recognizes this device on the last heartbeat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerTrust {
+    /// Signed ack verified against the pinned server key.
+    Trusted,
+    /// Ack present but no server key is pinned, so it can't be verified.
+    Unverified,
+    /// Server didn't return an ack (older bus).
+    Unacknowledged,
+    /// Ack failed verification (bad signature or nonce mismatch).
+    Invalid,
+}
+
+/// Small persisted record of what the agent last learned at runtime.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AgentState {
+    #[serde(default)]
+    pub server_trust: Option<ServerTrust>,
+    /// Server's reported view of this device (last-seen, policy flags).
+    #[serde(default)]
+    pub server_view: Option<serde_json::Value>,
+    #[serde(default)]
+    pub last_heartbeat_at: Option<String>,
+    /// Ack of the last event the bus accepted.
+    #[serde(default)]
+    pub last_event_ack: Option<crate::submit::EventAck>,
+    /// Last capabilities reported by the bus, and when they were fetched.
+    #[serde(default)]
+    pub capabilities: Option<crate::capabilities::ServerCapabilities>,
+    #[serde(default)]
+    pub capabilities_checked_at: Option<String>,
+    /// Bus clock minus local clock, from the last heartbeat's `Date` header.
+    #[serde(default)]
+    pub clock_offset_ms: Option<i64>,
+    /// Device id the bus echoed when it differed from ours; cleared once they agree.
+    #[serde(default)]
+    pub device_id_mismatch: Option<String>,
+    /// Whether the running agent had to fail a secret write over to another
+    /// vault backend; `run` keeps it current so `status` can show it.
+    #[serde(default)]
+    pub degraded_storage: bool,
+    /// Submit/heartbeat latency, kept across restarts.
+    #[serde(default)]
+    pub latency: Option<crate::latency::LatencyTracker>,
+}
+
+fn state_path() -> Result<PathBuf> {
+    Ok(crate::paths::state_dir()?.join("state.json"))
+}
+
+pub fn load() -> AgentState {
+    state_path().map(|p| load_from(&p)).unwrap_or_default()
+}
+
+/// Serializes read-modify-write cycles within the process, so concurrent
+/// updates (heartbeat, latency, delivery acks) don't drop each other's fields.
+static UPDATE: Mutex<()> = Mutex::new(());
+
+/// Apply `f` to the persisted state and write it back.
+pub fn update(f: impl FnOnce(&mut AgentState)) -> Result<()> {
+    update_in(&state_path()?, f)
+}
+
+fn update_in(path: &Path, f: impl FnOnce(&mut AgentState)) -> Result<()> {
+    let _guard = UPDATE.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut state = load_from(path);
+    f(&mut state);
+    save_to(path, &state)
+}
+
+/// The state at `path`; the default when there is none yet, or with a warning
+/// when it can't be parsed.
+pub fn load_from(path: &Path) -> AgentState {
+    let Ok(bytes) = fs::read(path) else { return AgentState::default() };
+    serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+        tracing::warn!(file = ?path, error = %e, "agent state unparseable, starting from defaults");
+        AgentState::default()
+    })
+}
+
+fn save_to(path: &Path, state: &AgentState) -> Result<()> {
+    crate::paths::write_atomic(path, &serde_json::to_vec_pretty(state)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_updates_keep_every_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::thread::scope(|s| {
+            s.spawn(|| for i in 0..50 { update_in(&path, |st| st.clock_offset_ms = Some(i)).unwrap(); });
+            s.spawn(|| for i in 0..50 { update_in(&path, |st| st.last_heartbeat_at = Some(i.to_string())).unwrap(); });
+        });
+        let st = load_from(&path);
+        assert_eq!(st.clock_offset_ms, Some(49));
+        assert_eq!(st.last_heartbeat_at.as_deref(), Some("49"));
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{Result, anyhow};
+use std::{collections::BTreeMap, net::{ToSocketAddrs, UdpSocket}, sync::OnceLock};
+
+/// Pushes run-loop metrics as DogStatsD packets (`name:value|type|#k:v`) over
+/// UDP. Sends never block and errors are dropped: metrics must not slow or
+/// fail the agent.
+pub struct Statsd {
+    socket: UdpSocket,
+    tags: String,
+}
+
+static STATSD: OnceLock<Statsd> = OnceLock::new();
+
+/// Push metrics to `statsd` for the rest of the process; call once at startup.
+pub fn configure(statsd: Statsd) {
+    let _ = STATSD.set(statsd);
+}
+
+impl Statsd {
+    /// Target `addr` (`host:port`), tagging every metric with `tags`.
+    pub fn new(addr: &str, tags: &BTreeMap<String, String>) -> Result<Self> {
+        let target = addr.to_socket_addrs().map_err(|e| anyhow!("--statsd-addr {}: {}", addr, e))?
+            .next().ok_or_else(|| anyhow!("--statsd-addr {} resolves to no address", addr))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+        let tags = tags.iter().map(|(k, v)| format!("{}:{}", k, v)).collect::<Vec<_>>().join(",");
+        Ok(Self { socket, tags })
+    }
+
+    fn send(&self, name: &str, value: impl std::fmt::Display, kind: &str, tags: &[(&str, &str)]) {
+        let mut line = format!("{}:{}|{}", name, value, kind);
+        let extra = tags.iter().map(|(k, v)| format!("{}:{}", k, v)).collect::<Vec<_>>().join(",");
+        match (self.tags.is_empty(), extra.is_empty()) {
+            (true, true) => {}
+            (false, true) => line = format!("{}|#{}", line, self.tags),
+            (true, false) => line = format!("{}|#{}", line, extra),
+            (false, false) => line = format!("{}|#{},{}", line, self.tags, extra),
+        }
+        let _ = self.socket.send(line.as_bytes());
+    }
+
+    pub fn count(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(name, value, "c", tags);
+    }
+
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.send(name, value, "g", &[]);
+    }
+
+    pub fn timing(&self, name: &str, ms: u128) {
+        self.send(name, ms, "ms", &[]);
+    }
+}
+
+pub fn count(name: &str, value: u64, tags: &[(&str, &str)]) {
+    if let Some(s) = STATSD.get() { s.count(name, value, tags); }
+}
+
+pub fn gauge(name: &str, value: u64) {
+    if let Some(s) = STATSD.get() { s.gauge(name, value); }
+}
+
+pub fn timing(name: &str, ms: u128) {
+    if let Some(s) = STATSD.get() { s.timing(name, ms); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_arrive_as_tagged_statsd_packets() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let tags = BTreeMap::from([("device_id".to_string(), "dev-1".to_string())]);
+        let statsd = Statsd::new(&collector.local_addr().unwrap().to_string(), &tags).unwrap();
+
+        statsd.count("pea_events_submitted_total", 3, &[]);
+        statsd.gauge("pea_queue_depth", 12);
+        statsd.timing("pea_submit_latency_ms", 48);
+        statsd.count("pea_heartbeats_total", 1, &[("outcome", "ok")]);
+
+        let mut buf = [0u8; 512];
+        let packets: Vec<String> = (0..4).map(|_| {
+            let n = collector.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        }).collect();
+        assert_eq!(packets, [
+            "pea_events_submitted_total:3|c|#device_id:dev-1",
+            "pea_queue_depth:12|g|#device_id:dev-1",
+            "pea_submit_latency_ms:48|ms|#device_id:dev-1",
+            "pea_heartbeats_total:1|c|#device_id:dev-1,outcome:ok",
+        ]);
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixDatagram, UnixListener, UnixStream};
+use tokio::sync::broadcast;
+
+/// How long one event line may take to reach a stream client before it is dropped.
+const STREAM_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Read-only status endpoint for local tools: every connection gets one JSON
+/// snapshot and is closed, or with [`StatusSocket::stream`], a JSON line per
+/// state change until it disconnects. The socket file is removed when this is
+/// dropped.
+pub struct StatusSocket {
+    path: PathBuf,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl StatusSocket {
+    /// Listen on `path`, answering each connection with `snapshot()`.
+    pub fn bind<F>(path: &Path, snapshot: F) -> Result<Self>
+    where F: Fn() -> serde_json::Value + Send + Sync + 'static {
+        let listener = listen(path)?;
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let body = serde_json::to_vec(&snapshot()).unwrap_or_default();
+                let _ = stream.write_all(&body).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        Ok(Self { path: path.to_path_buf(), task })
+    }
+
+    /// Listen on `path`, forwarding every line sent on `events` to each
+    /// connected client. A client that lags more than the channel's buffer or
+    /// stops reading is disconnected.
+    pub fn stream(path: &Path, events: broadcast::Sender<String>) -> Result<Self> {
+        let listener = listen(path)?;
+        let task = tokio::spawn(async move {
+            // Dropped with the accept loop, which aborts every client task.
+            let mut clients = tokio::task::JoinSet::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                clients.spawn(follow(stream, events.subscribe()));
+                while clients.try_join_next().is_some() {}
+            }
+        });
+        Ok(Self { path: path.to_path_buf(), task })
+    }
+
+    /// Receive event lines other processes publish to `path` (see
+    /// [`crate::events::forward`]) and pass them on to `events`, so stream
+    /// clients see them too. Datagrams that aren't a JSON object are dropped.
+    pub fn relay(path: &Path, events: broadcast::Sender<String>) -> Result<Self> {
+        clear_stale(path)?;
+        let socket = UnixDatagram::bind(path).map_err(|e| anyhow!("event relay socket {:?}: {}", path, e))?;
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; RELAY_DATAGRAM_MAX];
+            while let Ok(n) = socket.recv(&mut buf).await {
+                let Ok(line) = std::str::from_utf8(&buf[..n]) else { continue };
+                if serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(line).is_ok() {
+                    let _ = events.send(line.to_string());
+                }
+            }
+        });
+        Ok(Self { path: path.to_path_buf(), task })
+    }
+}
+
+/// Largest relayed event line; anything longer is truncated and then dropped
+/// as malformed.
+const RELAY_DATAGRAM_MAX: usize = 64 * 1024;
+
+fn listen(path: &Path) -> Result<UnixListener> {
+    clear_stale(path)?;
+    UnixListener::bind(path).map_err(|e| anyhow!("status socket {:?}: {}", path, e))
+}
+
+fn clear_stale(path: &Path) -> Result<()> {
+    // A socket left behind by a crashed run would make bind fail.
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        if !meta.file_type().is_socket() { return Err(anyhow!("{:?} exists and is not a socket", path)); }
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+async fn follow(mut stream: UnixStream, mut events: broadcast::Receiver<String>) {
+    // Lagged (the client fell a full buffer behind) ends the loop like Closed.
+    while let Ok(line) = events.recv().await {
+        let line = format!("{}\n", line);
+        if !matches!(tokio::time::timeout(STREAM_WRITE_TIMEOUT, stream.write_all(line.as_bytes())).await, Ok(Ok(()))) { return; }
+    }
+}
+
+impl Drop for StatusSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn connecting_yields_a_json_snapshot_and_drop_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pea.sock");
+        let socket = StatusSocket::bind(&path, || serde_json::json!({ "device_id": "dev-1", "queue_depth": 3 })).unwrap();
+
+        for _ in 0..2 {
+            let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+            let mut body = Vec::new();
+            stream.read_to_end(&mut body).await.unwrap();
+            let snapshot: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(snapshot["device_id"], "dev-1");
+            assert_eq!(snapshot["queue_depth"], 3);
+        }
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stream_clients_receive_a_line_per_event_and_laggards_are_dropped() {
+        use tokio::io::AsyncBufReadExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pea-stream.sock");
+        let (events, _) = broadcast::channel(4);
+        let _socket = StatusSocket::stream(&path, events.clone()).unwrap();
+
+        let mut reader = tokio::io::BufReader::new(UnixStream::connect(&path).await.unwrap()).lines();
+        let mut laggard = UnixStream::connect(&path).await.unwrap();
+        while events.receiver_count() < 2 { tokio::task::yield_now().await; }
+
+        for (kind, fields) in [("submit", serde_json::json!({ "latency_ms": 42 })), ("drain", serde_json::json!({ "delivered": 1 })), ("heartbeat", serde_json::json!({ "outcome": "ok" }))] {
+            let mut line = fields;
+            line["event"] = kind.into();
+            events.send(line.to_string()).unwrap();
+            let got: serde_json::Value = serde_json::from_str(&reader.next_line().await.unwrap().unwrap()).unwrap();
+            assert_eq!(got, line);
+        }
+
+        // The laggard never reads: once its socket buffer fills, it is cut off
+        // while the reading client keeps up.
+        let bulky = "x".repeat(64 * 1024);
+        for i in 0..64 {
+            events.send(serde_json::json!({ "event": "scan", "n": i, "pad": bulky }).to_string()).unwrap();
+            assert!(reader.next_line().await.unwrap().is_some());
+        }
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), laggard.read_to_end(&mut rest)).await.expect("laggard disconnected").unwrap();
+        assert!(rest.len() < 64 * bulky.len(), "laggard got a bounded prefix, not the whole stream");
+        while events.receiver_count() > 1 { tokio::task::yield_now().await; }
+    }
+
+    #[tokio::test]
+    async fn events_published_by_other_processes_reach_the_stream() {
+        use tokio::io::AsyncBufReadExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (stream_path, relay_path) = (dir.path().join("pea-stream.sock"), dir.path().join(crate::events::RELAY_SOCKET));
+        let (events, _) = broadcast::channel(8);
+        let _stream = StatusSocket::stream(&stream_path, events.clone()).unwrap();
+        let _relay = StatusSocket::relay(&relay_path, events.clone()).unwrap();
+
+        let mut reader = tokio::io::BufReader::new(UnixStream::connect(&stream_path).await.unwrap()).lines();
+        while events.receiver_count() < 1 { tokio::task::yield_now().await; }
+
+        // What a `submit` or `scan-*` process does, from a plain blocking socket.
+        std::thread::spawn(move || {
+            let junk = std::os::unix::net::UnixDatagram::unbound().unwrap();
+            junk.send_to(b"not json", &relay_path).unwrap();
+            crate::events::forward(&relay_path, "enqueue", serde_json::json!({ "name": "P-1" }));
+        }).join().unwrap();
+
+        let got: serde_json::Value = serde_json::from_str(&reader.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!((&got["event"], &got["name"]), (&serde_json::json!("enqueue"), &serde_json::json!("P-1")));
+        assert!(got["at"].is_string());
+
+        // With no daemon listening, publishing is a silent no-op.
+        crate::events::forward(&dir.path().join("absent.sock"), "scan", serde_json::json!({}));
+    }
+
+    #[tokio::test]
+    async fn stale_socket_is_replaced_but_regular_files_are_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pea.sock");
+        std::mem::forget(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(StatusSocket::bind(&path, || serde_json::json!({})).is_ok());
+
+        let file = dir.path().join("not-a-socket");
+        std::fs::write(&file, b"keep me").unwrap();
+        assert!(StatusSocket::bind(&file, || serde_json::json!({})).is_err());
+        assert_eq!(std::fs::read(&file).unwrap(), b"keep me");
+    }
+}
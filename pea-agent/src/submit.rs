@@ -0,0 +1,1183 @@
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Keypair, Signature, Signer};
+use sha2::{Sha256, Sha512, Digest};
+use serde::{Serialize, Deserialize};
+use std::{fmt, sync::OnceLock, time::Duration};
+use crate::attachments::{self, AttachmentRef};
+
+/// Shape of the event payload, carried in every event as `schemaVersion` and
+/// covered by its signature. Bump when fields are added, removed or change
+/// meaning; queued events keep the version they were built with.
+pub const EVENT_SCHEMA_VERSION: &str = "scan/v2";
+
+/// Event types `--event-type` accepts; `CUSTOM` is reported under the name
+/// given with `--event-type-name`.
+pub const EVENT_TYPES: [&str; 5] = ["RECEIVED", "SHIPPED", "INSPECTED", "QUALITY_CHECK", "CUSTOM"];
+
+/// The `eventType` to emit for `kind`, one of [`EVENT_TYPES`].
+pub fn event_type(kind: &str, custom_name: Option<&str>) -> Result<String> {
+    match (kind, custom_name.map(str::trim)) {
+        ("CUSTOM", Some(name)) if !name.is_empty() => Ok(name.to_string()),
+        ("CUSTOM", _) => Err(anyhow::anyhow!("--event-type CUSTOM needs a non-empty --event-type-name")),
+        (_, Some(_)) => Err(anyhow::anyhow!("--event-type-name only applies to --event-type CUSTOM")),
+        (kind, None) => Ok(kind.to_string()),
+    }
+}
+
+/// How the event `timestamp` field is rendered. Events also carry the format
+/// name in `timestampFormat`, so a queued event drained after the setting changed
+/// is still read the way it was stamped (and signed).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// RFC 3339 in UTC, e.g. `2024-05-01T12:00:00.123456789+00:00`.
+    #[default]
+    Rfc3339,
+    /// Milliseconds since the Unix epoch, as a JSON number.
+    EpochMs,
+    /// Seconds since the Unix epoch, as a JSON number.
+    EpochS,
+}
+
+impl TimestampFormat {
+    pub const NAMES: [&'static str; 3] = ["rfc3339", "epoch-ms", "epoch-s"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "rfc3339" => Some(Self::Rfc3339),
+            "epoch-ms" => Some(Self::EpochMs),
+            "epoch-s" => Some(Self::EpochS),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rfc3339 => "rfc3339",
+            Self::EpochMs => "epoch-ms",
+            Self::EpochS => "epoch-s",
+        }
+    }
+
+    pub fn render(self, at: chrono::DateTime<chrono::Utc>) -> serde_json::Value {
+        match self {
+            Self::Rfc3339 => serde_json::Value::String(at.to_rfc3339()),
+            Self::EpochMs => at.timestamp_millis().into(),
+            Self::EpochS => at.timestamp().into(),
+        }
+    }
+
+    /// Render the current time, corrected toward the bus clock.
+    pub fn now(self) -> anyhow::Result<serde_json::Value> {
+        Ok(self.render(crate::clock::corrected_now()?))
+    }
+}
+
+/// How `X-PEA-Payload-Hash` is computed over the exact signed bytes, advertised
+/// in `X-PEA-Hash-Alg` so the server can recompute it. Events carry the name in
+/// `hashAlgorithm`, so a queued event is hashed the way it was built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PayloadHash {
+    #[default]
+    Sha256Hex,
+    Sha256Base64,
+    Sha512Hex,
+    Sha512Base64,
+}
+
+static PAYLOAD_HASH: OnceLock<PayloadHash> = OnceLock::new();
+
+/// Install the hash used for new events and heartbeats; call once at startup.
+pub fn set_payload_hash(alg: PayloadHash) {
+    let _ = PAYLOAD_HASH.set(alg);
+}
+
+/// The configured hash for payloads built by this process.
+pub fn payload_hash() -> PayloadHash {
+    PAYLOAD_HASH.get().copied().unwrap_or_default()
+}
+
+impl PayloadHash {
+    pub const NAMES: [&'static str; 4] = ["sha256-hex", "sha256-base64", "sha512-hex", "sha512-base64"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sha256-hex" => Some(Self::Sha256Hex),
+            "sha256-base64" => Some(Self::Sha256Base64),
+            "sha512-hex" => Some(Self::Sha512Hex),
+            "sha512-base64" => Some(Self::Sha512Base64),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256Hex => "sha256-hex",
+            Self::Sha256Base64 => "sha256-base64",
+            Self::Sha512Hex => "sha512-hex",
+            Self::Sha512Base64 => "sha512-base64",
+        }
+    }
+
+    /// The algorithm an event payload recorded; events from before `hashAlgorithm`
+    /// existed were hashed with the default.
+    pub fn of_event(payload: &[u8]) -> Self {
+        serde_json::from_slice::<serde_json::Value>(payload).ok()
+            .and_then(|e| e.get("hashAlgorithm").and_then(|a| a.as_str()).and_then(Self::parse))
+            .unwrap_or_default()
+    }
+
+    pub fn digest(self, payload: &[u8]) -> String {
+        let raw = match self {
+            Self::Sha256Hex | Self::Sha256Base64 => Sha256::digest(payload).to_vec(),
+            Self::Sha512Hex | Self::Sha512Base64 => Sha512::digest(payload).to_vec(),
+        };
+        match self {
+            Self::Sha256Hex | Self::Sha512Hex => hex::encode(raw),
+            Self::Sha256Base64 | Self::Sha512Base64 => general_purpose::STANDARD.encode(raw),
+        }
+    }
+
+    /// Add `X-PEA-Payload-Hash` and `X-PEA-Hash-Alg` for `payload`.
+    pub fn headers(self, req: reqwest::RequestBuilder, payload: &[u8]) -> reqwest::RequestBuilder {
+        req.header("X-PEA-Payload-Hash", self.digest(payload)).header("X-PEA-Hash-Alg", self.name())
+    }
+}
+
+/// Nonce, timestamp and signature of one signed request, as sent in the
+/// `X-PEA-Nonce`, `X-PEA-Timestamp` and `X-PEA-Signature` headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedHeaders {
+    pub nonce: String,
+    pub timestamp: String,
+    /// Signature over [`SigningScheme::signed_bytes`], sent base64.
+    pub signature: Signature,
+}
+
+/// What an event signature covers, advertised in `X-PEA-Sig-Scheme`. Servers
+/// that predate the header only know `body`, kept for them behind
+/// `--signing-scheme body`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SigningScheme {
+    /// The payload bytes alone.
+    Body,
+    /// A canonical string binding the payload hash to the device id, nonce and
+    /// timestamp headers, so a captured body can't be replayed under a fresh nonce.
+    #[default]
+    Context,
+}
+
+static SIGNING_SCHEME: OnceLock<SigningScheme> = OnceLock::new();
+
+/// Install the signing scheme for events sent by this process; call once at startup.
+pub fn set_signing_scheme(scheme: SigningScheme) {
+    let _ = SIGNING_SCHEME.set(scheme);
+}
+
+pub fn signing_scheme() -> SigningScheme {
+    SIGNING_SCHEME.get().copied().unwrap_or_default()
+}
+
+impl SigningScheme {
+    pub const NAMES: [&'static str; 2] = ["body", "context"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "body" => Some(Self::Body),
+            "context" => Some(Self::Context),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Body => "body",
+            Self::Context => "context",
+        }
+    }
+
+    /// The bytes the signature is computed over. The context form is
+    /// `pea-context/v1`, device id, nonce, timestamp and the SHA-256 hex of the
+    /// payload, joined by newlines.
+    pub fn signed_bytes(self, payload: &[u8], device_id: &str, nonce: &str, timestamp: &str) -> Vec<u8> {
+        match self {
+            Self::Body => payload.to_vec(),
+            Self::Context => format!("pea-context/v1\n{}\n{}\n{}\n{}", device_id, nonce, timestamp, hex::encode(Sha256::digest(payload))).into_bytes(),
+        }
+    }
+
+    /// Sign `payload` sent with `nonce` and `timestamp` (unix milliseconds).
+    pub fn sign_request(self, signer: &Keypair, device_id: &str, payload: &[u8], nonce: &str, timestamp: &str) -> SignedHeaders {
+        let sig = signer.sign(&self.signed_bytes(payload, device_id, nonce, timestamp));
+        SignedHeaders { nonce: nonce.into(), timestamp: timestamp.into(), signature: sig }
+    }
+
+    /// Add the device identity, scheme and `signed` headers. `kp` is the device
+    /// key, whatever key signed.
+    pub fn attach(self, req: reqwest::RequestBuilder, kp: &Keypair, device_id: &str, signed: SignedHeaders) -> reqwest::RequestBuilder {
+        req.header("X-PEA-Device-Id", device_id)
+            .header("X-PEA-Public-Key", general_purpose::STANDARD.encode(kp.public.as_bytes()))
+            .header("X-PEA-Signature", general_purpose::STANDARD.encode(signed.signature.to_bytes()))
+            .header("X-PEA-Sig-Scheme", self.name())
+            .header("X-PEA-Nonce", signed.nonce)
+            .header("X-PEA-Timestamp", signed.timestamp)
+    }
+
+    /// Add the device identity, nonce, timestamp and signature headers for
+    /// `payload`, signing with the current session key when those are enabled.
+    /// Returns the request and the signature it carries.
+    pub fn sign(self, req: reqwest::RequestBuilder, kp: &Keypair, device_id: &str, payload: &[u8]) -> (reqwest::RequestBuilder, Signature) {
+        self.sign_with(req, kp, device_id, payload, crate::session::current(kp, device_id).as_deref())
+    }
+
+    /// [`SigningScheme::sign`] with an explicit session: its key signs and its
+    /// certificate rides along; without one the device key signs.
+    pub fn sign_with(self, req: reqwest::RequestBuilder, kp: &Keypair, device_id: &str, payload: &[u8], session: Option<&crate::session::Session>) -> (reqwest::RequestBuilder, Signature) {
+        let timestamp = chrono::Utc::now().timestamp_millis().to_string();
+        let signed = self.sign_request(session.map_or(kp, |s| &s.keypair), device_id, payload, &crate::rng::nonce(), &timestamp);
+        tracing::Span::current().record("nonce", signed.nonce.as_str());
+        let req = match session {
+            Some(session) => session.headers(req),
+            None => req,
+        };
+        let signature = signed.signature;
+        (self.attach(req, kp, device_id, signed), signature)
+    }
+}
+
+/// What to do with a queued event older than the configured maximum age.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StalePolicy {
+    /// Drop it; the bus would reject it as a replay anyway.
+    Drop,
+    /// Stamp it with the current time, keeping the old stamp in
+    /// `metadata.original_timestamp`. The new bytes are signed at send time.
+    #[default]
+    Restamp,
+}
+
+pub enum Aged {
+    Fresh(Vec<u8>),
+    Restamped(Vec<u8>),
+    Expired,
+}
+
+/// When an event payload was stamped, read back in its own `timestampFormat`.
+fn stamped_at(event: &serde_json::Value) -> Option<chrono::DateTime<chrono::Utc>> {
+    let format = event.get("timestampFormat").and_then(|f| f.as_str()).and_then(TimestampFormat::parse).unwrap_or_default();
+    let ts = event.get("timestamp")?;
+    match format {
+        TimestampFormat::Rfc3339 => chrono::DateTime::parse_from_rfc3339(ts.as_str()?).ok().map(|t| t.with_timezone(&chrono::Utc)),
+        TimestampFormat::EpochMs => chrono::DateTime::from_timestamp_millis(ts.as_i64()?),
+        TimestampFormat::EpochS => chrono::DateTime::from_timestamp(ts.as_i64()?, 0),
+    }
+}
+
+/// Apply the replay window to a queued payload. Payloads without a readable
+/// timestamp are passed through untouched.
+pub fn apply_max_age(payload: Vec<u8>, max_age: Option<Duration>, policy: StalePolicy, now: chrono::DateTime<chrono::Utc>) -> Aged {
+    let Some(max_age) = max_age else { return Aged::Fresh(payload) };
+    let Ok(mut event) = serde_json::from_slice::<serde_json::Value>(&payload) else { return Aged::Fresh(payload) };
+    let Some(at) = stamped_at(&event) else { return Aged::Fresh(payload) };
+    if now.signed_duration_since(at).to_std().map(|age| age <= max_age).unwrap_or(true) {
+        return Aged::Fresh(payload);
+    }
+    match policy {
+        StalePolicy::Drop => Aged::Expired,
+        StalePolicy::Restamp => {
+            let format = event.get("timestampFormat").and_then(|f| f.as_str()).and_then(TimestampFormat::parse).unwrap_or_default();
+            let original = event["timestamp"].take();
+            event["timestamp"] = format.render(now);
+            if !event.get("metadata").map(|m| m.is_object()).unwrap_or(false) { event["metadata"] = serde_json::json!({}); }
+            event["metadata"]["original_timestamp"] = original;
+            match serde_json::to_vec(&event) {
+                Ok(bytes) => Aged::Restamped(bytes),
+                Err(_) => Aged::Fresh(payload),
+            }
+        }
+    }
+}
+
+static COMPRESS_ABOVE: OnceLock<usize> = OnceLock::new();
+
+/// Gzip event bodies larger than `bytes` for the rest of the process; call once
+/// at startup. Smaller events, and all events when this is never called, go
+/// out uncompressed.
+pub fn set_compress_above(bytes: usize) {
+    let _ = COMPRESS_ABOVE.set(bytes);
+}
+
+/// The gzip of `payload` when it is over the budget. Signature and payload
+/// hash always cover the uncompressed bytes, so a compressed event carries
+/// `Content-Encoding: gzip` and `X-PEA-Signed-Bytes: uncompressed`: the bus
+/// decodes the body first and verifies exactly what an uncompressed send
+/// would have carried.
+fn compress_body(payload: &[u8], compress_above: Option<usize>) -> Option<Vec<u8>> {
+    use std::io::Write;
+    if payload.len() <= compress_above? { return None; }
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(payload).ok()?;
+    gz.finish().ok()
+}
+
+/// Who is submitting, and where to.
+pub struct SubmitContext<'a> {
+    pub client: &'a reqwest::Client,
+    pub bus: &'a str,
+    pub device_id: &'a str,
+    pub kp: &'a Keypair,
+    pub token: Option<String>,
+    pub attachment_endpoint: String,
+    pub negotiated: crate::capabilities::Negotiated,
+}
+
+/// Build the signed POST of one event. The bytes hashed are exactly the bytes
+/// sent; the signature covers them as the configured [`SigningScheme`] says.
+pub fn event_request(ctx: &SubmitContext<'_>, payload: Vec<u8>, timeout: Duration) -> (reqwest::RequestBuilder, Signature) {
+    event_request_via(ctx, payload, timeout, crate::relay::get())
+}
+
+/// [`event_request`], addressed to `relay` instead of the bus when given.
+fn event_request_via(ctx: &SubmitContext<'_>, payload: Vec<u8>, timeout: Duration, relay: Option<&crate::relay::Relay>) -> (reqwest::RequestBuilder, Signature) {
+    event_request_signed(ctx, payload, timeout, relay, signing_scheme(), COMPRESS_ABOVE.get().copied())
+}
+
+fn event_request_signed(ctx: &SubmitContext<'_>, payload: Vec<u8>, timeout: Duration, relay: Option<&crate::relay::Relay>, scheme: SigningScheme, compress_above: Option<usize>) -> (reqwest::RequestBuilder, Signature) {
+    let url = match relay {
+        Some(relay) => relay.event_url(),
+        None => format!("{}/api/supply-chain/event", ctx.bus),
+    };
+    let (req, sig) = scheme.sign(ctx.client.post(url), ctx.kp, ctx.device_id, &payload);
+    let mut req = PayloadHash::of_event(&payload).headers(req, &payload)
+        .header("X-PEA-Api-Version", ctx.negotiated.api_version.to_string())
+        .header("X-PEA-Canonicalization", ctx.negotiated.canonicalization.name())
+        .header("X-PEA-Sig-Alg", ctx.negotiated.sig_algorithm)
+        .header("Content-Type", "application/json")
+        .timeout(timeout);
+    if let Some(t) = &ctx.token { req = req.header("Authorization", format!("Bearer {}", t)); }
+    if let Some(relay) = relay { req = relay.authorize(req, &payload); }
+    let req = match compress_body(&payload, compress_above) {
+        Some(gz) => req.header("Content-Encoding", "gzip").header("X-PEA-Signed-Bytes", "uncompressed").body(gz),
+        None => req.body(payload),
+    };
+    (req, sig)
+}
+
+/// The bus's answer to an accepted event.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventAck {
+    #[serde(default)]
+    pub accepted: bool,
+    #[serde(default, alias = "eventId", alias = "id")]
+    pub event_id: Option<String>,
+    /// Kaspa transaction anchoring the event, once the bus has broadcast it.
+    #[serde(default, alias = "anchorTx", alias = "txId")]
+    pub anchor_tx: Option<String>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// An event the bus answered but didn't accept, or whose answer wasn't an ack.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EventRejected {
+    pub status: u16,
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for EventRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "event rejected ({} {}): {}", self.status, code, self.message),
+            None => write!(f, "event rejected ({}): {}", self.status, self.message),
+        }
+    }
+}
+
+impl std::error::Error for EventRejected {}
+
+impl EventRejected {
+    /// Whether the bus refused this payload for good: a 4xx other than a
+    /// timeout (408) or rate limit (429), so sending it again won't help.
+    pub fn is_definitive(&self) -> bool {
+        (400..500).contains(&self.status) && !matches!(self.status, 408 | 429)
+    }
+}
+
+/// Interpret a supply-chain event response.
+pub fn parse_event_response(status: reqwest::StatusCode, body: &str) -> Result<EventAck, EventRejected> {
+    let json = serde_json::from_str::<serde_json::Value>(body).ok().filter(|v| v.is_object());
+    if status.is_success() {
+        let Some(v) = json else {
+            return Err(EventRejected { status: status.as_u16(), code: Some("non_json_response".into()), message: body.chars().take(200).collect() });
+        };
+        let mut ack: EventAck = serde_json::from_value(v.clone()).map_err(|e| EventRejected { status: status.as_u16(), code: Some("malformed_ack".into()), message: e.to_string() })?;
+        if v.get("accepted").is_none() { ack.accepted = true; }
+        if !ack.accepted {
+            return Err(EventRejected { status: status.as_u16(), code: None, message: ack.warnings.join("; ") });
+        }
+        return Ok(ack);
+    }
+    let field = |k: &str| json.as_ref().and_then(|v| v.get(k)).and_then(|x| x.as_str()).map(str::to_string);
+    Err(EventRejected {
+        status: status.as_u16(),
+        code: field("code"),
+        message: field("error").or_else(|| field("message")).unwrap_or_else(|| body.chars().take(200).collect()),
+    })
+}
+
+/// Server-side lifecycle of an accepted event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventState {
+    Pending,
+    /// Durably persisted by the bus.
+    Committed,
+    /// Committed and anchored on-chain.
+    Anchored,
+    Failed,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct EventStatus {
+    pub status: EventState,
+    #[serde(default, alias = "anchorTx")]
+    pub anchor_tx: Option<String>,
+}
+
+/// How long and how often `--confirm` polls, and how far the event must get.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfirmOptions {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub require_anchor: bool,
+}
+
+/// Poll `/api/supply-chain/event/<id>/status` until the event is committed (or
+/// anchored, if required). Failure, or the timeout elapsing, is an error.
+pub async fn confirm(ctx: &SubmitContext<'_>, event_id: &str, opts: ConfirmOptions) -> Result<EventStatus> {
+    let deadline = tokio::time::Instant::now() + opts.timeout;
+    let url = format!("{}/api/supply-chain/event/{}/status", ctx.bus, event_id);
+    loop {
+        let mut req = ctx.client.get(&url).header("X-PEA-Device-Id", ctx.device_id).timeout(Duration::from_secs(10));
+        if let Some(t) = &ctx.token { req = req.header("Authorization", format!("Bearer {}", t)); }
+        // A poll that fails in transit is retried until the deadline like a pending one.
+        if let Ok(resp) = crate::outbound::send(req).await {
+            if resp.status().is_success() {
+                let status: EventStatus = resp.json().await?;
+                match status.status {
+                    EventState::Anchored => return Ok(status),
+                    EventState::Committed if !opts.require_anchor => return Ok(status),
+                    EventState::Failed => return Err(anyhow::anyhow!("bus reports event {} failed", event_id)),
+                    _ => {}
+                }
+            }
+        }
+        if tokio::time::Instant::now() + opts.interval > deadline {
+            return Err(anyhow::anyhow!("event {} not confirmed within {:?}", event_id, opts.timeout));
+        }
+        tokio::time::sleep(opts.interval).await;
+    }
+}
+
+/// Post one event, succeeding only once the bus accepted it and, through a
+/// relay, the relay proved it forwarded exactly these bytes. Returns the status,
+/// the bus's ack when its answer parsed as one, and the signature it accepted.
+#[tracing::instrument(name = "submit", skip_all, fields(device_id = ctx.device_id, nonce = tracing::field::Empty))]
+pub async fn post_event(ctx: &SubmitContext<'_>, payload: Vec<u8>, timeout: Duration) -> Result<(reqwest::StatusCode, Option<EventAck>, Signature)> {
+    let (req, sig) = event_request(ctx, payload.clone(), timeout);
+    let resp = crate::outbound::send(req).await
+        .inspect_err(|e| tracing::warn!(error = %e, "event not sent"))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        tracing::warn!(status = status.as_u16(), "event rejected");
+        return Err(parse_event_response(status, &body).err().map_or_else(|| anyhow::anyhow!("status {}", status), Into::into));
+    }
+    crate::relay::check_ack(&resp, &payload)?;
+    tracing::info!(status = status.as_u16(), "event submitted");
+    Ok((status, parse_event_response(status, &resp.text().await.unwrap_or_default()).ok(), sig))
+}
+
+/// How often [`submit_event`] tries to send before queueing the event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubmitRetry {
+    /// Total tries, including the first.
+    pub attempts: u32,
+    /// Wait before the first retry, doubling after each further one.
+    pub backoff: Duration,
+}
+
+impl Default for SubmitRetry {
+    fn default() -> Self {
+        Self { attempts: 3, backoff: Duration::from_millis(500) }
+    }
+}
+
+static SUBMIT_RETRY: OnceLock<SubmitRetry> = OnceLock::new();
+
+/// Install the retry policy for scanned events; call once at startup.
+pub fn set_submit_retry(retry: SubmitRetry) {
+    let _ = SUBMIT_RETRY.set(retry);
+}
+
+/// Whether sending again might get through: transport failures (timeouts,
+/// refused or dropped connections), 5xx answers and 408/429. Any other 4xx is
+/// the bus rejecting this payload, and an open circuit breaker or a bad relay
+/// proof won't clear within a retry.
+fn retryable(e: &anyhow::Error) -> bool {
+    if let Some(rejected) = e.downcast_ref::<EventRejected>() { return !rejected.is_definitive(); }
+    e.is::<reqwest::Error>()
+}
+
+/// How [`submit_event`] disposed of an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmitOutcome {
+    /// `signature` is the one the bus accepted with it. Attachment uploads that
+    /// failed afterwards are kept for a later drain, their errors in `deferred`.
+    Submitted { status: reqwest::StatusCode, ack: Option<EventAck>, signature: Signature, deferred: Vec<String> },
+    /// It didn't get through and is in the offline queue.
+    Enqueued { reason: String },
+    /// The bus refused it for good ([`EventRejected::is_definitive`]); it was
+    /// not queued, since resending it as is can't succeed.
+    Rejected(EventRejected),
+    /// It was already delivered within the dedup window, so nothing was sent.
+    Duplicate,
+}
+
+impl SubmitOutcome {
+    /// How the local sink records the event; a duplicate was recorded when it
+    /// was first delivered.
+    pub fn sink_status(&self) -> Option<crate::sink::SinkStatus> {
+        match self {
+            Self::Submitted { .. } => Some(crate::sink::SinkStatus::Delivered),
+            Self::Enqueued { .. } => Some(crate::sink::SinkStatus::Queued),
+            Self::Rejected(_) => Some(crate::sink::SinkStatus::Rejected),
+            Self::Duplicate => None,
+        }
+    }
+
+    /// The signature the bus accepted; none while the event waits in the queue.
+    pub fn signature(&self) -> Option<&Signature> {
+        match self {
+            Self::Submitted { signature, .. } => Some(signature),
+            Self::Enqueued { .. } | Self::Rejected(_) | Self::Duplicate => None,
+        }
+    }
+}
+
+/// Post one scanned event, retrying transient failures as configured with
+/// [`set_submit_retry`], and queue it as `name` with `ttl` when it still
+/// doesn't get through; a definitive rejection is reported, not queued. An
+/// event the dedup ring says was already delivered is skipped, and a delivered
+/// one is recorded there.
+pub async fn submit_event(ctx: &SubmitContext<'_>, name: &str, payload: &[u8], ttl: Option<Duration>) -> Result<SubmitOutcome> {
+    submit_event_with_attachments(ctx, name, payload, ttl, &[]).await
+}
+
+/// [`submit_event`] for an event referencing `attachments`. They are uploaded
+/// once the bus accepts the event and queued with it when it doesn't get
+/// through; an upload failing after acceptance is kept for a later drain
+/// rather than undo the delivery.
+pub async fn submit_event_with_attachments(ctx: &SubmitContext<'_>, name: &str, payload: &[u8], ttl: Option<Duration>, attachments: &[(AttachmentRef, Vec<u8>)]) -> Result<SubmitOutcome> {
+    let retry = SUBMIT_RETRY.get().copied().unwrap_or_default();
+    let enqueue = |payload: &[u8]| {
+        for (att, bytes) in attachments { crate::queue::stash_attachment(&att.sha256, bytes)?; }
+        crate::queue::enqueue(name, payload, ttl)
+    };
+    submit_attached_with(ctx, payload, attachments, retry, crate::dedup::ledger().as_ref(), enqueue, |att, bytes| crate::queue::defer_upload(att, Some(bytes))).await
+}
+
+async fn submit_attached_with(ctx: &SubmitContext<'_>, payload: &[u8], attachments: &[(AttachmentRef, Vec<u8>)], retry: SubmitRetry, dedup: Option<&crate::dedup::Ledger>,
+    enqueue: impl FnOnce(&[u8]) -> Result<()>, defer: impl Fn(&AttachmentRef, &[u8]) -> Result<()>) -> Result<SubmitOutcome> {
+    let mut outcome = submit_event_with(ctx, payload, retry, dedup, enqueue).await?;
+    if let SubmitOutcome::Submitted { deferred, .. } = &mut outcome {
+        // The event is in; a blob that doesn't go up now must not undo that.
+        for (att, bytes) in attachments {
+            if let Err(e) = attachments::upload(ctx.client, &ctx.attachment_endpoint, ctx.device_id, ctx.token.as_deref(), att, bytes.clone()).await {
+                tracing::warn!(attachment = %att.name, error = %e, "attachment upload failed, kept for retry");
+                defer(att, bytes)?;
+                deferred.push(e.to_string());
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+async fn submit_event_with(ctx: &SubmitContext<'_>, payload: &[u8], retry: SubmitRetry, dedup: Option<&crate::dedup::Ledger>, enqueue: impl FnOnce(&[u8]) -> Result<()>) -> Result<SubmitOutcome> {
+    if dedup.is_some_and(|d| d.seen(payload)) {
+        tracing::info!(device_id = ctx.device_id, "identical event already delivered, skipped");
+        return Ok(SubmitOutcome::Duplicate);
+    }
+    let mut backoff = retry.backoff;
+    let mut attempt = 1;
+    let err = loop {
+        // Each try is signed afresh, so a retry never reuses a nonce.
+        match post_event(ctx, payload.to_vec(), Duration::from_secs(15)).await {
+            Ok((status, ack, signature)) => {
+                if let Some(Err(e)) = dedup.map(|d| d.record(payload)) {
+                    tracing::warn!(error = %e, "delivered event not recorded for deduplication");
+                }
+                return Ok(SubmitOutcome::Submitted { status, ack, signature, deferred: Vec::new() });
+            }
+            Err(e) if attempt < retry.attempts && retryable(&e) => {
+                tracing::info!(attempt, backoff_ms = backoff.as_millis() as u64, "retrying submit");
+                // On shutdown the event goes to the queue rather than wait out the retries.
+                if crate::shutdown::global().sleep(backoff).await { break e; }
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => break e,
+        }
+    };
+    if let Some(rejected) = err.downcast_ref::<EventRejected>().filter(|r| r.is_definitive()) {
+        tracing::error!(device_id = ctx.device_id, reason = %rejected, "event rejected, not queued");
+        return Ok(SubmitOutcome::Rejected(rejected.clone()));
+    }
+    enqueue(payload)?;
+    tracing::warn!(device_id = ctx.device_id, attempts = attempt, reason = %err, "event enqueued");
+    Ok(SubmitOutcome::Enqueued { reason: err.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Verifier;
+
+    fn event_with(att: &AttachmentRef) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "productId": "P-1",
+            "metadata": { "device_id": "dev-1", "attachments": [att] }
+        })).unwrap()
+    }
+
+    #[test]
+    fn signed_payload_references_attachment_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, b"\xff\xd8jpeg-bytes").unwrap();
+        let (att, bytes) = attachments::load(&path).unwrap();
+        assert_eq!(att.sha256, attachments::sha256_hex(&bytes));
+        assert_eq!(att.size, bytes.len() as u64);
+
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let payload = event_with(&att);
+        assert_eq!(attachments::refs_in(&payload), vec![att.clone()]);
+        let sig = kp.sign(&payload);
+        assert!(kp.public.verify(&payload, &sig).is_ok());
+        // Swapping the referenced hash invalidates the signature.
+        let swapped = event_with(&AttachmentRef { sha256: attachments::sha256_hex(b"other"), ..att });
+        assert!(kp.public.verify(&swapped, &Signature::from_bytes(&sig.to_bytes()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn timestamp_formats_render_and_sign_consistently() {
+        let at = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00.250Z").unwrap().with_timezone(&chrono::Utc);
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        for (fmt, expected) in [
+            (TimestampFormat::Rfc3339, "\"2024-05-01T12:00:00.250+00:00\""),
+            (TimestampFormat::EpochMs, "1714564800250"),
+            (TimestampFormat::EpochS, "1714564800"),
+        ] {
+            assert_eq!(TimestampFormat::parse(fmt.name()), Some(fmt));
+            assert_eq!(fmt.render(at).to_string(), expected);
+            let payload = serde_json::to_vec(&serde_json::json!({
+                "productId": "P-1", "timestamp": fmt.render(at), "timestampFormat": fmt.name()
+            })).unwrap();
+            let sent: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+            assert_eq!(sent["timestamp"].to_string(), expected);
+            assert_eq!(sent["timestampFormat"], fmt.name());
+            assert!(kp.public.verify(&payload, &kp.sign(&payload)).is_ok());
+        }
+    }
+
+    #[test]
+    fn payload_hash_header_follows_the_recorded_algorithm() {
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let ctx = SubmitContext { client: &client, bus: "http://bus", device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let event = |alg: Option<&str>| {
+            let mut e = serde_json::json!({ "productId": "P-1" });
+            if let Some(alg) = alg { e["hashAlgorithm"] = alg.into(); }
+            serde_json::to_vec(&e).unwrap()
+        };
+        for (alg, payload) in [(PayloadHash::Sha256Hex, event(None)), (PayloadHash::Sha512Hex, event(Some("sha512-hex"))), (PayloadHash::Sha512Base64, event(Some("sha512-base64")))] {
+            assert_eq!(PayloadHash::parse(alg.name()), Some(alg));
+            let req = event_request(&ctx, payload.clone(), Duration::from_secs(10)).0.build().unwrap();
+            let expected = match alg {
+                PayloadHash::Sha512Base64 => general_purpose::STANDARD.encode(Sha512::digest(&payload)),
+                PayloadHash::Sha512Hex => hex::encode(Sha512::digest(&payload)),
+                _ => hex::encode(Sha256::digest(&payload)),
+            };
+            assert_eq!(req.headers()["X-PEA-Hash-Alg"], alg.name());
+            assert_eq!(req.headers()["X-PEA-Payload-Hash"], expected.as_str());
+        }
+        assert_eq!(PayloadHash::Sha256Hex.digest(b"").len(), 64);
+        assert_eq!(PayloadHash::Sha512Hex.digest(b"").len(), 128);
+    }
+
+    fn aged_event() -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "productId": "P-1", "timestamp": 1_700_000_000_000i64, "timestampFormat": "epoch-ms",
+            "metadata": { "device_id": "dev-1" }
+        })).unwrap()
+    }
+
+    #[test]
+    fn stale_event_is_dropped_under_drop_policy() {
+        let now = chrono::DateTime::from_timestamp(1_700_000_000 + 7200, 0).unwrap();
+        let window = Some(Duration::from_secs(3600));
+        assert!(matches!(apply_max_age(aged_event(), window, StalePolicy::Drop, now), Aged::Expired));
+        assert!(matches!(apply_max_age(aged_event(), None, StalePolicy::Drop, now), Aged::Fresh(_)));
+        assert!(matches!(apply_max_age(aged_event(), Some(Duration::from_secs(86_400)), StalePolicy::Drop, now), Aged::Fresh(_)));
+    }
+
+    #[test]
+    fn restamped_event_keeps_original_and_is_signed_as_sent() {
+        let now = chrono::DateTime::from_timestamp(1_700_000_000 + 7200, 0).unwrap();
+        let Aged::Restamped(bytes) = apply_max_age(aged_event(), Some(Duration::from_secs(3600)), StalePolicy::Restamp, now) else { panic!("not restamped") };
+        let event: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(event["timestamp"], 1_700_007_200_000i64);
+        assert_eq!(event["metadata"]["original_timestamp"], 1_700_000_000_000i64);
+        assert_eq!(event["metadata"]["device_id"], "dev-1");
+
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let ctx = SubmitContext { client: &client, bus: "http://bus", device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let req = event_request(&ctx, bytes.clone(), Duration::from_secs(10)).0.build().unwrap();
+        assert_eq!(req.body().and_then(|b| b.as_bytes()).unwrap(), &bytes[..]);
+        let header = |name: &str| req.headers()[name].to_str().unwrap().to_string();
+        let sig = general_purpose::STANDARD.decode(header("X-PEA-Signature")).unwrap();
+        let signed = SigningScheme::Context.signed_bytes(&bytes, "dev-1", &header("X-PEA-Nonce"), &header("X-PEA-Timestamp"));
+        assert!(kp.public.verify(&signed, &Signature::from_bytes(&sig).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn body_scheme_signs_the_payload_alone() {
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let ctx = SubmitContext { client: &client, bus: "http://bus", device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let payload = br#"{"productId":"P-1"}"#.to_vec();
+        let req = event_request_signed(&ctx, payload.clone(), Duration::from_secs(10), None, SigningScheme::Body, None).0.build().unwrap();
+        assert_eq!(req.headers()["X-PEA-Sig-Scheme"], "body");
+        let sig = Signature::from_bytes(&general_purpose::STANDARD.decode(req.headers()["X-PEA-Signature"].as_bytes()).unwrap()).unwrap();
+        assert!(kp.public.verify(&payload, &sig).is_ok());
+    }
+
+    #[test]
+    fn only_events_over_the_budget_are_compressed_and_signatures_cover_the_plain_bytes() {
+        use std::io::Read;
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let ctx = SubmitContext { client: &client, bus: "http://bus", device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let small = br#"{"productId":"P-1"}"#.to_vec();
+        let large = serde_json::to_vec(&serde_json::json!({ "productId": "P-2", "metadata": { "notes": "x".repeat(4096) } })).unwrap();
+
+        for (payload, compressed) in [(small, false), (large, true)] {
+            let req = event_request_signed(&ctx, payload.clone(), Duration::from_secs(10), None, SigningScheme::Body, Some(1024)).0.build().unwrap();
+            let body = req.body().and_then(|b| b.as_bytes()).unwrap().to_vec();
+            let received = if compressed {
+                assert_eq!((&req.headers()["Content-Encoding"], &req.headers()["X-PEA-Signed-Bytes"]), (&"gzip".parse().unwrap(), &"uncompressed".parse().unwrap()));
+                assert!(body.len() < payload.len());
+                let mut plain = Vec::new();
+                flate2::read::GzDecoder::new(&body[..]).read_to_end(&mut plain).unwrap();
+                plain
+            } else {
+                assert!(!req.headers().contains_key("Content-Encoding") && !req.headers().contains_key("X-PEA-Signed-Bytes"));
+                body
+            };
+            assert_eq!(received, payload);
+            let sig = Signature::from_bytes(&general_purpose::STANDARD.decode(req.headers()["X-PEA-Signature"].as_bytes()).unwrap()).unwrap();
+            assert!(kp.public.verify(&received, &sig).is_ok());
+            assert_eq!(req.headers()["X-PEA-Payload-Hash"], PayloadHash::Sha256Hex.digest(&received).as_str());
+        }
+    }
+
+    #[test]
+    fn context_scheme_binds_the_signature_to_nonce_timestamp_and_device() {
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let ctx = SubmitContext { client: &client, bus: "http://bus", device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let payload = br#"{"productId":"P-1"}"#.to_vec();
+        let req = event_request_signed(&ctx, payload.clone(), Duration::from_secs(10), None, SigningScheme::Context, None).0.build().unwrap();
+        let header = |name: &str| req.headers()[name].to_str().unwrap().to_string();
+        assert_eq!(header("X-PEA-Sig-Scheme"), "context");
+        let sig = Signature::from_bytes(&general_purpose::STANDARD.decode(header("X-PEA-Signature")).unwrap()).unwrap();
+
+        // What the server rebuilds from the headers it received.
+        let signed = |device: &str, nonce: &str, ts: &str| SigningScheme::Context.signed_bytes(&payload, device, nonce, ts);
+        assert!(kp.public.verify(&signed(&header("X-PEA-Device-Id"), &header("X-PEA-Nonce"), &header("X-PEA-Timestamp")), &sig).is_ok());
+        assert!(kp.public.verify(&payload, &sig).is_err(), "body alone must not verify");
+        assert!(kp.public.verify(&signed("dev-1", "replayed-nonce", &header("X-PEA-Timestamp")), &sig).is_err());
+        assert!(kp.public.verify(&signed("dev-1", &header("X-PEA-Nonce"), "0"), &sig).is_err());
+        assert!(kp.public.verify(&signed("dev-2", &header("X-PEA-Nonce"), &header("X-PEA-Timestamp")), &sig).is_err());
+    }
+
+    #[test]
+    fn context_signing_string_is_canonical() {
+        let signed = SigningScheme::Context.signed_bytes(b"{}", "dev-1", "n-1", "1704067200000");
+        assert_eq!(String::from_utf8(signed).unwrap(),
+            "pea-context/v1\ndev-1\nn-1\n1704067200000\n44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a");
+        assert_eq!(SigningScheme::Body.signed_bytes(b"{}", "dev-1", "n-1", "1704067200000"), b"{}");
+    }
+
+    #[test]
+    fn signed_requests_verify_and_carry_every_signed_header() {
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let payload = br#"{"productId":"P-1"}"#;
+        for scheme in [SigningScheme::Body, SigningScheme::Context] {
+            let signed = scheme.sign_request(&kp, "dev-1", payload, "n-1", "1704067200000");
+            assert_eq!((signed.nonce.as_str(), signed.timestamp.as_str()), ("n-1", "1704067200000"));
+            assert!(kp.public.verify(&scheme.signed_bytes(payload, "dev-1", "n-1", "1704067200000"), &signed.signature).is_ok());
+
+            let req = scheme.attach(reqwest::Client::new().post("http://bus.invalid/"), &kp, "dev-1", signed.clone()).build().unwrap();
+            let header = |name: &str| req.headers()[name].to_str().unwrap().to_string();
+            assert_eq!(header("X-PEA-Signature"), general_purpose::STANDARD.encode(signed.signature.to_bytes()));
+            assert_eq!(header("X-PEA-Sig-Scheme"), scheme.name());
+            assert_eq!((header("X-PEA-Nonce"), header("X-PEA-Timestamp")), ("n-1".to_string(), "1704067200000".to_string()));
+            assert_eq!(header("X-PEA-Public-Key"), general_purpose::STANDARD.encode(kp.public.as_bytes()));
+        }
+        assert_eq!(signing_scheme(), SigningScheme::Context, "body-only signatures are opt-in");
+        let sig = SigningScheme::Context.sign_request(&kp, "dev-1", payload, "n-1", "1704067200000").signature;
+        assert!(kp.public.verify(&SigningScheme::Context.signed_bytes(payload, "dev-1", "n-2", "1704067200000"), &sig).is_err());
+    }
+
+    #[tokio::test]
+    async fn device_signature_survives_the_relay_hop() {
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let device_public = kp.public;
+        let payload = br#"{"productId":"P-1","eventType":"QUALITY_CHECK"}"#.to_vec();
+        let relay_mac = |input: String| {
+            use hmac::{Hmac, Mac};
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"plant-secret").unwrap();
+            mac.update(input.as_bytes());
+            hex::encode(mac.finalize().into_bytes())
+        };
+        let proof = relay_mac(format!("relay-ack|{}", hex::encode(Sha256::digest(&payload))));
+
+        // The relay checks its own credential, then forwards the device's
+        // headers and body as received; the bus-side check is the signature.
+        let mut relay_server = mockito::Server::new_async().await;
+        let forwarded = relay_server.mock("POST", "/api/supply-chain/event")
+            .match_request(move |req| {
+                let header = |name: &str| req.header(name).first().and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+                let body = req.body().unwrap().clone();
+                let hop_ok = header("x-pea-relay-auth") == relay_mac(format!("{}|{}", header("x-pea-relay-timestamp"), hex::encode(Sha256::digest(&body))));
+                let sig = general_purpose::STANDARD.decode(header("x-pea-signature")).unwrap();
+                let signed = SigningScheme::Context.signed_bytes(&body, &header("x-pea-device-id"), &header("x-pea-nonce"), &header("x-pea-timestamp"));
+                hop_ok && device_public.verify(&signed, &Signature::from_bytes(&sig).unwrap()).is_ok()
+            })
+            .with_status(202)
+            .with_header("X-PEA-Relay-Proof", &proof)
+            .with_body(r#"{"accepted":true}"#)
+            .expect(1)
+            .create_async().await;
+
+        let client = reqwest::Client::new();
+        let ctx = SubmitContext { client: &client, bus: "http://bus.invalid", device_id: "dev-1", kp: &kp, token: Some("tok".into()), attachment_endpoint: String::new(), negotiated: Default::default() };
+        let relay = crate::relay::Relay::new(&relay_server.url(), "plant-secret");
+        let direct = event_request(&ctx, payload.clone(), Duration::from_secs(10)).0.build().unwrap();
+        let req = event_request_via(&ctx, payload.clone(), Duration::from_secs(10), Some(&relay)).0.build().unwrap();
+        assert_eq!(req.url().as_str(), format!("{}/api/supply-chain/event", relay_server.url()));
+        for name in ["X-PEA-Device-Id", "X-PEA-Public-Key", "X-PEA-Sig-Scheme"] {
+            assert_eq!(req.headers()[name], direct.headers()[name], "{} is the same through the relay", name);
+        }
+        assert_eq!(req.headers()["Authorization"], "Bearer tok");
+        assert_eq!(req.body().and_then(|b| b.as_bytes()).unwrap(), &payload[..]);
+        assert!(!direct.headers().contains_key("X-PEA-Relay-Auth"));
+
+        let resp = client.execute(req).await.unwrap();
+        assert_eq!(resp.status(), 202);
+        assert!(relay.verify_ack(resp.headers(), &payload).is_ok());
+        assert!(crate::relay::Relay::new(&relay_server.url(), "wrong").verify_ack(resp.headers(), &payload).is_err());
+        forwarded.assert_async().await;
+    }
+
+    #[test]
+    fn seeded_runs_produce_identical_submissions() {
+        let run = |seed| {
+            crate::rng::reseed(Some(seed));
+            let kp = crate::rng::keypair();
+            let client = reqwest::Client::new();
+            let ctx = SubmitContext { client: &client, bus: "http://bus", device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+            let payload = br#"{"productId":"P-1","timestamp":"2024-01-01T00:00:00Z"}"#.to_vec();
+            // Body signatures leave out the wall-clock timestamp, so the run is fully determined by the seed.
+            let req = event_request_signed(&ctx, payload, Duration::from_secs(10), None, SigningScheme::Body, None).0.build().unwrap();
+            let header = |name: &str| req.headers()[name].to_str().unwrap().to_string();
+            let golden = [header("X-PEA-Public-Key"), header("X-PEA-Signature"), header("X-PEA-Payload-Hash"), header("X-PEA-Nonce")];
+            crate::rng::reseed(None);
+            golden
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+        assert_ne!(crate::rng::nonce(), crate::rng::nonce(), "unseeded nonces stay random");
+    }
+
+    #[test]
+    fn successful_ack_is_parsed() {
+        let ack = parse_event_response(reqwest::StatusCode::OK,
+            r#"{"accepted":true,"eventId":"evt-42","anchorTx":"ab12","warnings":["clock skew 3s"]}"#).unwrap();
+        assert_eq!(ack, EventAck { accepted: true, event_id: Some("evt-42".into()), anchor_tx: Some("ab12".into()), warnings: vec!["clock skew 3s".into()] });
+        let ack = parse_event_response(reqwest::StatusCode::CREATED, r#"{"event_id":"evt-43"}"#).unwrap();
+        assert!(ack.accepted);
+        assert_eq!(ack.anchor_tx, None);
+    }
+
+    #[test]
+    fn error_bodies_become_structured_rejections() {
+        let err = parse_event_response(reqwest::StatusCode::BAD_REQUEST, r#"{"error":"signature invalid","code":"bad_signature"}"#).unwrap_err();
+        assert_eq!(err, EventRejected { status: 400, code: Some("bad_signature".into()), message: "signature invalid".into() });
+        let err = parse_event_response(reqwest::StatusCode::BAD_GATEWAY, "<html>upstream down</html>").unwrap_err();
+        assert_eq!((err.status, err.code, err.message.as_str()), (502, None, "<html>upstream down</html>"));
+        let err = parse_event_response(reqwest::StatusCode::OK, "ok").unwrap_err();
+        assert_eq!(err.code.as_deref(), Some("non_json_response"));
+    }
+
+    async fn poll_until(states: &[&str], opts: ConfirmOptions) -> Result<EventStatus> {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/api/supply-chain/event/evt-1/status";
+        // Mocks still owed hits are matched first, so these answer in order.
+        let mut mocks = Vec::new();
+        for st in states {
+            mocks.push(server.mock("GET", path).with_status(200)
+                .with_body(format!(r#"{{"status":"{}","anchorTx":"{}"}}"#, st, if *st == "anchored" { "ab12" } else { "" }))
+                .expect(1).create_async().await);
+        }
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let url = server.url();
+        let ctx = SubmitContext { client: &client, bus: &url, device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        confirm(&ctx, "evt-1", opts).await
+    }
+
+    fn quick(require_anchor: bool) -> ConfirmOptions {
+        ConfirmOptions { interval: Duration::from_millis(10), timeout: Duration::from_secs(5), require_anchor }
+    }
+
+    #[tokio::test]
+    async fn confirm_waits_through_pending_until_committed() {
+        let status = poll_until(&["pending", "pending", "committed"], quick(false)).await.unwrap();
+        assert_eq!(status.status, EventState::Committed);
+    }
+
+    #[tokio::test]
+    async fn confirm_can_require_anchoring() {
+        let status = poll_until(&["pending", "committed", "anchored"], quick(true)).await.unwrap();
+        assert_eq!(status.status, EventState::Anchored);
+        assert_eq!(status.anchor_tx.as_deref(), Some("ab12"));
+    }
+
+    #[tokio::test]
+    async fn confirm_times_out_or_fails() {
+        let opts = ConfirmOptions { interval: Duration::from_millis(10), timeout: Duration::from_millis(50), require_anchor: false };
+        assert!(poll_until(&["pending"; 20], opts).await.is_err());
+        assert!(poll_until(&["failed"], quick(false)).await.is_err());
+    }
+
+    /// Submit an event with one attachment; returns the outcome, the uploads
+    /// kept for later and the payloads queued.
+    async fn run_with(event_status: usize, upload_status: usize) -> (SubmitOutcome, Vec<AttachmentRef>, Vec<Vec<u8>>) {
+        let mut server = mockito::Server::new_async().await;
+        let event = server.mock("POST", "/api/supply-chain/event").with_status(event_status).expect_at_least(1).create_async().await;
+        let bytes = b"trace-data".to_vec();
+        let att = AttachmentRef { sha256: attachments::sha256_hex(&bytes), name: "trace.bin".into(), size: bytes.len() as u64 };
+        let upload = server.mock("PUT", format!("/api/attachments/{}", att.sha256).as_str())
+            .with_status(upload_status)
+            .expect(if event_status < 300 { 1 } else { 0 })
+            .create_async().await;
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let url = server.url();
+        let ctx = SubmitContext { client: &client, bus: &url, device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: attachments::endpoint(&url, None), negotiated: Default::default() };
+        let (kept, mut queued) = (std::sync::Mutex::new(Vec::new()), Vec::new());
+        let outcome = submit_attached_with(&ctx, &event_with(&att), &[(att, bytes)], QUICK_RETRY, None,
+            |p| { queued.push(p.to_vec()); Ok(()) }, |att, _| { kept.lock().unwrap().push(att.clone()); Ok(()) }).await.unwrap();
+        event.assert_async().await;
+        upload.assert_async().await;
+        (outcome, kept.into_inner().unwrap(), queued)
+    }
+
+    #[tokio::test]
+    async fn attachments_upload_after_event_is_accepted() {
+        let (outcome, kept, queued) = run_with(200, 201).await;
+        assert!(matches!(&outcome, SubmitOutcome::Submitted { status, deferred, .. } if status.is_success() && deferred.is_empty()), "{:?}", outcome);
+        assert!(kept.is_empty() && queued.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_upload_is_kept_and_the_event_still_counts_as_delivered() {
+        let (outcome, kept, queued) = run_with(200, 503).await;
+        let SubmitOutcome::Submitted { status, deferred, .. } = outcome else { panic!("event was accepted") };
+        assert!(status.is_success());
+        assert_eq!(deferred.len(), 1);
+        assert!(deferred[0].contains("503"), "{:?}", deferred);
+        assert_eq!(kept.iter().map(|a| a.name.as_str()).collect::<Vec<_>>(), ["trace.bin"]);
+        assert!(queued.is_empty());
+    }
+
+    #[tokio::test]
+    async fn attachments_not_uploaded_when_event_rejected() {
+        let (outcome, kept, queued) = run_with(500, 201).await;
+        assert!(matches!(outcome, SubmitOutcome::Enqueued { .. }), "{:?}", outcome);
+        assert!(kept.is_empty());
+        assert_eq!(queued.len(), 1, "the event is queued and its attachment stashed with it");
+
+        let (outcome, kept, queued) = run_with(400, 201).await;
+        assert!(matches!(outcome, SubmitOutcome::Rejected(_)), "{:?}", outcome);
+        assert!(kept.is_empty() && queued.is_empty(), "nothing kept for an event the bus refused");
+    }
+
+    const QUICK_RETRY: SubmitRetry = SubmitRetry { attempts: 3, backoff: Duration::from_millis(1) };
+
+    /// Submit one event to a bus answering `statuses` in turn, the last one for good.
+    async fn submit_against(statuses: &[usize]) -> (SubmitOutcome, Vec<Vec<u8>>) {
+        let mut server = mockito::Server::new_async().await;
+        let (last, first) = statuses.split_last().unwrap();
+        let mut mocks = Vec::new();
+        for &status in first {
+            mocks.push(server.mock("POST", "/api/supply-chain/event").with_status(status).with_body(r#"{"error":"try later"}"#).expect(1).create_async().await);
+        }
+        mocks.push(server.mock("POST", "/api/supply-chain/event").match_header("X-PEA-Device-Id", "dev-1")
+            .with_status(*last).with_body(r#"{"accepted":true,"eventId":"ev-1"}"#).create_async().await);
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let url = server.url();
+        let ctx = SubmitContext { client: &client, bus: &url, device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let mut queued = Vec::new();
+        let outcome = submit_event_with(&ctx, br#"{"productId":"P-1"}"#, QUICK_RETRY, None, |p| { queued.push(p.to_vec()); Ok(()) }).await.unwrap();
+        for m in &mocks[..first.len()] { m.assert_async().await; }
+        (outcome, queued)
+    }
+
+    #[tokio::test]
+    async fn accepted_events_are_submitted_not_queued() {
+        let (outcome, queued) = submit_against(&[200]).await;
+        let SubmitOutcome::Submitted { status, ack, .. } = &outcome else { panic!("expected submitted, got {:?}", outcome) };
+        assert_eq!(status.as_u16(), 200);
+        assert_eq!(ack.as_ref().and_then(|a| a.event_id.as_deref()), Some("ev-1"));
+        assert_eq!(outcome.sink_status(), Some(crate::sink::SinkStatus::Delivered));
+        assert!(queued.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_events_are_queued_as_sent() {
+        let (outcome, queued) = submit_against(&[429, 503, 504]).await;
+        assert!(matches!(&outcome, SubmitOutcome::Enqueued { reason } if reason.starts_with("event rejected (504)")), "{:?}", outcome);
+        assert_eq!(outcome.sink_status(), Some(crate::sink::SinkStatus::Queued));
+        assert_eq!(queued, vec![br#"{"productId":"P-1"}"#.to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn an_event_delivered_before_a_restart_is_not_sent_again() {
+        let mut server = mockito::Server::new_async().await;
+        let bus = server.mock("POST", "/api/supply-chain/event").with_status(200).with_body(r#"{"accepted":true}"#).expect(1).create_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let policy = crate::dedup::DedupPolicy { window: Duration::from_secs(3600), capacity: 16 };
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let url = server.url();
+        let ctx = SubmitContext { client: &client, bus: &url, device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let payload = br#"{"productId":"P-1","timestamp":"2024-01-01T00:00:00Z"}"#;
+        let never_queued = |_: &[u8]| -> Result<()> { panic!("nothing should be queued") };
+
+        // Each submit gets a fresh ledger, as a restarted process would: only the file carries over.
+        let first = submit_event_with(&ctx, payload, QUICK_RETRY, Some(&crate::dedup::Ledger::at(dir.path().join("delivered.json"), policy)), never_queued).await.unwrap();
+        assert!(matches!(first, SubmitOutcome::Submitted { .. }), "{:?}", first);
+        let again = submit_event_with(&ctx, payload, QUICK_RETRY, Some(&crate::dedup::Ledger::at(dir.path().join("delivered.json"), policy)), never_queued).await.unwrap();
+        assert_eq!(again, SubmitOutcome::Duplicate);
+        assert_eq!((again.sink_status(), again.signature()), (None, None));
+        bus.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_before_queueing() {
+        let (outcome, queued) = submit_against(&[503, 502, 200]).await;
+        assert!(matches!(outcome, SubmitOutcome::Submitted { .. }), "{:?}", outcome);
+        assert!(queued.is_empty(), "recovered within the retries, nothing queued");
+
+        let (outcome, queued) = submit_against(&[503, 503, 503]).await;
+        assert!(matches!(&outcome, SubmitOutcome::Enqueued { reason } if reason.contains("503")), "{:?}", outcome);
+        assert_eq!(queued.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_events_are_not_retried() {
+        let (outcome, queued) = submit_against(&[422, 200]).await;
+        assert!(matches!(&outcome, SubmitOutcome::Rejected(r) if r.status == 422 && r.message.contains("try later")), "{:?}", outcome);
+        assert_eq!(outcome.sink_status(), Some(crate::sink::SinkStatus::Rejected));
+        assert!(queued.is_empty(), "resending the same payload can't succeed");
+
+        let refused = anyhow::Error::from(reqwest::Client::new().get("http://127.0.0.1:1/").send().await.unwrap_err());
+        assert!(retryable(&refused));
+        assert!(!retryable(&anyhow::anyhow!("relay proof mismatch")));
+    }
+
+    #[tokio::test]
+    async fn submits_log_structured_events() {
+        let captured = crate::logging::capture::Captured::default();
+        let subscriber = crate::logging::subscriber(crate::logging::LogFormat::Json, tracing_subscriber::EnvFilter::new("info"), captured.clone());
+        let _default = tracing::subscriber::set_default(subscriber);
+        submit_against(&[503, 200]).await;
+        let events = captured.events();
+        let find = |message: &str| events.iter().find(|e| e["fields"]["message"] == message).unwrap_or_else(|| panic!("no {:?} in {:?}", message, events)).clone();
+        let (rejected, submitted) = (find("event rejected"), find("event submitted"));
+        assert_eq!((&rejected["level"], &rejected["fields"]["status"]), (&serde_json::json!("WARN"), &serde_json::json!(503)));
+        assert_eq!((&submitted["level"], &submitted["fields"]["status"]), (&serde_json::json!("INFO"), &serde_json::json!(200)));
+        for event in [&rejected, &submitted] {
+            assert_eq!(event["span"]["name"], "submit");
+            assert_eq!(event["span"]["device_id"], "dev-1");
+            assert!(event["span"]["nonce"].as_str().is_some_and(|n| !n.is_empty()), "{}", event);
+        }
+        assert_ne!(rejected["span"]["nonce"], submitted["span"]["nonce"], "the retry is signed with a fresh nonce");
+        assert_eq!(find("retrying submit")["fields"]["attempt"], 1);
+    }
+
+    /// A keep-alive bus answering every request with 200, counting the TCP
+    /// connections it accepts.
+    async fn counting_bus() -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 4096];
+                    loop {
+                        let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4) else {
+                            match conn.read(&mut chunk).await { Ok(0) | Err(_) => return, Ok(n) => buf.extend_from_slice(&chunk[..n]) }
+                            continue;
+                        };
+                        let head = String::from_utf8_lossy(&buf[..end]).to_ascii_lowercase();
+                        let len = head.lines().find_map(|l| l.strip_prefix("content-length:")).map_or(0, |v| v.trim().parse().unwrap());
+                        while buf.len() < end + len {
+                            match conn.read(&mut chunk).await { Ok(0) | Err(_) => return, Ok(n) => buf.extend_from_slice(&chunk[..n]) }
+                        }
+                        buf.drain(..end + len);
+                        if conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.is_err() { return; }
+                    }
+                });
+            }
+        });
+        (url, accepted)
+    }
+
+    #[tokio::test]
+    async fn submits_share_one_client_and_its_connection() {
+        assert!(std::ptr::eq(crate::outbound::client(), crate::outbound::client()));
+        let (bus, accepted) = counting_bus().await;
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        for i in 0..3 {
+            let ctx = SubmitContext { client: crate::outbound::client(), bus: &bus, device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+            let payload = serde_json::to_vec(&serde_json::json!({ "productId": format!("P-{}", i) })).unwrap();
+            let (status, ..) = post_event(&ctx, payload, Duration::from_secs(5)).await.unwrap();
+            assert_eq!(status, 200);
+        }
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1, "later submits reuse the pooled connection");
+    }
+}
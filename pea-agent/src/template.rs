@@ -0,0 +1,211 @@
+use anyhow::{Result, anyhow};
+use std::{path::Path, sync::OnceLock};
+
+/// Placeholders a template may use besides `{{meta.*}}`.
+const PLACEHOLDERS: [&str; 8] = ["product", "device_id", "timestamp", "event_type", "location", "timestamp_format", "hash_algorithm", "schema_version"];
+/// Top-level keys every template must fill with exactly these placeholders.
+/// The queue reads them back from stored payloads: `queue-list` and
+/// `queue-dead-list` show the product and type, `--dead-letter` policies match
+/// on the type, and `--max-event-age` checks the timestamp.
+const PINNED: [(&str, &str); 3] = [("productId", "product"), ("eventType", "event_type"), ("timestamp", "timestamp")];
+
+/// Operator-supplied event shape (`--payload-template`). String values of the
+/// form `{{name}}` are replaced by the value itself, keeping its JSON type;
+/// placeholders inside longer strings are interpolated as text. Events built
+/// from a template without `timestamp_format` / `hash_algorithm` are read back
+/// with the defaults when drained from the queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadTemplate {
+    root: serde_json::Value,
+}
+
+static TEMPLATE: OnceLock<PayloadTemplate> = OnceLock::new();
+
+/// Shape events with `template` for the rest of the process; call once at startup.
+pub fn configure(template: PayloadTemplate) {
+    let _ = TEMPLATE.set(template);
+}
+
+/// Reshape a default-shaped event with the configured template, if any.
+pub fn shape(event: serde_json::Value, device_id: &str) -> Result<serde_json::Value> {
+    match TEMPLATE.get() {
+        Some(template) => template.render(&event, device_id),
+        None => Ok(event),
+    }
+}
+
+impl PayloadTemplate {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| anyhow!("payload template {:?}: {}", path, e))?;
+        let root = serde_json::from_str(&text).map_err(|e| anyhow!("payload template {:?} is not JSON: {}", path, e))?;
+        Self::new(root)
+    }
+
+    /// Check that the template is an object using only known placeholders,
+    /// with the [`PINNED`] keys at the top level.
+    pub fn new(root: serde_json::Value) -> Result<Self> {
+        if !root.is_object() { return Err(anyhow!("payload template must be a JSON object")); }
+        let mut used = Vec::new();
+        collect_placeholders(&root, &mut used);
+        for name in &used {
+            if !PLACEHOLDERS.contains(&name.as_str()) && name.strip_prefix("meta.").is_none_or(str::is_empty) {
+                return Err(anyhow!("payload template: unknown placeholder {{{{{}}}}}", name));
+            }
+        }
+        for (key, name) in PINNED {
+            let pinned = root.get(key).and_then(|v| v.as_str())
+                .is_some_and(|s| matches!(placeholders(s).collect::<Vec<_>>().as_slice(), [(0, end, found)] if *end == s.len() && *found == name));
+            if !pinned {
+                return Err(anyhow!("payload template must have \"{}\": \"{{{{{}}}}}\" at the top level; the queue reads it back", key, name));
+            }
+        }
+        Ok(Self { root })
+    }
+
+    /// Fill the template from `event` (the agent's default event shape).
+    pub fn render(&self, event: &serde_json::Value, device_id: &str) -> Result<serde_json::Value> {
+        let lookup = |name: &str| -> Result<serde_json::Value> {
+            let value = match name {
+                "product" => event.get("productId"),
+                "device_id" => return Ok(device_id.into()),
+                "timestamp" => event.get("timestamp"),
+                "event_type" => event.get("eventType"),
+                "location" => event.get("location"),
+                "timestamp_format" => event.get("timestampFormat"),
+                "hash_algorithm" => event.get("hashAlgorithm"),
+                "schema_version" => event.get("schemaVersion"),
+                meta => meta.strip_prefix("meta.").and_then(|k| event.get("metadata").and_then(|m| m.get(k))),
+            };
+            value.cloned().ok_or_else(|| anyhow!("payload template: {{{{{}}}}} does not resolve for this event", name))
+        };
+        fill(&self.root, &lookup)
+    }
+}
+
+fn placeholders(s: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    let mut from = 0;
+    std::iter::from_fn(move || {
+        let start = from + s[from..].find("{{")?;
+        let end = start + 2 + s[start + 2..].find("}}")?;
+        from = end + 2;
+        Some((start, end + 2, s[start + 2..end].trim()))
+    })
+}
+
+fn collect_placeholders(v: &serde_json::Value, out: &mut Vec<String>) {
+    match v {
+        serde_json::Value::String(s) => out.extend(placeholders(s).map(|(_, _, name)| name.to_string())),
+        serde_json::Value::Array(items) => items.iter().for_each(|i| collect_placeholders(i, out)),
+        serde_json::Value::Object(map) => map.values().for_each(|i| collect_placeholders(i, out)),
+        _ => {}
+    }
+}
+
+fn fill(v: &serde_json::Value, lookup: &impl Fn(&str) -> Result<serde_json::Value>) -> Result<serde_json::Value> {
+    Ok(match v {
+        serde_json::Value::String(s) => {
+            let found: Vec<_> = placeholders(s).collect();
+            match found.as_slice() {
+                [] => v.clone(),
+                [(0, end, name)] if *end == s.len() => lookup(name)?,
+                _ => {
+                    let mut out = String::new();
+                    let mut at = 0;
+                    for (start, end, name) in found {
+                        out.push_str(&s[at..start]);
+                        match lookup(name)? {
+                            serde_json::Value::String(text) => out.push_str(&text),
+                            other => out.push_str(&other.to_string()),
+                        }
+                        at = end;
+                    }
+                    out.push_str(&s[at..]);
+                    out.into()
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter().map(|i| fill(i, lookup)).collect::<Result<_>>()?,
+        serde_json::Value::Object(map) => map.iter().map(|(k, i)| Ok((k.clone(), fill(i, lookup)?))).collect::<Result<_>>()?,
+        other => other.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Keypair;
+
+    fn event() -> serde_json::Value {
+        serde_json::json!({
+            "schemaVersion": "scan/v2", "productId": "P-1", "eventType": "QUALITY_CHECK", "location": "line-4",
+            "timestamp": 1_700_000_000_000i64, "timestampFormat": "epoch-ms", "hashAlgorithm": "sha256-hex",
+            "metadata": { "device_id": "dev-1", "batch": "B-7" }
+        })
+    }
+
+    fn shaped() -> PayloadTemplate {
+        PayloadTemplate::new(serde_json::json!({
+            "productId": "{{product}}",
+            "eventType": "{{ event_type }}",
+            "timestamp": "{{timestamp}}",
+            "timestampFormat": "{{timestamp_format}}",
+            "source": { "device": "{{device_id}}", "label": "{{product}}@{{location}}", "batch": "{{meta.batch}}" },
+            "kind": "scan",
+            "tags": ["{{event_type}}", "ts={{timestamp}}"]
+        })).unwrap()
+    }
+
+    #[test]
+    fn template_renders_placeholders_and_the_result_is_what_gets_sent() {
+        let rendered = shaped().render(&event(), "dev-1").unwrap();
+        assert_eq!(rendered, serde_json::json!({
+            "productId": "P-1",
+            "eventType": "QUALITY_CHECK",
+            "timestamp": 1_700_000_000_000i64,
+            "timestampFormat": "epoch-ms",
+            "source": { "device": "dev-1", "label": "P-1@line-4", "batch": "B-7" },
+            "kind": "scan",
+            "tags": ["QUALITY_CHECK", "ts=1700000000000"]
+        }));
+
+        let payload = crate::capabilities::Canonicalization::SortedKeys.encode(&rendered).unwrap();
+        assert_eq!(payload, br#"{"eventType":"QUALITY_CHECK","kind":"scan","productId":"P-1","source":{"batch":"B-7","device":"dev-1","label":"P-1@line-4"},"tags":["QUALITY_CHECK","ts=1700000000000"],"timestamp":1700000000000,"timestampFormat":"epoch-ms"}"#);
+        let client = reqwest::Client::new();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let ctx = crate::submit::SubmitContext { client: &client, bus: "http://bus", device_id: "dev-1", kp: &kp, token: None, attachment_endpoint: String::new(), negotiated: Default::default() };
+        let req = crate::submit::event_request(&ctx, payload.clone(), std::time::Duration::from_secs(5)).0.build().unwrap();
+        assert_eq!(req.body().and_then(|b| b.as_bytes()), Some(payload.as_slice()), "the rendered bytes go out as the signed body");
+    }
+
+    #[test]
+    fn templated_events_keep_what_the_queue_reads_back() {
+        let payload = serde_json::to_vec(&shaped().render(&event(), "dev-1").unwrap()).unwrap();
+        let long_after = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap() + chrono::Duration::days(2);
+        let aged = crate::submit::apply_max_age(payload, Some(std::time::Duration::from_secs(3600)), crate::submit::StalePolicy::Drop, long_after);
+        assert!(matches!(aged, crate::submit::Aged::Expired), "the max event age sees the templated timestamp");
+
+        let err = PayloadTemplate::new(serde_json::json!({ "sku": "{{product}}", "eventType": "{{event_type}}", "timestamp": "{{timestamp}}" })).unwrap_err();
+        assert!(err.to_string().contains("\"productId\""), "{}", err);
+        let err = PayloadTemplate::new(serde_json::json!({ "productId": "{{product}}", "eventType": "type {{event_type}}", "timestamp": "{{timestamp}}" })).unwrap_err();
+        assert!(err.to_string().contains("\"eventType\""), "{}", err);
+        let nested = serde_json::json!({ "productId": "{{product}}", "eventType": "{{event_type}}", "event": { "timestamp": "{{timestamp}}" } });
+        assert!(PayloadTemplate::new(nested).is_err(), "timestamp must be at the top level");
+    }
+
+    #[test]
+    fn templates_with_unknown_missing_or_unresolved_placeholders_are_rejected() {
+        let pinned = serde_json::json!({ "productId": "{{product}}", "eventType": "{{event_type}}", "timestamp": "{{timestamp}}" });
+        let mut unknown = pinned.clone();
+        unknown["x"] = "{{serial}}".into();
+        let err = PayloadTemplate::new(unknown).unwrap_err();
+        assert!(err.to_string().contains("{{serial}}"), "{}", err);
+        assert!(PayloadTemplate::new(serde_json::json!({ "productId": "{{product}}", "eventType": "{{event_type}}" })).is_err(), "timestamp is required");
+        assert!(PayloadTemplate::new(serde_json::json!(["{{product}}", "{{timestamp}}"])).is_err());
+
+        let mut lot = pinned;
+        lot["lot"] = "{{meta.lot}}".into();
+        let template = PayloadTemplate::new(lot).unwrap();
+        let err = template.render(&event(), "dev-1").unwrap_err();
+        assert!(err.to_string().contains("{{meta.lot}}"), "{}", err);
+    }
+}
@@ -1,18 +1,195 @@
+/// Set to `1` to keep the File vault's key sealed in the TPM instead of
+/// deriving it from hostname and username. Needs a `tpm` build.
+pub const VAULT_TPM_ENV: &str = "PEA_VAULT_TPM";
+
+/// Whether the File vault key comes from the TPM in this process.
+pub fn vault_enabled() -> bool {
+    cfg!(feature = "tpm") && std::env::var(VAULT_TPM_ENV).is_ok_and(|v| v.trim() == "1")
+}
+
+/// Sealing goes through tpm2-tools rather than linking the TSS. The
+/// tss-esapi bindings would drop the external tools, but they need the
+/// tpm2-tss C libraries at build time, which the agent's release builds don't
+/// carry; a missing `tpm2_*` binary is reported at runtime instead.
 #[cfg(feature = "tpm")]
-pub mod tpm {
+mod sealed {
     use anyhow::{Result, anyhow};
-    // Placeholder: integrate tss-esapi here when available
-    pub fn seal_secret(_data: &[u8]) -> Result<Vec<u8>> {
-        Err(anyhow!("TPM seal not implemented yet"))
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::{Serialize, Deserialize};
+    use std::{ffi::OsStr, fs, io::Write, path::PathBuf, process::{Command, Stdio}};
+    use zeroize::Zeroizing;
+
+    /// File under the state dir holding the vault key sealed by [`seal_secret`].
+    const VAULT_KEY_FILE: &str = "vault-key.tpm";
+    const BLOB_VERSION: u8 = 1;
+
+    /// A secret sealed under this TPM's owner-hierarchy primary key: the public
+    /// and private parts of the sealed object as `tpm2_create` writes them. The
+    /// private part is wrapped by the primary key, which never leaves the TPM,
+    /// so the blob is useless on another machine.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct SealedBlob {
+        version: u8,
+        public: String,
+        private: String,
     }
-    pub fn unseal_secret(_blob: &[u8]) -> Result<Vec<u8>> {
-        Err(anyhow!("TPM unseal not implemented yet"))
+
+    /// Scratch dir for the context files tpm2-tools passes between steps;
+    /// none of them hold the secret in the clear.
+    struct WorkDir(PathBuf);
+
+    impl WorkDir {
+        fn new() -> Result<Self> {
+            let dir = crate::paths::state_subdir("tpm")?.join(format!("work-{}-{:08x}", std::process::id(), rand::random::<u32>()));
+            fs::create_dir_all(&dir)?;
+            Ok(Self(dir))
+        }
+
+        fn file(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for WorkDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Run one tpm2-tools command, feeding `stdin` if given, returning stdout.
+    /// The TPM is picked by `TPM2TOOLS_TCTI` (e.g. `swtpm:port=2321`), or the
+    /// default device.
+    fn tpm2(tool: &str, args: &[&OsStr], stdin: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut child = Command::new(tool).args(args).stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+            .map_err(|e| anyhow!("{} not runnable ({}); install tpm2-tools", tool, e))?;
+        if let (Some(data), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(data)?;
+        }
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            return Err(anyhow!("{} failed: {}", tool, String::from_utf8_lossy(&out.stderr).trim()));
+        }
+        Ok(out.stdout)
+    }
+
+    /// Recreate the owner-hierarchy primary key. The template is fixed, so the
+    /// TPM derives the same key every time from its owner seed.
+    fn primary(work: &WorkDir) -> Result<PathBuf> {
+        let ctx = work.file("primary.ctx");
+        tpm2("tpm2_createprimary", &["-C".as_ref(), "o".as_ref(), "-g".as_ref(), "sha256".as_ref(), "-G".as_ref(), "ecc".as_ref(), "-c".as_ref(), ctx.as_ref()], None)?;
+        Ok(ctx)
+    }
+
+    pub fn seal_secret(data: &[u8]) -> Result<Vec<u8>> {
+        let work = WorkDir::new()?;
+        let primary = primary(&work)?;
+        let (public, private) = (work.file("sealed.pub"), work.file("sealed.priv"));
+        tpm2("tpm2_create", &["-C".as_ref(), primary.as_ref(), "-i".as_ref(), "-".as_ref(), "-u".as_ref(), public.as_ref(), "-r".as_ref(), private.as_ref()], Some(data))?;
+        let blob = SealedBlob {
+            version: BLOB_VERSION,
+            public: general_purpose::STANDARD.encode(fs::read(&public)?),
+            private: general_purpose::STANDARD.encode(fs::read(&private)?),
+        };
+        Ok(serde_json::to_vec(&blob)?)
+    }
+
+    pub fn unseal_secret(blob: &[u8]) -> Result<Vec<u8>> {
+        let blob: SealedBlob = serde_json::from_slice(blob).map_err(|e| anyhow!("not a sealed TPM blob: {}", e))?;
+        if blob.version != BLOB_VERSION { return Err(anyhow!("unsupported sealed TPM blob version {}", blob.version)); }
+        let work = WorkDir::new()?;
+        let (public, private, object) = (work.file("sealed.pub"), work.file("sealed.priv"), work.file("sealed.ctx"));
+        fs::write(&public, general_purpose::STANDARD.decode(&blob.public)?)?;
+        fs::write(&private, general_purpose::STANDARD.decode(&blob.private)?)?;
+        let primary = primary(&work)?;
+        tpm2("tpm2_load", &["-C".as_ref(), primary.as_ref(), "-u".as_ref(), public.as_ref(), "-r".as_ref(), private.as_ref(), "-c".as_ref(), object.as_ref()], None)?;
+        tpm2("tpm2_unseal", &["-c".as_ref(), object.as_ref()], None)
+    }
+
+    /// The File vault key: unsealed from `vault-key.tpm`, or generated and
+    /// sealed there on first use.
+    pub fn vault_key() -> Result<Zeroizing<[u8; 32]>> {
+        let path = crate::paths::state_dir()?.join(VAULT_KEY_FILE);
+        let key = match fs::read(&path) {
+            Ok(blob) => Zeroizing::new(unseal_secret(&blob)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = Zeroizing::new(rand::random::<[u8; 32]>().to_vec());
+                if create_new(&path, &seal_secret(&key)?)? { key } else {
+                    // Another process sealed its key first; that one is the vault key.
+                    Zeroizing::new(unseal_secret(&fs::read(&path)?)?)
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        crate::vault::key32(&key).ok_or_else(|| anyhow!("sealed vault key in {:?} is not 32 bytes", path))
+    }
+
+    /// Write `data` to `path` only if nothing is there yet, returning whether
+    /// it was written. The blob is staged in a `create_new` temp file and
+    /// hard-linked into place, so `path` never appears half-written to a
+    /// process racing to read it.
+    fn create_new(path: &std::path::Path, data: &[u8]) -> Result<bool> {
+        let tmp = path.with_file_name(format!("{}.{}-{:08x}.tmp", VAULT_KEY_FILE, std::process::id(), rand::random::<u32>()));
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        let staged = file.write_all(data).and_then(|_| file.sync_all()).and_then(|_| fs::hard_link(&tmp, path));
+        let _ = fs::remove_file(&tmp);
+        match staged {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Integration tests need tpm2-tools and a TPM; run them against swtpm
+        /// with `TPM2TOOLS_TCTI=swtpm:port=2321`. Skipped otherwise.
+        fn swtpm_available() -> bool {
+            std::env::var("TPM2TOOLS_TCTI").is_ok_and(|t| t.starts_with("swtpm"))
+                && Command::new("tpm2_getrandom").arg("8").stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok_and(|s| s.success())
+        }
+
+        #[test]
+        fn sealed_secrets_unseal_on_the_same_tpm() {
+            if !swtpm_available() { eprintln!("skipping: no swtpm (set TPM2TOOLS_TCTI=swtpm:...)"); return; }
+            let blob = seal_secret(b"vault key material").unwrap();
+            assert!(!blob.windows(18).any(|w| w == b"vault key material"), "blob must not carry the secret in the clear");
+            assert_eq!(unseal_secret(&blob).unwrap(), b"vault key material");
+
+            let mut tampered: SealedBlob = serde_json::from_slice(&blob).unwrap();
+            let mut private = general_purpose::STANDARD.decode(&tampered.private).unwrap();
+            let last = private.len() - 1;
+            private[last] ^= 0x01;
+            tampered.private = general_purpose::STANDARD.encode(private);
+            assert!(unseal_secret(&serde_json::to_vec(&tampered).unwrap()).is_err());
+            assert!(unseal_secret(b"{}").is_err());
+        }
+
+        #[test]
+        fn the_first_sealed_key_wins_and_is_never_replaced() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join(VAULT_KEY_FILE);
+            assert!(create_new(&path, b"first").unwrap());
+            assert!(!create_new(&path, b"second").unwrap());
+            assert_eq!(fs::read(&path).unwrap(), b"first");
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "no temp files left behind");
+        }
     }
 }
 
+// Sealing is public for other secrets that should stay on this machine; the
+// File vault key is the only one the agent seals itself so far.
+#[cfg(feature = "tpm")]
+#[allow(unused_imports)]
+pub use sealed::{seal_secret, unseal_secret, vault_key};
+
 #[cfg(not(feature = "tpm"))]
-pub mod tpm {
-    use anyhow::{Result, anyhow};
-    pub fn seal_secret(_data: &[u8]) -> Result<Vec<u8>> { Err(anyhow!("TPM feature not enabled")) }
-    pub fn unseal_secret(_blob: &[u8]) -> Result<Vec<u8>> { Err(anyhow!("TPM feature not enabled")) }
-} 
\ No newline at end of file
+#[allow(dead_code)]
+pub fn seal_secret(_data: &[u8]) -> anyhow::Result<Vec<u8>> { Err(anyhow::anyhow!("TPM feature not enabled")) }
+#[cfg(not(feature = "tpm"))]
+#[allow(dead_code)]
+pub fn unseal_secret(_blob: &[u8]) -> anyhow::Result<Vec<u8>> { Err(anyhow::anyhow!("TPM feature not enabled")) }
+#[cfg(not(feature = "tpm"))]
+pub fn vault_key() -> anyhow::Result<zeroize::Zeroizing<[u8; 32]>> { Err(anyhow::anyhow!("TPM feature not enabled")) }
@@ -0,0 +1,190 @@
+use anyhow::{Result, anyhow};
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use serde::{Serialize, Deserialize};
+use std::{fs, path::{Path, PathBuf}};
+
+/// What the last `update-apply` replaced, so `update-rollback` can undo it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateRecord {
+    pub previous_version: String,
+    pub applied_version: String,
+    /// Copy of the binary that was running before the update.
+    pub backup: PathBuf,
+    pub target: PathBuf,
+    pub applied_at: String,
+}
+
+/// Puts a new binary in place of the installed one. Abstracted so the
+/// bookkeeping can be tested without replacing a real executable.
+pub trait BinarySwap {
+    /// Replace `target` with `binary`, leaving the old `target` at `backup`.
+    fn swap_in(&self, binary: &[u8], target: &Path, backup: &Path) -> Result<()>;
+}
+
+pub struct FsSwap;
+
+impl BinarySwap for FsSwap {
+    #[cfg(not(windows))]
+    fn swap_in(&self, binary: &[u8], target: &Path, backup: &Path) -> Result<()> {
+        use std::{io::Write, os::unix::fs::OpenOptionsExt};
+        fs::copy(target, backup)?;
+        // Stage beside the target so the final rename stays on one filesystem
+        // and is atomic; the running process keeps its old inode. A fresh file,
+        // so nothing left at the staging path is written through.
+        let tmp = target.with_extension("new");
+        let _ = fs::remove_file(&tmp);
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o755).open(&tmp)?;
+        file.write_all(binary)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, target)?;
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn swap_in(&self, binary: &[u8], target: &Path, backup: &Path) -> Result<()> {
+        // A running .exe can't be overwritten but can be renamed; the new binary
+        // takes its place and is picked up on the next start.
+        if backup.exists() { fs::remove_file(backup)?; }
+        fs::rename(target, backup)?;
+        if let Err(e) = fs::write(target, binary) {
+            let _ = fs::rename(backup, target);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+fn update_dir() -> Result<PathBuf> {
+    crate::paths::state_subdir("updates")
+}
+
+/// Key that signs release binaries, from the flag or `PEA_UPDATE_PUBKEY`.
+pub fn update_key(arg: Option<&String>) -> Result<PublicKey> {
+    let b64 = arg.cloned().or_else(|| std::env::var("PEA_UPDATE_PUBKEY").ok())
+        .ok_or_else(|| anyhow!("no update signing key: pass --update-key or set PEA_UPDATE_PUBKEY"))?;
+    let bytes = general_purpose::STANDARD.decode(b64.trim())?;
+    PublicKey::from_bytes(&bytes).map_err(|e| anyhow!("invalid update key: {}", e))
+}
+
+/// Read a staged binary and check it against its detached base64 ed25519
+/// signature. Returns the bytes that verified, which are what gets installed:
+/// the staged file may change after this reads it.
+pub fn verify_staged(staged: &Path, signature: &Path, key: &PublicKey) -> Result<Vec<u8>> {
+    let binary = fs::read(staged).map_err(|e| anyhow!("staged binary {:?}: {}", staged, e))?;
+    let sig_b64 = fs::read_to_string(signature).map_err(|e| anyhow!("signature {:?}: {}", signature, e))?;
+    let sig = Signature::from_bytes(&general_purpose::STANDARD.decode(sig_b64.trim())?)
+        .map_err(|e| anyhow!("malformed signature: {}", e))?;
+    key.verify(&binary, &sig).map_err(|_| anyhow!("staged binary signature does not verify; not applying"))?;
+    Ok(binary)
+}
+
+pub fn apply(staged: &Path, signature: &Path, key: &PublicKey, version: &str) -> Result<UpdateRecord> {
+    apply_in(&update_dir()?, &std::env::current_exe()?, staged, signature, key, version, &FsSwap)
+}
+
+fn apply_in(dir: &Path, target: &Path, staged: &Path, signature: &Path, key: &PublicKey, version: &str, swap: &dyn BinarySwap) -> Result<UpdateRecord> {
+    // Verified again here even if update-check already did: the staged file may
+    // have changed since.
+    let binary = verify_staged(staged, signature, key)?;
+    let previous_version = env!("CARGO_PKG_VERSION").to_string();
+    let backup = dir.join(format!("pea-agent-{}.bak", previous_version));
+    swap.swap_in(&binary, target, &backup)?;
+    let record = UpdateRecord {
+        previous_version,
+        applied_version: version.to_string(),
+        backup,
+        target: target.to_path_buf(),
+        applied_at: chrono::Utc::now().to_rfc3339(),
+    };
+    fs::write(dir.join("last-update.json"), serde_json::to_vec_pretty(&record)?)?;
+    Ok(record)
+}
+
+pub fn rollback() -> Result<UpdateRecord> {
+    rollback_in(&update_dir()?, &FsSwap)
+}
+
+fn rollback_in(dir: &Path, swap: &dyn BinarySwap) -> Result<UpdateRecord> {
+    let record_path = dir.join("last-update.json");
+    let record: UpdateRecord = serde_json::from_slice(&fs::read(&record_path).map_err(|_| anyhow!("no applied update to roll back"))?)?;
+    if !record.backup.exists() { return Err(anyhow!("backup {:?} is missing, cannot roll back", record.backup)); }
+    let discarded = dir.join(format!("pea-agent-{}.rolled-back", record.applied_version));
+    let previous = fs::read(&record.backup)?;
+    swap.swap_in(&previous, &record.target, &discarded)?;
+    fs::remove_file(&record_path)?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, Signer};
+    use std::cell::RefCell;
+
+    /// Records swaps and moves plain files around like the real thing.
+    #[derive(Default)]
+    struct FakeSwap { calls: RefCell<Vec<(PathBuf, PathBuf)>>, tamper: Option<PathBuf> }
+
+    impl BinarySwap for FakeSwap {
+        fn swap_in(&self, binary: &[u8], target: &Path, backup: &Path) -> Result<()> {
+            self.calls.borrow_mut().push((target.into(), backup.into()));
+            if let Some(staged) = &self.tamper { fs::write(staged, b"swapped-after-verification")?; }
+            fs::copy(target, backup)?;
+            fs::write(target, binary)?;
+            Ok(())
+        }
+    }
+
+    fn staged(dir: &Path, kp: &Keypair, body: &[u8]) -> (PathBuf, PathBuf) {
+        let bin = dir.join("pea-agent.staged");
+        let sig = dir.join("pea-agent.staged.sig");
+        fs::write(&bin, body).unwrap();
+        fs::write(&sig, general_purpose::STANDARD.encode(kp.sign(body).to_bytes())).unwrap();
+        (bin, sig)
+    }
+
+    #[test]
+    fn apply_records_previous_version_and_rollback_restores_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let target = dir.path().join("pea-agent");
+        fs::write(&target, b"old-binary").unwrap();
+        let (bin, sig) = staged(dir.path(), &kp, b"new-binary");
+        let swap = FakeSwap::default();
+
+        let record = apply_in(dir.path(), &target, &bin, &sig, &kp.public, "0.3.0", &swap).unwrap();
+        assert_eq!(record.previous_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(record.applied_version, "0.3.0");
+        assert_eq!(fs::read(&target).unwrap(), b"new-binary");
+        assert_eq!(fs::read(&record.backup).unwrap(), b"old-binary");
+
+        let rolled = rollback_in(dir.path(), &swap).unwrap();
+        assert_eq!(rolled, record);
+        assert_eq!(fs::read(&target).unwrap(), b"old-binary");
+        assert!(rollback_in(dir.path(), &swap).is_err(), "nothing left to roll back");
+    }
+
+    #[test]
+    fn unsigned_or_tampered_binary_is_not_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let kp = Keypair::generate(&mut rand::rngs::OsRng);
+        let target = dir.path().join("pea-agent");
+        fs::write(&target, b"old-binary").unwrap();
+        let (bin, sig) = staged(dir.path(), &kp, b"new-binary");
+        fs::write(&bin, b"new-binary-with-implant").unwrap();
+        let swap = FakeSwap::default();
+
+        assert!(apply_in(dir.path(), &target, &bin, &sig, &kp.public, "0.3.0", &swap).is_err());
+        assert!(swap.calls.borrow().is_empty());
+        assert_eq!(fs::read(&target).unwrap(), b"old-binary");
+        assert!(!dir.path().join("last-update.json").exists());
+
+        // Replacing the staged file once it has verified changes nothing.
+        let (bin, sig) = staged(dir.path(), &kp, b"new-binary");
+        let racing = FakeSwap { tamper: Some(bin.clone()), ..Default::default() };
+        apply_in(dir.path(), &target, &bin, &sig, &kp.public, "0.3.0", &racing).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new-binary");
+    }
+}
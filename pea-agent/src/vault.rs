@@ -1,140 +1,939 @@
 use anyhow::{Result, anyhow};
 use keyring::Entry;
 use sha2::{Sha256, Digest};
-use aead::{Aead, KeyInit};
+use aead::{Aead, KeyInit, Payload};
 use aes_gcm::{Aes256Gcm, Nonce};
-use std::{fs, path::PathBuf};
-use directories::ProjectDirs;
+use std::{fmt, fs, path::{Path, PathBuf}, sync::{Arc, OnceLock, atomic::{AtomicBool, Ordering}}};
 use base64::{engine::general_purpose, Engine as _};
+use zeroize::{Zeroize, Zeroizing};
 
-#[derive(Clone, Copy)]
+/// Process-wide rules for how tolerant secret storage is of backend failures.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VaultPolicy {
+    /// Never fall back from the preferred backend to another one (`--strict`).
+    pub strict: bool,
+    /// Permit falling back to the File backend, whose key is derived from the
+    /// hostname and username (`--allow-insecure-vault`). Selecting the File
+    /// backend explicitly with `PEA_VAULT_BACKEND=file`, or keying it with an
+    /// installer secret, doesn't need this.
+    pub allow_insecure_file: bool,
+}
+
+impl VaultPolicy {
+    /// Whether `vault` may be used as a fallback for an unusable preferred backend.
+    fn check_fallback(self, vault: &Vault, cause: &anyhow::Error) -> Result<()> {
+        if self.strict {
+            return Err(anyhow!("preferred vault backend unusable and --strict forbids fallback: {}", cause));
+        }
+        if vault.backend == VaultBackend::File && !self.allow_insecure_file && !vault.installer_secret().is_ok_and(|s| s.is_some()) {
+            return Err(anyhow!("preferred vault backend unusable ({}); refusing the weak file vault without --allow-insecure-vault", cause));
+        }
+        Ok(())
+    }
+}
+
+static POLICY: OnceLock<VaultPolicy> = OnceLock::new();
+
+/// Install the vault policy; call once at startup before any secret is loaded.
+pub fn set_policy(policy: VaultPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+fn policy() -> VaultPolicy {
+    POLICY.get().copied().unwrap_or_default()
+}
+
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+
+/// True once a secret write had to fail over from the preferred backend during
+/// this run (e.g. the keyring went away after a session change).
+pub fn degraded() -> bool {
+    DEGRADED.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum VaultBackend {
     OsKeyring,
     File,
+    /// Read-only directory of mounted secret files (Kubernetes/Docker secrets),
+    /// one file per account holding the raw secret bytes.
+    SecretsDir { path: PathBuf },
+    /// Encrypted files like `File`, under a random key that DPAPI protects for
+    /// the current user (`PEA_VAULT_BACKEND=dpapi`, Windows only).
+    Dpapi,
 }
 
+/// DPAPI-protected key of the Dpapi backend, next to its secret files.
+const DPAPI_KEY_FILE: &str = "vault-key.dpapi";
+
+/// Vault files start with this magic and a format version. [`FILE_V1`] is a
+/// nonce and ciphertext under the backend key; [`FILE_V2`] and [`FILE_V3`]
+/// put the device salt first and use the installer key. V1 and V3 authenticate
+/// [`Vault::aad`], so a file copied over another account's fails to decrypt.
+/// V2 and headerless files (nonce and ciphertext under the host- or TPM-derived
+/// key) predate that and are re-sealed when loaded.
+const FILE_MAGIC: &[u8; 4] = b"PEAV";
+const FILE_V1: u8 = 1;
+const FILE_V2: u8 = 2;
+const FILE_V3: u8 = 3;
+const SALT_LEN: usize = 16;
+/// Per-device Argon2id salt, generated once next to the vault files.
+const SALT_FILE: &str = "vault.salt";
+
 pub struct Vault {
     backend: VaultBackend,
     service: String,
     account: String,
+    dir: Option<PathBuf>,
+    entry: Option<Arc<Entry>>,
+    installer_secret: Option<Zeroizing<Vec<u8>>>,
 }
 
-impl Vault {
-    pub fn auto(service: &str, account: &str) -> Self {
-        Self { backend: VaultBackend::OsKeyring, service: service.to_string(), account: account.to_string() }
+/// A 32-byte key copied out of `bytes`, wiped on drop.
+pub(crate) fn key32(bytes: &[u8]) -> Option<Zeroizing<[u8; 32]>> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    if bytes.len() != key.len() { return None; }
+    key.copy_from_slice(bytes);
+    Some(key)
+}
+
+/// A backend holds no secret for the account. The one load error after which
+/// a fresh secret may be generated: a secret that fails to decrypt, or can't be
+/// read, is surfaced instead of being replaced.
+#[derive(Debug)]
+pub struct NotFound {
+    pub backend: &'static str,
+    pub account: String,
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no {} secret in the {} vault", self.account, self.backend)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.is::<NotFound>()
+}
+
+/// Delete `path`; one that is already gone counts as deleted.
+fn remove_if_present(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(anyhow!("removing {:?}: {}", path, e)),
+        _ => Ok(()),
     }
+}
 
+/// Keyring errors that say nothing about whether the entry exists: the store
+/// was busy, locked or unreachable (the macOS keychain briefly refuses access
+/// while another process, e.g. a manual `status` next to the run loop, holds
+/// it). The caller retries later; a fresh secret must never be generated.
+fn is_transient(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<keyring::Error>(), Some(keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_)))
+}
+
+impl Vault {
     pub fn with_backend(service: &str, account: &str, backend: VaultBackend) -> Self {
-        Self { backend, service: service.to_string(), account: account.to_string() }
+        Self { backend, service: service.to_string(), account: account.to_string(), dir: None, entry: None, installer_secret: None }
     }
 
-    fn file_path(&self) -> Result<PathBuf> {
-        let proj = ProjectDirs::from("com","kmp","pea-agent").ok_or_else(|| anyhow!("no project dirs"))?;
-        let dir = proj.data_dir().to_path_buf();
+    /// Keep File backend secrets under `dir` instead of the project data dir.
+    #[cfg(test)]
+    pub fn in_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Use `entry` for keyring access instead of opening one per call.
+    #[cfg(test)]
+    pub fn with_keyring_entry(mut self, entry: Entry) -> Self {
+        self.entry = Some(Arc::new(entry));
+        self
+    }
+
+    /// Key File backend secrets with `secret` instead of `PEA_INSTALLER_SECRET`.
+    #[cfg(test)]
+    pub fn with_installer_secret(mut self, secret: &[u8]) -> Self {
+        self.installer_secret = Some(Zeroizing::new(secret.to_vec()));
+        self
+    }
+
+    /// Secret the installer provisioned for keying the File backend, from
+    /// `PEA_INSTALLER_SECRET` or the file named by `PEA_INSTALLER_SECRET_FILE`,
+    /// which must not be readable by other users.
+    fn installer_secret(&self) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        if let Some(secret) = &self.installer_secret { return Ok(Some(secret.clone())); }
+        if let Some(secret) = std::env::var("PEA_INSTALLER_SECRET").ok().filter(|v| !v.is_empty()) {
+            return Ok(Some(Zeroizing::new(secret.into_bytes())));
+        }
+        let Some(path) = std::env::var_os("PEA_INSTALLER_SECRET_FILE").filter(|v| !v.is_empty()).map(PathBuf::from) else { return Ok(None) };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(anyhow!("installer secret file {:?} is accessible to other users (mode {:o}); restrict it to 600", path, mode & 0o777));
+            }
+        }
+        let mut secret = Zeroizing::new(fs::read(&path)?);
+        let len = secret.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+        if len == 0 { return Err(anyhow!("installer secret file {:?} is empty", path)); }
+        secret.truncate(len);
+        Ok(Some(secret))
+    }
+
+    /// This device's Argon2id salt, generated and persisted on first use.
+    fn device_salt(&self) -> Result<[u8; SALT_LEN]> {
+        let path = self.dir()?.join(SALT_FILE);
+        match fs::read(&path) {
+            Ok(salt) => salt.try_into().map_err(|_| anyhow!("vault salt {:?} is not {} bytes", path, SALT_LEN)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let salt = rand::random::<[u8; SALT_LEN]>();
+                crate::paths::write_atomic(&path, &salt)?;
+                Ok(salt)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn installer_key(secret: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        argon2::Argon2::default().hash_password_into(secret, salt, key.as_mut()).map_err(|e| anyhow!("argon2: {}", e))?;
+        Ok(key)
+    }
+
+    /// Decrypt a [`FILE_V2`] or [`FILE_V3`] body (salt, nonce, ciphertext).
+    fn open_installer(&self, path: &std::path::Path, body: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if body.len() < SALT_LEN + 12 { return Err(anyhow!("vault file {:?} truncated", path)); }
+        let (salt, sealed) = body.split_at(SALT_LEN);
+        let secret = self.installer_secret()?.ok_or_else(|| anyhow!("vault file {:?} is keyed by the installer secret; set PEA_INSTALLER_SECRET", path))?;
+        Self::open(&*Self::installer_key(&secret, salt)?, sealed, aad)
+    }
+
+    /// Associated data binding a vault file to its backend, service and account.
+    fn aad(&self) -> Vec<u8> {
+        format!("pea-vault/{}/{}/{}", self.backend_name(), self.service, self.account).into_bytes()
+    }
+
+    fn keyring_entry(&self) -> Result<Arc<Entry>> {
+        match &self.entry {
+            Some(entry) => Ok(entry.clone()),
+            None => Ok(Arc::new(Entry::new(&self.service, &self.account)?)),
+        }
+    }
+
+    fn dir(&self) -> Result<PathBuf> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => crate::paths::state_dir()?,
+        };
         fs::create_dir_all(&dir)?;
-        Ok(dir.join(format!("{}.bin", self.account)))
+        Ok(dir)
+    }
+
+    fn file_path(&self) -> Result<PathBuf> {
+        Ok(self.dir()?.join(format!("{}.bin", self.account)))
+    }
+
+    fn dpapi_path(&self) -> Result<PathBuf> {
+        Ok(self.dir()?.join(format!("{}.dpapi", self.account)))
     }
 
     fn safe_hostname() -> String {
         whoami::fallible::hostname().unwrap_or_else(|_| "unknown-host".to_string())
     }
 
-    fn file_key() -> [u8; 32] {
+    /// Key of File backend secrets: sealed in the TPM when `PEA_VAULT_TPM=1`
+    /// in a `tpm` build, otherwise derived from hostname and username.
+    fn file_key() -> Result<Zeroizing<[u8; 32]>> {
+        if crate::tpm::vault_enabled() { crate::tpm::vault_key() } else { Ok(Self::host_key()) }
+    }
+
+    fn host_key() -> Zeroizing<[u8; 32]> {
         let mut h = Sha256::new();
         h.update(Self::safe_hostname());
         h.update(whoami::username());
-        let out = h.finalize();
-        let mut key = [0u8;32];
+        let mut out = h.finalize();
+        let mut key = Zeroizing::new([0u8;32]);
         key.copy_from_slice(&out);
+        out.zeroize();
         key
     }
 
+    /// Key of Dpapi backend secrets, generated and protected on first store.
+    fn dpapi_key(&self, create: bool) -> Result<Zeroizing<[u8; 32]>> {
+        let path = self.dir()?.join(DPAPI_KEY_FILE);
+        let key = match fs::read(&path) {
+            Ok(blob) => Zeroizing::new(crate::dpapi::unprotect(&blob)?),
+            Err(e) if create && e.kind() == std::io::ErrorKind::NotFound => {
+                let key = Zeroizing::new(rand::random::<[u8; 32]>().to_vec());
+                crate::paths::write_atomic(&path, &crate::dpapi::protect(&key)?)?;
+                key
+            }
+            Err(e) => return Err(e.into()),
+        };
+        key32(&key).ok_or_else(|| anyhow!("dpapi vault key in {:?} is not 32 bytes", path))
+    }
+
+    /// Nonce followed by the AES-GCM ciphertext of `data`, authenticating `aad`.
+    fn seal(key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+        let nonce_bytes = rand::random::<[u8;12]>();
+        let nonce = Nonce::from_slice(&nonce_bytes);
+        let ct = cipher.encrypt(nonce, Payload { msg: data, aad }).map_err(|_| anyhow!("encrypt failed"))?;
+        let mut out = Vec::with_capacity(12 + ct.len());
+        out.extend_from_slice(&nonce_bytes);
+        out.extend_from_slice(&ct);
+        Ok(out)
+    }
+
+    fn open(key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < 12 { return Err(anyhow!("vault file truncated")); }
+        let (nonce_bytes, ct) = data.split_at(12);
+        Aes256Gcm::new_from_slice(key).unwrap().decrypt(Nonce::from_slice(nonce_bytes), Payload { msg: ct, aad }).map_err(|_| anyhow!("decrypt failed"))
+    }
+
+    /// A [`FILE_V1`] file of `data` under the backend key `key`.
+    fn seal_v1(&self, key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = [FILE_MAGIC.as_slice(), &[FILE_V1]].concat();
+        out.extend(Self::seal(key, data, &self.aad())?);
+        Ok(out)
+    }
+
+    /// Decrypt a backend-key file: [`FILE_V1`], or headerless without
+    /// associated data. Also says whether the file predates the current format
+    /// or key and should be re-sealed. `fallback` is an older key to try.
+    fn open_backend(&self, data: &[u8], key: &[u8; 32], fallback: Option<Zeroizing<[u8; 32]>>) -> Result<(Vec<u8>, bool)> {
+        let (sealed, aad, current) = match data.strip_prefix([FILE_MAGIC.as_slice(), &[FILE_V1]].concat().as_slice()) {
+            Some(sealed) => (sealed, self.aad(), true),
+            None => (data, Vec::new(), false),
+        };
+        match (Self::open(key, sealed, &aad), fallback) {
+            (Err(_), Some(old)) => Ok((Self::open(&old, sealed, &aad)?, true)),
+            (res, _) => Ok((res?, !current)),
+        }
+    }
+
+    fn not_found(&self) -> anyhow::Error {
+        NotFound { backend: self.backend_name(), account: self.account.clone() }.into()
+    }
+
+    /// `fs::read`, with a missing file as [`NotFound`].
+    fn read_file(&self, path: &std::path::Path) -> Result<Vec<u8>> {
+        match fs::read(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(self.not_found()),
+            res => Ok(res?),
+        }
+    }
+
+    /// Contents of a sealed vault file, clearing a stale interrupted write.
+    fn read_sealed(&self, path: &std::path::Path) -> Result<Vec<u8>> {
+        crate::paths::remove_stale_tmps(path);
+        let data = self.read_file(path)?;
+        if data.len() < 12 { return Err(anyhow!("vault file {:?} truncated", path)); }
+        Ok(data)
+    }
+
     pub fn store_secret(&self, data: &[u8]) -> Result<()> {
-        match self.backend {
+        match &self.backend {
             VaultBackend::OsKeyring => {
-                let entry = Entry::new(&self.service, &self.account)?;
-                entry.set_password(&general_purpose::STANDARD.encode(data))?;
-                Ok(())
+                let entry = self.keyring_entry()?;
+                let encoded = general_purpose::STANDARD.encode(data);
+                Ok(entry.set_password(&encoded)?)
             }
             VaultBackend::File => {
-                let key = Self::file_key();
-                let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
-                let nonce_bytes = rand::random::<[u8;12]>();
-                let nonce = Nonce::from_slice(&nonce_bytes);
-                let ct = cipher.encrypt(nonce, data).map_err(|_| anyhow!("encrypt failed"))?;
-                let mut out = Vec::with_capacity(12 + ct.len());
-                out.extend_from_slice(&nonce_bytes);
-                out.extend_from_slice(&ct);
-                fs::write(self.file_path()?, out)?;
-                Ok(())
+                let out = match self.installer_secret()? {
+                    Some(secret) => {
+                        let salt = self.device_salt()?;
+                        let mut out = [FILE_MAGIC.as_slice(), &[FILE_V3], &salt].concat();
+                        out.extend(Self::seal(&*Self::installer_key(&secret, &salt)?, data, &self.aad())?);
+                        out
+                    }
+                    None => self.seal_v1(&*Self::file_key()?, data)?,
+                };
+                crate::paths::write_atomic(&self.file_path()?, &out)
             }
+            VaultBackend::Dpapi => crate::paths::write_atomic(&self.dpapi_path()?, &self.seal_v1(&*self.dpapi_key(true)?, data)?),
+            VaultBackend::SecretsDir { path } => Err(anyhow!("secrets dir {:?} is read-only", path)),
+        }
+    }
+
+    /// Re-seal a secret that was read under an outdated format or key. The
+    /// read already succeeded, so a failed write only leaves the old sealing in
+    /// place for the next load to retry.
+    fn reseal(&self, pt: &[u8]) {
+        if let Err(e) = self.store_secret(pt) {
+            tracing::warn!(account = %self.account, error = %e, "vault entry not re-sealed, keeping the old sealing");
         }
     }
 
     pub fn load_secret(&self) -> Result<Vec<u8>> {
-        match self.backend {
+        match &self.backend {
             VaultBackend::OsKeyring => {
-                let entry = Entry::new(&self.service, &self.account)?;
-                let val = entry.get_password()?;
-                let bytes = general_purpose::STANDARD.decode(val)?;
+                let entry = self.keyring_entry()?;
+                let val = match entry.get_password() {
+                    Ok(val) => Zeroizing::new(val),
+                    Err(keyring::Error::NoEntry) => return Err(self.not_found()),
+                    Err(e) => return Err(e.into()),
+                };
+                // Left behind by deletes that could only blank the entry.
+                if val.is_empty() { return Err(self.not_found()); }
+                let bytes = general_purpose::STANDARD.decode(val.as_bytes())?;
                 Ok(bytes)
             }
             VaultBackend::File => {
-                let key = Self::file_key();
-                let data = fs::read(self.file_path()?)?;
-                let (nonce_bytes, ct) = data.split_at(12);
-                let nonce = Nonce::from_slice(nonce_bytes);
-                let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
-                let pt = cipher.decrypt(nonce, ct).map_err(|_| anyhow!("decrypt failed"))?;
+                let path = self.file_path()?;
+                let data = self.read_sealed(&path)?;
+                let (pt, stale) = match data.strip_prefix(FILE_MAGIC.as_slice()).and_then(|b| b.split_first()) {
+                    Some((&FILE_V3, body)) => return self.open_installer(&path, body, &self.aad()),
+                    Some((&FILE_V2, body)) => (self.open_installer(&path, body, b"")?, true),
+                    _ => {
+                        let fallback = crate::tpm::vault_enabled().then(Self::host_key);
+                        let (pt, stale) = self.open_backend(&data, &*Self::file_key()?, fallback)?;
+                        (pt, stale || self.installer_secret()?.is_some())
+                    }
+                };
+                // Written in an older format or before the key moved into the
+                // TPM or to the installer secret: re-seal under the current one.
+                if stale { self.reseal(&pt); }
+                Ok(pt)
+            }
+            VaultBackend::Dpapi => {
+                let data = self.read_sealed(&self.dpapi_path()?)?;
+                let (pt, stale) = self.open_backend(&data, &*self.dpapi_key(false)?, None)?;
+                if stale { self.reseal(&pt); }
                 Ok(pt)
             }
+            VaultBackend::SecretsDir { path } => {
+                let mut data = self.read_file(&path.join(&self.account))?;
+                // Mounted text secrets usually end in a newline the writer
+                // added; raw key bytes are left alone.
+                if std::str::from_utf8(&data).is_ok() {
+                    let trimmed = data.strip_suffix(b"\r\n").or_else(|| data.strip_suffix(b"\n")).map_or(data.len(), <[u8]>::len);
+                    data.truncate(trimmed);
+                }
+                Ok(data)
+            }
         }
     }
 
     pub fn delete_secret(&self) -> Result<()> {
-        match self.backend {
+        match &self.backend {
             VaultBackend::OsKeyring => {
-                let entry = Entry::new(&self.service, &self.account)?;
-                // keyring crate lacks delete on some platforms; overwrite to empty
-                let _ = entry.set_password("");
-                Ok(())
-            }
-            VaultBackend::File => {
-                let path = self.file_path()?;
-                if path.exists() { let _ = fs::remove_file(path); }
-                Ok(())
+                let entry = self.keyring_entry()?;
+                match entry.delete_password() {
+                    Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                    // The store can't delete here: scrub the value instead, so at
+                    // worst an empty entry is left, never the old secret.
+                    Err(e) => {
+                        tracing::warn!(account = %self.account, error = %e, "keyring delete failed, overwriting the entry instead");
+                        let noise = general_purpose::STANDARD.encode(rand::random::<[u8; 32]>());
+                        entry.set_password(&noise)?;
+                        Ok(entry.set_password("")?)
+                    }
+                }
             }
+            VaultBackend::File => remove_if_present(&self.file_path()?),
+            VaultBackend::Dpapi => remove_if_present(&self.dpapi_path()?),
+            VaultBackend::SecretsDir { path } => Err(anyhow!("secrets dir {:?} is read-only", path)),
         }
     }
 
     pub fn select_backend() -> VaultBackend {
         match std::env::var("PEA_VAULT_BACKEND").ok().as_deref() {
             Some("file") => VaultBackend::File,
+            Some("dpapi") => VaultBackend::Dpapi,
             _ => VaultBackend::OsKeyring,
         }
     }
 
-    pub fn load_or_store_secret_auto(service: &str, account: &str, generator: impl Fn() -> Vec<u8>) -> Result<Vec<u8>> {
-        let preferred = Self::select_backend();
-        let primary = Vault::with_backend(service, account, preferred);
-        match primary.load_secret() {
-            Ok(bytes) => return Ok(bytes),
-            Err(_) => {
-                let bytes = generator();
-                if primary.store_secret(&bytes).is_ok() {
-                    return Ok(bytes);
+    /// Writable backends in the order new secrets are stored.
+    pub fn write_backends() -> Vec<VaultBackend> {
+        match Self::select_backend() {
+            VaultBackend::File => vec![VaultBackend::File, VaultBackend::OsKeyring],
+            VaultBackend::Dpapi => vec![VaultBackend::Dpapi, VaultBackend::OsKeyring],
+            _ => vec![VaultBackend::OsKeyring, VaultBackend::File],
+        }
+    }
+
+    /// Mounted secrets directory from `PEA_SECRETS_DIR`, if configured.
+    pub fn secrets_dir() -> Option<PathBuf> {
+        std::env::var_os("PEA_SECRETS_DIR").filter(|v| !v.is_empty()).map(PathBuf::from)
+    }
+
+    /// Backends consulted when reading a secret, in priority order.
+    pub fn read_backends() -> Vec<VaultBackend> {
+        let mut backends = Vec::new();
+        if let Some(path) = Self::secrets_dir() { backends.push(VaultBackend::SecretsDir { path }); }
+        if Self::select_backend() == VaultBackend::Dpapi { backends.push(VaultBackend::Dpapi); }
+        backends.push(VaultBackend::OsKeyring);
+        backends.push(VaultBackend::File);
+        backends
+    }
+
+    pub fn load_or_store_secret_auto(service: &str, account: &str, generator: impl Fn() -> Vec<u8>) -> Result<Zeroizing<Vec<u8>>> {
+        let mounted = Self::secrets_dir().map(|path| Vault::with_backend(service, account, VaultBackend::SecretsDir { path }));
+        let writable: Vec<Vault> = Self::write_backends().into_iter().map(|b| Vault::with_backend(service, account, b)).collect();
+        Self::adopt_for_selected(service, account, &writable)?;
+        Self::load_or_store_secret_in(mounted.as_ref(), &writable, policy(), generator)
+    }
+
+    /// Load a secret without ever generating one; see [`Vault::load_secret_in`].
+    pub fn load_secret_auto(service: &str, account: &str) -> Result<Zeroizing<Vec<u8>>> {
+        let mounted = Self::secrets_dir().map(|path| Vault::with_backend(service, account, VaultBackend::SecretsDir { path }));
+        let writable: Vec<Vault> = Self::write_backends().into_iter().map(|b| Vault::with_backend(service, account, b)).collect();
+        Self::adopt_for_selected(service, account, &writable)?;
+        Self::load_secret_in(mounted.as_ref(), &writable, policy())
+    }
+
+    /// With `dpapi` selected on a device provisioned under the default
+    /// backends, move its secret into DPAPI first; see [`Vault::adopt_into`].
+    fn adopt_for_selected(service: &str, account: &str, writable: &[Vault]) -> Result<()> {
+        if Self::select_backend() != VaultBackend::Dpapi { return Ok(()); }
+        let legacy = [VaultBackend::OsKeyring, VaultBackend::File].map(|b| Vault::with_backend(service, account, b));
+        Self::adopt_into(&writable[0], &legacy)
+    }
+
+    /// Move a secret kept in `legacy` into `target` when `target` has none, so
+    /// switching backends keeps the device's identity instead of generating a
+    /// new one. Fails, rather than letting a new secret be generated, when a
+    /// legacy copy can't be read or `target` can't store it.
+    fn adopt_into(target: &Vault, legacy: &[Vault]) -> Result<()> {
+        match target.load_secret() {
+            Err(e) if is_not_found(&e) => {}
+            _ => return Ok(()),
+        }
+        let mut found = None;
+        for v in legacy {
+            match v.load_secret() {
+                Ok(bytes) => { found = Some(Zeroizing::new(bytes)); break; }
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return Err(anyhow!("{} vault selected, but the {} vault may hold {} and can't be read: {}", target.backend_name(), v.backend_name(), target.account, e)),
+            }
+        }
+        let Some(secret) = found else { return Ok(()) };
+        target.store_secret(&secret)
+            .map_err(|e| anyhow!("{} vault selected, but the existing {} can't be moved into it: {}", target.backend_name(), target.account, e))?;
+        for v in legacy { let _ = v.delete_secret(); }
+        tracing::info!(account = %target.account, backend = target.backend_name(), "moved existing secret into the selected vault backend");
+        Ok(())
+    }
+
+    /// Store a secret with the current policy; see [`Vault::store_with_failover_in`].
+    pub fn store_with_failover(vaults: &[Vault], data: &[u8]) -> Result<()> {
+        Self::store_with_failover_in(vaults, policy(), data)
+    }
+
+    /// Write to the first vault that accepts the secret and clear it from the
+    /// others, so two backends never hold conflicting copies. Failing over past
+    /// the preferred vault, where the policy allows it, is logged and marks
+    /// storage degraded.
+    pub fn store_with_failover_in(vaults: &[Vault], policy: VaultPolicy, data: &[u8]) -> Result<()> {
+        let mut stored = false;
+        let mut last_err = anyhow!("no writable vault backend");
+        for (i, v) in vaults.iter().enumerate() {
+            if stored { let _ = v.delete_secret(); continue; }
+            if i > 0 { policy.check_fallback(v, &last_err)?; }
+            match v.store_secret(data) {
+                Ok(()) => {
+                    stored = true;
+                    if i > 0 {
+                        tracing::warn!(failed = vaults[0].backend_name(), error = %last_err, account = %v.account, backend = ?v.backend, "vault write failed over to another backend");
+                        DEGRADED.store(true, Ordering::Relaxed);
+                    }
                 }
+                Err(e) => last_err = e,
             }
         }
-        let fallback = match preferred { VaultBackend::OsKeyring => VaultBackend::File, VaultBackend::File => VaultBackend::OsKeyring };
-        let alt = Vault::with_backend(service, account, fallback);
-        match alt.load_secret() {
-            Ok(bytes) => Ok(bytes),
-            Err(_) => {
-                let bytes = generator();
-                alt.store_secret(&bytes)?;
-                Ok(bytes)
+        if stored { Ok(()) } else { Err(last_err) }
+    }
+
+    fn backend_name(&self) -> &'static str {
+        match self.backend {
+            VaultBackend::OsKeyring => "keyring",
+            VaultBackend::File => "file",
+            VaultBackend::SecretsDir { .. } => "secrets dir",
+            VaultBackend::Dpapi => "dpapi",
+        }
+    }
+
+    /// Load a secret, generating and storing it only where it is [`NotFound`].
+    /// A mounted secret wins; otherwise each writable vault is tried in order,
+    /// as far as the policy allows falling back from the first (preferred) one.
+    /// Once a vault fails transiently, later ones are only searched for an
+    /// existing copy: with the busy one unreadable, a fresh secret could replace
+    /// the device's identity, so its error is returned instead. Any other error,
+    /// such as a file that no longer decrypts, is returned as is. The secret is
+    /// wiped from memory when the caller drops it.
+    pub fn load_or_store_secret_in(mounted: Option<&Vault>, writable: &[Vault], policy: VaultPolicy, generator: impl Fn() -> Vec<u8>) -> Result<Zeroizing<Vec<u8>>> {
+        if let Some(v) = mounted {
+            match v.load_secret() {
+                Ok(bytes) => return Ok(Zeroizing::new(bytes)),
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut last_err = anyhow!("no writable vault backend");
+        let mut unavailable = None;
+        for (i, v) in writable.iter().enumerate() {
+            if i > 0 { policy.check_fallback(v, &last_err)?; }
+            match v.load_secret() {
+                Ok(bytes) => return Ok(Zeroizing::new(bytes)),
+                Err(e) if is_transient(&e) => {
+                    last_err = anyhow!("{}", e);
+                    unavailable.get_or_insert(e);
+                }
+                Err(e) if !is_not_found(&e) => return Err(e),
+                Err(_) if unavailable.is_some() => {}
+                Err(_) => {
+                    let bytes = Zeroizing::new(generator());
+                    match v.store_secret(&bytes) {
+                        Ok(()) => return Ok(bytes),
+                        Err(e) => last_err = e,
+                    }
+                }
+            }
+        }
+        Err(unavailable.unwrap_or(last_err))
+    }
+
+    /// Load a secret from the same places, in the same order, as
+    /// [`Vault::load_or_store_secret_in`], for callers that must not create
+    /// one; vaults the policy won't fall back to aren't searched. [`NotFound`]
+    /// when no vault holds it; a busy vault's error when the secret may be in it.
+    pub fn load_secret_in(mounted: Option<&Vault>, writable: &[Vault], policy: VaultPolicy) -> Result<Zeroizing<Vec<u8>>> {
+        if let Some(v) = mounted {
+            match v.load_secret() {
+                Ok(bytes) => return Ok(Zeroizing::new(bytes)),
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut last_err = anyhow!("no writable vault backend");
+        let mut unavailable = None;
+        for (i, v) in writable.iter().enumerate() {
+            if i > 0 && policy.check_fallback(v, &last_err).is_err() { break; }
+            match v.load_secret() {
+                Ok(bytes) => return Ok(Zeroizing::new(bytes)),
+                Err(e) if is_transient(&e) => {
+                    last_err = anyhow!("{}", e);
+                    unavailable.get_or_insert(e);
+                }
+                Err(e) if is_not_found(&e) => last_err = e,
+                Err(e) => return Err(e),
             }
         }
+        Err(unavailable.unwrap_or(last_err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_dir_backend_reads_mounted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("trust-ack-jwt"), b"a.b.c").unwrap();
+        let v = Vault::with_backend("kmp-pea", "trust-ack-jwt", VaultBackend::SecretsDir { path: dir.path().to_path_buf() });
+        assert_eq!(v.load_secret().unwrap(), b"a.b.c");
+        assert!(v.store_secret(b"new").is_err());
+        assert!(v.delete_secret().is_err());
+        assert_eq!(fs::read(dir.path().join("trust-ack-jwt")).unwrap(), b"a.b.c");
+
+        fs::write(dir.path().join("trust-ack-jwt"), b"a.b.c\n").unwrap();
+        assert_eq!(v.load_secret().unwrap(), b"a.b.c");
+        fs::write(dir.path().join("trust-ack-jwt"), b"a.b.c\r\n").unwrap();
+        assert_eq!(v.load_secret().unwrap(), b"a.b.c");
+        let key = [0xff, 0x10, b'\n'];
+        fs::write(dir.path().join("trust-ack-jwt"), key).unwrap();
+        assert_eq!(v.load_secret().unwrap(), key, "binary secrets are read as-is");
+    }
+
+    #[test]
+    fn interrupted_store_leaves_the_previous_secret_loadable() {
+        let dir = tempfile::tempdir().unwrap();
+        let v = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path());
+        v.store_secret(b"good secret").unwrap();
+        let path = v.file_path().unwrap();
+        let good = fs::read(&path).unwrap();
+
+        // Power lost halfway through staging a replacement: the temp file is
+        // truncated and was never renamed over the real one.
+        let tmp = crate::paths::tmp_path(&path);
+        fs::write(&tmp, &good[..good.len() / 2]).unwrap();
+        assert_eq!(v.load_secret().unwrap(), b"good secret");
+        assert!(tmp.exists(), "a fresh temp file may still be mid-write");
+        fs::File::options().write(true).open(&tmp).unwrap().set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(120)).unwrap();
+        assert_eq!(v.load_secret().unwrap(), b"good secret");
+        assert!(!tmp.exists(), "stale temp file cleaned up");
+
+        v.store_secret(b"rotated").unwrap();
+        assert_eq!(v.load_secret().unwrap(), b"rotated");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        fs::write(&path, b"short").unwrap();
+        assert!(v.load_secret().unwrap_err().to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn auto_load_prefers_secrets_dir_without_generating() {
+        let dir = tempfile::tempdir().unwrap();
+        let sk = [7u8; 32];
+        fs::write(dir.path().join("device-ed25519-sk"), sk).unwrap();
+        let mounted = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::SecretsDir { path: dir.path().to_path_buf() });
+        let bytes = Vault::load_or_store_secret_in(Some(&mounted), &[], VaultPolicy::default(), || {
+            panic!("generator must not run when the secret is mounted")
+        }).unwrap();
+        assert_eq!(*bytes, sk);
+    }
+
+    #[test]
+    fn load_only_finds_an_existing_secret_and_never_creates_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = unwritable_then_file(dir.path());
+        let err = Vault::load_secret_in(None, &vaults, INSECURE_OK).unwrap_err();
+        assert!(is_not_found(&err), "{}", err);
+        assert!(is_not_found(&vaults[1].load_secret().unwrap_err()));
+
+        vaults[1].store_secret(&[9u8; 32]).unwrap();
+        assert_eq!(*Vault::load_secret_in(None, &vaults, INSECURE_OK).unwrap(), vec![9u8; 32]);
+        let strict = Vault::load_secret_in(None, &vaults, VaultPolicy { strict: true, ..Default::default() }).unwrap_err();
+        assert!(is_not_found(&strict), "no fallback, so the copy in the file vault isn't looked at: {}", strict);
+    }
+
+    // A read-only secrets dir stands in for a preferred backend that can't store.
+    fn unwritable_then_file(dir: &std::path::Path) -> Vec<Vault> {
+        vec![
+            Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::SecretsDir { path: dir.join("ro") }),
+            Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir),
+        ]
     }
-} 
\ No newline at end of file
+
+    const INSECURE_OK: VaultPolicy = VaultPolicy { strict: false, allow_insecure_file: true };
+
+    #[test]
+    fn a_newly_selected_backend_adopts_the_existing_secret() {
+        let (old, new) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let file = |dir: &std::path::Path| Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir);
+        let legacy = [file(old.path())];
+        legacy[0].store_secret(&[5u8; 32]).unwrap();
+
+        Vault::adopt_into(&file(new.path()), &legacy).unwrap();
+        assert_eq!(file(new.path()).load_secret().unwrap(), vec![5u8; 32]);
+        assert!(is_not_found(&legacy[0].load_secret().unwrap_err()), "the old copy is cleared once moved");
+
+        // A target that can't take it refuses instead of leaving a new one to be generated.
+        legacy[0].store_secret(&[6u8; 32]).unwrap();
+        let unwritable = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::SecretsDir { path: new.path().join("ro") });
+        let err = Vault::adopt_into(&unwritable, &legacy).unwrap_err();
+        assert!(err.to_string().contains("can't be moved"), "{}", err);
+        assert_eq!(legacy[0].load_secret().unwrap(), vec![6u8; 32]);
+
+        // So does a legacy copy that can't be read, and a target with a secret is left alone.
+        fs::write(legacy[0].file_path().unwrap(), b"short").unwrap();
+        let empty = tempfile::tempdir().unwrap();
+        assert!(Vault::adopt_into(&file(empty.path()), &legacy).unwrap_err().to_string().contains("can't be read"));
+        Vault::adopt_into(&file(new.path()), &legacy).unwrap();
+        assert_eq!(file(new.path()).load_secret().unwrap(), vec![5u8; 32]);
+    }
+
+    #[test]
+    fn lenient_policy_falls_back_to_next_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = unwritable_then_file(dir.path());
+        let bytes = Vault::load_or_store_secret_in(None, &vaults, INSECURE_OK, || vec![9u8; 32]).unwrap();
+        assert_eq!(*bytes, vec![9u8; 32]);
+        assert_eq!(vaults[1].load_secret().unwrap(), vec![9u8; 32]);
+    }
+
+    #[test]
+    fn strict_policy_refuses_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = unwritable_then_file(dir.path());
+        let res = Vault::load_or_store_secret_in(None, &vaults, VaultPolicy { strict: true, ..Default::default() }, || vec![9u8; 32]);
+        assert!(res.is_err());
+        assert!(vaults[1].load_secret().is_err());
+    }
+
+    #[test]
+    fn keyring_write_failure_fails_over_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = unwritable_then_file(dir.path());
+        Vault::store_with_failover_in(&vaults, INSECURE_OK, b"renewed-token").unwrap();
+        assert_eq!(vaults[1].load_secret().unwrap(), b"renewed-token");
+        assert!(degraded());
+        assert!(Vault::store_with_failover_in(&vaults, VaultPolicy { strict: true, ..Default::default() }, b"again").is_err());
+    }
+
+    #[test]
+    fn failover_store_leaves_a_single_copy() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let vaults = vec![
+            Vault::with_backend("kmp-pea", "trust-ack-jwt", VaultBackend::File).in_dir(a.path()),
+            Vault::with_backend("kmp-pea", "trust-ack-jwt", VaultBackend::File).in_dir(b.path()),
+        ];
+        vaults[1].store_secret(b"stale").unwrap();
+        Vault::store_with_failover_in(&vaults, VaultPolicy::default(), b"fresh").unwrap();
+        assert_eq!(vaults[0].load_secret().unwrap(), b"fresh");
+        assert!(vaults[1].load_secret().is_err());
+    }
+
+    #[test]
+    fn weak_file_fallback_needs_explicit_opt_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = unwritable_then_file(dir.path());
+        let err = Vault::load_or_store_secret_in(None, &vaults, VaultPolicy::default(), || vec![9u8; 32]).unwrap_err();
+        assert!(err.to_string().contains("--allow-insecure-vault"));
+        assert!(Vault::store_with_failover_in(&vaults, VaultPolicy::default(), b"token").is_err());
+        assert!(vaults[1].load_secret().is_err());
+
+        assert_eq!(Vault::load_or_store_secret_in(None, &vaults, INSECURE_OK, || vec![9u8; 32]).unwrap().to_vec(), vec![9u8; 32]);
+    }
+
+    #[test]
+    fn explicitly_selected_file_backend_needs_no_opt_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = vec![Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path())];
+        assert!(Vault::load_or_store_secret_in(None, &vaults, VaultPolicy::default(), || vec![9u8; 32]).is_ok());
+    }
+
+    #[test]
+    fn transient_keyring_error_fails_without_generating() {
+        use keyring::mock::MockCredential;
+        let cred = keyring::mock::default_credential_builder().build(None, "kmp-pea", "device-ed25519-sk").unwrap();
+        let entry = Entry::new_with_credential(cred);
+        entry.set_password(&general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+        let mock: &MockCredential = entry.get_credential().downcast_ref().unwrap();
+        mock.set_error(keyring::Error::PlatformFailure("errSecInteractionNotAllowed".into()));
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = vec![
+            Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::OsKeyring).with_keyring_entry(entry),
+            Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path()),
+        ];
+
+        let started = std::time::Instant::now();
+        let err = Vault::load_or_store_secret_in(None, &vaults, INSECURE_OK, || {
+            panic!("a busy keyring must not lead to a new identity")
+        }).unwrap_err();
+        assert!(is_transient(&err), "{}", err);
+        assert!(started.elapsed() < std::time::Duration::from_millis(100), "no sleeping on the hot path");
+        assert!(vaults[1].load_secret().is_err(), "nothing generated into the fallback vault");
+
+        // The next attempt finds the keyring free and the original identity.
+        let bytes = Vault::load_or_store_secret_in(None, &vaults, INSECURE_OK, || panic!("still the original key")).unwrap();
+        assert_eq!(*bytes, vec![7u8; 32]);
+    }
+
+    #[test]
+    fn installer_secret_keys_the_file_vault_with_argon2id() {
+        let dir = tempfile::tempdir().unwrap();
+        let v = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path()).with_installer_secret(b"from the installer");
+        v.store_secret(b"device key").unwrap();
+        let stored = fs::read(v.file_path().unwrap()).unwrap();
+        let salt = fs::read(dir.path().join(SALT_FILE)).unwrap();
+        assert_eq!(&stored[..5], b"PEAV\x03");
+        assert_eq!(&stored[5..5 + SALT_LEN], salt.as_slice(), "the device salt is stored alongside the ciphertext");
+        assert_eq!(v.load_secret().unwrap(), b"device key");
+
+        v.store_secret(b"rotated").unwrap();
+        assert_eq!(fs::read(dir.path().join(SALT_FILE)).unwrap(), salt, "the salt is generated once per device");
+
+        let wrong = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path()).with_installer_secret(b"guessed");
+        assert!(wrong.load_secret().unwrap_err().to_string().contains("decrypt failed"));
+    }
+
+    #[test]
+    fn undecryptable_vault_files_are_never_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyed = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path()).with_installer_secret(b"from the installer");
+        keyed.store_secret(b"device key").unwrap();
+        let sealed = fs::read(keyed.file_path().unwrap()).unwrap();
+        let never = || -> Vec<u8> { panic!("an unreadable identity must not be replaced") };
+
+        // PEA_INSTALLER_SECRET unset (this vault has none), then a wrong one.
+        let unset = vec![Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path())];
+        let err = Vault::load_or_store_secret_in(None, &unset, INSECURE_OK, never).unwrap_err();
+        assert!(err.to_string().contains("PEA_INSTALLER_SECRET"), "{}", err);
+        let wrong = vec![Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path()).with_installer_secret(b"guessed")];
+        assert!(Vault::load_or_store_secret_in(None, &wrong, INSECURE_OK, never).unwrap_err().to_string().contains("decrypt failed"));
+
+        // A file copied from another account fails its associated data check.
+        let other = vec![Vault::with_backend("kmp-pea", "trust-ack-jwt", VaultBackend::File).in_dir(dir.path()).with_installer_secret(b"from the installer")];
+        fs::write(other[0].file_path().unwrap(), &sealed).unwrap();
+        assert!(Vault::load_or_store_secret_in(None, &other, INSECURE_OK, never).unwrap_err().to_string().contains("decrypt failed"));
+
+        assert_eq!(fs::read(keyed.file_path().unwrap()).unwrap(), sealed, "the vault file is untouched");
+        assert!(is_not_found(&Vault::with_backend("kmp-pea", "absent", VaultBackend::File).in_dir(dir.path()).load_secret().unwrap_err()));
+    }
+
+    #[test]
+    fn legacy_file_vault_entries_move_to_the_installer_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path());
+        fs::write(legacy.file_path().unwrap(), Vault::seal(&Vault::host_key(), b"old secret", b"").unwrap()).unwrap();
+
+        let v = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path()).with_installer_secret(b"from the installer");
+        assert_eq!(v.load_secret().unwrap(), b"old secret");
+        assert_eq!(&fs::read(v.file_path().unwrap()).unwrap()[..4], FILE_MAGIC, "re-sealed under the installer secret");
+        assert_eq!(v.load_secret().unwrap(), b"old secret");
+    }
+
+    #[test]
+    fn swapped_vault_files_fail_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let sk = Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path());
+        let jwt = Vault::with_backend("kmp-pea", "trust-ack-jwt", VaultBackend::File).in_dir(dir.path());
+        sk.store_secret(b"signing key").unwrap();
+        jwt.store_secret(b"a.b.c").unwrap();
+        let (sk_bytes, jwt_bytes) = (fs::read(sk.file_path().unwrap()).unwrap(), fs::read(jwt.file_path().unwrap()).unwrap());
+        fs::write(sk.file_path().unwrap(), &jwt_bytes).unwrap();
+        fs::write(jwt.file_path().unwrap(), &sk_bytes).unwrap();
+        assert!(sk.load_secret().unwrap_err().to_string().contains("decrypt failed"));
+        assert!(jwt.load_secret().unwrap_err().to_string().contains("decrypt failed"));
+
+        // Headerless files from before associated data still load, and are upgraded.
+        fs::write(sk.file_path().unwrap(), Vault::seal(&Vault::host_key(), b"signing key", b"").unwrap()).unwrap();
+        assert_eq!(sk.load_secret().unwrap(), b"signing key");
+        assert_eq!(&fs::read(sk.file_path().unwrap()).unwrap()[..5], b"PEAV\x01");
+    }
+
+    #[test]
+    fn loaded_secrets_and_keys_come_back_zeroizing() {
+        // Dropping a `Zeroizing` wipes it; pinning the types keeps a refactor
+        // from quietly handing out plain buffers.
+        type Secret = Result<Zeroizing<Vec<u8>>>;
+        let _: fn() -> Result<Zeroizing<[u8; 32]>> = Vault::file_key;
+        let _: fn(&str, &str) -> Secret = Vault::load_secret_auto;
+        let _: fn(Option<&Vault>, &[Vault], VaultPolicy) -> Secret = Vault::load_secret_in;
+        let _: fn(&[u8]) -> Zeroizing<[u8; 32]> = crate::queue::derive_key;
+
+        let dir = tempfile::tempdir().unwrap();
+        let vaults = vec![Vault::with_backend("kmp-pea", "device-ed25519-sk", VaultBackend::File).in_dir(dir.path())];
+        let secret: Zeroizing<Vec<u8>> = Vault::load_or_store_secret_in(None, &vaults, INSECURE_OK, || vec![5u8; 32]).unwrap();
+        assert_eq!(*secret, vec![5u8; 32]);
+    }
+
+    #[test]
+    fn keyring_delete_removes_the_entry() {
+        use keyring::mock::MockCredential;
+        let cred = keyring::mock::default_credential_builder().build(None, "kmp-pea", "trust-ack-jwt").unwrap();
+        let v = Vault::with_backend("kmp-pea", "trust-ack-jwt", VaultBackend::OsKeyring).with_keyring_entry(Entry::new_with_credential(cred));
+        v.store_secret(b"a.b.c").unwrap();
+        v.delete_secret().unwrap();
+        let err = v.load_secret().unwrap_err();
+        assert!(is_not_found(&err), "{}", err);
+        v.delete_secret().unwrap();
+
+        // A store that refuses deletes is blanked, which still reads as absent.
+        v.store_secret(b"a.b.c").unwrap();
+        let entry = v.keyring_entry().unwrap();
+        let mock: &MockCredential = entry.get_credential().downcast_ref().unwrap();
+        mock.set_error(keyring::Error::Invalid("delete".into(), "unsupported".into()));
+        v.delete_secret().unwrap();
+        assert_eq!(entry.get_password().unwrap(), "");
+        assert!(is_not_found(&v.load_secret().unwrap_err()));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::shutdown::Shutdown;
+use std::time::Duration;
+
+/// How long the service manager should wait for a stop: the run loop only
+/// stops between iterations, and a drain can take a while to finish.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(30);
+
+/// The states the agent reports to the service control manager.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    StartPending,
+    Running,
+    StopPending,
+    Stopped,
+}
+
+/// The control requests the agent acts on; the rest are refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Stop,
+    /// The machine is shutting down.
+    Shutdown,
+    Interrogate,
+    Other,
+}
+
+/// One status report for the service control manager.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub state: State,
+    /// Whether stop and shutdown requests are accepted in this state.
+    pub accepts_stop: bool,
+    pub exit_code: u32,
+    pub checkpoint: u32,
+    pub wait_hint: Duration,
+}
+
+/// How a control request was handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// Handled; report this status.
+    Report(Status),
+    /// Handled; the last reported status still holds.
+    Ack,
+    NotImplemented,
+}
+
+/// The service's side of the start/stop protocol, kept apart from the service
+/// manager so it can be driven without one. A stop requests the graceful
+/// shutdown the `run` loop already honours for SIGTERM.
+#[derive(Debug)]
+pub struct Lifecycle {
+    state: State,
+    exit_code: u32,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self { state: State::StartPending, exit_code: 0 }
+    }
+}
+
+impl Lifecycle {
+    pub fn status(&self) -> Status {
+        let pending = matches!(self.state, State::StartPending | State::StopPending);
+        Status {
+            state: self.state,
+            accepts_stop: self.state == State::Running,
+            exit_code: self.exit_code,
+            checkpoint: u32::from(pending),
+            wait_hint: if pending { STOP_WAIT_HINT } else { Duration::ZERO },
+        }
+    }
+
+    /// The run loop is up. A stop that arrived while starting still stands.
+    pub fn running(&mut self) -> Status {
+        if self.state == State::StartPending { self.state = State::Running; }
+        self.status()
+    }
+
+    pub fn control(&mut self, control: Control, shutdown: &Shutdown) -> Reply {
+        match control {
+            Control::Stop | Control::Shutdown => match self.state {
+                State::StartPending | State::Running => {
+                    self.state = State::StopPending;
+                    shutdown.request();
+                    Reply::Report(self.status())
+                }
+                State::StopPending | State::Stopped => Reply::Ack,
+            },
+            Control::Interrogate => Reply::Ack,
+            Control::Other => Reply::NotImplemented,
+        }
+    }
+
+    /// The run loop returned; a failure becomes a service-specific exit code
+    /// so the service manager's recovery actions apply.
+    pub fn stopped(&mut self, result: &anyhow::Result<()>) -> Status {
+        self.state = State::Stopped;
+        self.exit_code = u32::from(result.is_err());
+        self.status()
+    }
+}
+
+#[cfg(all(windows, feature = "windows-service"))]
+pub use scm::{dispatch, install, uninstall};
+
+#[cfg(all(windows, feature = "windows-service"))]
+mod scm {
+    use super::{Control, Lifecycle, Reply, Status};
+    use anyhow::{Result, anyhow};
+    use std::{ffi::OsString, path::PathBuf, sync::{Arc, Mutex}};
+    use windows_service::{
+        define_windows_service,
+        service::{ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+            ServiceStartType, ServiceState, ServiceStatus, ServiceType},
+        service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    const SERVICE_NAME: &str = "pea-agent";
+
+    type Body = Box<dyn FnOnce() -> Result<()> + Send>;
+
+    /// The `run` invocation [`dispatch`] hands to the service thread.
+    static BODY: Mutex<Option<Body>> = Mutex::new(None);
+
+    fn report(handle: &ServiceStatusHandle, status: Status) {
+        let state = match status.state {
+            super::State::StartPending => ServiceState::StartPending,
+            super::State::Running => ServiceState::Running,
+            super::State::StopPending => ServiceState::StopPending,
+            super::State::Stopped => ServiceState::Stopped,
+        };
+        let accepted = if status.accepts_stop { ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN } else { ServiceControlAccept::empty() };
+        let exit_code = if status.exit_code == 0 { ServiceExitCode::Win32(0) } else { ServiceExitCode::ServiceSpecific(status.exit_code) };
+        let _ = handle.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: accepted,
+            exit_code,
+            checkpoint: status.checkpoint,
+            wait_hint: status.wait_hint,
+            process_id: None,
+        });
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some(body) = BODY.lock().unwrap().take() else { return };
+        let lifecycle = Arc::new(Mutex::new(Lifecycle::default()));
+        let handle = Arc::new(Mutex::new(None::<ServiceStatusHandle>));
+        let (handler_lifecycle, handler_handle) = (lifecycle.clone(), handle.clone());
+        let registered = service_control_handler::register(SERVICE_NAME, move |control| {
+            let control = match control {
+                ServiceControl::Stop => Control::Stop,
+                ServiceControl::Shutdown => Control::Shutdown,
+                ServiceControl::Interrogate => Control::Interrogate,
+                _ => Control::Other,
+            };
+            match handler_lifecycle.lock().unwrap().control(control, crate::shutdown::global()) {
+                Reply::Report(status) => {
+                    if let Some(handle) = *handler_handle.lock().unwrap() { report(&handle, status); }
+                    ServiceControlHandlerResult::NoError
+                }
+                Reply::Ack => ServiceControlHandlerResult::NoError,
+                Reply::NotImplemented => ServiceControlHandlerResult::NotImplemented,
+            }
+        });
+        let Ok(status_handle) = registered else { return };
+        *handle.lock().unwrap() = Some(status_handle);
+        report(&status_handle, lifecycle.lock().unwrap().status());
+        report(&status_handle, lifecycle.lock().unwrap().running());
+        let result = body();
+        if let Err(e) = &result { tracing::error!(error = %e, "service run loop failed"); }
+        report(&status_handle, lifecycle.lock().unwrap().stopped(&result));
+    }
+
+    /// Hand `body` (the `run` loop) to the service manager and block until it
+    /// stops the service.
+    pub fn dispatch(body: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+        *BODY.lock().unwrap() = Some(Box::new(body));
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .map_err(|e| anyhow!("not started by the service manager ({}); use `pea-agent run` from a console", e))
+    }
+
+    /// Register an auto-start service running `binary` with `args`, then start it.
+    pub fn install(binary: PathBuf, args: Vec<String>) -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "KMP PEA Agent".into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: binary,
+            launch_arguments: args.into_iter().map(OsString::from).collect(),
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)?;
+        service.set_description("Signs and submits supply-chain events for this device")?;
+        service.start(&[] as &[&str])?;
+        Ok(())
+    }
+
+    /// Stop the service if it is running and delete it.
+    pub fn uninstall() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)?;
+        if service.query_status()?.current_state != ServiceState::Stopped { service.stop()?; }
+        service.delete()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_requests_drive_the_graceful_shutdown() {
+        let shutdown = Shutdown::default();
+        let mut svc = Lifecycle::default();
+        assert_eq!((svc.status().state, svc.status().accepts_stop), (State::StartPending, false));
+        let running = svc.running();
+        assert_eq!((running.state, running.accepts_stop, running.checkpoint), (State::Running, true, 0));
+        assert_eq!(svc.control(Control::Interrogate, &shutdown), Reply::Ack);
+        assert_eq!(svc.control(Control::Other, &shutdown), Reply::NotImplemented);
+        assert!(!shutdown.requested());
+
+        let Reply::Report(stopping) = svc.control(Control::Stop, &shutdown) else { panic!("a stop is reported") };
+        assert_eq!((stopping.state, stopping.accepts_stop), (State::StopPending, false));
+        assert!(stopping.wait_hint > Duration::ZERO);
+        assert!(shutdown.requested(), "a stop asks the run loop to finish");
+        assert_eq!(svc.control(Control::Stop, &shutdown), Reply::Ack, "a repeated stop changes nothing");
+        assert_eq!(svc.stopped(&Ok(())), Status { state: State::Stopped, accepts_stop: false, exit_code: 0, checkpoint: 0, wait_hint: Duration::ZERO });
+    }
+
+    #[test]
+    fn a_stop_while_starting_is_not_lost() {
+        let shutdown = Shutdown::default();
+        let mut svc = Lifecycle::default();
+        assert!(matches!(svc.control(Control::Shutdown, &shutdown), Reply::Report(Status { state: State::StopPending, .. })));
+        assert_eq!(svc.running().state, State::StopPending);
+        assert!(shutdown.requested());
+        assert_eq!(svc.stopped(&Err(anyhow::anyhow!("vault locked"))).exit_code, 1);
+    }
+}